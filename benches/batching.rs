@@ -0,0 +1,79 @@
+//! The counter workload's throughput with the server batching its messages
+//! and without: many increments in flight at once over TLS, each answered
+//! with a response and a state change.
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_util::future::join_all;
+use hardlight::{
+    impl_state, Client, ConnectionState, Context, Handler, HandlerResult, Server, ServerConfig,
+    StateUpdateChannel,
+};
+use tokio::sync::oneshot;
+
+/// How many increments are in flight at once, short of the 256 call ids a
+/// connection has.
+const CALLS: usize = 200;
+
+#[derive(Clone, Default)]
+struct CounterState {
+    counter: u32,
+}
+
+impl_state!(CounterState { counter: u32 = 0 });
+
+struct CounterHandler {
+    state: ConnectionState<CounterState>,
+}
+
+#[async_trait]
+impl Handler for CounterHandler {
+    fn new(state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self {
+            state: ConnectionState::new(state_update_channel, CounterState::default()),
+        }
+    }
+
+    async fn handle_rpc_call(&self, _ctx: &Context, _input: &[u8]) -> HandlerResult<Vec<u8>> {
+        let mut state = self.state.lock().await;
+        state.counter = state.counter.wrapping_add(1);
+        state.commit().await?;
+        Ok(rkyv::to_bytes::<u32, 1024>(&state.counter).unwrap().to_vec())
+    }
+}
+
+fn batching(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("batching");
+    group.throughput(Throughput::Elements(CALLS as u64));
+    let variants = [("on", "localhost:8190", Some(64 * 1024)), ("off", "localhost:8191", None)];
+    for (name, address, max_batch_size) in variants {
+        // a real socket, as batching saves writes and TLS records, which an
+        // in-memory pipe doesn't have
+        let mut config = ServerConfig::new_self_signed(address);
+        config.max_batch_size = max_batch_size;
+        let server = Server::new(config, |state_update_channel, ctx| {
+            Box::new(CounterHandler::new(state_update_channel, ctx)) as Box<dyn Handler + Send + Sync>
+        });
+        let client = rt.block_on(async {
+            let (ready_tx, ready_rx) = oneshot::channel();
+            tokio::spawn(async move {
+                let _ = server.run_with_ready(ready_tx).await;
+            });
+            ready_rx.await.unwrap();
+            Client::<CounterState>::new_self_signed(address).connect().await.unwrap()
+        });
+        group.bench_function(BenchmarkId::new("increments", name), |b| {
+            b.to_async(&rt).iter(|| async {
+                let calls = (0..CALLS).map(|_| client.call(vec![]));
+                for output in join_all(calls).await {
+                    output.unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, batching);
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+//! How long a handler takes to get at the counter example's arguments, by
+//! deserializing the call and then its arguments into owned values, as
+//! handlers used to, and by reading both in place.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use hardlight::Aligned;
+use rkyv::{Archive, CheckBytes, Deserialize, Serialize};
+
+#[derive(Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes))]
+#[repr(u8)]
+enum Method {
+    Increment,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes))]
+struct IncrementArgs {
+    amount: u32,
+}
+
+#[derive(Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes))]
+struct RpcCall {
+    method: Method,
+    #[with(Aligned)]
+    args: Vec<u8>,
+}
+
+fn call_args(c: &mut Criterion) {
+    let args = rkyv::to_bytes::<_, 64>(&IncrementArgs { amount: 5 }).unwrap().to_vec();
+    let input = rkyv::to_bytes::<_, 256>(&RpcCall {
+        method: Method::Increment,
+        args,
+    })
+    .unwrap();
+
+    // deserializing copies the arguments out of the call into a Vec, and then
+    // the fields out of that. Reading in place copies nothing.
+    let call = rkyv::check_archived_root::<RpcCall>(&input).unwrap();
+    let copied = call.args.len() + std::mem::size_of::<IncrementArgs>();
+    println!("bytes copied per call: owned {copied}, archived 0");
+
+    let mut group = c.benchmark_group("call_args");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("owned", |b| {
+        b.iter(|| {
+            let call: RpcCall = rkyv::from_bytes(black_box(&input)).unwrap();
+            let args: IncrementArgs = rkyv::from_bytes(&call.args).unwrap();
+            black_box(args.amount)
+        })
+    });
+    group.bench_function("archived", |b| {
+        b.iter(|| {
+            let call = rkyv::check_archived_root::<RpcCall>(black_box(&input)).unwrap();
+            let args = rkyv::check_archived_root::<IncrementArgs>(&call.args).unwrap();
+            black_box(args.amount)
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, call_args);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+//! How fast RPC responses are serialized for sending, by copying them out of
+//! an [AlignedVec](rkyv::AlignedVec), as the send paths used to, and as the
+//! server serializes them now.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use hardlight::{Codec, RkyvCodec, ServerMessage};
+
+fn response_serialization(c: &mut Criterion) {
+    let mut group = c.benchmark_group("response_serialization");
+    // the counter's u32, and a larger result
+    for size in [4, 16 * 1024] {
+        let msg = ServerMessage::RPCResponse {
+            id: 0,
+            output: Ok(vec![7; size]),
+        };
+        let len = RkyvCodec.encode_server(&msg).unwrap().len();
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new("to_vec", size), &msg, |b, msg| {
+            b.iter(|| rkyv::to_bytes::<_, 1024>(black_box(msg)).unwrap().to_vec())
+        });
+        group.bench_with_input(BenchmarkId::new("direct", size), &msg, |b, msg| {
+            b.iter(|| RkyvCodec.encode_server(black_box(msg)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, response_serialization);
+criterion_main!(benches);
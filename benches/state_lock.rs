@@ -0,0 +1,66 @@
+//! What it costs to lock a state with a 10k-element Vec in it, change a
+//! small field and unlock it, with a [ConnectionState], which clones the
+//! state to compare against, and with a [TrackedState], which doesn't.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use hardlight::{state_diff, track_changes, Changed, ConnectionState, FieldId, TrackedState};
+
+const ITEMS: FieldId = 0;
+const REVISION: FieldId = 1;
+
+const INVENTORY_SIZE: u32 = 10_000;
+
+#[derive(Clone)]
+struct PlainInventory {
+    items: Vec<u32>,
+    revision: u32,
+}
+
+state_diff!(PlainInventory { items = ITEMS, revision = REVISION });
+
+#[derive(Default)]
+struct TrackedInventory {
+    items: Changed<Vec<u32>>,
+    revision: Changed<u32>,
+}
+
+track_changes!(TrackedInventory { items = ITEMS, revision = REVISION });
+
+fn state_lock(c: &mut Criterion) {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("state_lock");
+
+    let (channel, mut changes) = tokio::sync::mpsc::channel(1);
+    let cloned = ConnectionState::new(
+        channel,
+        PlainInventory {
+            items: (0..INVENTORY_SIZE).collect(),
+            revision: 0,
+        },
+    );
+    group.bench_function("cloned", |b| {
+        b.iter(|| {
+            rt.block_on(cloned.lock()).revision += 1;
+            changes.try_recv().unwrap();
+        })
+    });
+
+    let (channel, mut changes) = tokio::sync::mpsc::channel(1);
+    let tracked = TrackedState::new(
+        channel,
+        TrackedInventory {
+            items: Changed::new((0..INVENTORY_SIZE).collect()),
+            revision: Changed::default(),
+        },
+    );
+    group.bench_function("tracked", |b| {
+        b.iter(|| {
+            *rt.block_on(tracked.lock()).revision += 1;
+            changes.try_recv().unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, state_lock);
+criterion_main!(benches);
@@ -0,0 +1,90 @@
+// A minimal HardLight client for browsers, for servers that use
+// `JsonTextCodec`. Messages are JSON in text frames, as described in the
+// README. The payloads in them are still whatever the application encodes
+// them as, e.g. rkyv, so they're handed over as bytes.
+
+/** An error from the other side, e.g. `"Unauthorized"` or `{ RateLimited: { retry_after_ms: 500 } }`. */
+export type RpcError = string | { [variant: string]: unknown };
+
+/** A call's output, with the payload in base64. */
+export type Output = { Ok: string } | { Err: RpcError };
+
+export type ServerMessage =
+  | { RPCResponse: { id: number; output: Output } }
+  | { RPCRequest: { id: number; internal: string } }
+  | { NewEvent: { topic: string; event: string } }
+  | { RoomEvent: { room: string; event: string } }
+  | { StateChange: [number, string][] }
+  | { RPCStreamItem: { id: number; payload: string } }
+  | { RPCStreamEnd: { id: number } }
+  | { Batch: string[] };
+
+/** The HardLight wire protocol version this client speaks, `PROTOCOL_VERSION` in the crate. */
+export const PROTOCOL_VERSION = 5;
+
+const toBase64 = (bytes: Uint8Array) => btoa(String.fromCharCode(...bytes));
+const fromBase64 = (text: string) => Uint8Array.from(atob(text), (c) => c.charCodeAt(0));
+
+type PendingCall = { resolve: (output: Uint8Array) => void; reject: (error: RpcError) => void };
+
+export class HardlightConnection {
+  /** The latest value of each state field, by field id. */
+  readonly state = new Map<number, Uint8Array>();
+  /** Called with the ids of the fields that changed. */
+  onStateChange?: (fields: number[]) => void;
+
+  private readonly pending = new Map<number, PendingCall>();
+
+  private constructor(private readonly socket: WebSocket) {
+    socket.onmessage = (event) => this.handle(JSON.parse(event.data));
+    socket.onclose = () => {
+      for (const call of this.pending.values()) call.reject("ClientNotConnected");
+      this.pending.clear();
+    };
+  }
+
+  /** Connects to `url`, e.g. `wss://example.com/`, speaking HardLight protocol `version`. */
+  static connect(url: string, version = PROTOCOL_VERSION): Promise<HardlightConnection> {
+    // browsers don't allow a `/` in a subprotocol, so it's e.g. `hl.5+json-text`
+    const socket = new WebSocket(url, `hl.${version}+json-text`);
+    return new Promise((resolve, reject) => {
+      socket.onopen = () => resolve(new HardlightConnection(socket));
+      socket.onerror = () => reject(new Error(`failed to connect to ${url}`));
+    });
+  }
+
+  /** Calls a method, with the method and arguments encoded by the application. */
+  call(internal: Uint8Array): Promise<Uint8Array> {
+    let id = 0;
+    while (this.pending.has(id)) id++;
+    if (id > 255) return Promise.reject("TooManyCallsInFlight");
+    return new Promise((resolve, reject) => {
+      this.pending.set(id, { resolve, reject });
+      this.socket.send(JSON.stringify({ RPCRequest: { id, internal: toBase64(internal) } }));
+    });
+  }
+
+  close() {
+    this.socket.close();
+  }
+
+  private handle(msg: ServerMessage) {
+    if ("Batch" in msg) {
+      // each message in a batch is JSON of its own, in base64
+      for (const inner of msg.Batch) this.handle(JSON.parse(new TextDecoder().decode(fromBase64(inner))));
+    } else if ("StateChange" in msg) {
+      for (const [field, value] of msg.StateChange) this.state.set(field, fromBase64(value));
+      this.onStateChange?.(msg.StateChange.map(([field]) => field));
+    } else if ("RPCResponse" in msg) {
+      const { id, output } = msg.RPCResponse;
+      const call = this.pending.get(id);
+      this.pending.delete(id);
+      if ("Ok" in output) call?.resolve(fromBase64(output.Ok));
+      else call?.reject(output.Err);
+    } else if ("RPCRequest" in msg) {
+      // this client doesn't handle calls from the server
+      const { id } = msg.RPCRequest;
+      this.socket.send(JSON.stringify({ RPCResponse: { id, output: { Err: "Unsupported" } } }));
+    }
+  }
+}
@@ -0,0 +1,32 @@
+use crate::context::Context;
+
+/// Checks the bearer token a client connects with, for deployments that
+/// can't use client certificates. Set one with
+/// [ServerConfig::token_validator](crate::ServerConfig::token_validator), and
+/// give clients their token with [Client::set_token](crate::Client::set_token).
+///
+/// The token is checked during the WebSocket upgrade, so a client with a
+/// missing or invalid token is turned away with HTTP 401 and never connects.
+///
+/// ```ignore
+/// struct Tokens;
+///
+/// impl TokenValidator for Tokens {
+///     fn validate(&self, token: &str, ctx: &Context) -> bool {
+///         let Some(claims) = verify_jwt(token) else { return false };
+///         ctx.grant_scope("counter.write");
+///         ctx.extensions().insert(claims);
+///         true
+///     }
+/// }
+/// ```
+pub trait TokenValidator: Send + Sync {
+    /// Returns whether `token` lets the client connect. Attach what the token
+    /// proves to `ctx`, e.g. its claims as an extension or the scopes it
+    /// grants, for calls to check later. This runs before the handler is
+    /// created, so the handler factory and [Handler::new](crate::Handler::new)
+    /// can read them too, and a client that's turned away never gets one.
+    ///
+    /// This runs during the handshake, so it must not block.
+    fn validate(&self, token: &str, ctx: &Context) -> bool;
+}
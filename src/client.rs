@@ -1,54 +1,750 @@
-use std::{str::FromStr, sync::Arc, time::SystemTime};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use futures_util::{SinkExt, StreamExt};
+use async_trait::async_trait;
+use futures_util::{
+    stream::{FuturesUnordered, SplitSink},
+    SinkExt, StreamExt,
+};
 use rustls_native_certs::load_native_certs;
 use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpStream},
     select,
-    sync::{mpsc, oneshot},
+    sync::{broadcast, mpsc, oneshot, watch},
+    task::JoinHandle,
 };
-use tokio_rustls::rustls::{
-    client::{ServerCertVerified, ServerCertVerifier},
-    Certificate, ClientConfig as TLSClientConfig, RootCertStore, ServerName,
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::{
+    rustls::{
+        client::{ServerCertVerified, ServerCertVerifier},
+        Certificate, ClientConfig as TLSClientConfig, RootCertStore, ServerName,
+    },
+    TlsConnector,
 };
 use tokio_tungstenite::{
-    connect_async_tls_with_config,
+    client_async_with_config,
     tungstenite::{
-        error::ProtocolError,
-        handshake::client::generate_key,
-        http::{HeaderValue, Request},
-        Error, Message,
+        handshake::client::{generate_key, Response},
+        http::{header::AUTHORIZATION, HeaderName, HeaderValue, Request},
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Message,
     },
-    Connector,
+    tungstenite, MaybeTlsStream, WebSocketStream,
 };
-use tracing::{debug, error, span, warn, Level};
-use version::Version;
+use tracing::{debug, error, field, span, warn, Level};
 
 use crate::{
-    server::{HandlerResult, HL_VERSION},
-    wire::{ClientMessage, RpcHandlerError, ServerMessage},
+    codec::{client_message_from_rkyv, Codec, RkyvCodec},
+    deflate::{self, CompressionConfig, Deflate, Side},
+    error::ConnectError,
+    field_sync::LAZY_FIELDS_HEADER,
+    method_stats::MethodStats,
+    outbox::{outbox, Outbox, OutboxQueue},
+    proxy::ProxyConfig,
+    query,
+    retry::{RetryClassifier, RetryDecision},
+    protocol::{parse_offers, parse_protocols, protocol_list, protocol_name},
+    server::{HandlerResult, CODEC_HEADER, FULL_VERSION_HEADER, HL_VERSION, PROTOCOL_VERSION, SUPPORTED_VERSIONS_HEADER},
+    socket::set_linger,
+    stats::ClientStats,
+    streaming::{forward_stream, StreamFeedback, STREAM_WINDOW},
+    wire::{to_message_bytes, BatchedCall, ClientMessage, FieldId, FieldSync, RpcHandlerError, ServerMessage, TransactionId, TransactionOp},
 };
+#[cfg(feature = "trace-context")]
+use crate::trace_context::new_traceparent;
+
+/// How long [Client::connect] waits for the server by default.
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the client waits on one of a host's addresses before trying the
+/// next as well, the delay RFC 8305 recommends.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// How long a closing connection waits for queued messages to be written.
+const WRITER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct ClientConfig {
     tls: TLSClientConfig,
     host: String,
+    ws_config: WebSocketConfig,
+    linger: Option<Duration>,
+    connect_timeout: Duration,
+    retry_classifier: Option<Arc<dyn RetryClassifier>>,
+    stats: Arc<ClientStats>,
+    lazy_fields: Vec<FieldId>,
+    token: Option<String>,
+    unix_socket: Option<PathBuf>,
+    proxy: Option<ProxyConfig>,
+    fallback_hosts: Vec<String>,
+    path: String,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    query: Vec<(String, String)>,
+    on_state_error: Option<StateErrorHook>,
+    codec: Arc<dyn Codec>,
+    compression: Option<CompressionConfig>,
+}
+
+/// Where the connection loop sends the result of a call made through
+/// [ControlChannels].
+pub type ResponseSender = oneshot::Sender<HandlerResult<Vec<u8>>>;
+
+/// Where the connection loop sends the chunks of a streaming call. See
+/// [ControlChannels::stream_tx].
+pub type StreamSubscriber = mpsc::Sender<HandlerResult<Vec<u8>>>;
+
+/// Told when a batch of state changes can't be applied. See
+/// [Client::set_on_state_error].
+pub type StateErrorHook = Arc<dyn Fn(&RpcHandlerError) + Send + Sync>;
+
+/// Channels the application uses to talk to a connected [Client]'s connection
+/// loop. These are sent to the application once the client has connected.
+pub struct ControlChannels<T> {
+    /// Sends RPC calls to the server. The result is sent back on the oneshot.
+    pub rpc_tx: mpsc::Sender<(Vec<u8>, ResponseSender)>,
+    /// Sends RPC calls to the server along with how long the application
+    /// waits for them. See [ControlChannels::call_with_timeout].
+    pub timed_rpc_tx: mpsc::Sender<(Vec<u8>, Duration, ResponseSender)>,
+    /// Sends streaming RPC calls to the server. The chunks are sent to the
+    /// given channel, which is closed when the stream ends. Dropping the
+    /// receiver cancels the call.
+    pub stream_tx: mpsc::Sender<(Vec<u8>, StreamSubscriber)>,
+    /// Sends several RPC calls to the server in one frame. Each result is
+    /// sent back on its own oneshot. See [ControlChannels::call_batch].
+    pub batch_tx: mpsc::Sender<Vec<(Vec<u8>, ResponseSender)>>,
+    /// Requests the server's [MethodStats]. The serialized stats are sent
+    /// back on the oneshot. See [ControlChannels::method_stats].
+    pub stats_tx: mpsc::Sender<ResponseSender>,
+    /// Begins, commits or rolls back a transaction. The result is sent back
+    /// on the oneshot. See [ControlChannels::begin_transaction].
+    pub transaction_tx: mpsc::Sender<(TransactionOp, ResponseSender)>,
+    /// Sends fire-and-forget events to the server. Unlike RPC calls, these
+    /// don't take up an RPC id and have no response.
+    pub event_tx: mpsc::Sender<Vec<u8>>,
+    /// Registers a subscriber for a topic. Events published to the topic are
+    /// sent to the given channel until it is dropped.
+    pub subscribe_tx: mpsc::Sender<(String, mpsc::Sender<Vec<u8>>)>,
+    /// Changes whether the server pushes a state field as it changes. See
+    /// [ControlChannels::set_field_sync].
+    pub field_sync_tx: mpsc::Sender<(FieldId, FieldSync)>,
+    /// Fetches the current value of lazy state fields. The result is sent
+    /// back on the oneshot. See [ControlChannels::fetch_fields].
+    pub fetch_tx: mpsc::Sender<(Vec<FieldId>, ResponseSender)>,
+    /// The connection state, kept up to date by the connection loop.
+    pub state: StateHandle<T>,
+    room_events: broadcast::Sender<(String, Vec<u8>)>,
+    server_version: Option<Arc<str>>,
+    protocol_version: Arc<str>,
+    server_host: Arc<str>,
+    server_addr: Option<SocketAddr>,
+    stats: Arc<ClientStats>,
+}
+
+// derive(Clone) would require T: Clone
+impl<T> Clone for ControlChannels<T> {
+    fn clone(&self) -> Self {
+        Self {
+            rpc_tx: self.rpc_tx.clone(),
+            timed_rpc_tx: self.timed_rpc_tx.clone(),
+            stream_tx: self.stream_tx.clone(),
+            batch_tx: self.batch_tx.clone(),
+            stats_tx: self.stats_tx.clone(),
+            transaction_tx: self.transaction_tx.clone(),
+            event_tx: self.event_tx.clone(),
+            subscribe_tx: self.subscribe_tx.clone(),
+            field_sync_tx: self.field_sync_tx.clone(),
+            fetch_tx: self.fetch_tx.clone(),
+            state: self.state.clone(),
+            room_events: self.room_events.clone(),
+            server_version: self.server_version.clone(),
+            protocol_version: self.protocol_version.clone(),
+            server_host: self.server_host.clone(),
+            server_addr: self.server_addr,
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<T> ControlChannels<T> {
+    /// Makes an RPC call and waits for its output. State changes the handler
+    /// sent before returning have already been applied to [Self::state] by
+    /// the time this returns.
+    pub async fn call(&self, input: Vec<u8>) -> HandlerResult<Vec<u8>> {
+        let started = Instant::now();
+        let _in_flight = self.stats.start_call();
+        let (tx, rx) = oneshot::channel();
+        let output = match self.rpc_tx.send((input, tx)).await {
+            Ok(()) => {
+                self.stats.record_queue_wait(started.elapsed());
+                rx.await.map_err(|_| RpcHandlerError::ClientNotConnected)?
+            }
+            Err(_) => Err(RpcHandlerError::ClientNotConnected),
+        };
+        self.stats.record_call(started.elapsed(), output.is_err());
+        output
+    }
+
+    /// Makes an RPC call, giving up with [RpcHandlerError::Timeout] if the
+    /// output hasn't arrived within `timeout`. The server is told the
+    /// deadline too, so the handler can see it in its
+    /// [CallContext](crate::CallContext), and stops the handler if it's still
+    /// running then. An output that arrives too late is dropped.
+    pub async fn call_with_timeout(&self, input: Vec<u8>, timeout: Duration) -> HandlerResult<Vec<u8>> {
+        let started = Instant::now();
+        let _in_flight = self.stats.start_call();
+        let (tx, rx) = oneshot::channel();
+        let output = match self.timed_rpc_tx.send((input, timeout, tx)).await {
+            Ok(()) => {
+                self.stats.record_queue_wait(started.elapsed());
+                match tokio::time::timeout(timeout.saturating_sub(started.elapsed()), rx).await {
+                    Ok(output) => output.unwrap_or(Err(RpcHandlerError::ClientNotConnected)),
+                    Err(_) => Err(RpcHandlerError::Timeout),
+                }
+            }
+            Err(_) => Err(RpcHandlerError::ClientNotConnected),
+        };
+        self.stats.record_call(started.elapsed(), output.is_err());
+        output
+    }
+
+    /// Makes several RPC calls at once, sent to the server in a single frame
+    /// to save the framing and writes of sending them one at a time. The
+    /// server starts them in order but runs them concurrently, like any other
+    /// calls, so one that depends on another's result should go in a later
+    /// batch. The outputs are returned in the same order as `inputs`. A call
+    /// fails with [RpcHandlerError::TooManyCallsInFlight] if there was no id
+    /// left for it.
+    pub async fn call_batch(&self, inputs: Vec<Vec<u8>>) -> Vec<HandlerResult<Vec<u8>>> {
+        let started = Instant::now();
+        let in_flight: Vec<_> = inputs.iter().map(|_| self.stats.start_call()).collect();
+        let (calls, receivers): (Vec<_>, Vec<_>) = inputs
+            .into_iter()
+            .map(|input| {
+                let (tx, rx) = oneshot::channel();
+                ((input, tx), rx)
+            })
+            .unzip();
+        let sent = self.batch_tx.send(calls).await.is_ok();
+        if sent {
+            self.stats.record_queue_wait(started.elapsed());
+        }
+        let mut outputs = Vec::with_capacity(receivers.len());
+        for rx in receivers {
+            let output = match sent {
+                true => rx.await.unwrap_or(Err(RpcHandlerError::ClientNotConnected)),
+                false => Err(RpcHandlerError::ClientNotConnected),
+            };
+            self.stats.record_call(started.elapsed(), output.is_err());
+            outputs.push(output);
+        }
+        drop(in_flight);
+        outputs
+    }
+
+    /// The client's call statistics. See [ClientStats].
+    pub fn stats(&self) -> &ClientStats {
+        &self.stats
+    }
+
+    /// Returns a receiver for events broadcast to the rooms the server has put
+    /// this connection in, as `(room, event)` pairs. Only events broadcast
+    /// after this is called are received.
+    pub fn room_events(&self) -> broadcast::Receiver<(String, Vec<u8>)> {
+        self.room_events.subscribe()
+    }
+
+    /// The server's full HardLight version, e.g. `0.2.0`, as it reported in
+    /// the handshake. Only the [PROTOCOL_VERSION] has to match ours, so this
+    /// helps diagnose subtler mismatches. `None` if the server didn't report it.
+    pub fn server_version(&self) -> Option<&str> {
+        self.server_version.as_deref()
+    }
+
+    /// The HardLight subprotocol negotiated with the server, e.g. `hl/5`:
+    /// the highest one both sides speak.
+    pub fn protocol_version(&self) -> &str {
+        &self.protocol_version
+    }
+
+    /// The host the client connected to, which is one of its
+    /// [fallback hosts](Client::set_fallback_hosts) if the first couldn't be
+    /// reached.
+    pub fn server_host(&self) -> &str {
+        &self.server_host
+    }
+
+    /// The address the client connected to, out of those its host resolved
+    /// to, or the proxy's if it connected through one. `None` if it didn't
+    /// connect over TCP, e.g. over a Unix socket.
+    pub fn server_addr(&self) -> Option<SocketAddr> {
+        self.server_addr
+    }
+
+    /// Fetches call statistics for every RPC method on the server. Fails with
+    /// [RpcHandlerError::Unauthorized] if the server doesn't let this client
+    /// see them.
+    pub async fn method_stats(&self) -> HandlerResult<Vec<MethodStats>> {
+        let (tx, rx) = oneshot::channel();
+        self.stats_tx
+            .send(tx)
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)?;
+        let output = rx.await.map_err(|_| RpcHandlerError::ClientNotConnected)??;
+        rkyv::from_bytes(&output).map_err(|_| RpcHandlerError::BadOutputBytes)
+    }
+
+    /// Begins a transaction, returning its id. State changes from calls made
+    /// until it's committed reach the client together on commit, and are
+    /// discarded if it's rolled back. Changes to shared state made in it only
+    /// reach other clients once it's committed. Only one transaction can be
+    /// open on a connection at a time. Fails with
+    /// [RpcHandlerError::CallsInFlight] while any of the client's calls are
+    /// running, and with [RpcHandlerError::Unsupported] if the server's
+    /// handler doesn't support transactions.
+    pub async fn begin_transaction(&self) -> HandlerResult<TransactionId> {
+        let output = self.transaction(TransactionOp::Begin).await?;
+        let id = output.try_into().map_err(|_| RpcHandlerError::BadOutputBytes)?;
+        Ok(TransactionId::from_le_bytes(id))
+    }
+
+    /// Commits the transaction with `id`. Wait for the calls made in it to
+    /// return first. If this fails, the transaction stays open.
+    pub async fn commit_transaction(&self, id: TransactionId) -> HandlerResult<()> {
+        self.transaction(TransactionOp::Commit(id)).await?;
+        Ok(())
+    }
+
+    /// Rolls back the transaction with `id`, undoing the changes made in it.
+    /// Wait for the calls made in it to return first.
+    pub async fn rollback_transaction(&self, id: TransactionId) -> HandlerResult<()> {
+        self.transaction(TransactionOp::Rollback(id)).await?;
+        Ok(())
+    }
+
+    async fn transaction(&self, op: TransactionOp) -> HandlerResult<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        self.transaction_tx
+            .send((op, tx))
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)?;
+        rx.await.map_err(|_| RpcHandlerError::ClientNotConnected)?
+    }
+
+    /// Makes a streaming RPC call, returning a receiver for its chunks. If the
+    /// call fails, the last item is the error.
+    pub async fn call_streaming(
+        &self,
+        internal: Vec<u8>,
+    ) -> HandlerResult<mpsc::Receiver<HandlerResult<Vec<u8>>>> {
+        let (tx, rx) = mpsc::channel(STREAM_WINDOW as usize);
+        self.stream_tx
+            .send((internal, tx))
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)?;
+        Ok(rx)
+    }
+
+    /// Subscribes to events published to `topic`. The subscription lasts until
+    /// the returned receiver is dropped.
+    pub async fn subscribe(&self, topic: &str) -> HandlerResult<mpsc::Receiver<Vec<u8>>> {
+        let (tx, rx) = mpsc::channel(64);
+        self.subscribe_tx
+            .send((topic.to_string(), tx))
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)?;
+        Ok(rx)
+    }
+
+    /// Changes whether the server pushes `field` to the client as it changes.
+    /// A [FieldSync::Lazy] field only changes in [Self::state] when it's
+    /// fetched with [Self::fetch_fields]. Making it eager again sends it
+    /// straight away if it changed in the meantime. To make fields lazy from
+    /// the start, use [Client::set_field_sync] instead.
+    pub async fn set_field_sync(&self, field: FieldId, sync: FieldSync) -> HandlerResult<()> {
+        self.field_sync_tx
+            .send((field, sync))
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)
+    }
+
+    /// Fetches the current value of lazy state `fields`. Fields that haven't
+    /// changed since the client last had them aren't sent again. The values
+    /// have been applied to [Self::state] by the time this returns.
+    pub async fn fetch_fields(&self, fields: &[FieldId]) -> HandlerResult<()> {
+        let fields = fields.to_vec();
+        let (tx, rx) = oneshot::channel();
+        self.fetch_tx
+            .send((fields, tx))
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)?;
+        rx.await.map_err(|_| RpcHandlerError::ClientNotConnected)??;
+        Ok(())
+    }
+}
+
+/// A cheaply cloneable, read-only view of a connection's state.
+///
+/// The connection loop is the only writer: it applies every
+/// [ServerMessage::StateChange] as it arrives, and all handles see the new
+/// state immediately afterwards. Reads take a short read lock, so they never
+/// wait on the network, but they do briefly block the connection loop from
+/// applying the next change, so keep the closures passed to
+/// [StateHandle::get_field] cheap.
+pub struct StateHandle<T> {
+    state: watch::Receiver<T>,
+}
+
+impl<T> Clone for StateHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T> StateHandle<T> {
+    /// Reads part of the current state, e.g. `state.get_field(|s| s.counter)`.
+    pub fn get_field<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.state.borrow())
+    }
+
+    /// Returns a copy of the current state.
+    pub fn snapshot(&self) -> T
+    where
+        T: Clone,
+    {
+        self.state.borrow().clone()
+    }
+
+    /// Waits until the server next changes the state. Returns
+    /// [RpcHandlerError::ClientNotConnected] once the connection has closed.
+    pub async fn changed(&mut self) -> HandlerResult<()> {
+        self.state
+            .changed()
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)
+    }
+}
+
+/// A connection to the server, returned by [Client::connect]. The connection
+/// runs in a background task, which is stopped once the last clone of the
+/// connection is dropped. Clones are cheap and share the same connection.
+pub struct Connection<T> {
+    channels: ControlChannels<T>,
+    state: watch::Receiver<T>,
+    retry_classifier: Option<Arc<dyn RetryClassifier>>,
+    task: Arc<ConnectionTask>,
+}
+
+/// Stops the connection's task when dropped.
+struct ConnectionTask {
+    shutdown: std::sync::Mutex<Option<oneshot::Sender<()>>>,
+    handle: JoinHandle<()>,
+}
+
+impl Drop for ConnectionTask {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+// derive(Clone) would require T: Clone
+impl<T> Clone for Connection<T> {
+    fn clone(&self) -> Self {
+        Self {
+            channels: self.channels.clone(),
+            state: self.state.clone(),
+            retry_classifier: self.retry_classifier.clone(),
+            task: self.task.clone(),
+        }
+    }
+}
+
+impl<T> Connection<T>
+where
+    T: State + Default + Clone + Send + Sync + 'static,
+{
+    /// Runs `client` in a background task, using `connect` to run it, and
+    /// waits until it's connected.
+    pub(crate) async fn spawn<F, Fut>(client: Client<T>, connect: F) -> Result<Self, ConnectError>
+    where
+        F: FnOnce(
+            Client<T>,
+            oneshot::Receiver<()>,
+            oneshot::Sender<ControlChannels<T>>,
+            oneshot::Sender<()>,
+        ) -> Fut,
+        Fut: Future<Output = Result<(), ConnectError>> + Send + 'static,
+    {
+        let state = client.state_changed();
+        let retry_classifier = client.config.retry_classifier.clone();
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let (control_channels_tx, control_channels_rx) = oneshot::channel();
+        let (ok_tx, ok_rx) = oneshot::channel();
+        let (error_tx, error_rx) = oneshot::channel();
+        let connection = connect(client, shutdown_rx, control_channels_tx, ok_tx);
+        let handle = tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                let _ = error_tx.send(e);
+            }
+        });
+
+        if ok_rx.await.is_err() {
+            return Err(error_rx.await.expect("client panicked while connecting"));
+        }
+        let channels = control_channels_rx
+            .await
+            .expect("client panicked while connecting");
+        Ok(Self {
+            channels,
+            state,
+            retry_classifier,
+            task: Arc::new(ConnectionTask {
+                shutdown: std::sync::Mutex::new(Some(shutdown)),
+                handle,
+            }),
+        })
+    }
 }
 
+impl<T> Connection<T> {
+    /// Makes an RPC call and waits for its output. If the client has a
+    /// [RetryClassifier], failed calls are retried as it decides.
+    pub async fn call(&self, input: Vec<u8>) -> HandlerResult<Vec<u8>> {
+        let Some(classifier) = &self.retry_classifier else {
+            return self.channels.call(input).await;
+        };
+        let mut attempt = 1;
+        loop {
+            let error = match self.channels.call(input.clone()).await {
+                Ok(output) => return Ok(output),
+                Err(error) => error,
+            };
+            match classifier.classify(&error, attempt) {
+                RetryDecision::RetryAfter(delay) => {
+                    debug!("Call failed with {:?}, retrying in {:?}", error, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                RetryDecision::GiveUp => return Err(error),
+            }
+        }
+    }
+
+    /// Makes an RPC call with a timeout, which the handler is told about.
+    /// Failed calls aren't retried. See [ControlChannels::call_with_timeout].
+    pub async fn call_with_timeout(&self, input: Vec<u8>, timeout: Duration) -> HandlerResult<Vec<u8>> {
+        self.channels.call_with_timeout(input, timeout).await
+    }
+
+    /// Makes several RPC calls at once, in a single frame. Failed calls aren't
+    /// retried. See [ControlChannels::call_batch].
+    pub async fn call_batch(&self, inputs: Vec<Vec<u8>>) -> Vec<HandlerResult<Vec<u8>>> {
+        self.channels.call_batch(inputs).await
+    }
+
+    /// Returns a receiver for the connection state, which is notified every
+    /// time the server changes it.
+    pub fn state(&self) -> watch::Receiver<T> {
+        self.state.clone()
+    }
+
+    /// The client's call statistics, e.g. the number of calls in flight and
+    /// their recent latency. See [ClientStats].
+    pub fn stats(&self) -> &ClientStats {
+        self.channels.stats()
+    }
+
+    /// The server's full HardLight version. See
+    /// [ControlChannels::server_version].
+    pub fn server_version(&self) -> Option<&str> {
+        self.channels.server_version()
+    }
+
+    /// The HardLight subprotocol negotiated with the server. See
+    /// [ControlChannels::protocol_version].
+    pub fn protocol_version(&self) -> &str {
+        self.channels.protocol_version()
+    }
+
+    /// The host the client connected to. See [ControlChannels::server_host].
+    pub fn server_host(&self) -> &str {
+        self.channels.server_host()
+    }
+
+    /// The address the client connected to. See
+    /// [ControlChannels::server_addr].
+    pub fn server_addr(&self) -> Option<SocketAddr> {
+        self.channels.server_addr()
+    }
+
+    /// Subscribes to events published to `topic`. See
+    /// [ControlChannels::subscribe].
+    pub async fn subscribe(&self, topic: &str) -> HandlerResult<mpsc::Receiver<Vec<u8>>> {
+        self.channels.subscribe(topic).await
+    }
+
+    /// Fetches the current value of lazy state fields. See
+    /// [ControlChannels::fetch_fields].
+    pub async fn fetch_fields(&self, fields: &[FieldId]) -> HandlerResult<()> {
+        self.channels.fetch_fields(fields).await
+    }
+
+    /// Returns a receiver for events broadcast to the rooms the server has put
+    /// this connection in. See [ControlChannels::room_events].
+    pub fn room_events(&self) -> broadcast::Receiver<(String, Vec<u8>)> {
+        self.channels.room_events()
+    }
+
+    /// The connection's control channels, for everything else, e.g.
+    /// streaming calls and fire-and-forget events.
+    pub fn channels(&self) -> &ControlChannels<T> {
+        &self.channels
+    }
+
+    /// Closes the connection for every clone. Calls made afterwards fail with
+    /// [RpcHandlerError::ClientNotConnected].
+    pub fn close(&self) {
+        if let Some(shutdown) = self.task.shutdown.lock().unwrap().take() {
+            let _ = shutdown.send(());
+        }
+    }
+
+    /// Whether the connection has closed, either by [Connection::close] or
+    /// because the server went away.
+    pub fn is_closed(&self) -> bool {
+        self.task.handle.is_finished()
+    }
+}
+
+/// The client-side connection state.
+///
+/// All the changes in a single [ServerMessage::StateChange] are applied as one
+/// batch with [State::apply_batch], so the application never sees half a
+/// batch.
 pub trait State {
-    fn apply_changes(&mut self, changes: Vec<(String, Vec<u8>)>) -> HandlerResult<()>;
+    /// Applies a batch of changes from the server. It's fine to return early
+    /// on the first bad change, as [State::apply_batch] throws a failed batch
+    /// away. A value that doesn't decode, including an empty one, should be
+    /// reported as [RpcHandlerError::BadInputBytes]. A field the state
+    /// doesn't have should be passed to [State::unknown_field].
+    fn apply_changes(&mut self, changes: Vec<(FieldId, Vec<u8>)>) -> HandlerResult<()>;
+
+    /// Applies a batch of changes from the server, all of them or none. By
+    /// default they're applied to a copy of the state, which replaces it if
+    /// every change applied, so a bad change can't leave the state partly
+    /// updated. That copy is expensive for a large state, so states that can
+    /// decode every change before applying any, like those implemented with
+    /// [impl_state!](crate::impl_state), apply them in place instead.
+    fn apply_batch(&mut self, changes: Vec<(FieldId, Vec<u8>)>) -> HandlerResult<()>
+    where
+        Self: Clone,
+    {
+        let mut next = self.clone();
+        next.apply_changes(changes)?;
+        *self = next;
+        Ok(())
+    }
+
+    /// Handles a change to a field the state doesn't have, e.g. one added to
+    /// a newer server. By default it's ignored, so older clients keep
+    /// working. A state can be strict instead, returning
+    /// [RpcHandlerError::UnknownStateField] so the batch is thrown away and
+    /// the mismatch is logged and passed to [Client::set_on_state_error], or
+    /// record the field to report it.
+    fn unknown_field(&mut self, field: FieldId) -> HandlerResult<()> {
+        let _ = field;
+        Ok(())
+    }
+
+    /// Whether the state has a field with this id. The client logs the
+    /// first change to each field it doesn't have, as a sign the server's
+    /// schema has moved on. By default every field is assumed known;
+    /// [impl_state!](crate::impl_state) implements it from its fields.
+    fn knows_field(&self, field: FieldId) -> bool {
+        let _ = field;
+        true
+    }
+}
+
+/// Handles calls the server makes to the client, e.g. asking the user to
+/// confirm an action. This mirrors the server's [Handler](crate::Handler).
+#[async_trait]
+pub trait ClientHandler {
+    /// Handle an RPC call (method + arguments) from the server.
+    async fn handle_rpc_call(&self, input: &[u8]) -> HandlerResult<Vec<u8>>;
 }
 
+/// A call the client has made that's waiting on the server.
+enum PendingCall {
+    Unary(ResponseSender),
+    Stream {
+        /// Tells this stream apart from later streams that reuse its id.
+        key: u64,
+        /// Chunks waiting to be forwarded to the application.
+        items: mpsc::UnboundedSender<HandlerResult<Vec<u8>>>,
+    },
+}
+
+/// Configures a connection to a server. [Client::connect] consumes the client
+/// and returns a [Connection], so calls can't be made before connecting. See
+/// [Reconnectable](crate::Reconnectable) for a client that can be held while
+/// disconnected.
 pub struct Client<T>
 where
-    T: State + Default,
+    T: State + Default + Clone,
 {
     config: ClientConfig,
-    state: T,
-    hl_version_string: HeaderValue,
+    /// Answers calls from the server. If there isn't one, the server's calls
+    /// fail with [RpcHandlerError::Unsupported].
+    handler: Option<Arc<dyn ClientHandler + Send + Sync>>,
+    /// The connection state, as last reported by the server. Held in a
+    /// [watch] channel so the application can observe changes made by the
+    /// connection loop without polling.
+    state: watch::Sender<T>,
+    /// The HardLight protocol versions the client speaks. See
+    /// [PROTOCOL_VERSION].
+    protocol_versions: Vec<u64>,
+}
+
+impl ClientConfig {
+    /// The default settings for connecting to `host`, trusting the server
+    /// according to `tls`.
+    fn default_for(host: &str, tls: TLSClientConfig) -> Self {
+        Self {
+            tls,
+            host: host.to_string(),
+            ws_config: WebSocketConfig::default(),
+            linger: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            retry_classifier: None,
+            stats: Arc::default(),
+            lazy_fields: Vec::new(),
+            token: None,
+            unix_socket: None,
+            proxy: None,
+            fallback_hosts: Vec::new(),
+            path: "/".to_string(),
+            headers: Vec::new(),
+            query: Vec::new(),
+            on_state_error: None,
+            codec: Arc::new(RkyvCodec),
+            compression: None,
+        }
+    }
 }
 
 impl<T> Client<T>
 where
-    T: State + Default,
+    T: State + Default + Clone,
 {
     /// Creates a new client that doesn't verify the server's certificate.
     pub fn new_self_signed(host: &str) -> Self {
@@ -56,11 +752,32 @@ where
             .with_safe_defaults()
             .with_custom_certificate_verifier(Arc::new(NoCertificateVerification {}))
             .with_no_client_auth();
-        let config = ClientConfig {
-            tls,
-            host: host.to_string(),
-        };
-        Self::new_with_config(config)
+        Self::new_with_config(ClientConfig::default_for(host, tls))
+    }
+
+    /// Creates a new client that only trusts the server if it presents
+    /// exactly this certificate (DER-encoded), e.g. a self-hosted server's
+    /// self-signed certificate. Unlike [Client::new_self_signed], this isn't
+    /// open to a man-in-the-middle. The server has to be reconnected with a
+    /// new pin when its certificate changes.
+    pub fn new_with_pinned_cert(host: &str, cert_der: Vec<u8>) -> Self {
+        let tls = TLSClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificateVerification {
+                pinned: Certificate(cert_der),
+            }))
+            .with_no_client_auth();
+        Self::new_with_config(ClientConfig::default_for(host, tls))
+    }
+
+    /// Creates a new client that connects to a server listening on a Unix
+    /// socket at `path`, e.g. a sidecar, without TCP or TLS. See
+    /// [ServerConfig::new_unix](crate::ServerConfig::new_unix).
+    #[cfg(unix)]
+    pub fn new_unix(path: impl Into<PathBuf>) -> Self {
+        let mut client = Self::new_self_signed("localhost");
+        client.config.unix_socket = Some(path.into());
+        client
     }
 
     /// Create a new client using the system's root certificates.
@@ -73,174 +790,743 @@ where
             .with_safe_defaults()
             .with_root_certificates(root_store)
             .with_no_client_auth();
-        let config = ClientConfig {
-            tls,
-            host: host.to_string(),
-        };
-        Self::new_with_config(config)
+        Self::new_with_config(ClientConfig::default_for(host, tls))
     }
 
     /// Create a new client using the given configuration.
     pub fn new_with_config(config: ClientConfig) -> Self {
-        let version = Version::from_str(HL_VERSION).unwrap();
+        let (state, _) = watch::channel(T::default());
         Self {
             config,
-            state: T::default(),
-            hl_version_string: format!("hl/{}", version.major).parse().unwrap(),
+            handler: None,
+            state,
+            protocol_versions: vec![PROTOCOL_VERSION],
         }
     }
 
-    pub async fn connect(
+    /// Sets the WebSocket settings used when connecting, e.g. the largest
+    /// message the server can send, which are passed straight to
+    /// tungstenite. If not set, tungstenite's defaults are used: 64 MiB
+    /// messages, 16 MiB frames and an unlimited send queue.
+    pub fn set_ws_config(&mut self, ws_config: WebSocketConfig) {
+        self.config.ws_config = ws_config;
+    }
+
+    /// Offers to compress messages with the permessage-deflate WebSocket
+    /// extension, which the connection uses if the server accepts, i.e. it
+    /// has [ServerConfig::compression](crate::ServerConfig::compression) set.
+    /// Worth it for large, repetitive messages over slow links, at the cost
+    /// of CPU time on both ends. Off by default.
+    pub fn set_compression(&mut self, compression: CompressionConfig) {
+        self.config.compression = Some(compression);
+    }
+
+    /// Sets `SO_LINGER` on the connection's socket, so closing it waits up to
+    /// `linger` for the last of its data to be delivered. This blocks the
+    /// thread closing the socket. Left at the OS default if not set.
+    pub fn set_linger(&mut self, linger: Duration) {
+        self.config.linger = Some(linger);
+    }
+
+    /// Tunnels the connection through an HTTP or SOCKS5 proxy, e.g.
+    /// [ProxyConfig::from_env] to use the one the environment gives, unless
+    /// the server is one it should be skipped for. The TLS handshake is done
+    /// through the tunnel, so the proxy can't read the connection. Doesn't
+    /// apply to [Unix sockets](Client::new_unix).
+    pub fn set_proxy(&mut self, proxy: ProxyConfig) {
+        self.config.proxy = Some(proxy);
+    }
+
+    /// Hosts to connect to, in order, if the client's own host can't be
+    /// reached, e.g. the same service in other regions. A host that answers
+    /// but turns the client away isn't moved on from. The connect timeout
+    /// applies to each host in turn. See [ControlChannels::server_host] for
+    /// the host that was used.
+    pub fn set_fallback_hosts(&mut self, hosts: Vec<String>) {
+        self.config.fallback_hosts = hosts;
+    }
+
+    /// Gives up on connecting if the server hasn't completed the handshake
+    /// within `timeout`, returning [ConnectError::Timeout]. This covers
+    /// resolving the host, dialing it and the TLS and WebSocket handshakes, so
+    /// a server that accepts the socket but never answers the upgrade can't
+    /// hang the application. Defaults to 30 seconds.
+    pub fn set_connect_timeout(&mut self, timeout: Duration) {
+        self.config.connect_timeout = timeout;
+    }
+
+    /// Sets the classifier [Connection::call] consults to retry failed calls.
+    /// Failed calls aren't retried if there isn't one.
+    pub fn set_retry_classifier(&mut self, classifier: Arc<dyn RetryClassifier>) {
+        self.config.retry_classifier = Some(classifier);
+    }
+
+    /// Records the client's call statistics in `stats` instead of its own,
+    /// e.g. to share them between the clients a
+    /// [Reconnectable](crate::Reconnectable) creates so they survive
+    /// reconnecting.
+    pub fn set_stats(&mut self, stats: Arc<ClientStats>) {
+        self.config.stats = stats;
+    }
+
+    /// Sends `token` to the server as a bearer token when connecting, for
+    /// servers that authenticate clients with a
+    /// [TokenValidator](crate::TokenValidator). If the server doesn't accept
+    /// it, connecting fails with [ConnectError::HandshakeRejected] and status
+    /// 401.
+    pub fn set_token(&mut self, token: &str) {
+        self.config.token = Some(token.to_string());
+    }
+
+    /// Connects to the service at `path`, e.g. `/chat`, on a server that
+    /// hosts several. See [Server::new_multi](crate::Server::new_multi).
+    /// The path can have a query string, e.g. `/v2/ws?room=abc`, for
+    /// gateways that route on it. Defaults to `/`.
+    pub fn set_path(&mut self, path: &str) {
+        self.config.path = format!("/{}", path.trim_start_matches('/'));
+    }
+
+    /// Adds a header to the request that opens the connection, e.g. a tenant
+    /// id or a `traceparent` for distributed tracing, for gateways and
+    /// servers that need it. The server can read it with
+    /// [Context::header](crate::Context::header). It replaces any header
+    /// with the same name, including the ones the client sends itself, such
+    /// as `Authorization`.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is one of the `Sec-WebSocket-*` headers, which the WebSocket
+    /// handshake depends on.
+    pub fn set_header(&mut self, name: HeaderName, value: HeaderValue) {
+        assert!(
+            !name.as_str().starts_with("sec-websocket-"),
+            "{name} is set by the WebSocket handshake and can't be replaced"
+        );
+        self.config.headers.retain(|(existing, _)| existing != name);
+        self.config.headers.push((name, value));
+    }
+
+    /// Adds a query parameter to the request that opens the connection, e.g.
+    /// a client version for a gateway to route on. It's percent-encoded, and
+    /// can be given more than once. The server can read it with
+    /// [Context::query_param](crate::Context::query_param).
+    pub fn set_query_param(&mut self, key: &str, value: &str) {
+        self.config.query.push((key.to_string(), value.to_string()));
+    }
+
+    /// Sets whether the server pushes state `field` to the client as it
+    /// changes, from the moment the client connects. See
+    /// [ControlChannels::set_field_sync] to change it once connected.
+    pub fn set_field_sync(&mut self, field: FieldId, sync: FieldSync) {
+        self.config.lazy_fields.retain(|&lazy| lazy != field);
+        if sync == FieldSync::Lazy {
+            self.config.lazy_fields.push(field);
+        }
+    }
+
+    /// Sets how messages are encoded on the wire, e.g. [JsonCodec](crate::JsonCodec)
+    /// with the `json` feature. The server has to use the same codec, or
+    /// connecting fails with [ConnectError::CodecMismatch]. Defaults to
+    /// [RkyvCodec].
+    pub fn set_codec(&mut self, codec: impl Codec + 'static) {
+        self.config.codec = Arc::new(codec);
+    }
+
+    /// Overrides the protocol versions the client offers, for testing.
+    pub(crate) fn set_protocol_versions(&mut self, versions: Vec<u64>) {
+        self.protocol_versions = versions;
+    }
+
+    /// Calls `on_state_error` whenever a batch of state changes from the
+    /// server can't be applied, e.g. because a value didn't decode. The
+    /// batch is thrown away, so the client's state may now be behind the
+    /// server's, and the application might want to reconnect to get it all
+    /// afresh, or alert someone.
+    pub fn set_on_state_error(&mut self, on_state_error: StateErrorHook) {
+        self.config.on_state_error = Some(on_state_error);
+    }
+
+    /// Sets the handler used to answer calls from the server.
+    pub fn set_handler(&mut self, handler: Arc<dyn ClientHandler + Send + Sync>) {
+        self.handler = Some(handler);
+    }
+
+    /// Connects to the server, running the connection in the background.
+    pub async fn connect(self) -> Result<Connection<T>, ConnectError>
+    where
+        T: Send + Sync + 'static,
+    {
+        Connection::spawn(self, |mut client, shutdown, control_channels_tx, ok_tx| async move {
+            client.connect_with_channels(shutdown, control_channels_tx, ok_tx).await
+        })
+        .await
+    }
+
+    /// Connects to the server and runs the connection on the calling task
+    /// until it closes, talking to the application over channels. Most
+    /// applications should use [Client::connect] instead, which does this in
+    /// the background and hands back a [Connection].
+    pub async fn connect_with_channels(
         &mut self,
         // Allows the application's wrapping client to shut down the connection
-        mut shutdown: oneshot::Receiver<()>,
+        shutdown: oneshot::Receiver<()>,
         // Sends control channels to the application so it can send RPC calls,
         // events, and other things to the server.
-        control_channels_tx: oneshot::Sender<(
-            mpsc::Sender<(Vec<u8>, oneshot::Sender<Result<Vec<u8>, RpcHandlerError>>)>,
-        )>,
+        control_channels_tx: oneshot::Sender<ControlChannels<T>>,
         // This will send immediately once the client has connected to the server.
         // The client is guaranteed to not return an error after this is sent
         // so it is safe to ignore the result.
         ok_tx: oneshot::Sender<()>,
-    ) -> Result<(), Error> {
+    ) -> Result<(), ConnectError> {
+        let span = span!(Level::DEBUG, "connection", host = self.config.host);
+        let _enter = span.enter();
+
+        debug!("Connecting to server...");
+        #[cfg(unix)]
+        if let Some(path) = self.config.unix_socket.clone() {
+            let req = self.upgrade_request(&self.config.host);
+            let open = async {
+                let stream = UnixStream::connect(&path).await.map_err(ConnectError::Tcp)?;
+                self.handshake(req, stream).await
+            };
+            let (stream, res) = tokio::time::timeout(self.config.connect_timeout, open).await??;
+            let host = self.config.host.clone();
+            return self.run(stream, res, (host, None), shutdown, control_channels_tx, ok_tx).await;
+        }
+        let hosts: Vec<String> = std::iter::once(&self.config.host).chain(&self.config.fallback_hosts).cloned().collect();
+        let mut last_error = None;
+        for host in hosts {
+            let req = self.upgrade_request(&host);
+            let error = match tokio::time::timeout(self.config.connect_timeout, self.open(req)).await {
+                Ok(Ok((stream, res, addr))) => {
+                    debug!("Connected to {} at {}", host, addr);
+                    return self.run(stream, res, (host, Some(addr)), shutdown, control_channels_tx, ok_tx).await;
+                }
+                Ok(Err(error)) => error,
+                Err(elapsed) => elapsed.into(),
+            };
+            // a server that answered has made up its mind, so only move on
+            // from hosts that couldn't be reached
+            if !matches!(
+                error,
+                ConnectError::Dns(_) | ConnectError::Tcp(_) | ConnectError::Tls(_) | ConnectError::Proxy(_) | ConnectError::Timeout
+            ) {
+                return Err(error);
+            }
+            warn!("Failed to connect to {}: {}", host, error);
+            last_error = Some(error);
+        }
+        Err(last_error.expect("there's always a host to try"))
+    }
+
+    /// Like [Client::connect_with_channels], but over an already established stream, e.g.
+    /// one end of an in-memory pipe. Only the WebSocket handshake is done.
+    pub(crate) async fn connect_over<S>(
+        &mut self,
+        stream: S,
+        shutdown: oneshot::Receiver<()>,
+        control_channels_tx: oneshot::Sender<ControlChannels<T>>,
+        ok_tx: oneshot::Sender<()>,
+    ) -> Result<(), ConnectError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let span = span!(Level::DEBUG, "connection", host = self.config.host);
         let _enter = span.enter();
 
-        let connector = Connector::Rustls(Arc::new(self.config.tls.clone()));
+        let req = self.upgrade_request(&self.config.host);
+        let (stream, res) = tokio::time::timeout(self.config.connect_timeout, self.handshake(req, stream)).await??;
+        let host = self.config.host.clone();
+        self.run(stream, res, (host, None), shutdown, control_channels_tx, ok_tx).await
+    }
 
-        let req = Request::builder()
+    fn upgrade_uri(&self, host: &str) -> String {
+        let mut uri = format!("wss://{}{}", host, self.config.path);
+        if !self.config.query.is_empty() {
+            // the path may already have a query string of its own
+            uri.push(if uri.contains('?') { '&' } else { '?' });
+            uri.push_str(&query::encode(&self.config.query));
+        }
+        uri
+    }
+
+    fn upgrade_request(&self, host: &str) -> Request<()> {
+        let mut req = Request::builder()
             .method("GET")
-            .header("Host", self.config.host.clone())
+            .header("Host", host)
             .header("Connection", "Upgrade")
             .header("Upgrade", "websocket")
             .header("Sec-WebSocket-Version", "13")
             .header("Sec-WebSocket-Key", generate_key())
-            .header("Sec-WebSocket-Protocol", self.hl_version_string.clone())
-            .uri(format!("wss://{}/", self.config.host))
-            .body(())
-            .expect("Failed to build request");
-
-        debug!("Connecting to server...");
-        let (mut stream, res) = connect_async_tls_with_config(req, None, Some(connector)).await?;
+            .header("Sec-WebSocket-Protocol", protocol_list(&self.protocol_versions, self.config.codec.name()))
+            .header(FULL_VERSION_HEADER, HL_VERSION)
+            .uri(self.upgrade_uri(host));
+        if let Some(token) = &self.config.token {
+            req = req.header(AUTHORIZATION, format!("Bearer {token}"));
+        }
+        if !self.config.lazy_fields.is_empty() {
+            let lazy_fields: Vec<_> = self.config.lazy_fields.iter().map(|field| field.to_string()).collect();
+            req = req.header(LAZY_FIELDS_HEADER, lazy_fields.join(","));
+        }
+        if let Some(compression) = &self.config.compression {
+            req = req.header(deflate::EXTENSIONS_HEADER, deflate::offer(compression));
+        }
+        let mut req = req.body(()).expect("Failed to build request");
+        for (name, value) in &self.config.headers {
+            req.headers_mut().insert(name, value.clone());
+        }
+        req
+    }
 
+    /// Checks the server's handshake response, then runs the connection loop
+    /// until the connection closes or the application shuts it down.
+    async fn run<S>(
+        &mut self,
+        stream: WebSocketStream<S>,
+        res: Response,
+        // the host we ended up connecting to, and its address if it's over TCP
+        (server_host, server_addr): (String, Option<SocketAddr>),
+        mut shutdown: oneshot::Receiver<()>,
+        control_channels_tx: oneshot::Sender<ControlChannels<T>>,
+        ok_tx: oneshot::Sender<()>,
+    ) -> Result<(), ConnectError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // the server picks one of the versions we offered, with our codec
+        let codec = self.config.codec.name();
         let protocol = res.headers().get("Sec-WebSocket-Protocol");
-        if protocol.is_none() || protocol.unwrap() != &self.hl_version_string {
-            error!("Received bad version from server. Wanted {:?}, got {:?}", self.hl_version_string, protocol);
-            return Err(Error::Protocol(ProtocolError::HandshakeIncomplete));
+        let picked = protocol.and_then(|v| v.to_str().ok()).map(|v| parse_protocols([v])).unwrap_or_default();
+        let protocol_version: Arc<str> = match picked[..] {
+            [major] if self.protocol_versions.contains(&major) => protocol_name(major, codec).into(),
+            _ => {
+                error!("Received bad version from server. Wanted one of {:?}, got {:?}", self.protocol_versions, protocol);
+                return Err(ConnectError::VersionMismatch {
+                    ours: protocol_list(&self.protocol_versions, codec),
+                    theirs: protocol.map(|theirs| String::from_utf8_lossy(theirs.as_bytes()).into_owned()),
+                });
+            }
+        };
+        let picked = protocol.and_then(|v| v.to_str().ok()).map(|v| parse_offers([v])).unwrap_or_default();
+        if !matches!(&picked[..], [offer] if offer.codec == codec) {
+            error!("Received bad codec from server. Wanted {}, got {:?}", codec, protocol);
+            return Err(ConnectError::CodecMismatch {
+                ours: codec.to_string(),
+                theirs: picked.first().map(|offer| offer.codec.to_string()),
+            });
         }
-        
-        debug!("Connected to server. Sending ok to application...");
-        ok_tx.send(()).unwrap();
+
+        // older servers don't send their full version
+        let server_version: Option<Arc<str>> = res
+            .headers()
+            .get(FULL_VERSION_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(Into::into);
+        debug!(
+            "Connected to server running HardLight {:?}, speaking {}. Sending ok to application...",
+            server_version, protocol_version
+        );
+        self.config.stats.record_connect();
+        // fails if the application has stopped waiting, which is fine
+        let _ = ok_tx.send(());
         debug!("Ok sent.");
         debug!("Sending control channels to application...");
         let (rpc_tx, mut rpc_rx) = mpsc::channel(10);
-        control_channels_tx.send((rpc_tx,)).unwrap();
+        let (timed_rpc_tx, mut timed_rpc_rx) = mpsc::channel(10);
+        let (stream_tx, mut stream_rx) = mpsc::channel(10);
+        let (batch_tx, mut batch_rx) = mpsc::channel::<Vec<(Vec<u8>, ResponseSender)>>(10);
+        let (stats_tx, mut stats_rx) = mpsc::channel(10);
+        let (transaction_tx, mut transaction_rx) = mpsc::channel(10);
+        let (event_tx, mut event_rx) = mpsc::channel(10);
+        let (subscribe_tx, mut subscribe_rx) = mpsc::channel(10);
+        let (field_sync_tx, mut field_sync_rx) = mpsc::channel(10);
+        let (fetch_tx, mut fetch_rx) = mpsc::channel(10);
+        let (room_events, _) = broadcast::channel(64);
+        let control_channels = ControlChannels {
+            rpc_tx,
+            timed_rpc_tx,
+            stream_tx,
+            batch_tx,
+            stats_tx,
+            transaction_tx,
+            event_tx,
+            subscribe_tx,
+            field_sync_tx,
+            fetch_tx,
+            state: self.state_handle(),
+            room_events: room_events.clone(),
+            server_version,
+            protocol_version,
+            server_host: server_host.into(),
+            server_addr,
+            stats: self.config.stats.clone(),
+        };
+        if control_channels_tx.send(control_channels).is_err() {
+            warn!("Application dropped the control channels receiver. Disconnecting.");
+            return Ok(());
+        }
         debug!("Control channels sent.");
 
+        // responses to calls the server has made to us
+        let (server_rpc_tx, mut server_rpc_rx) = mpsc::channel(10);
+
+        // local subscribers for each topic we're subscribed to on the server
+        let mut subscriptions: HashMap<String, Vec<mpsc::Sender<Vec<u8>>>> = HashMap::new();
+
         // keep track of active RPC calls
-        // we have to do this dumb thing because we can't copy a oneshot::Sender
-        let mut active_rpc_calls: [Option<oneshot::Sender<Result<Vec<u8>, RpcHandlerError>>>; 256] = [
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None, None, None, None, None, None, None, None, None, None, None,
-            None, None, None, None,
-        ];
+        let mut active_rpc_calls: [Option<PendingCall>; 256] = std::array::from_fn(|_| None);
+        // fields the server changed that the state doesn't have, each logged once
+        let mut unknown_fields: HashSet<FieldId> = HashSet::new();
+
+        // chunks of streaming calls are handed to the application by a
+        // forwarder task per stream, which reports back here
+        let (stream_feedback_tx, mut stream_feedback_rx) = mpsc::channel(10);
+        let mut next_stream_key = 0u64;
+
+        // The socket is split in two. This task reads from the server and
+        // handles everything else, and a writer task owns the write half.
+        // Outgoing messages are queued for the writer in order, so a slow
+        // socket never holds up reading responses and state changes. On
+        // shutdown the writer flushes the queue and closes the socket before
+        // this task returns. While the queue is full, nothing more is taken
+        // from the application, whose calls then wait, but the server is
+        // still read from, so neither side waits on the other.
+        let (sink, mut source) = stream.split();
+        let (outbox, outbox_rx) = outbox();
+        let mut writer = tokio::spawn(write_messages(sink, outbox_rx, self.config.codec.clone()));
+
+        // whether the application closed the connection
+        let mut closing = false;
 
         debug!("Starting RPC handler loop");
         loop {
             select! {
                 // await RPC requests from the application
-                Some((internal, completion_tx)) = rpc_rx.recv() => {
+                Some((internal, completion_tx)) = rpc_rx.recv(), if !outbox.is_full() => {
                     debug!("Received RPC request from application");
-                    // find a free rpc id
-                    if let Some(id) = active_rpc_calls.iter().position(|x| x.is_none()) {
-                        let span = span!(Level::DEBUG, "rpc", id = id as u8);
-                        let _enter = span.enter();
-                        debug!("Found free RPC id");
-
-                        let msg = ClientMessage::RPCRequest {
-                            id: id as u8,
-                            internal
+                    send_unary_call(&mut active_rpc_calls, &outbox, internal, None, completion_tx);
+                }
+                // await RPC calls with a timeout from the application
+                Some((internal, timeout, completion_tx)) = timed_rpc_rx.recv(), if !outbox.is_full() => {
+                    debug!("Received RPC request with a timeout of {:?} from application", timeout);
+                    send_unary_call(&mut active_rpc_calls, &outbox, internal, Some(timeout), completion_tx);
+                }
+                // await batches of RPC calls from the application
+                Some(calls) = batch_rx.recv(), if !outbox.is_full() => {
+                    let mut batch = Vec::with_capacity(calls.len());
+                    for (internal, completion_tx) in calls {
+                        let Some(id) = active_rpc_calls.iter().position(|x| x.is_none()) else {
+                            warn!("No free RPC id available. Responding with an error.");
+                            let _ = completion_tx.send(Err(RpcHandlerError::TooManyCallsInFlight));
+                            continue;
                         };
-
-                        let binary = match rkyv::to_bytes::<ClientMessage, 1024>(&msg) {
-                            Ok(bytes) => bytes,
-                            Err(e) => {
-                                warn!("Failed to serialize RPC call. Ignoring. Error: {e}");
-                                // we don't care if the receiver has dropped
-                                let _ = completion_tx.send(Err(RpcHandlerError::BadInputBytes));
-                                continue
-                            }
-                        }.to_vec();
-
-                        debug!("Sending RPC call to server");
-
-                        match stream.send(Message::Binary(binary)).await {
-                            Ok(_) => (),
-                            Err(e) => {
-                                warn!("Failed to send RPC call. Ignoring. Error: {e}");
-                                // we don't care if the receiver has dropped
+                        active_rpc_calls[id] = Some(PendingCall::Unary(completion_tx));
+                        batch.push(BatchedCall { id: id as u8, internal });
+                    }
+                    if batch.is_empty() {
+                        continue;
+                    }
+                    debug!("Sending a batch of {} RPC calls to server", batch.len());
+                    let ids: Vec<u8> = batch.iter().map(|call| call.id).collect();
+                    let binary = to_message_bytes(&ClientMessage::RPCBatch(batch));
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send RPC batch. Ignoring. Error: {e}");
+                        for id in ids {
+                            if let Some(PendingCall::Unary(completion_tx)) = active_rpc_calls[id as usize].take() {
                                 let _ = completion_tx.send(Err(RpcHandlerError::ClientNotConnected));
-                                continue
                             }
                         }
-
-                        debug!("RPC call sent to server");
-
-                        active_rpc_calls[id] = Some(completion_tx);
-                    } else {
+                    }
+                }
+                // await stats requests from the application
+                Some(completion_tx) = stats_rx.recv(), if !outbox.is_full() => {
+                    let Some(id) = active_rpc_calls.iter().position(|x| x.is_none()) else {
+                        warn!("No free RPC id available. Responding with an error.");
+                        let _ = completion_tx.send(Err(RpcHandlerError::TooManyCallsInFlight));
+                        continue;
+                    };
+                    debug!("Requesting method stats from server");
+                    let binary = to_message_bytes(&ClientMessage::StatsRequest { id: id as u8 });
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send stats request. Error: {e}");
+                        let _ = completion_tx.send(Err(RpcHandlerError::ClientNotConnected));
+                        continue;
+                    }
+                    active_rpc_calls[id] = Some(PendingCall::Unary(completion_tx));
+                }
+                // await transaction requests from the application
+                Some((op, completion_tx)) = transaction_rx.recv(), if !outbox.is_full() => {
+                    let Some(id) = active_rpc_calls.iter().position(|x| x.is_none()) else {
+                        warn!("No free RPC id available. Responding with an error.");
+                        let _ = completion_tx.send(Err(RpcHandlerError::TooManyCallsInFlight));
+                        continue;
+                    };
+                    debug!("Requesting transaction {:?} from server", op);
+                    let binary = to_message_bytes(&ClientMessage::Transaction { id: id as u8, op });
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send transaction request. Error: {e}");
+                        let _ = completion_tx.send(Err(RpcHandlerError::ClientNotConnected));
+                        continue;
+                    }
+                    active_rpc_calls[id] = Some(PendingCall::Unary(completion_tx));
+                }
+                // await fetches of lazy state fields from the application
+                Some((fields, completion_tx)) = fetch_rx.recv(), if !outbox.is_full() => {
+                    let Some(id) = active_rpc_calls.iter().position(|x| x.is_none()) else {
                         warn!("No free RPC id available. Responding with an error.");
                         let _ = completion_tx.send(Err(RpcHandlerError::TooManyCallsInFlight));
+                        continue;
+                    };
+                    debug!("Fetching {} field(s) from server", fields.len());
+                    let binary = to_message_bytes(&ClientMessage::FetchFields { id: id as u8, fields });
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send fetch request. Error: {e}");
+                        let _ = completion_tx.send(Err(RpcHandlerError::ClientNotConnected));
+                        continue;
                     }
+                    active_rpc_calls[id] = Some(PendingCall::Unary(completion_tx));
                 }
+                // await changes to how state fields are synced
+                Some((field, sync)) = field_sync_rx.recv(), if !outbox.is_full() => {
+                    debug!("Setting field {field} to {:?}", sync);
+                    let binary = to_message_bytes(&ClientMessage::SetFieldSync { field, sync });
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send field sync. Error: {e}");
+                    }
+                }
+                // await streaming RPC requests from the application
+                Some((internal, subscriber)) = stream_rx.recv(), if !outbox.is_full() => {
+                    debug!("Received streaming RPC request from application");
+                    let Some(id) = active_rpc_calls.iter().position(|x| x.is_none()) else {
+                        warn!("No free RPC id available. Responding with an error.");
+                        let _ = subscriber.send(Err(RpcHandlerError::TooManyCallsInFlight)).await;
+                        continue;
+                    };
+                    let span = span!(Level::DEBUG, "rpc", id = id);
+                    let _enter = span.enter();
+
+                    debug!("Sending streaming RPC call to server");
+                    let binary = to_message_bytes(&ClientMessage::RPCStreamRequest { id: id as u8, internal });
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send streaming RPC call. Ignoring. Error: {e}");
+                        let _ = subscriber.send(Err(RpcHandlerError::ClientNotConnected)).await;
+                        continue;
+                    }
+
+                    let key = next_stream_key;
+                    next_stream_key += 1;
+                    let (items_tx, items_rx) = mpsc::unbounded_channel();
+                    tokio::spawn(forward_stream(id as u8, key, items_rx, subscriber, stream_feedback_tx.clone()));
+                    active_rpc_calls[id] = Some(PendingCall::Stream { key, items: items_tx });
+                }
+                // await progress from stream forwarders
+                Some((id, key, feedback)) = stream_feedback_rx.recv() => {
+                    // the stream may have ended, and its id reused
+                    match &active_rpc_calls[id as usize] {
+                        Some(PendingCall::Stream { key: active_key, .. }) if *active_key == key => {}
+                        _ => continue,
+                    }
+                    let msg = match feedback {
+                        StreamFeedback::Credit => ClientMessage::StreamCredit { id, credits: 1 },
+                        StreamFeedback::Cancel => {
+                            // keep the id until the server confirms with RPCStreamEnd
+                            debug!("Application dropped stream {id}. Cancelling.");
+                            ClientMessage::CancelStream { id }
+                        }
+                    };
+                    let binary = to_message_bytes(&msg);
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send stream update. Error: {e}");
+                    }
+                }
+                // await events from the application
+                Some(event) = event_rx.recv(), if !outbox.is_full() => {
+                    debug!("Sending event to server");
+                    let binary = to_message_bytes(&ClientMessage::Event(event));
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send event. Ignoring. Error: {e}");
+                    }
+                }
+                // await topic subscriptions from the application
+                Some((topic, subscriber)) = subscribe_rx.recv(), if !outbox.is_full() => {
+                    let subscribers = subscriptions.entry(topic.clone()).or_default();
+                    subscribers.push(subscriber);
+                    // only the first local subscriber subscribes on the server
+                    if subscribers.len() > 1 {
+                        continue;
+                    }
+                    debug!("Subscribing to {topic}");
+                    let binary = to_message_bytes(&ClientMessage::Subscribe { topic });
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send subscription. Error: {e}");
+                    }
+                }
+                // await responses to the server's calls from our handler
+                Some((id, output)) = server_rpc_rx.recv(), if !outbox.is_full() => {
+                    let span = span!(Level::DEBUG, "server_rpc", id = id);
+                    let _enter = span.enter();
+                    debug!("Sending response to server's call");
+                    let binary = to_message_bytes(&ClientMessage::RPCResponse { id, output });
+                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                        warn!("Failed to send response to server's call. Error: {e}");
+                    }
+                }
+                // wait for the writer to catch up
+                _ = outbox.drained(), if outbox.is_full() => {}
                 // await RPC responses from the server
-                Some(msg) = stream.next() => {
+                msg = source.next() => {
+                    let Some(msg) = msg else {
+                        debug!("Server closed the connection.");
+                        break;
+                    };
                     if let Ok(msg) = msg {
-                        if let Message::Binary(bytes) = msg {
-                            let msg: ServerMessage = match rkyv::from_bytes(&bytes) {
+                        if let Message::Close(frame) = &msg {
+                            match frame {
+                                Some(frame) => debug!("Server closed the connection with {}: {}", frame.code, frame.reason),
+                                None => debug!("Server closed the connection."),
+                            }
+                            break;
+                        }
+                        if let Message::Text(text) = &msg {
+                            if !self.config.codec.text() {
+                                warn!("Server sent a text message ({} bytes). Disconnecting.", text.len());
+                                let close = CloseFrame {
+                                    code: CloseCode::Unsupported,
+                                    reason: "text messages aren't supported".into(),
+                                };
+                                let _ = outbox.send(Message::Close(Some(close)));
+                                closing = true;
+                                break;
+                            }
+                        }
+                        if msg.is_binary() || msg.is_text() {
+                            let bytes = msg.into_data();
+                            let msg = match self.config.codec.decode_server(&bytes) {
                                 Ok(msg) => msg,
                                 Err(e) => {
                                     warn!("Received invalid RPC response. Ignoring. Error: {e}");
                                     continue;
                                 }
                             };
-                            match msg {
-                                ServerMessage::RPCResponse { id, output } => {
-                                    let span = span!(Level::DEBUG, "rpc", id = id as u8);
-                                    let _enter = span.enter();
-                                    debug!("Received RPC response from server");
-                                    if let Some(completion_tx) = active_rpc_calls[id as usize].take() {
-                                        let _ = completion_tx.send(output);
-                                    } else {
-                                        warn!("Received RPC response for unknown RPC call. Ignoring.");
+                            // a batch is handled as the messages in it, in order
+                            let messages = match msg {
+                                ServerMessage::Batch(batch) => batch
+                                    .into_iter()
+                                    .filter_map(|bytes| match self.config.codec.decode_server(&bytes) {
+                                        Ok(msg) => Some(msg),
+                                        Err(e) => {
+                                            warn!("Received invalid message in a batch. Ignoring. Error: {e}");
+                                            None
+                                        }
+                                    })
+                                    .collect(),
+                                msg => vec![msg],
+                            };
+                            for msg in messages {
+                                match msg {
+                                    ServerMessage::RPCResponse { id, output } => {
+                                        let span = span!(Level::DEBUG, "rpc", id = id);
+                                        let _enter = span.enter();
+                                        debug!("Received RPC response from server");
+                                        match active_rpc_calls[id as usize].take() {
+                                            Some(PendingCall::Unary(completion_tx)) => {
+                                                let _ = completion_tx.send(output);
+                                            }
+                                            // a failed stream ends with the error
+                                            Some(PendingCall::Stream { items, .. }) => {
+                                                if let Err(e) = output {
+                                                    let _ = items.send(Err(e));
+                                                }
+                                            }
+                                            None => warn!("Received RPC response for unknown RPC call. Ignoring."),
+                                        }
                                     }
-                                }
-                                ServerMessage::StateChange(changes) => {
-                                    let span = span!(Level::DEBUG, "state_change");
-                                    let _enter = span.enter();
-                                    debug!("Received {} state change(s) from server", changes.len());
-                                    if let Err(e) = self.state.apply_changes(changes) {
-                                        warn!("Failed to apply state changes. Error: {:?}", e);
-                                    };
-                                }
-                                ServerMessage::NewEvent { .. } => {
-                                    warn!("NewEvent has not been implemented yet. Ignoring.")
+                                    ServerMessage::RPCStreamItem { id, payload } => {
+                                        match &active_rpc_calls[id as usize] {
+                                            Some(PendingCall::Stream { items, .. }) => {
+                                                let _ = items.send(Ok(payload));
+                                            }
+                                            _ => warn!("Received chunk for unknown stream. Ignoring."),
+                                        }
+                                    }
+                                    ServerMessage::RPCStreamEnd { id } => {
+                                        let span = span!(Level::DEBUG, "rpc", id = id);
+                                        let _enter = span.enter();
+                                        debug!("Stream ended");
+                                        match active_rpc_calls[id as usize] {
+                                            // dropping the sender lets the forwarder finish up
+                                            Some(PendingCall::Stream { .. }) => active_rpc_calls[id as usize] = None,
+                                            _ => warn!("Received end of unknown stream. Ignoring."),
+                                        }
+                                    }
+                                    ServerMessage::RPCRequest { id, internal } => {
+                                        let span = span!(Level::DEBUG, "server_rpc", id = id);
+                                        let _enter = span.enter();
+                                        debug!("Received call from server");
+                                        let tx = server_rpc_tx.clone();
+                                        let handler = self.handler.clone();
+                                        if handler.is_none() {
+                                            warn!("No handler for calls from the server. Responding with an error.");
+                                        }
+                                        tokio::spawn(async move {
+                                            let output = match handler {
+                                                Some(handler) => handler.handle_rpc_call(&internal).await,
+                                                None => Err(RpcHandlerError::Unsupported),
+                                            };
+                                            let _ = tx.send((id, output)).await;
+                                        });
+                                    }
+                                    ServerMessage::StateChange(changes) => {
+                                        let span = span!(Level::DEBUG, "state_change");
+                                        let _enter = span.enter();
+                                        debug!("Received {} state change(s) from server", changes.len());
+                                        self.state.send_if_modified(|state| {
+                                            for (field, _) in &changes {
+                                                if !state.knows_field(*field) && unknown_fields.insert(*field) {
+                                                    warn!("Server changed state field {field}, which the client's state doesn't have. Is the client out of date?");
+                                                }
+                                            }
+                                            match state.apply_batch(changes) {
+                                                Ok(()) => true,
+                                                Err(e) => {
+                                                    warn!("Failed to apply state changes. Discarding batch. Error: {:?}", e);
+                                                    if let Some(on_state_error) = &self.config.on_state_error {
+                                                        on_state_error(&e);
+                                                    }
+                                                    false
+                                                }
+                                            }
+                                        });
+                                    }
+                                    ServerMessage::RoomEvent { room, event } => {
+                                        debug!("Received event for room {room}");
+                                        // fails if nobody is listening, which is fine
+                                        let _ = room_events.send((room, event));
+                                    }
+                                    ServerMessage::NewEvent { topic, event } => {
+                                        let span = span!(Level::DEBUG, "event", topic = topic);
+                                        let _enter = span.enter();
+                                        let Some(subscribers) = subscriptions.get_mut(&topic) else {
+                                            debug!("Received event for a topic we're not subscribed to. Ignoring.");
+                                            continue;
+                                        };
+                                        // drop subscribers whose receivers have gone away
+                                        subscribers.retain(|subscriber| !subscriber.is_closed());
+                                        for subscriber in subscribers.iter() {
+                                            if subscriber.try_send(event.clone()).is_err() {
+                                                warn!("Subscriber is lagging behind. Dropping event.");
+                                            }
+                                        }
+                                        if subscribers.is_empty() {
+                                            debug!("No subscribers left. Unsubscribing.");
+                                            subscriptions.remove(&topic);
+                                            let binary = to_message_bytes(&ClientMessage::Unsubscribe { topic });
+                                            if let Err(e) = outbox.send(Message::Binary(binary)) {
+                                                warn!("Failed to send unsubscription. Error: {e}");
+                                            }
+                                        }
+                                    }
+                                    ServerMessage::Batch(_) => warn!("Received a batch inside a batch. Ignoring."),
                                 }
                             }
                         }
@@ -248,18 +1534,265 @@ where
                 }
                 // await shutdown signal
                 _ = &mut shutdown => {
+                    debug!("Closing the connection.");
+                    let close = CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "client closed the connection".into(),
+                    };
+                    let _ = outbox.send(Message::Close(Some(close)));
+                    closing = true;
                     break;
                 }
             }
         }
 
         debug!("RPC handler loop exited.");
+        // let the writer flush what's queued and close the socket
+        drop(outbox);
+        if tokio::time::timeout(WRITER_SHUTDOWN_TIMEOUT, &mut writer).await.is_err() {
+            warn!("Timed out flushing messages to the server. Dropping them.");
+            writer.abort();
+        }
+        if closing {
+            // wait for the server to answer our close frame, so it sees an
+            // orderly close rather than the socket being reset
+            let answered = async { while let Some(Ok(_)) = source.next().await {} };
+            if tokio::time::timeout(WRITER_SHUTDOWN_TIMEOUT, answered).await.is_err() {
+                debug!("Server didn't answer the close frame in time.");
+            }
+        }
         Ok(())
     }
 
-    pub fn state(&self) -> &T {
-        &self.state
+    /// Returns a snapshot of the current connection state.
+    ///
+    /// The returned guard holds a read lock on the state, so don't hold it
+    /// across an `.await` or the connection loop won't be able to apply new
+    /// changes.
+    pub fn state(&self) -> watch::Ref<'_, T> {
+        self.state.borrow()
+    }
+
+    /// Returns a receiver that is notified every time the server changes the
+    /// connection state. Use [watch::Receiver::changed] to wait for the next
+    /// change and [watch::Receiver::borrow] to read it.
+    ///
+    /// As [Client::connect] holds onto the client for the lifetime of the
+    /// connection, grab a receiver before connecting if you want to observe
+    /// the state from elsewhere in your application. The same state is also
+    /// available from [ControlChannels::state].
+    pub fn state_changed(&self) -> watch::Receiver<T> {
+        self.state.subscribe()
+    }
+
+    /// Returns a cloneable handle to the connection state.
+    pub fn state_handle(&self) -> StateHandle<T> {
+        StateHandle {
+            state: self.state.subscribe(),
+        }
+    }
+
+    /// Resolves the server, dials it, or the proxy, and performs the TLS and
+    /// WebSocket handshakes, telling apart which step failed.
+    async fn open(
+        &self,
+        req: Request<()>,
+    ) -> Result<(WebSocketStream<Deflate<MaybeTlsStream<TcpStream>>>, Response, SocketAddr), ConnectError> {
+        let host = req.uri().host().unwrap_or_default().trim_start_matches('[').trim_end_matches(']').to_string();
+        let port = req.uri().port_u16().unwrap_or(443);
+
+        let socket = match self.config.proxy.as_ref().filter(|proxy| !proxy.bypasses(&host)) {
+            Some(proxy) => {
+                debug!("Connecting through proxy {}:{}", proxy.host(), proxy.port());
+                let mut socket = dial(proxy.host(), proxy.port()).await?;
+                proxy.open_tunnel(&mut socket, &host, port).await.map_err(ConnectError::Proxy)?;
+                socket
+            }
+            None => dial(&host, port).await?,
+        };
+        let addr = socket.peer_addr().map_err(ConnectError::Tcp)?;
+        // calls are small and waited on, so don't hold them back for Nagle's
+        // algorithm
+        socket.set_nodelay(true).map_err(ConnectError::Tcp)?;
+        if let Some(linger) = self.config.linger {
+            set_linger(&socket, linger).map_err(ConnectError::Tcp)?;
+        }
+
+        let server_name = ServerName::try_from(host.as_str())
+            .map_err(|e| ConnectError::Tls(io::Error::new(io::ErrorKind::InvalidInput, e)))?;
+        let tls_stream = TlsConnector::from(Arc::new(self.config.tls.clone()))
+            .connect(server_name, socket)
+            .await
+            .map_err(ConnectError::Tls)?;
+
+        let (stream, res) = self.handshake(req, MaybeTlsStream::Rustls(tls_stream)).await?;
+        Ok((stream, res, addr))
+    }
+
+    /// Performs the WebSocket handshake over `stream`. A server that turns
+    /// the client away for speaking another protocol version says which ones
+    /// it speaks, which is reported as [ConnectError::VersionMismatch].
+    async fn handshake<S>(&self, req: Request<()>, stream: S) -> Result<(WebSocketStream<Deflate<S>>, Response), ConnectError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let stream = Deflate::new(stream, Side::Client, self.config.compression, &self.config.ws_config);
+        match client_async_with_config(req, stream, Some(self.config.ws_config)).await {
+            Ok(handshake) => Ok(handshake),
+            Err(tungstenite::Error::Http(response)) if response.headers().contains_key(SUPPORTED_VERSIONS_HEADER) => {
+                let theirs = &response.headers()[SUPPORTED_VERSIONS_HEADER];
+                error!("Server speaks {:?}, but we speak {:?}", theirs, self.protocol_versions);
+                Err(ConnectError::VersionMismatch {
+                    ours: protocol_list(&self.protocol_versions, self.config.codec.name()),
+                    theirs: Some(String::from_utf8_lossy(theirs.as_bytes()).into_owned()),
+                })
+            }
+            Err(tungstenite::Error::Http(response)) if response.headers().contains_key(CODEC_HEADER) => {
+                let theirs = &response.headers()[CODEC_HEADER];
+                error!("Server uses the {:?} codec, but we use {}", theirs, self.config.codec.name());
+                Err(ConnectError::CodecMismatch {
+                    ours: self.config.codec.name().to_string(),
+                    theirs: Some(String::from_utf8_lossy(theirs.as_bytes()).into_owned()),
+                })
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Sends a unary call to the server under a free id, or fails it straight
+/// away if there isn't one.
+fn send_unary_call(
+    active_rpc_calls: &mut [Option<PendingCall>],
+    outbox: &Outbox,
+    internal: Vec<u8>,
+    timeout: Option<Duration>,
+    completion_tx: ResponseSender,
+) {
+    // find a free rpc id
+    let Some(id) = active_rpc_calls.iter().position(|x| x.is_none()) else {
+        warn!("No free RPC id available. Responding with an error.");
+        let _ = completion_tx.send(Err(RpcHandlerError::TooManyCallsInFlight));
+        return;
+    };
+    let span = span!(Level::DEBUG, "rpc", id = id as u8, traceparent = field::Empty);
+    let _enter = span.enter();
+    debug!("Found free RPC id");
+
+    #[cfg(feature = "trace-context")]
+    let traceparent = {
+        let traceparent = new_traceparent();
+        span.record("traceparent", traceparent.as_str());
+        Some(traceparent)
+    };
+    #[cfg(not(feature = "trace-context"))]
+    let traceparent: Option<String> = None;
+
+    let id = id as u8;
+    let msg = match (timeout, traceparent) {
+        (Some(timeout), traceparent) => ClientMessage::TimedRPCRequest {
+            id,
+            internal,
+            timeout_ms: timeout.as_millis().try_into().unwrap_or(u32::MAX),
+            traceparent,
+        },
+        (None, Some(traceparent)) => ClientMessage::TracedRPCRequest { id, internal, traceparent },
+        (None, None) => ClientMessage::RPCRequest { id, internal },
+    };
+    let binary = to_message_bytes(&msg);
+
+    debug!("Sending RPC call to server");
+    if let Err(e) = outbox.send(Message::Binary(binary)) {
+        warn!("Failed to send RPC call. Ignoring. Error: {e}");
+        // we don't care if the receiver has dropped
+        let _ = completion_tx.send(Err(RpcHandlerError::ClientNotConnected));
+        return;
+    }
+    debug!("RPC call sent to server");
+    active_rpc_calls[id as usize] = Some(PendingCall::Unary(completion_tx));
+}
+
+/// Resolves `host` and connects to whichever of its addresses accepts first.
+/// The socket is dialed here rather than by tungstenite so it can be
+/// configured before the handshake.
+///
+/// Addresses are tried as RFC 8305 ("Happy Eyeballs") suggests, so one that
+/// doesn't answer, e.g. an IPv6 address on a network without IPv6, can't
+/// stall the connection: IPv6 and IPv4 addresses are taken in turn, starting
+/// with IPv6, and each gets [CONNECTION_ATTEMPT_DELAY] before the next is
+/// tried alongside it, or none if it fails sooner. The other attempts are
+/// dropped once one connects.
+async fn dial(host: &str, port: u16) -> Result<TcpStream, ConnectError> {
+    let addrs = interleave_families(lookup_host((host, port)).await.map_err(ConnectError::Dns)?.collect());
+    let mut addrs = addrs.into_iter();
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "host resolved to no addresses");
+    let mut attempts = FuturesUnordered::new();
+    loop {
+        if attempts.is_empty() {
+            match addrs.next() {
+                Some(addr) => attempts.push(TcpStream::connect(addr)),
+                None => return Err(ConnectError::Tcp(last_error)),
+            }
+        }
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(socket) => return Ok(socket),
+                Err(e) => {
+                    last_error = e;
+                    if let Some(addr) = addrs.next() {
+                        attempts.push(TcpStream::connect(addr));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if addrs.len() > 0 => {
+                if let Some(addr) = addrs.next() {
+                    attempts.push(TcpStream::connect(addr));
+                }
+            }
+        }
+    }
+}
+
+/// Orders addresses IPv6, IPv4, IPv6 and so on, keeping the resolver's order
+/// within each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (VecDeque<_>, VecDeque<_>) = addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop_front());
+        ordered.extend(v4.pop_front());
     }
+    ordered
+}
+
+/// Writes the messages the connection loop queues to the socket, then closes
+/// it once the queue is closed and drained. Stops early if the socket fails.
+/// Messages are queued serialized with rkyv, and encoded with `codec` as
+/// they're written.
+async fn write_messages<S>(
+    mut sink: SplitSink<WebSocketStream<S>, Message>,
+    mut outbox: OutboxQueue,
+    codec: Arc<dyn Codec>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    while let Some(msg) = outbox.recv().await {
+        let msg = match msg {
+            Message::Binary(bytes) => match client_message_from_rkyv(&*codec, bytes) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Failed to encode a message for the server. Dropping it. Error: {e}");
+                    continue;
+                }
+            },
+            msg => msg,
+        };
+        if let Err(e) = sink.send(msg).await {
+            warn!("Failed to write to server. Error: {e}");
+            return;
+        }
+    }
+    let _ = sink.close().await;
 }
 
 struct NoCertificateVerification {}
@@ -277,3 +1810,29 @@ impl ServerCertVerifier for NoCertificateVerification {
         Ok(ServerCertVerified::assertion())
     }
 }
+
+/// Trusts the server only if its certificate is exactly the pinned one.
+struct PinnedCertificateVerification {
+    pinned: Certificate,
+}
+
+impl ServerCertVerifier for PinnedCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        if end_entity == &self.pinned {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            warn!("Server presented a certificate that doesn't match the pinned one");
+            Err(tokio_rustls::rustls::Error::InvalidCertificateData(
+                "certificate doesn't match the pinned certificate".into(),
+            ))
+        }
+    }
+}
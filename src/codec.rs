@@ -0,0 +1,275 @@
+use std::fmt;
+
+use rkyv::{de::deserializers::SharedDeserializeMap, validation::validators::DefaultValidator, AlignedVec, Archive, CheckBytes, Deserialize};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::wire::{to_message_bytes, ClientMessage, ServerMessage};
+
+/// The codec a bare subprotocol like `hl/5` means, as clients from before
+/// codecs could be chosen don't name one.
+pub(crate) const DEFAULT_CODEC: &str = "rkyv";
+
+/// How [ClientMessage]s and [ServerMessage]s are encoded on the wire. Both
+/// sides have to use the same one, which they agree on in the handshake: its
+/// name follows the version in the `Sec-WebSocket-Protocol` header, e.g.
+/// `hl/5+json`, and a server turns away clients that use another with
+/// [ConnectError::CodecMismatch](crate::ConnectError::CodecMismatch).
+///
+/// Only the messages themselves are encoded by the codec. The payloads in
+/// them, e.g. a call's arguments and the values of state fields, are opaque
+/// bytes encoded by the application, with rkyv when it uses the macros.
+pub trait Codec: Send + Sync {
+    /// The codec's name in the subprotocol, e.g. `json`. Sides whose codecs
+    /// have the same name must encode messages the same way.
+    fn name(&self) -> &'static str;
+
+    /// A byte that identifies the codec, e.g. to record which one encoded
+    /// messages that are stored. Unique among the codecs this crate has.
+    fn id(&self) -> u8;
+
+    /// Whether messages are sent in text frames rather than binary ones, for
+    /// clients that can only handle text, e.g. browsers reading JSON. The
+    /// codec's encoding must then be valid UTF-8.
+    fn text(&self) -> bool {
+        false
+    }
+
+    /// Encodes a message for the client to send.
+    fn encode_client(&self, msg: &ClientMessage) -> Result<Vec<u8>, CodecError>;
+
+    /// Decodes a message the server received from a client.
+    fn decode_client(&self, bytes: &[u8]) -> Result<ClientMessage, CodecError>;
+
+    /// Encodes a message for the server to send. A [ServerMessage::Batch]
+    /// holds messages that have already been encoded with the same codec.
+    fn encode_server(&self, msg: &ServerMessage) -> Result<Vec<u8>, CodecError>;
+
+    /// Decodes a message the client received from the server.
+    fn decode_server(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError>;
+}
+
+/// A message that a [Codec] couldn't encode or decode.
+#[derive(Debug)]
+pub struct CodecError(String);
+
+impl CodecError {
+    pub fn new(error: impl fmt::Display) -> Self {
+        Self(error.to_string())
+    }
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// Encodes messages with rkyv, which the runtime reads in place without
+/// copying their payloads out. This is the default, and the codec clients
+/// that don't name one use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RkyvCodec;
+
+impl Codec for RkyvCodec {
+    fn name(&self) -> &'static str {
+        DEFAULT_CODEC
+    }
+
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn encode_client(&self, msg: &ClientMessage) -> Result<Vec<u8>, CodecError> {
+        Ok(to_message_bytes(msg))
+    }
+
+    fn decode_client(&self, bytes: &[u8]) -> Result<ClientMessage, CodecError> {
+        from_rkyv(bytes)
+    }
+
+    fn encode_server(&self, msg: &ServerMessage) -> Result<Vec<u8>, CodecError> {
+        Ok(to_message_bytes(msg))
+    }
+
+    fn decode_server(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        from_rkyv(bytes)
+    }
+}
+
+/// Encodes messages as JSON, e.g. to read them while debugging. Payloads are
+/// base64 strings. See [JsonTextCodec] for one browsers can use.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl Codec for JsonCodec {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn encode_client(&self, msg: &ClientMessage) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(msg).map_err(CodecError::new)
+    }
+
+    fn decode_client(&self, bytes: &[u8]) -> Result<ClientMessage, CodecError> {
+        serde_json::from_slice(bytes).map_err(CodecError::new)
+    }
+
+    fn encode_server(&self, msg: &ServerMessage) -> Result<Vec<u8>, CodecError> {
+        serde_json::to_vec(msg).map_err(CodecError::new)
+    }
+
+    fn decode_server(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        serde_json::from_slice(bytes).map_err(CodecError::new)
+    }
+}
+
+/// Encodes messages as JSON like [JsonCodec], but sends them in text frames,
+/// so a client with no rkyv, e.g. in a browser, can talk to the server. Its
+/// subprotocol is `hl/5+json-text`, or `hl.5+json-text` for browsers, which
+/// don't allow a `/` in one. See the README for the messages' JSON.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonTextCodec;
+
+#[cfg(feature = "json")]
+impl Codec for JsonTextCodec {
+    fn name(&self) -> &'static str {
+        "json-text"
+    }
+
+    fn id(&self) -> u8 {
+        3
+    }
+
+    fn text(&self) -> bool {
+        true
+    }
+
+    fn encode_client(&self, msg: &ClientMessage) -> Result<Vec<u8>, CodecError> {
+        JsonCodec.encode_client(msg)
+    }
+
+    fn decode_client(&self, bytes: &[u8]) -> Result<ClientMessage, CodecError> {
+        JsonCodec.decode_client(bytes)
+    }
+
+    fn encode_server(&self, msg: &ServerMessage) -> Result<Vec<u8>, CodecError> {
+        JsonCodec.encode_server(msg)
+    }
+
+    fn decode_server(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        JsonCodec.decode_server(bytes)
+    }
+}
+
+/// Encodes messages with bincode, for clients in languages that have a
+/// bincode implementation but no rkyv one.
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl Codec for BincodeCodec {
+    fn name(&self) -> &'static str {
+        "bincode"
+    }
+
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn encode_client(&self, msg: &ClientMessage) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(msg).map_err(CodecError::new)
+    }
+
+    fn decode_client(&self, bytes: &[u8]) -> Result<ClientMessage, CodecError> {
+        bincode::deserialize(bytes).map_err(CodecError::new)
+    }
+
+    fn encode_server(&self, msg: &ServerMessage) -> Result<Vec<u8>, CodecError> {
+        bincode::serialize(msg).map_err(CodecError::new)
+    }
+
+    fn decode_server(&self, bytes: &[u8]) -> Result<ServerMessage, CodecError> {
+        bincode::deserialize(bytes).map_err(CodecError::new)
+    }
+}
+
+/// Reads an rkyv message, copying it to realign it first if it has to.
+fn from_rkyv<T>(bytes: &[u8]) -> Result<T, CodecError>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<T, SharedDeserializeMap>,
+{
+    if (bytes.as_ptr() as usize).is_multiple_of(AlignedVec::ALIGNMENT) {
+        return rkyv::from_bytes(bytes).map_err(CodecError::new);
+    }
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(bytes);
+    rkyv::from_bytes(&aligned).map_err(CodecError::new)
+}
+
+/// Whether `codec` encodes messages with rkyv, as the runtime does, so they
+/// can be sent and read as they are.
+pub(crate) fn is_native(codec: &dyn Codec) -> bool {
+    codec.name() == DEFAULT_CODEC
+}
+
+/// Converts a message from the client to rkyv, so the runtime can read it in
+/// place.
+pub(crate) fn client_message_to_rkyv(codec: &dyn Codec, bytes: Vec<u8>) -> Result<Vec<u8>, CodecError> {
+    if is_native(codec) {
+        return Ok(bytes);
+    }
+    Ok(to_message_bytes(&codec.decode_client(&bytes)?))
+}
+
+/// Converts a message the client's runtime serialized with rkyv to the frame
+/// the codec sends it in.
+pub(crate) fn client_message_from_rkyv(codec: &dyn Codec, bytes: Vec<u8>) -> Result<Message, CodecError> {
+    if is_native(codec) {
+        return Ok(Message::Binary(bytes));
+    }
+    to_frame(codec, codec.encode_client(&from_rkyv(&bytes)?)?)
+}
+
+/// Converts a message the server's runtime serialized with rkyv to the frame
+/// the codec sends it in.
+pub(crate) fn server_message_from_rkyv(codec: &dyn Codec, bytes: Vec<u8>) -> Result<Message, CodecError> {
+    if is_native(codec) {
+        return Ok(Message::Binary(bytes));
+    }
+    to_frame(codec, encode_server_message(codec, bytes)?)
+}
+
+/// Re-encodes a [ServerMessage] serialized with rkyv with the codec,
+/// including the messages in a [ServerMessage::Batch].
+fn encode_server_message(codec: &dyn Codec, bytes: Vec<u8>) -> Result<Vec<u8>, CodecError> {
+    let msg = match from_rkyv(&bytes)? {
+        ServerMessage::Batch(batch) => ServerMessage::Batch(
+            batch
+                .into_iter()
+                .map(|bytes| encode_server_message(codec, bytes))
+                .collect::<Result<_, _>>()?,
+        ),
+        msg => msg,
+    };
+    codec.encode_server(&msg)
+}
+
+/// Wraps an encoded message in a text or binary frame, whichever the codec
+/// uses.
+fn to_frame(codec: &dyn Codec, bytes: Vec<u8>) -> Result<Message, CodecError> {
+    if codec.text() {
+        return String::from_utf8(bytes).map(Message::Text).map_err(CodecError::new);
+    }
+    Ok(Message::Binary(bytes))
+}
@@ -0,0 +1,239 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc::error::TrySendError, Mutex, MutexGuard},
+};
+
+use crate::{
+    server::{HandlerResult, StateUpdateChannel},
+    transaction::{self, Participant},
+    wire::{FieldId, RpcHandlerError},
+};
+
+/// A state whose changes can be worked out by comparing it to an earlier
+/// copy, so [ConnectionState] can send them to the client. Implement it with
+/// [state_diff!](crate::state_diff), or with [impl_state!](crate::impl_state)
+/// along with [State](crate::State).
+pub trait StateDiff: Clone {
+    /// The fields that differ from `previous`, with their new values
+    /// serialized, as they're sent on a [StateUpdateChannel].
+    fn diff(&self, previous: &Self) -> Vec<(FieldId, Vec<u8>)>;
+}
+
+/// Implements [StateDiff] for a state struct by comparing the listed fields,
+/// each with the [FieldId] it's sent as. Ids must be unique, which is
+/// checked at compile time, and shouldn't change once clients use them.
+///
+/// ```ignore
+/// #[derive(Clone, Default)]
+/// struct CounterState {
+///     counter: u32,
+/// }
+///
+/// state_diff!(CounterState { counter = 0 });
+/// ```
+#[macro_export]
+macro_rules! state_diff {
+    ($state:ident { $($field:ident = $id:expr),* $(,)? }) => {
+        const _: () = $crate::assert_unique_field_ids(&[$($id),*]);
+
+        impl $crate::StateDiff for $state {
+            fn diff(&self, previous: &Self) -> Vec<($crate::FieldId, Vec<u8>)> {
+                let mut changes = Vec::new();
+                $(
+                    if self.$field != previous.$field {
+                        changes.push(($id, $crate::serialize_field(&self.$field)));
+                    }
+                )*
+                changes
+            }
+        }
+    };
+}
+
+/// A connection's state on the server, which sends the client what changed
+/// each time it's unlocked. Handlers keep one in place of hand-rolling a
+/// guard around a mutex and their [StateUpdateChannel].
+///
+/// The lock is async, so it can be held across `.await`s without blocking
+/// the runtime. Awaiting [ConnectionStateGuard::commit] sends the changes at
+/// a point of your choosing, waits if the client is behind, and says if they
+/// couldn't be sent. Dropping the guard sends whatever hasn't been
+/// committed as best it can, which is fine for simple handlers.
+///
+/// A state created by the handler factory or a call takes part in the
+/// connection's transactions: the runtime snapshots it when the client
+/// begins one, and puts the snapshot back if it's rolled back.
+pub struct ConnectionState<S> {
+    inner: Arc<Inner<S>>,
+    channel: StateUpdateChannel,
+}
+
+struct Inner<S> {
+    state: Mutex<S>,
+    /// The state as of the beginning of the open transaction, if there is one.
+    snapshot: std::sync::Mutex<Option<S>>,
+}
+
+impl<S> ConnectionState<S>
+where
+    S: StateDiff + Send + 'static,
+{
+    /// Wraps `state`, sending its changes on `channel`, the one the runtime
+    /// gave [Handler::new](crate::Handler::new).
+    pub fn new(channel: StateUpdateChannel, state: S) -> Self {
+        let inner = Arc::new(Inner {
+            state: Mutex::new(state),
+            snapshot: std::sync::Mutex::new(None),
+        });
+        if let Some(transactions) = transaction::current() {
+            transactions.register(Arc::downgrade(&inner) as _);
+        }
+        Self { inner, channel }
+    }
+
+    /// Locks the state, waiting for other calls to unlock it first.
+    pub async fn lock(&self) -> ConnectionStateGuard<'_, S> {
+        let state = self.inner.state.lock().await;
+        ConnectionStateGuard {
+            starting_state: state.clone(),
+            state,
+            channel: &self.channel,
+            suppressed: false,
+        }
+    }
+}
+
+// The runtime only begins, commits or rolls back a transaction while none
+// of the client's calls are running, so the state is normally unlocked. If
+// something else has it locked, the client is told to try again.
+#[async_trait]
+impl<S> Participant for Inner<S>
+where
+    S: StateDiff + Send + 'static,
+{
+    async fn begin(&self) -> HandlerResult<()> {
+        let state = self.state.try_lock().map_err(|_| RpcHandlerError::Overloaded)?;
+        *self.snapshot.lock().unwrap() = Some(state.clone());
+        Ok(())
+    }
+
+    async fn commit(&self) {
+        self.snapshot.lock().unwrap().take();
+    }
+
+    async fn rollback(&self) -> HandlerResult<()> {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        if snapshot.is_some() {
+            let mut state = self.state.try_lock().map_err(|_| RpcHandlerError::Overloaded)?;
+            // the client never saw the transaction's changes, so there's
+            // nothing to tell it
+            *state = snapshot.take().unwrap();
+        }
+        Ok(())
+    }
+}
+
+/// A locked [ConnectionState]. Derefs to the state.
+pub struct ConnectionStateGuard<'a, S>
+where
+    S: StateDiff,
+{
+    state: MutexGuard<'a, S>,
+    /// The state as of locking or the last commit, to compare against.
+    starting_state: S,
+    channel: &'a StateUpdateChannel,
+    suppressed: bool,
+}
+
+impl<S> ConnectionStateGuard<'_, S>
+where
+    S: StateDiff,
+{
+    /// Sends the changes made so far, as one state change, waiting while the
+    /// [StateUpdateChannel] is full because the client is behind. Like any
+    /// changes a call sends before it returns, they reach the client before
+    /// the call's response. Fails with [RpcHandlerError::ClientNotConnected]
+    /// if the client has disconnected, so there's no one to tell.
+    pub async fn commit(&mut self) -> HandlerResult<()> {
+        let changes = self.take_changes();
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.channel
+            .send(changes)
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)
+    }
+
+    /// The changes since locking or the last commit, which are then the
+    /// state to compare against.
+    fn take_changes(&mut self) -> Vec<(FieldId, Vec<u8>)> {
+        let changes = self.state.diff(&self.starting_state);
+        if !changes.is_empty() {
+            self.starting_state = self.state.clone();
+        }
+        changes
+    }
+
+    /// Stops the guard from sending anything when it's dropped, e.g. for a
+    /// transient change that's undone before unlocking. Changes made while
+    /// suppressed are never sent.
+    pub fn suppress(&mut self) {
+        self.suppressed = true;
+    }
+}
+
+impl<S> Drop for ConnectionStateGuard<'_, S>
+where
+    S: StateDiff,
+{
+    fn drop(&mut self) {
+        if !self.suppressed {
+            let changes = self.take_changes();
+            send_dropped(self.channel, changes);
+        }
+    }
+}
+
+/// Sends the changes a guard had when it was dropped, which can't wait. If
+/// the channel is full, they're sent from a task of their own, so they may
+/// reach the client after changes committed later.
+pub(crate) fn send_dropped(channel: &StateUpdateChannel, changes: Vec<(FieldId, Vec<u8>)>) {
+    if changes.is_empty() {
+        return;
+    }
+    if let Err(TrySendError::Full(changes)) = channel.try_send(changes) {
+        if let Ok(runtime) = Handle::try_current() {
+            let channel = channel.clone();
+            runtime.spawn(async move {
+                let _ = channel.send(changes).await;
+            });
+        }
+    }
+}
+
+impl<S> Deref for ConnectionStateGuard<'_, S>
+where
+    S: StateDiff,
+{
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl<S> DerefMut for ConnectionStateGuard<'_, S>
+where
+    S: StateDiff,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.state
+    }
+}
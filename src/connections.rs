@@ -0,0 +1,76 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Counts open connections, in total and per IP address.
+#[derive(Default)]
+pub(crate) struct ConnectionTracker {
+    counts: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Why a connection was turned away.
+pub(crate) enum ConnectionRejection {
+    ServerFull,
+    TooManyFromIp,
+    /// The client asked for a path that a multi-service server doesn't serve.
+    UnknownService,
+}
+
+impl ConnectionTracker {
+    /// Counts a new connection from `ip`, unless that would go over either
+    /// limit. The connection is counted until the returned slot is dropped.
+    pub fn try_open(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        max_connections: Option<usize>,
+        max_connections_per_ip: Option<usize>,
+    ) -> Result<ConnectionSlot, ConnectionRejection> {
+        let mut counts = self.counts.lock().unwrap();
+        if max_connections.is_some_and(|max| counts.total >= max) {
+            return Err(ConnectionRejection::ServerFull);
+        }
+        let from_ip = counts.per_ip.get(&ip).copied().unwrap_or(0);
+        if max_connections_per_ip.is_some_and(|max| from_ip >= max) {
+            return Err(ConnectionRejection::TooManyFromIp);
+        }
+        counts.total += 1;
+        *counts.per_ip.entry(ip).or_default() += 1;
+        Ok(ConnectionSlot {
+            tracker: self.clone(),
+            ip,
+        })
+    }
+
+    /// The number of open connections.
+    pub fn count(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+}
+
+/// A counted connection. Dropping it frees the slot, so the count stays right
+/// however the connection ends, including if its task panics.
+pub(crate) struct ConnectionSlot {
+    tracker: Arc<ConnectionTracker>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.tracker.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(from_ip) = counts.per_ip.get_mut(&self.ip) {
+            *from_ip -= 1;
+            if *from_ip == 0 {
+                counts.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use std::{
+    any::{Any, TypeId},
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock, RwLock},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_tungstenite::tungstenite::http::{header::AsHeaderName, HeaderMap, HeaderValue};
+
+use crate::{
+    idempotency::IdempotencyStore,
+    server::{HandlerResult, ServerHandle, SharedStateChannel},
+    stats::ConnectionStats,
+    tls::TlsInfo,
+    topics::{ConnectionId, Subscriptions},
+    transaction::Transactions,
+    wire::RpcHandlerError,
+};
+
+/// Used by a [Context] to ask the connection task to call the client.
+pub(crate) type ClientCallSender = mpsc::Sender<(Vec<u8>, oneshot::Sender<HandlerResult<Vec<u8>>>)>;
+
+/// Per-connection information that is available to every RPC call made on the
+/// connection. A [Context] is created when a client connects and is passed to
+/// the handler factory once the handshake has been validated, so it can be
+/// used to set up initial [Extensions], e.g. from what the
+/// [TokenValidator](crate::TokenValidator) attached.
+pub struct Context {
+    connection_id: ConnectionId,
+    peer_addr: SocketAddr,
+    version: String,
+    negotiated_version: OnceLock<String>,
+    client_version: OnceLock<String>,
+    headers: OnceLock<HeaderMap>,
+    query: OnceLock<Vec<(String, String)>>,
+    tls: Option<TlsInfo>,
+    extensions: Extensions,
+    scopes: RwLock<HashSet<String>>,
+    stats: ConnectionStats,
+    server: ServerHandle,
+    client_calls: ClientCallSender,
+    client_call_timeout: Duration,
+    rooms: Mutex<Subscriptions>,
+    idempotency: IdempotencyStore,
+    /// Where [SharedState](crate::SharedState)s send their changes, apart
+    /// from the connection's own, which its transactions hold back.
+    shared_state_changes: SharedStateChannel,
+    transactions: Arc<Transactions>,
+    cancellation: CancellationToken,
+}
+
+impl Context {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        connection_id: ConnectionId,
+        peer_addr: SocketAddr,
+        version: String,
+        tls: Option<TlsInfo>,
+        server: ServerHandle,
+        client_calls: ClientCallSender,
+        client_call_timeout: Duration,
+        rooms: Subscriptions,
+        idempotency: IdempotencyStore,
+        shared_state_changes: SharedStateChannel,
+        cancellation: CancellationToken,
+    ) -> Self {
+        Self {
+            connection_id,
+            peer_addr,
+            version,
+            negotiated_version: OnceLock::new(),
+            client_version: OnceLock::new(),
+            headers: OnceLock::new(),
+            query: OnceLock::new(),
+            tls,
+            extensions: Extensions::default(),
+            scopes: RwLock::default(),
+            stats: ConnectionStats::default(),
+            server,
+            client_calls,
+            client_call_timeout,
+            rooms: Mutex::new(rooms),
+            idempotency,
+            shared_state_changes,
+            transactions: Arc::default(),
+            cancellation,
+        }
+    }
+
+    pub(crate) fn shared_state_changes(&self) -> &SharedStateChannel {
+        &self.shared_state_changes
+    }
+
+    /// The connection's open transaction, and the states that take part in
+    /// it.
+    pub(crate) fn transactions(&self) -> &Arc<Transactions> {
+        &self.transactions
+    }
+
+    /// The server-assigned id of this connection.
+    pub fn connection_id(&self) -> ConnectionId {
+        self.connection_id
+    }
+
+    /// The address of the client.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// The HardLight subprotocol negotiated with the client, e.g. `hl/5`.
+    /// Until it's been negotiated, e.g. in a
+    /// [TokenValidator](crate::TokenValidator), it's the highest one the
+    /// server speaks.
+    pub fn version(&self) -> &str {
+        self.negotiated_version.get().unwrap_or(&self.version)
+    }
+
+    pub(crate) fn set_version(&self, version: String) {
+        let _ = self.negotiated_version.set(version);
+    }
+
+    /// The client's full HardLight version, e.g. `0.2.0`, for diagnosing
+    /// mismatches that [Self::version] doesn't catch. `None` until the
+    /// handshake is done, or if the client didn't send it.
+    pub fn client_version(&self) -> Option<&str> {
+        self.client_version.get().map(String::as_str)
+    }
+
+    pub(crate) fn set_client_version(&self, version: String) {
+        let _ = self.client_version.set(version);
+    }
+
+    /// A header the client connected with, e.g. one it added with
+    /// [Client::set_header](crate::Client::set_header). `None` until the
+    /// handshake has started, so it can be read from a
+    /// [TokenValidator](crate::TokenValidator) and the handler factory.
+    pub fn header(&self, name: impl AsHeaderName) -> Option<&HeaderValue> {
+        self.headers.get()?.get(name)
+    }
+
+    /// The first value of a query parameter the client connected with, e.g.
+    /// one it added with [Client::set_query_param](crate::Client::set_query_param),
+    /// decoded. Like [Self::header], it's `None` until the handshake has
+    /// started.
+    pub fn query_param(&self, key: &str) -> Option<&str> {
+        let query = self.query.get()?;
+        query.iter().find(|(k, _)| k == key).map(|(_, value)| value.as_str())
+    }
+
+    pub(crate) fn set_request(&self, headers: HeaderMap, query: Vec<(String, String)>) {
+        let _ = self.headers.set(headers);
+        let _ = self.query.set(query);
+    }
+
+    /// The TLS session the client connected with, e.g. to log its cipher
+    /// suite or check its certificate. `None` if the connection doesn't use
+    /// TLS, e.g. one made with [testing::connect](crate::testing::connect).
+    pub fn tls(&self) -> Option<&TlsInfo> {
+        self.tls.as_ref()
+    }
+
+    /// Arbitrary typed data attached to this connection, e.g. the
+    /// authenticated user.
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    /// Grants this connection a permission scope, e.g. `counter.write`, once
+    /// the client has proven it has it, e.g. from its certificate or a token.
+    /// Calls to methods that require a scope fail with
+    /// [RpcHandlerError::Unauthorized] until it's granted. See
+    /// [Handler::required_scope](crate::Handler::required_scope).
+    pub fn grant_scope(&self, scope: &str) {
+        self.scopes.write().unwrap().insert(scope.to_string());
+    }
+
+    /// Takes a scope away from this connection, e.g. when its token expires.
+    pub fn revoke_scope(&self, scope: &str) {
+        self.scopes.write().unwrap().remove(scope);
+    }
+
+    /// Whether this connection has been granted `scope`.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.read().unwrap().contains(scope)
+    }
+
+    /// Counters for this connection, e.g. bytes sent and received.
+    pub fn stats(&self) -> &ConnectionStats {
+        &self.stats
+    }
+
+    /// Responses to calls made with an idempotency key, kept so retried calls
+    /// aren't run twice.
+    pub fn idempotency(&self) -> &IdempotencyStore {
+        &self.idempotency
+    }
+
+    /// A handle to the server this connection belongs to.
+    pub fn server(&self) -> &ServerHandle {
+        &self.server
+    }
+
+    /// Cancelled when the connection closes. Calls get it as
+    /// [CallContext::cancellation]; it's here for work that isn't a call,
+    /// e.g. [Handler::handle_event](crate::Handler::handle_event), which is
+    /// aborted if it hasn't returned shortly after.
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Adds this connection to a room, so it receives events broadcast to the
+    /// room with [ServerHandle::broadcast]. Joining a room twice is a no-op.
+    /// The connection leaves all its rooms when it closes.
+    pub fn join_room(&self, room: &str) {
+        self.rooms.lock().unwrap().subscribe(room.to_string());
+    }
+
+    /// Removes this connection from a room.
+    pub fn leave_room(&self, room: &str) {
+        self.rooms.lock().unwrap().unsubscribe(room);
+    }
+
+    /// The rooms this connection is in.
+    pub fn rooms(&self) -> Vec<String> {
+        self.rooms.lock().unwrap().topics().cloned().collect()
+    }
+
+    /// Calls the client's [ClientHandler](crate::ClientHandler) and waits for
+    /// its response, e.g. to ask the user to confirm an action. Fails with
+    /// [RpcHandlerError::Timeout] if the client doesn't respond within the
+    /// server's configured `client_call_timeout`. A call that timed out keeps
+    /// its id until the client answers it anyway, and calls made while all
+    /// 256 ids are taken fail with [RpcHandlerError::TooManyCallsInFlight].
+    pub async fn call_client(&self, internal: Vec<u8>) -> HandlerResult<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        self.client_calls
+            .send((internal, tx))
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)?;
+        match tokio::time::timeout(self.client_call_timeout, rx).await {
+            Ok(Ok(output)) => output,
+            Ok(Err(_)) => Err(RpcHandlerError::ClientNotConnected),
+            Err(_) => Err(RpcHandlerError::Timeout),
+        }
+    }
+}
+
+/// A map holding at most one value of each type.
+///
+/// As a connection's [Context] is shared between concurrent RPC calls, the map
+/// uses interior mutability. Values are cloned out of the map rather than
+/// borrowed, so keep them cheap to clone (wrap big values in an `Arc`).
+#[derive(Default)]
+pub struct Extensions {
+    map: RwLock<HashMap<TypeId, Box<dyn Any + Send + Sync>>>,
+}
+
+impl Extensions {
+    /// Inserts a value, returning the previous value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&self, value: T) -> Option<T> {
+        self.map
+            .write()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok().map(|previous| *previous))
+    }
+
+    /// Returns a copy of the value of type `T`, if there is one.
+    pub fn get<T: Any + Send + Sync + Clone>(&self) -> Option<T> {
+        self.with(|value: &T| value.clone())
+    }
+
+    /// Calls `f` with a reference to the value of type `T`, if there is one.
+    pub fn with<T: Any + Send + Sync, R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let map = self.map.read().unwrap();
+        map.get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+            .map(f)
+    }
+
+    /// Returns whether there's a value of type `T`.
+    pub fn contains<T: Any + Send + Sync>(&self) -> bool {
+        self.map.read().unwrap().contains_key(&TypeId::of::<T>())
+    }
+
+    /// Removes and returns the value of type `T`, if there is one.
+    pub fn remove<T: Any + Send + Sync>(&self) -> Option<T> {
+        self.map
+            .write()
+            .unwrap()
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|value| *value))
+    }
+}
+
+/// What a handler knows about one RPC call, passed to
+/// [Handler::handle_call](crate::Handler::handle_call): the call's id, the
+/// connection it was made on, when the client gives up on it, and whether
+/// the connection has closed.
+pub struct CallContext<'a> {
+    connection: &'a Context,
+    id: u8,
+    deadline: Option<Instant>,
+    cancellation: CancellationToken,
+}
+
+impl<'a> CallContext<'a> {
+    pub(crate) fn new(connection: &'a Context, id: u8, deadline: Option<Instant>, cancellation: CancellationToken) -> Self {
+        Self {
+            connection,
+            id,
+            deadline,
+            cancellation,
+        }
+    }
+
+    /// The connection the call was made on, e.g. for the peer's address.
+    pub fn connection(&self) -> &'a Context {
+        self.connection
+    }
+
+    /// The call's id. It's only unique among the connection's calls in
+    /// flight, as ids are reused once a call has been answered.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// When the client stops waiting for the output, if it made the call with
+    /// [ControlChannels::call_with_timeout](crate::ControlChannels::call_with_timeout).
+    /// A handler still running then is dropped wherever it's up to, so one
+    /// with side effects should check it before starting on them.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Cancelled when the connection closes, after which the output can't
+    /// be sent. A handler that hasn't returned shortly after is aborted, so
+    /// long-running ones should check it between steps or `select!` on
+    /// [CancellationToken::cancelled].
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Rebinds the call to `connection`, e.g. the one an interceptor passed
+    /// on.
+    pub(crate) fn with_connection<'b>(&self, connection: &'b Context) -> CallContext<'b> {
+        CallContext {
+            connection,
+            id: self.id,
+            deadline: self.deadline,
+            cancellation: self.cancellation.clone(),
+        }
+    }
+}
+
+/// Tells a handler that the work it's doing is no longer wanted, e.g.
+/// because the connection it was for has closed.
+#[derive(Clone)]
+pub struct CancellationToken {
+    /// Closes when the token is cancelled, as its sender is dropped.
+    open: watch::Receiver<()>,
+}
+
+impl CancellationToken {
+    /// A token that's cancelled once `open`'s sender is dropped.
+    pub(crate) fn new(open: watch::Receiver<()>) -> Self {
+        Self { open }
+    }
+
+    /// Whether the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.open.has_changed().is_err()
+    }
+
+    /// Waits until the token is cancelled, e.g. to `select!` against
+    /// long-running work.
+    pub async fn cancelled(&self) {
+        let mut open = self.open.clone();
+        while open.changed().await.is_ok() {}
+    }
+}
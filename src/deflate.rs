@@ -0,0 +1,665 @@
+//! The permessage-deflate WebSocket extension (RFC 7692), which compresses
+//! each message with DEFLATE. tungstenite doesn't implement it and refuses
+//! the frames it marks, so [Deflate] does it underneath tungstenite instead:
+//! it inflates compressed messages as they're read, before tungstenite sees
+//! them, and deflates the messages tungstenite writes on their way out.
+
+use std::{
+    io::{self, Cursor},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::{
+    http::{HeaderMap, HeaderValue},
+    protocol::{
+        frame::{
+            coding::{Data, OpCode},
+            Frame, FrameHeader,
+        },
+        WebSocketConfig,
+    },
+};
+
+/// The header WebSocket extensions are negotiated in.
+pub(crate) const EXTENSIONS_HEADER: &str = "Sec-WebSocket-Extensions";
+
+const EXTENSION: &str = "permessage-deflate";
+
+/// How every deflated message ends, which is left off on the wire.
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// The largest LZ77 window, which is used unless either side asks for less.
+const MAX_WINDOW_BITS: u8 = 15;
+
+/// The smallest window zlib deflates with. RFC 7692 allows 8, which a peer
+/// is given 9 for instead.
+const MIN_WINDOW_BITS: u8 = 9;
+
+/// The most bytes read or written looking for the end of the handshake's
+/// HTTP head, after which the connection is left alone.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// How messages are compressed with the permessage-deflate WebSocket
+/// extension. See [ServerConfig::compression](crate::ServerConfig::compression)
+/// and [Client::set_compression](crate::Client::set_compression).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompressionConfig {
+    /// The LZ77 window messages are compressed with, from 9 to 15 bits, i.e.
+    /// 512 bytes to 32 KiB. The peer is asked to compress with no bigger a
+    /// window, too, so each end needs less memory per connection, at the
+    /// cost of compressing less. Defaults to 15.
+    pub window_bits: u8,
+    /// Messages smaller than this many bytes are sent uncompressed, as
+    /// compressing them costs more CPU time than it saves bandwidth.
+    /// Defaults to 256.
+    pub min_size: usize,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            window_bits: MAX_WINDOW_BITS,
+            min_size: 256,
+        }
+    }
+}
+
+impl CompressionConfig {
+    /// [Self::window_bits], as a window zlib can have.
+    fn window(&self) -> u8 {
+        self.window_bits.clamp(MIN_WINDOW_BITS, MAX_WINDOW_BITS)
+    }
+}
+
+/// What the client offers: to deflate each message on its own, as it
+/// doesn't keep its context between messages, with as small a window as the
+/// server asks for, and for the server to keep to `config`'s window.
+pub(crate) fn offer(config: &CompressionConfig) -> HeaderValue {
+    let mut offer = format!("{EXTENSION}; client_max_window_bits");
+    if config.window() < MAX_WINDOW_BITS {
+        offer += &format!("; server_max_window_bits={}", config.window());
+    }
+    HeaderValue::from_str(&offer).unwrap()
+}
+
+/// The server's answer to the extensions the client offered in `headers`,
+/// if it offered permessage-deflate in a way the server can accept. The
+/// server doesn't keep its context between messages either, and deflates
+/// with the smaller of `config`'s window and the one the client asks for.
+pub(crate) fn accept(headers: &HeaderMap, config: &CompressionConfig) -> Option<HeaderValue> {
+    let offers = headers
+        .get_all(EXTENSIONS_HEADER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(extensions)
+        .filter(|(name, _)| *name == EXTENSION);
+    // the client lists its offers in the order it prefers them
+    'offers: for (_, params) in offers {
+        let mut server_bits = config.window();
+        let mut client_limitable = false;
+        for (name, value) in params {
+            match name {
+                "server_no_context_takeover" | "client_no_context_takeover" if value.is_none() => {}
+                // the client can be told to keep to a smaller window
+                "client_max_window_bits" if value.is_none() || window_bits(value).is_some() => client_limitable = true,
+                "server_max_window_bits" => match window_bits(value) {
+                    Some(bits) if bits >= MIN_WINDOW_BITS => server_bits = server_bits.min(bits),
+                    _ => continue 'offers,
+                },
+                _ => continue 'offers,
+            }
+        }
+        let mut answer = format!("{EXTENSION}; server_no_context_takeover");
+        if server_bits < MAX_WINDOW_BITS {
+            answer += &format!("; server_max_window_bits={server_bits}");
+        }
+        if client_limitable && config.window() < MAX_WINDOW_BITS {
+            answer += &format!("; client_max_window_bits={}", config.window());
+        }
+        return HeaderValue::from_str(&answer).ok();
+    }
+    None
+}
+
+/// Splits a `Sec-WebSocket-Extensions` value into its extensions, each with
+/// its parameters.
+fn extensions(value: &str) -> impl Iterator<Item = (&str, Vec<(&str, Option<&str>)>)> {
+    value.split(',').map(|extension| {
+        let mut parts = extension.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let params = parts
+            .filter(|param| !param.is_empty())
+            .map(|param| match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param, None),
+            })
+            .collect();
+        (name, params)
+    })
+}
+
+/// The window size a `*_max_window_bits` parameter gives, if it's valid.
+fn window_bits(value: Option<&str>) -> Option<u8> {
+    value?.parse().ok().filter(|bits| (8..=MAX_WINDOW_BITS).contains(bits))
+}
+
+/// Which end of the connection a [Deflate] is on. The server's handshake
+/// response says what was negotiated, which the server writes and the
+/// client reads.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Side {
+    Client,
+    Server,
+}
+
+/// What the handshake negotiated for one end of the connection.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Params {
+    /// The window this end deflates its messages with.
+    deflate_bits: u8,
+    /// The window the peer deflates its messages with, at most.
+    inflate_bits: u8,
+}
+
+/// What the handshake response `head` negotiated for `side`, if it accepted
+/// the extension, with this end's window kept to `config`'s.
+fn negotiated(head: &[u8], side: Side, config: &CompressionConfig) -> Option<Params> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+    // only an upgrade negotiates anything
+    if lines.next()?.split(' ').nth(1) != Some("101") {
+        return None;
+    }
+    let (ours, theirs) = match side {
+        Side::Client => ("client_max_window_bits", "server_max_window_bits"),
+        Side::Server => ("server_max_window_bits", "client_max_window_bits"),
+    };
+    let (_, params) = lines
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case(EXTENSIONS_HEADER))
+        .flat_map(|(_, value)| extensions(value))
+        .find(|(name, _)| *name == EXTENSION)?;
+    let bits = |param| {
+        params
+            .iter()
+            .find(|(name, _)| *name == param)
+            .and_then(|(_, value)| window_bits(*value))
+            .unwrap_or(MAX_WINDOW_BITS)
+            .max(MIN_WINDOW_BITS)
+    };
+    Some(Params {
+        deflate_bits: bits(ours).min(config.window()),
+        inflate_bits: bits(theirs),
+    })
+}
+
+/// A connection that speaks permessage-deflate with its peer, if the
+/// handshake that goes through it negotiates it, and passes everything
+/// through untouched if not.
+pub(crate) struct Deflate<S> {
+    inner: S,
+    side: Side,
+    /// What the handshake negotiated, once it's known. `Some(None)` if it
+    /// didn't negotiate the extension.
+    negotiated: Option<Option<Params>>,
+    config: CompressionConfig,
+    max_message_size: usize,
+    max_frame_size: usize,
+    reading: Reading,
+    writing: Writing,
+    /// How many bytes have been written to the connection, compressed or
+    /// not, and including their framing.
+    written: Arc<AtomicUsize>,
+}
+
+#[derive(Default)]
+struct Reading {
+    /// Whether the handshake's HTTP head has been read, after which the
+    /// connection is made of frames.
+    past_head: bool,
+    /// Bytes read from the connection that haven't been handled yet.
+    raw: Vec<u8>,
+    /// Handled bytes for tungstenite to read, from `ready_from` on.
+    ready: Vec<u8>,
+    ready_from: usize,
+    /// A compressed message that's still arriving.
+    partial: Option<Partial>,
+    /// Kept between messages, as the peer may refer back to earlier ones.
+    inflater: Option<Decompress>,
+    eof: bool,
+}
+
+#[derive(Default)]
+struct Writing {
+    /// Whether the handshake's HTTP head has been written.
+    past_head: bool,
+    /// Bytes tungstenite has written that haven't been handled yet.
+    raw: Vec<u8>,
+    /// Handled bytes to write to the connection, from `out_from` on.
+    out: Vec<u8>,
+    out_from: usize,
+    /// A message that's still being written.
+    partial: Option<Partial>,
+    deflater: Option<Compress>,
+}
+
+/// The frames of a message so far, unmasked.
+struct Partial {
+    opcode: OpCode,
+    mask: Option<[u8; 4]>,
+    data: Vec<u8>,
+}
+
+impl<S> Deflate<S> {
+    /// Wraps `inner`, ahead of the handshake. Without a `config`, the
+    /// extension is never used, whatever the handshake says. Messages are
+    /// limited to the sizes in `ws_config`, as they are by tungstenite.
+    pub fn new(inner: S, side: Side, config: Option<CompressionConfig>, ws_config: &WebSocketConfig) -> Self {
+        let enabled = config.is_some();
+        Self {
+            inner,
+            side,
+            negotiated: (!enabled).then_some(None),
+            config: config.unwrap_or_default(),
+            max_message_size: ws_config.max_message_size.unwrap_or(usize::MAX),
+            max_frame_size: ws_config.max_frame_size.unwrap_or(usize::MAX),
+            reading: Reading {
+                past_head: !enabled,
+                ..Reading::default()
+            },
+            writing: Writing {
+                past_head: !enabled,
+                ..Writing::default()
+            },
+            written: Arc::default(),
+        }
+    }
+
+    /// Counts the bytes written to the connection from now on, as they go
+    /// out on the wire.
+    pub fn written(&self) -> Arc<AtomicUsize> {
+        self.written.clone()
+    }
+
+    /// Whether reads can go straight to the connection, as there's nothing
+    /// to do to them.
+    fn reads_untouched(&self) -> bool {
+        let reading = &self.reading;
+        reading.past_head && self.negotiated == Some(None) && reading.raw.is_empty() && reading.partial.is_none()
+    }
+
+    /// Whether writes can go straight to the connection.
+    fn writes_untouched(&self) -> bool {
+        let writing = &self.writing;
+        writing.past_head
+            && self.negotiated == Some(None)
+            && writing.raw.is_empty()
+            && writing.out_from == writing.out.len()
+            && writing.partial.is_none()
+    }
+
+    /// Moves what can be handled of the bytes read so far to the bytes for
+    /// tungstenite. Returns whether there were any.
+    fn handle_reads(&mut self) -> io::Result<bool> {
+        let reading = &mut self.reading;
+        if !reading.past_head {
+            let Some(end) = head_end(&reading.raw) else {
+                if reading.raw.len() > MAX_HEAD {
+                    // not a handshake, which tungstenite can turn away
+                    reading.past_head = true;
+                    self.negotiated.get_or_insert(None);
+                    reading.ready.append(&mut reading.raw);
+                    return Ok(true);
+                }
+                return Ok(false);
+            };
+            reading.ready.extend(reading.raw.drain(..end));
+            reading.past_head = true;
+            if self.side == Side::Client {
+                self.negotiated = Some(negotiated(&reading.ready, self.side, &self.config));
+            }
+            return Ok(true);
+        }
+        // the client doesn't send frames until it has the server's answer,
+        // so they aren't read before it's known
+        let Some(Some(params)) = self.negotiated else {
+            let moved = !reading.raw.is_empty();
+            reading.ready.append(&mut reading.raw);
+            return Ok(moved);
+        };
+        let mut moved = false;
+        while let Some((header, payload)) = next_frame(&reading.raw, self.max_message_size)? {
+            let frame: Vec<u8> = reading.raw.drain(..payload.end).collect();
+            moved = true;
+            match header.opcode {
+                OpCode::Data(Data::Text | Data::Binary) if header.rsv1 => {
+                    let mut data = frame[payload].to_vec();
+                    unmask(&mut data, header.mask);
+                    reading.partial = Some(Partial {
+                        opcode: header.opcode,
+                        mask: header.mask,
+                        data,
+                    });
+                }
+                OpCode::Data(Data::Continue) if reading.partial.is_some() => {
+                    let partial = reading.partial.as_mut().unwrap();
+                    let start = partial.data.len();
+                    partial.data.extend_from_slice(&frame[payload]);
+                    unmask(&mut partial.data[start..], header.mask);
+                    if partial.data.len() > self.max_message_size {
+                        return Err(too_big());
+                    }
+                }
+                // control frames, and messages that aren't compressed
+                _ => {
+                    reading.ready.extend_from_slice(&frame);
+                    continue;
+                }
+            }
+            if header.is_final {
+                let partial = reading.partial.take().unwrap();
+                let inflater = reading
+                    .inflater
+                    .get_or_insert_with(|| Decompress::new_with_window_bits(false, params.inflate_bits));
+                let message = inflate(inflater, partial.data, self.max_message_size)?;
+                // split up as tungstenite would have had it
+                write_message(&mut reading.ready, partial.opcode, partial.mask, false, &message, self.max_frame_size);
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Moves what can be handled of the bytes tungstenite has written to the
+    /// bytes to write to the connection.
+    fn handle_writes(&mut self) -> io::Result<()> {
+        let writing = &mut self.writing;
+        if !writing.past_head {
+            let Some(end) = head_end(&writing.raw) else {
+                if writing.raw.len() > MAX_HEAD {
+                    writing.past_head = true;
+                    self.negotiated.get_or_insert(None);
+                    writing.out.append(&mut writing.raw);
+                }
+                return Ok(());
+            };
+            writing.out.extend(writing.raw.drain(..end));
+            writing.past_head = true;
+            if self.side == Side::Server {
+                self.negotiated = Some(negotiated(&writing.out[writing.out_from..], self.side, &self.config));
+            }
+        }
+        let Some(Some(params)) = self.negotiated else {
+            writing.out.append(&mut writing.raw);
+            return Ok(());
+        };
+        while let Some((header, payload)) = next_frame(&writing.raw, usize::MAX)? {
+            let frame: Vec<u8> = writing.raw.drain(..payload.end).collect();
+            match header.opcode {
+                // too small to be worth compressing
+                OpCode::Data(Data::Text | Data::Binary) if header.is_final && payload.len() < self.config.min_size => {
+                    writing.out.extend_from_slice(&frame);
+                    continue;
+                }
+                OpCode::Data(Data::Text | Data::Binary) => {
+                    let mut data = frame[payload].to_vec();
+                    unmask(&mut data, header.mask);
+                    writing.partial = Some(Partial {
+                        opcode: header.opcode,
+                        mask: header.mask,
+                        data,
+                    });
+                }
+                OpCode::Data(Data::Continue) if writing.partial.is_some() => {
+                    let partial = writing.partial.as_mut().unwrap();
+                    let start = partial.data.len();
+                    partial.data.extend_from_slice(&frame[payload]);
+                    unmask(&mut partial.data[start..], header.mask);
+                }
+                _ => {
+                    writing.out.extend_from_slice(&frame);
+                    continue;
+                }
+            }
+            if header.is_final {
+                let partial = writing.partial.take().unwrap();
+                if partial.data.len() < self.config.min_size {
+                    write_message(&mut writing.out, partial.opcode, partial.mask, false, &partial.data, usize::MAX);
+                    continue;
+                }
+                let deflater = writing.deflater.get_or_insert_with(|| {
+                    Compress::new_with_window_bits(Compression::default(), false, params.deflate_bits)
+                });
+                let message = deflate(deflater, &partial.data)?;
+                write_message(&mut writing.out, partial.opcode, partial.mask, true, &message, usize::MAX);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> Deflate<S> {
+    /// Writes the handled bytes to the connection.
+    fn poll_write_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let writing = &mut self.writing;
+        while writing.out_from < writing.out.len() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &writing.out[writing.out_from..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            writing.out_from += written;
+            self.written.fetch_add(written, Ordering::Relaxed);
+        }
+        writing.out.clear();
+        writing.out_from = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Deflate<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            let reading = &mut this.reading;
+            if reading.ready_from < reading.ready.len() {
+                let ready = &reading.ready[reading.ready_from..];
+                let len = ready.len().min(buf.remaining());
+                buf.put_slice(&ready[..len]);
+                reading.ready_from += len;
+                if reading.ready_from == reading.ready.len() {
+                    reading.ready.clear();
+                    reading.ready_from = 0;
+                }
+                return Poll::Ready(Ok(()));
+            }
+            // tungstenite doesn't flush its handshake, whose answer won't
+            // come until it's been written
+            if let Poll::Ready(Err(e)) = this.poll_write_out(cx) {
+                return Poll::Ready(Err(e));
+            }
+            if this.reads_untouched() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+            if this.handle_reads()? {
+                continue;
+            }
+            let reading = &mut this.reading;
+            if reading.eof {
+                // whatever's left is cut short, which tungstenite can report
+                if reading.raw.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                reading.ready.append(&mut reading.raw);
+                continue;
+            }
+            let mut chunk = [0; 16 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            match chunk.filled() {
+                [] => reading.eof = true,
+                read => reading.raw.extend_from_slice(read),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deflate<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.writes_untouched() {
+            let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+            this.written.fetch_add(written, Ordering::Relaxed);
+            return Poll::Ready(Ok(written));
+        }
+        // taking on more only once what's been handled is written, so a
+        // peer that doesn't read holds tungstenite up as it would without
+        // the extension
+        ready!(this.poll_write_out(cx))?;
+        this.writing.raw.extend_from_slice(buf);
+        this.handle_writes()?;
+        // written as far as it can be now, and the rest when flushed
+        if let Poll::Ready(Err(e)) = this.poll_write_out(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_write_out(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Where the HTTP head at the start of `bytes` ends, if it's all there.
+fn head_end(bytes: &[u8]) -> Option<usize> {
+    bytes.windows(4).position(|window| window == b"\r\n\r\n").map(|end| end + 4)
+}
+
+/// Parses the frame at the start of `bytes`, if it's all there, returning
+/// its header and where its payload is.
+fn next_frame(bytes: &[u8], max_size: usize) -> io::Result<Option<(FrameHeader, std::ops::Range<usize>)>> {
+    let mut cursor = Cursor::new(bytes);
+    let Some((header, len)) = FrameHeader::parse(&mut cursor).map_err(invalid_data)? else {
+        return Ok(None);
+    };
+    let start = cursor.position() as usize;
+    let len = usize::try_from(len).ok().filter(|len| *len <= max_size).ok_or_else(too_big)?;
+    if bytes.len() - start < len {
+        return Ok(None);
+    }
+    Ok(Some((header, start..start + len)))
+}
+
+/// Writes `message` out as frames of up to `max_frame_size` bytes, as they
+/// were masked.
+fn write_message(out: &mut Vec<u8>, opcode: OpCode, mask: Option<[u8; 4]>, rsv1: bool, message: &[u8], max_frame_size: usize) {
+    let mut chunks = message.chunks(max_frame_size.max(1)).peekable();
+    let mut header = FrameHeader {
+        is_final: chunks.peek().is_none(),
+        rsv1,
+        opcode,
+        mask,
+        ..FrameHeader::default()
+    };
+    // an empty message is still a frame
+    let mut chunk: &[u8] = chunks.next().unwrap_or_default();
+    loop {
+        header.is_final = chunks.peek().is_none();
+        Frame::from_payload(header.clone(), chunk.to_vec())
+            .format(out)
+            .expect("writing to a Vec can't fail");
+        let Some(next) = chunks.next() else {
+            return;
+        };
+        // only the first frame of a message is marked
+        header.rsv1 = false;
+        header.opcode = OpCode::Data(Data::Continue);
+        chunk = next;
+    }
+}
+
+/// Deflates a message on its own, leaving off the trailer.
+fn deflate(deflater: &mut Compress, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut message = Vec::with_capacity(data.len() / 2 + 64);
+    let start = deflater.total_in();
+    loop {
+        let read = (deflater.total_in() - start) as usize;
+        deflater
+            .compress_vec(&data[read..], &mut message, FlushCompress::Sync)
+            .map_err(invalid_data)?;
+        let read = (deflater.total_in() - start) as usize;
+        // flushed once there's room to spare
+        if read == data.len() && message.len() < message.capacity() {
+            break;
+        }
+        message.reserve(message.capacity());
+    }
+    // the next message starts afresh
+    deflater.reset();
+    if message.ends_with(&TRAILER) {
+        message.truncate(message.len() - TRAILER.len());
+    }
+    Ok(message)
+}
+
+/// Inflates a message that had its trailer left off, failing if it comes
+/// to more than `max_size` bytes.
+fn inflate(inflater: &mut Decompress, mut data: Vec<u8>, max_size: usize) -> io::Result<Vec<u8>> {
+    data.extend_from_slice(&TRAILER);
+    let mut message = Vec::with_capacity(data.len().saturating_mul(4).min(max_size).max(1024));
+    let start = inflater.total_in();
+    loop {
+        if message.len() == message.capacity() {
+            message.reserve(message.capacity());
+        }
+        let before = (inflater.total_in(), message.len());
+        let read = (inflater.total_in() - start) as usize;
+        let status = inflater
+            .decompress_vec(&data[read..], &mut message, FlushDecompress::Sync)
+            .map_err(invalid_data)?;
+        if message.len() > max_size {
+            return Err(too_big());
+        }
+        if status == Status::StreamEnd {
+            // the peer ended its stream, so the next message starts a new one
+            inflater.reset(false);
+            return Ok(message);
+        }
+        let read = (inflater.total_in() - start) as usize;
+        if read == data.len() && message.len() < message.capacity() {
+            return Ok(message);
+        }
+        if (inflater.total_in(), message.len()) == before {
+            return Err(invalid_data("truncated compressed message"));
+        }
+    }
+}
+
+fn unmask(data: &mut [u8], mask: Option<[u8; 4]>) {
+    if let Some(mask) = mask {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+}
+
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn too_big() -> io::Error {
+    invalid_data("message too big")
+}
@@ -0,0 +1,144 @@
+use std::{fmt, io, net::SocketAddr};
+
+use tokio_tungstenite::tungstenite::{self, http::StatusCode};
+
+/// An error from the [Server](crate::Server). [Server::run](crate::Server::run)
+/// returns the errors that stop the server, and the per-connection ones are
+/// passed to [ServerConfig::on_accept_error](crate::ServerConfig::on_accept_error).
+#[derive(Debug)]
+pub enum ServerError {
+    /// The server couldn't listen on its address.
+    Bind(io::Error),
+    /// The listener failed to accept a new connection.
+    Accept(io::Error),
+    /// A client connected, but the TLS handshake failed.
+    Tls {
+        peer_addr: SocketAddr,
+        error: io::Error,
+    },
+    /// A client completed the TLS handshake, but the WebSocket upgrade
+    /// failed, e.g. because it asked for the wrong protocol version.
+    Handshake {
+        peer_addr: SocketAddr,
+        error: tungstenite::Error,
+    },
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Bind(error) => write!(f, "failed to bind: {error}"),
+            ServerError::Accept(error) => write!(f, "failed to accept connection: {error}"),
+            ServerError::Tls { peer_addr, error } => {
+                write!(f, "TLS handshake with {peer_addr} failed: {error}")
+            }
+            ServerError::Handshake { peer_addr, error } => {
+                write!(f, "WebSocket handshake with {peer_addr} failed: {error}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ServerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ServerError::Bind(error) | ServerError::Accept(error) => Some(error),
+            ServerError::Tls { error, .. } => Some(error),
+            ServerError::Handshake { error, .. } => Some(error),
+        }
+    }
+}
+
+/// An error connecting a [Client](crate::Client) to a server, returned by
+/// [Client::connect](crate::Client::connect).
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The server's host name couldn't be resolved.
+    Dns(io::Error),
+    /// None of the server's addresses accepted a TCP connection, or the
+    /// server's Unix socket couldn't be connected to.
+    Tcp(io::Error),
+    /// The TLS handshake failed, e.g. because the server's certificate
+    /// wasn't trusted.
+    Tls(io::Error),
+    /// The [proxy](crate::Client::set_proxy) wouldn't open a tunnel to the
+    /// server, e.g. because it didn't accept the client's credentials.
+    Proxy(io::Error),
+    /// The server answered the WebSocket upgrade with an HTTP error instead,
+    /// e.g. `503 Service Unavailable` when it's full.
+    HandshakeRejected { status: StatusCode },
+    /// The server speaks a different version of the protocol. `theirs` is
+    /// `None` if the server didn't say which version it speaks.
+    VersionMismatch {
+        ours: String,
+        theirs: Option<String>,
+    },
+    /// The server encodes messages with a different [Codec](crate::Codec).
+    /// `theirs` is `None` if the server didn't say which one it uses.
+    CodecMismatch {
+        ours: String,
+        theirs: Option<String>,
+    },
+    /// The connection wasn't established within the client's
+    /// [connect timeout](crate::Client::set_connect_timeout).
+    Timeout,
+    /// The WebSocket handshake failed for another reason.
+    WebSocket(tungstenite::Error),
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectError::Dns(error) => write!(f, "failed to resolve host: {error}"),
+            ConnectError::Tcp(error) => write!(f, "failed to connect: {error}"),
+            ConnectError::Tls(error) => write!(f, "TLS handshake failed: {error}"),
+            ConnectError::Proxy(error) => write!(f, "proxy failed: {error}"),
+            ConnectError::HandshakeRejected { status } => {
+                write!(f, "server rejected the connection: {status}")
+            }
+            ConnectError::VersionMismatch { ours, theirs } => match theirs {
+                Some(theirs) => write!(
+                    f,
+                    "version mismatch: we speak {ours}, server speaks {theirs}"
+                ),
+                None => write!(f, "version mismatch: we speak {ours}, server didn't say"),
+            },
+            ConnectError::CodecMismatch { ours, theirs } => match theirs {
+                Some(theirs) => write!(f, "codec mismatch: we use {ours}, server uses {theirs}"),
+                None => write!(f, "codec mismatch: we use {ours}, server didn't say"),
+            },
+            ConnectError::Timeout => write!(f, "timed out connecting"),
+            ConnectError::WebSocket(error) => write!(f, "WebSocket handshake failed: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectError::Dns(error)
+            | ConnectError::Tcp(error)
+            | ConnectError::Tls(error)
+            | ConnectError::Proxy(error) => Some(error),
+            ConnectError::WebSocket(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<tungstenite::Error> for ConnectError {
+    fn from(error: tungstenite::Error) -> Self {
+        match error {
+            tungstenite::Error::Http(response) => ConnectError::HandshakeRejected {
+                status: response.status(),
+            },
+            error => ConnectError::WebSocket(error),
+        }
+    }
+}
+
+impl From<tokio::time::error::Elapsed> for ConnectError {
+    fn from(_: tokio::time::error::Elapsed) -> Self {
+        ConnectError::Timeout
+    }
+}
@@ -0,0 +1,73 @@
+use std::collections::HashMap;
+
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+
+use crate::wire::{FieldId, FieldSync};
+
+/// The handshake header a client lists the ids of its lazy state fields in,
+/// separated by commas, so they're held back from the very first state change.
+pub(crate) const LAZY_FIELDS_HEADER: &str = "hl-lazy-fields";
+
+/// The state fields a connection's client has made lazy, and the changes to
+/// them that haven't been sent.
+#[derive(Default)]
+pub(crate) struct LazyFields {
+    /// The latest unsent value of each lazy field, if it has changed since
+    /// the client last had it.
+    fields: HashMap<FieldId, Option<Vec<u8>>>,
+}
+
+impl LazyFields {
+    /// Reads the fields a client made lazy before connecting.
+    pub fn from_header(header: Option<&HeaderValue>) -> Self {
+        let fields = header
+            .and_then(|header| header.to_str().ok())
+            .into_iter()
+            .flat_map(|fields| fields.split(','))
+            .filter_map(|field| field.trim().parse().ok())
+            .map(|field| (field, None))
+            .collect();
+        Self { fields }
+    }
+
+    /// Changes how `field` is synced. Returns the field's unsent value if it
+    /// was made eager and changed while it was lazy.
+    pub fn set(&mut self, field: FieldId, sync: FieldSync) -> Option<(FieldId, Vec<u8>)> {
+        match sync {
+            FieldSync::Eager => {
+                let value = self.fields.remove(&field)??;
+                Some((field, value))
+            }
+            FieldSync::Lazy => {
+                self.fields.entry(field).or_default();
+                None
+            }
+        }
+    }
+
+    /// Takes the changes to lazy fields out of `changes`, keeping only their
+    /// latest values.
+    pub fn hold_back(&mut self, changes: &mut Vec<(FieldId, Vec<u8>)>) {
+        if self.fields.is_empty() {
+            return;
+        }
+        changes.retain_mut(|(field, value)| match self.fields.get_mut(field) {
+            Some(held) => {
+                *held = Some(std::mem::take(value));
+                false
+            }
+            None => true,
+        });
+    }
+
+    /// Takes the unsent values of `fields`, to send them to the client.
+    pub fn fetch(&mut self, fields: impl IntoIterator<Item = FieldId>) -> Vec<(FieldId, Vec<u8>)> {
+        fields
+            .into_iter()
+            .filter_map(|field| {
+                let value = self.fields.get_mut(&field)?.take()?;
+                Some((field, value))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// A range of IP addresses in CIDR notation, e.g. `10.0.0.0/8` or
+/// `fd00::/8`. A bare address is a range of just that address. See
+/// [ServerConfig::trusted_proxies](crate::ServerConfig::trusted_proxies).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// The addresses that share the first `prefix_len` bits of `addr`.
+    /// Returns `None` if `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Option<Self> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        (prefix_len <= max_len).then_some(Self {
+            addr: addr.to_canonical(),
+            prefix_len,
+        })
+    }
+
+    /// Whether `ip` is in the range. IPv4 addresses mapped into IPv6, as a
+    /// dual-stack socket reports them, are treated as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = InvalidIpNet;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| InvalidIpNet)?;
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| InvalidIpNet)?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix_len).ok_or(InvalidIpNet)
+    }
+}
+
+/// A string that isn't an IP address or a CIDR range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidIpNet;
+
+impl fmt::Display for InvalidIpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid IP address range")
+    }
+}
+
+impl std::error::Error for InvalidIpNet {}
+
+/// Finds the client's address when a connection comes through proxies, from
+/// the `X-Forwarded-For` headers the proxies added, in order. They're only
+/// believed if `peer_addr` is one of the `trusted` proxies. Then the
+/// rightmost address that isn't trusted is the client, as anything to its
+/// left could have been made up by the client. Falls back to the last
+/// address that could be believed if an entry isn't an IP address.
+///
+/// The port is the one given in the header, or 0 if there isn't one, as the
+/// proxy's own port says nothing about the client.
+pub(crate) fn client_addr(peer_addr: SocketAddr, forwarded_for: &[String], trusted: &[IpNet]) -> SocketAddr {
+    let is_trusted = |addr: SocketAddr| trusted.iter().any(|net| net.contains(addr.ip()));
+    if !is_trusted(peer_addr) {
+        return peer_addr;
+    }
+    let mut client = peer_addr;
+    let entries = forwarded_for.iter().flat_map(|header| header.split(','));
+    for entry in entries.collect::<Vec<_>>().into_iter().rev() {
+        let Some(addr) = parse_entry(entry) else {
+            break;
+        };
+        client = addr;
+        if !is_trusted(addr) {
+            break;
+        }
+    }
+    client
+}
+
+/// Parses an `X-Forwarded-For` entry: an IP address, optionally with a port,
+/// with IPv6 addresses optionally in brackets.
+fn parse_entry(entry: &str) -> Option<SocketAddr> {
+    let entry = entry.trim();
+    if let Ok(addr) = entry.parse::<SocketAddr>() {
+        return Some(SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+    }
+    let ip = entry.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')).unwrap_or(entry);
+    let ip: IpAddr = ip.parse().ok()?;
+    Some(SocketAddr::new(ip.to_canonical(), 0))
+}
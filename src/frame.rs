@@ -0,0 +1,56 @@
+use std::{ops::Deref, ops::Range, sync::Arc};
+
+use rkyv::AlignedVec;
+
+/// A message received from the other side, kept whole so the payloads inside
+/// it can be handed out without copying them. The buffer is aligned to 16
+/// bytes, so payloads serialized with [Aligned](crate::Aligned) stay aligned.
+#[derive(Clone)]
+pub(crate) struct Frame(Arc<dyn AsRef<[u8]> + Send + Sync>);
+
+impl Frame {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        // allocations are aligned in practice, but it isn't guaranteed
+        if (bytes.as_ptr() as usize).is_multiple_of(AlignedVec::ALIGNMENT) {
+            return Self(Arc::new(bytes));
+        }
+        let mut aligned = AlignedVec::with_capacity(bytes.len());
+        aligned.extend_from_slice(&bytes);
+        Self(Arc::new(aligned))
+    }
+
+    /// Returns the part of the frame that `part` borrows, which keeps the
+    /// frame alive without borrowing it.
+    pub fn payload(&self, part: &[u8]) -> Payload {
+        let start = (part.as_ptr() as usize)
+            .checked_sub(self.as_ptr() as usize)
+            .filter(|start| start + part.len() <= self.len())
+            .expect("payload isn't part of the frame");
+        Payload {
+            frame: self.clone(),
+            range: start..start + part.len(),
+        }
+    }
+}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        (*self.0).as_ref()
+    }
+}
+
+/// Part of a [Frame], e.g. a call's arguments.
+pub(crate) struct Payload {
+    frame: Frame,
+    range: Range<usize>,
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.frame[self.range.clone()]
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::debug;
+
+/// The most bytes read looking for the end of a request's headers.
+const MAX_REQUEST_HEAD: usize = 16 * 1024;
+
+/// How long a client has to send its request's headers. Connections aren't
+/// counted towards the server's limits until they have.
+const REQUEST_HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Reads a connection's HTTP request before the WebSocket handshake, as
+/// tungstenite fails requests that aren't upgrades without a response.
+/// Returns the connection, with the request put back, and what the server
+/// needs from the request if it's an upgrade.
+/// Otherwise it's answered here, e.g. a load balancer's health check, and
+/// closed: with 200 if it's for `health_check_path`, and 404 if not.
+pub(crate) async fn screen<S>(mut stream: S, health_check_path: Option<&str>) -> Option<(Replay<S>, RequestHead)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let deadline = tokio::time::Instant::now() + REQUEST_HEAD_TIMEOUT;
+    let mut head = Vec::with_capacity(1024);
+    let end = loop {
+        if let Some(end) = head.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if head.len() >= MAX_REQUEST_HEAD {
+            let _ = respond(&mut stream, "431 Request Header Fields Too Large", "", true).await;
+            return None;
+        }
+        let mut chunk = [0; 1024];
+        match tokio::time::timeout_at(deadline, stream.read(&mut chunk)).await {
+            // the client went away or stalled before finishing its request
+            Ok(Ok(0) | Err(_)) | Err(_) => return None,
+            Ok(Ok(read)) => head.extend_from_slice(&chunk[..read]),
+        }
+    };
+
+    let request = String::from_utf8_lossy(&head[..end]);
+    let mut lines = request.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default();
+    // the query string doesn't pick the service
+    let path = request_line.next().unwrap_or_default().split('?').next().unwrap_or_default();
+    let mut is_upgrade = false;
+    let mut forwarded_for = Vec::new();
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("upgrade") && value.eq_ignore_ascii_case("websocket") {
+            is_upgrade = true;
+        } else if name.eq_ignore_ascii_case("x-forwarded-for") {
+            forwarded_for.push(value.to_string());
+        }
+    }
+    if is_upgrade {
+        let request = RequestHead {
+            path: path.to_string(),
+            forwarded_for,
+        };
+        return Some((Replay { head, read: 0, stream }, request));
+    }
+
+    let (status, body) = if health_check_path == Some(path) && matches!(method, "GET" | "HEAD") {
+        ("200 OK", "ok\n")
+    } else {
+        ("404 Not Found", "not found\n")
+    };
+    // a response to HEAD describes the body without sending it
+    let result = respond(&mut stream, status, body, method != "HEAD").await;
+    if let Err(error) = result {
+        debug!("Failed to answer HTTP request: {}", error);
+    }
+    None
+}
+
+/// What the server needs from an upgrade request before the handshake.
+pub(crate) struct RequestHead {
+    /// The path, without the query string.
+    pub path: String,
+    /// Every `X-Forwarded-For` header, in order.
+    pub forwarded_for: Vec<String>,
+}
+
+async fn respond<S>(stream: &mut S, status: &str, body: &str, send_body: bool) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        if send_body { body } else { "" }
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// A connection whose first bytes have already been read, which reads them
+/// again before the rest of the connection.
+pub(crate) struct Replay<S> {
+    head: Vec<u8>,
+    /// How much of `head` has been read again.
+    read: usize,
+    stream: S,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Replay<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.read < this.head.len() {
+            let unread = &this.head[this.read..];
+            let len = unread.len().min(buf.remaining());
+            buf.put_slice(&unread[..len]);
+            this.read += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Replay<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
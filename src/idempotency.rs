@@ -0,0 +1,128 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Remembers the responses to calls made with an idempotency key, so a call
+/// the client retries (e.g. after reconnecting) can be answered without
+/// running it again.
+///
+/// Each connection has its own store, bounded both in size and in time: keys
+/// expire after the configured TTL, and the least recently used key is
+/// evicted once the store is full. A call replayed after its key has been
+/// evicted runs again.
+pub struct IdempotencyStore {
+    ttl: Duration,
+    max_keys: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    /// Keys in order of last use, oldest first.
+    lru: BTreeMap<u64, String>,
+    /// Incremented on every use, so it orders [Inner::lru].
+    clock: u64,
+}
+
+struct Entry {
+    response: Vec<u8>,
+    stored_at: Instant,
+    last_used: u64,
+}
+
+impl IdempotencyStore {
+    pub(crate) fn new(ttl: Duration, max_keys: usize) -> Self {
+        Self {
+            ttl,
+            max_keys,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    /// Returns the stored response for `key`, if it's still held.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?;
+        if entry.stored_at.elapsed() >= self.ttl {
+            inner.remove(key);
+            return None;
+        }
+        let response = entry.response.clone();
+        inner.touch(key);
+        Some(response)
+    }
+
+    /// Stores the response for `key`, evicting expired keys and then the
+    /// least recently used ones to stay within the size limit.
+    pub fn insert(&self, key: String, response: Vec<u8>) {
+        if self.max_keys == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        inner.evict_expired(self.ttl);
+        while inner.entries.len() >= self.max_keys {
+            inner.evict_oldest();
+        }
+        inner.clock += 1;
+        let last_used = inner.clock;
+        inner.lru.insert(last_used, key.clone());
+        inner.entries.insert(
+            key,
+            Entry {
+                response,
+                stored_at: Instant::now(),
+                last_used,
+            },
+        );
+    }
+
+    /// Forgets the response for `key`, so a replay runs the call again.
+    pub fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().remove(key);
+    }
+
+    /// The number of keys currently held, including any that have expired but
+    /// haven't been evicted yet.
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Inner {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(entry) = self.entries.get_mut(key) {
+            let key = self.lru.remove(&entry.last_used).unwrap();
+            entry.last_used = clock;
+            self.lru.insert(clock, key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+        }
+    }
+
+    fn evict_oldest(&mut self) {
+        if let Some((_, key)) = self.lru.pop_first() {
+            self.entries.remove(&key);
+        }
+    }
+
+    fn evict_expired(&mut self, ttl: Duration) {
+        self.entries
+            .retain(|_, entry| entry.stored_at.elapsed() < ttl);
+        let entries = &self.entries;
+        self.lru.retain(|_, key| entries.contains_key(key));
+    }
+}
@@ -0,0 +1,73 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{
+    context::{CallContext, Context},
+    server::{Handler, HandlerResult},
+};
+
+/// Wraps every RPC call on the server, e.g. to log, time or authorize calls.
+///
+/// Interceptors run in the order they're listed in
+/// [ServerConfig::interceptors](crate::ServerConfig::interceptors). Each one
+/// decides whether to continue by calling [Next::run], optionally with a
+/// different input, or to short-circuit by returning early. The handler is
+/// called once every interceptor has run.
+///
+/// ```ignore
+/// struct Timing;
+///
+/// #[async_trait]
+/// impl Interceptor for Timing {
+///     async fn around(&self, ctx: &Context, input: &[u8], next: Next<'_>) -> HandlerResult<Vec<u8>> {
+///         let started_at = Instant::now();
+///         let output = next.run(ctx, input).await;
+///         info!("Call took {:?}", started_at.elapsed());
+///         output
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Interceptor: Send + Sync {
+    async fn around(&self, ctx: &Context, input: &[u8], next: Next<'_>) -> HandlerResult<Vec<u8>>;
+}
+
+/// The rest of the interceptor chain, ending with the handler.
+pub struct Next<'a> {
+    handler: &'a (dyn Handler + Send + Sync),
+    interceptors: &'a [Arc<dyn Interceptor>],
+    call: &'a CallContext<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        handler: &'a (dyn Handler + Send + Sync),
+        interceptors: &'a [Arc<dyn Interceptor>],
+        call: &'a CallContext<'a>,
+    ) -> Self {
+        Self {
+            handler,
+            interceptors,
+            call,
+        }
+    }
+
+    /// The call being intercepted, e.g. for its id.
+    pub fn call(&self) -> &CallContext<'a> {
+        self.call
+    }
+
+    /// Calls the next interceptor in the chain, or the handler if there are
+    /// none left.
+    pub async fn run(self, ctx: &Context, input: &[u8]) -> HandlerResult<Vec<u8>> {
+        match self.interceptors.split_first() {
+            Some((interceptor, rest)) => {
+                interceptor
+                    .around(ctx, input, Next::new(self.handler, rest, self.call))
+                    .await
+            }
+            None => self.handler.handle_call(&self.call.with_connection(ctx), input).await,
+        }
+    }
+}
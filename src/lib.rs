@@ -1,8 +1,71 @@
 mod wire;
 mod server;
 mod client;
+mod topics;
+mod context;
+mod stats;
+mod interceptor;
+mod streaming;
+mod rate_limit;
+mod state_field;
+mod connection_state;
+mod shared_state;
+mod tracked_state;
+mod transaction;
+mod idempotency;
+mod tls;
+mod connections;
+mod socket;
+mod method_stats;
+mod error;
+mod retry;
+mod reconnectable;
+mod pool;
+mod frame;
+mod outbox;
+mod deflate;
+mod metrics_hook;
+mod field_sync;
+mod state_coalescing;
+mod auth;
+mod health;
+mod origin;
+mod forwarded;
+mod proxy;
+mod proxy_protocol;
+mod protocol;
+mod codec;
+#[cfg(feature = "serde")]
+mod payload_serde;
+mod query;
+#[cfg(feature = "trace-context")]
+mod trace_context;
+pub mod testing;
 
 pub use wire::*;
+pub use codec::*;
 pub use server::*;
 pub use client::*;
+pub use topics::{Broadcaster, ConnectionId};
+pub use context::*;
+pub use stats::*;
+pub use interceptor::*;
+pub use streaming::StreamSender;
+pub use rate_limit::Rate;
+pub use deflate::CompressionConfig;
+pub use state_field::*;
+pub use connection_state::*;
+pub use shared_state::*;
+pub use tracked_state::*;
+pub use idempotency::IdempotencyStore;
+pub use tls::{PemError, TlsInfo};
+pub use method_stats::MethodStats;
+pub use metrics_hook::*;
+pub use auth::TokenValidator;
+pub use forwarded::{InvalidIpNet, IpNet};
+pub use proxy::{InvalidProxy, ProxyConfig};
+pub use error::*;
+pub use retry::*;
+pub use reconnectable::Reconnectable;
+pub use pool::ClientPool;
 pub use tokio_tungstenite::tungstenite;
\ No newline at end of file
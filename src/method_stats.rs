@@ -0,0 +1,94 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Duration,
+};
+
+use rkyv::{Archive, CheckBytes, Deserialize, Serialize};
+
+/// Aggregated statistics for one RPC method, across all connections.
+/// Latencies are calculated from the most recent calls.
+#[derive(Archive, Serialize, Deserialize, Debug, Clone)]
+#[archive_attr(derive(CheckBytes))]
+pub struct MethodStats {
+    /// The method's name, as reported by
+    /// [Handler::method_name](crate::Handler::method_name).
+    pub method: String,
+    /// The number of calls made.
+    pub calls: u64,
+    /// The number of calls that returned an error.
+    pub errors: u64,
+    /// Median latency, in microseconds.
+    pub p50_us: u64,
+    /// 95th percentile latency, in microseconds.
+    pub p95_us: u64,
+    /// 99th percentile latency, in microseconds.
+    pub p99_us: u64,
+}
+
+/// Used for calls whose handler doesn't report a method name.
+pub(crate) const UNKNOWN_METHOD: &str = "<unknown>";
+
+/// How many of a method's most recent latencies are kept for percentiles.
+const LATENCY_SAMPLES: usize = 1024;
+
+#[derive(Default)]
+struct MethodRecorder {
+    calls: u64,
+    errors: u64,
+    latencies_us: VecDeque<u64>,
+}
+
+/// Collects [MethodStats] for every method on the server.
+#[derive(Default)]
+pub(crate) struct MethodStatsRegistry {
+    methods: Mutex<HashMap<&'static str, MethodRecorder>>,
+}
+
+impl MethodStatsRegistry {
+    pub fn record(&self, method: Option<&'static str>, latency: Duration, ok: bool) {
+        let mut methods = self.methods.lock().unwrap();
+        let recorder = methods.entry(method.unwrap_or(UNKNOWN_METHOD)).or_default();
+        recorder.calls = recorder.calls.saturating_add(1);
+        if !ok {
+            recorder.errors = recorder.errors.saturating_add(1);
+        }
+        if recorder.latencies_us.len() == LATENCY_SAMPLES {
+            recorder.latencies_us.pop_front();
+        }
+        recorder
+            .latencies_us
+            .push_back(latency.as_micros().min(u64::MAX as u128) as u64);
+    }
+
+    /// Returns the stats for every method, sorted by name.
+    pub fn snapshot(&self) -> Vec<MethodStats> {
+        let methods = self.methods.lock().unwrap();
+        let mut stats: Vec<_> = methods
+            .iter()
+            .map(|(method, recorder)| {
+                let mut latencies: Vec<_> = recorder.latencies_us.iter().copied().collect();
+                latencies.sort_unstable();
+                MethodStats {
+                    method: method.to_string(),
+                    calls: recorder.calls,
+                    errors: recorder.errors,
+                    p50_us: percentile(&latencies, 50),
+                    p95_us: percentile(&latencies, 95),
+                    p99_us: percentile(&latencies, 99),
+                }
+            })
+            .collect();
+        stats.sort_by(|a, b| a.method.cmp(&b.method));
+        stats
+    }
+}
+
+/// Nearest-rank percentile of sorted samples.
+fn percentile(sorted: &[u64], percentile: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (percentile * sorted.len()).div_ceil(100);
+    sorted[rank.saturating_sub(1)]
+}
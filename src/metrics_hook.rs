@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use crate::{server::HandlerResult, topics::ConnectionId};
+
+/// Receives events from the server so they can be turned into metrics, e.g.
+/// for a Prometheus exporter. Set one with
+/// [ServerConfig::metrics_hook](crate::ServerConfig::metrics_hook). Every
+/// method does nothing unless it's implemented.
+///
+/// The hook is called from each connection's task, so it should be quick and
+/// must not block.
+pub trait ServerMetricsHook: Send + Sync {
+    /// A client connected and finished its handshake.
+    fn on_connection_open(&self, _connection_id: ConnectionId) {}
+    /// A client disconnected.
+    fn on_connection_close(&self, _connection_id: ConnectionId) {}
+    /// A call waited `wait` between arriving and its handler starting, e.g.
+    /// for one of the connection's
+    /// [call slots](crate::ServerConfig::max_concurrent_calls_per_connection).
+    /// Called just before [Self::on_call_start], so a slow call can be told
+    /// apart from a throttled one.
+    fn on_call_queued(&self, _method: Option<&'static str>, _wait: Duration) {}
+    /// A call's handler started. `method` is the name reported by
+    /// [Handler::method_name](crate::Handler::method_name), if any.
+    fn on_call_start(&self, _method: Option<&'static str>, _connection_id: ConnectionId) {}
+    /// A call's handler finished after running for `duration`, not counting
+    /// time spent queued.
+    fn on_call_end(
+        &self,
+        _method: Option<&'static str>,
+        _duration: Duration,
+        _result: &HandlerResult<Vec<u8>>,
+    ) {
+    }
+    /// A message was received from a client.
+    fn on_bytes_in(&self, _bytes: usize) {}
+    /// A message was written to a client's socket, taking `bytes` there,
+    /// i.e. after [compression](crate::ServerConfig::compression) and
+    /// including its WebSocket framing.
+    fn on_bytes_out(&self, _bytes: usize) {}
+}
+
+/// Reports the server's metrics through the [metrics] crate's macros, so any
+/// `metrics` exporter (e.g. `metrics-exporter-prometheus`) picks them up.
+/// Requires the `metrics` feature.
+///
+/// Calls are labelled with their `method`:
+///
+/// - `hardlight_connections` (gauge) and `hardlight_connections_opened_total`
+/// - `hardlight_calls_in_flight` (gauge), `hardlight_calls_total` and
+///   `hardlight_call_errors_total`
+/// - `hardlight_call_duration_seconds` and
+///   `hardlight_call_queue_wait_seconds` (histograms)
+/// - `hardlight_bytes_in_total` and `hardlight_bytes_out_total`
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MetricsCrateHook;
+
+#[cfg(feature = "metrics")]
+impl ServerMetricsHook for MetricsCrateHook {
+    fn on_connection_open(&self, _connection_id: ConnectionId) {
+        metrics::increment_counter!("hardlight_connections_opened_total");
+        metrics::increment_gauge!("hardlight_connections", 1.0);
+    }
+
+    fn on_connection_close(&self, _connection_id: ConnectionId) {
+        metrics::decrement_gauge!("hardlight_connections", 1.0);
+    }
+
+    fn on_call_queued(&self, method: Option<&'static str>, wait: Duration) {
+        let method = method.unwrap_or(crate::method_stats::UNKNOWN_METHOD);
+        metrics::histogram!("hardlight_call_queue_wait_seconds", wait.as_secs_f64(), "method" => method);
+    }
+
+    fn on_call_start(&self, method: Option<&'static str>, _connection_id: ConnectionId) {
+        let method = method.unwrap_or(crate::method_stats::UNKNOWN_METHOD);
+        metrics::increment_gauge!("hardlight_calls_in_flight", 1.0, "method" => method);
+    }
+
+    fn on_call_end(
+        &self,
+        method: Option<&'static str>,
+        duration: Duration,
+        result: &HandlerResult<Vec<u8>>,
+    ) {
+        let method = method.unwrap_or(crate::method_stats::UNKNOWN_METHOD);
+        metrics::decrement_gauge!("hardlight_calls_in_flight", 1.0, "method" => method);
+        metrics::increment_counter!("hardlight_calls_total", "method" => method);
+        if result.is_err() {
+            metrics::increment_counter!("hardlight_call_errors_total", "method" => method);
+        }
+        metrics::histogram!("hardlight_call_duration_seconds", duration.as_secs_f64(), "method" => method);
+    }
+
+    fn on_bytes_in(&self, bytes: usize) {
+        metrics::counter!("hardlight_bytes_in_total", bytes as u64);
+    }
+
+    fn on_bytes_out(&self, bytes: usize) {
+        metrics::counter!("hardlight_bytes_out_total", bytes as u64);
+    }
+}
@@ -0,0 +1,57 @@
+/// Whether a browser's `Origin` header, e.g. `https://example.com`, is one of
+/// the `allowed` origins. Schemes and hosts are compared case-insensitively,
+/// and a scheme's default port matches leaving it out, so
+/// `https://example.com:443` is the same origin as `https://example.com`.
+pub(crate) fn origin_allowed(origin: &str, allowed: &[String]) -> bool {
+    let Some(origin) = parse_origin(origin) else {
+        return false;
+    };
+    allowed.iter().any(|allowed| parse_origin(allowed).as_ref() == Some(&origin))
+}
+
+/// Whether a request's `Host` header, e.g. `example.com`, is one of the
+/// `allowed` hosts. Hosts are compared case-insensitively, and leaving out
+/// the port is the same as port 443, as clients connect over TLS.
+pub(crate) fn host_allowed(host: &str, allowed: &[String]) -> bool {
+    let Some(host) = parse_authority(host, 443) else {
+        return false;
+    };
+    allowed.iter().any(|allowed| parse_authority(allowed, 443).as_ref() == Some(&host))
+}
+
+/// Splits an origin into its lowercase scheme, host and port.
+fn parse_origin(origin: &str) -> Option<(String, String, u16)> {
+    let (scheme, authority) = origin.trim().split_once("://")?;
+    let scheme = scheme.to_ascii_lowercase();
+    let default_port = match scheme.as_str() {
+        "http" | "ws" => 80,
+        "https" | "wss" => 443,
+        _ => return None,
+    };
+    let (host, port) = parse_authority(authority.trim_end_matches('/'), default_port)?;
+    Some((scheme, host, port))
+}
+
+/// Splits `host[:port]` into its lowercase host and port, filling in
+/// `default_port` if it's left out. IPv6 addresses are in brackets.
+fn parse_authority(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    let authority = authority.trim();
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']')?;
+            (host, rest.strip_prefix(':'))
+        }
+        None => match authority.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    Some((host.trim_end_matches('.').to_ascii_lowercase(), port))
+}
@@ -0,0 +1,124 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use tokio::sync::{
+    mpsc::{self, error::SendError},
+    Notify,
+};
+use tokio_tungstenite::tungstenite::Message;
+
+/// How many bytes of messages may wait for a connection's writer before the
+/// side queueing them stops taking on work that would queue more, so a peer
+/// that doesn't read can't make its queue grow without end.
+pub(crate) const OUTBOX_CAPACITY: usize = 1024 * 1024;
+
+/// The bytes waiting in a connection's queues, shared by both ends.
+#[derive(Default)]
+struct Queued {
+    bytes: AtomicUsize,
+    drained: Notify,
+}
+
+/// The sending end of a queue of messages for a connection's writer. Sending
+/// never waits, as messages are queued from inside the connection's loop,
+/// which must keep going; the loop checks [Outbox::is_full] instead, and
+/// stops reading new work until the writer catches up.
+#[derive(Clone)]
+pub(crate) struct Outbox {
+    tx: mpsc::UnboundedSender<Message>,
+    queued: Arc<Queued>,
+}
+
+/// The receiving end of an [Outbox], which the writer takes messages from.
+pub(crate) struct OutboxQueue {
+    rx: mpsc::UnboundedReceiver<Message>,
+    queued: Arc<Queued>,
+}
+
+/// Creates a queue for a connection's writer.
+pub(crate) fn outbox() -> (Outbox, OutboxQueue) {
+    let queued = Arc::new(Queued::default());
+    let (tx, rx) = mpsc::unbounded_channel();
+    (
+        Outbox {
+            tx,
+            queued: queued.clone(),
+        },
+        OutboxQueue { rx, queued },
+    )
+}
+
+impl Outbox {
+    /// Creates another queue whose messages count towards this one's
+    /// capacity, for messages the writer takes in a different order.
+    pub fn sibling(&self) -> (Outbox, OutboxQueue) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (
+            Outbox {
+                tx,
+                queued: self.queued.clone(),
+            },
+            OutboxQueue {
+                rx,
+                queued: self.queued.clone(),
+            },
+        )
+    }
+
+    /// Queues `msg`, whether or not the queue is full.
+    pub fn send(&self, msg: Message) -> Result<(), SendError<Message>> {
+        let len = msg.len();
+        self.queued.bytes.fetch_add(len, Ordering::Relaxed);
+        self.tx.send(msg).inspect_err(|_| {
+            self.queued.bytes.fetch_sub(len, Ordering::Relaxed);
+        })
+    }
+
+    /// Whether more than [OUTBOX_CAPACITY] bytes are waiting for the writer.
+    /// Never once the writer has stopped, so the loop can notice the
+    /// connection is gone rather than wait for it.
+    pub fn is_full(&self) -> bool {
+        !self.tx.is_closed() && self.queued.bytes.load(Ordering::Relaxed) > OUTBOX_CAPACITY
+    }
+
+    /// Waits until the queue isn't full.
+    pub async fn drained(&self) {
+        loop {
+            let drained = self.queued.drained.notified();
+            if !self.is_full() {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+impl OutboxQueue {
+    /// Waits for the next message. `None` once every [Outbox] for the queue
+    /// has been dropped and it's empty.
+    pub async fn recv(&mut self) -> Option<Message> {
+        let msg = self.rx.recv().await?;
+        self.taken(&msg);
+        Some(msg)
+    }
+
+    /// Takes the next message that's ready, without waiting.
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let msg = self.rx.try_recv().ok()?;
+        self.taken(&msg);
+        Some(msg)
+    }
+
+    fn taken(&self, msg: &Message) {
+        self.queued.bytes.fetch_sub(msg.len(), Ordering::Relaxed);
+        self.queued.drained.notify_waiters();
+    }
+}
+
+impl Drop for OutboxQueue {
+    fn drop(&mut self) {
+        self.queued.drained.notify_waiters();
+    }
+}
@@ -0,0 +1,119 @@
+//! How the payloads in wire messages are encoded by serde codecs. In formats
+//! meant for people, e.g. JSON, they're base64 strings rather than arrays of
+//! numbers. Other formats, e.g. bincode, write the bytes as they are.
+
+use std::fmt;
+
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::wire::{FieldId, RpcHandlerError};
+
+/// A payload to serialize.
+struct Payload<'a>(&'a [u8]);
+
+impl Serialize for Payload<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::encode(self.0))
+        } else {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+}
+
+/// A deserialized payload.
+struct PayloadBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for PayloadBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            return base64::decode(encoded).map(PayloadBuf).map_err(de::Error::custom);
+        }
+        deserializer.deserialize_byte_buf(PayloadVisitor)
+    }
+}
+
+struct PayloadVisitor;
+
+impl<'de> Visitor<'de> for PayloadVisitor {
+    type Value = PayloadBuf;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("bytes")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(PayloadBuf(bytes.to_vec()))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(PayloadBuf(bytes))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(PayloadBuf(bytes))
+    }
+}
+
+/// A single payload, e.g. a call's arguments.
+pub(crate) mod bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(payload: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        Payload(payload).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        PayloadBuf::deserialize(deserializer).map(|payload| payload.0)
+    }
+}
+
+/// A call's output.
+pub(crate) mod output {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(output: &Result<Vec<u8>, RpcHandlerError>, serializer: S) -> Result<S::Ok, S::Error> {
+        output.as_ref().map(|output| Payload(output)).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Result<Vec<u8>, RpcHandlerError>, D::Error> {
+        let output = Result::<PayloadBuf, RpcHandlerError>::deserialize(deserializer)?;
+        Ok(output.map(|output| output.0))
+    }
+}
+
+/// Several payloads, e.g. the messages in a batch.
+pub(crate) mod list {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(payloads: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(payloads.iter().map(|payload| Payload(payload)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        let payloads = Vec::<PayloadBuf>::deserialize(deserializer)?;
+        Ok(payloads.into_iter().map(|payload| payload.0).collect())
+    }
+}
+
+/// State changes, each a field id and its new value.
+pub(crate) mod state_changes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(changes: &[(FieldId, Vec<u8>)], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(changes.iter().map(|(field, value)| (field, Payload(value))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(FieldId, Vec<u8>)>, D::Error> {
+        let changes = Vec::<(FieldId, PayloadBuf)>::deserialize(deserializer)?;
+        Ok(changes.into_iter().map(|(field, value)| (field, value.0)).collect())
+    }
+}
@@ -0,0 +1,187 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+use futures_util::future::join_all;
+use tracing::{debug, warn};
+
+use crate::{
+    client::{Client, Connection, State},
+    error::ConnectError,
+    reconnectable::Reconnectable,
+    server::HandlerResult,
+    wire::RpcHandlerError,
+};
+
+/// Several connections used as one, for applications that need more than
+/// one socket, e.g. to have more than 256 calls in flight or to spread calls
+/// across server instances behind a load balancer. Each call goes to the
+/// connection with the fewest calls in flight, taking turns between equally
+/// busy ones.
+///
+/// A connection that closes is reconnected in the background the next time
+/// a call is made, and calls go to the others meanwhile.
+pub struct ClientPool<T>
+where
+    T: State + Default + Clone,
+{
+    slots: Vec<Arc<Slot<T>>>,
+    /// Where the search for the least busy connection starts, so ties are
+    /// broken in turn.
+    next: AtomicUsize,
+}
+
+struct Slot<T>
+where
+    T: State + Default + Clone,
+{
+    connection: Reconnectable<T>,
+    in_flight: AtomicUsize,
+    reconnecting: AtomicBool,
+}
+
+impl<T> ClientPool<T>
+where
+    T: State + Default + Clone + Send + Sync + 'static,
+{
+    /// Creates a pool of `size` disconnected connections. `new_client` is
+    /// called with a connection's index, from 0, every time it connects,
+    /// e.g. `|_| Client::new("example.com:443")`.
+    ///
+    /// # Panics
+    ///
+    /// If `size` is 0.
+    pub fn new(size: usize, new_client: impl Fn(usize) -> Client<T> + Send + Sync + 'static) -> Self {
+        assert!(size > 0, "a pool needs at least one connection");
+        let new_client = Arc::new(new_client);
+        let slots = (0..size)
+            .map(|index| {
+                let new_client = new_client.clone();
+                Arc::new(Slot {
+                    connection: Reconnectable::new(move || new_client(index)),
+                    in_flight: AtomicUsize::new(0),
+                    reconnecting: AtomicBool::new(false),
+                })
+            })
+            .collect();
+        Self {
+            slots,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Connects every connection that isn't connected, at the same time.
+    /// Returns the first error if any of them fail, but the ones that
+    /// connected can still be used.
+    pub async fn connect(&self) -> Result<(), ConnectError> {
+        let attempts = self
+            .slots
+            .iter()
+            .filter(|slot| !slot.connection.is_connected())
+            .map(|slot| slot.connection.connect());
+        join_all(attempts).await.into_iter().collect()
+    }
+
+    /// Closes every connection.
+    pub fn disconnect(&self) {
+        for slot in &self.slots {
+            slot.connection.disconnect();
+        }
+    }
+
+    /// The number of connections in the pool, connected or not.
+    pub fn size(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Whether any connection is connected.
+    pub fn is_connected(&self) -> bool {
+        self.slots.iter().any(|slot| slot.connection.is_connected())
+    }
+
+    /// The connections that are connected, e.g. to subscribe to a topic on
+    /// each of them.
+    pub fn connections(&self) -> Vec<Connection<T>> {
+        self.slots
+            .iter()
+            .filter(|slot| slot.connection.is_connected())
+            .filter_map(|slot| slot.connection.connection())
+            .collect()
+    }
+
+    /// Makes an RPC call on the least busy connection, retrying as it's
+    /// configured to. See [Connection::call]. Fails with
+    /// [RpcHandlerError::ClientNotConnected] if no connection is connected.
+    pub async fn call(&self, input: Vec<u8>) -> HandlerResult<Vec<u8>> {
+        let (slot, connection) = self.pick().ok_or(RpcHandlerError::ClientNotConnected)?;
+        let _in_flight = InFlight::start(&slot.in_flight, 1);
+        connection.call(input).await
+    }
+
+    /// Makes several RPC calls at once, in a single frame on the least busy
+    /// connection. See [Connection::call_batch].
+    pub async fn call_batch(&self, inputs: Vec<Vec<u8>>) -> Vec<HandlerResult<Vec<u8>>> {
+        let Some((slot, connection)) = self.pick() else {
+            return inputs.iter().map(|_| Err(RpcHandlerError::ClientNotConnected)).collect();
+        };
+        let _in_flight = InFlight::start(&slot.in_flight, inputs.len());
+        connection.call_batch(inputs).await
+    }
+
+    /// Finds the connected connection with the fewest calls in flight, and
+    /// starts reconnecting any that have closed.
+    fn pick(&self) -> Option<(&Slot<T>, Connection<T>)> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut best: Option<(&Slot<T>, Connection<T>, usize)> = None;
+        for offset in 0..self.slots.len() {
+            let slot = &self.slots[(start + offset) % self.slots.len()];
+            let connection = slot.connection.connection().filter(|connection| !connection.is_closed());
+            let Some(connection) = connection else {
+                self.reconnect(slot);
+                continue;
+            };
+            let in_flight = slot.in_flight.load(Ordering::Relaxed);
+            if best.as_ref().is_none_or(|(_, _, least)| in_flight < *least) {
+                best = Some((&**slot, connection, in_flight));
+            }
+        }
+        best.map(|(slot, connection, _)| (slot, connection))
+    }
+
+    /// Reconnects a closed connection in the background, unless it already
+    /// is being.
+    fn reconnect(&self, slot: &Arc<Slot<T>>) {
+        if slot.reconnecting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let slot = slot.clone();
+        tokio::spawn(async move {
+            debug!("Reconnecting a pooled connection");
+            if let Err(e) = slot.connection.connect().await {
+                warn!("Failed to reconnect a pooled connection: {}", e);
+            }
+            slot.reconnecting.store(false, Ordering::Release);
+        });
+    }
+}
+
+/// Counts calls towards a connection's calls in flight until it's dropped,
+/// including when the call is cancelled.
+struct InFlight<'a> {
+    count: &'a AtomicUsize,
+    calls: usize,
+}
+
+impl<'a> InFlight<'a> {
+    fn start(count: &'a AtomicUsize, calls: usize) -> Self {
+        count.fetch_add(calls, Ordering::Relaxed);
+        Self { count, calls }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.count.fetch_sub(self.calls, Ordering::Relaxed);
+    }
+}
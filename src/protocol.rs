@@ -0,0 +1,58 @@
+use crate::codec::DEFAULT_CODEC;
+
+/// The subprotocol name for a HardLight protocol version and the codec messages
+/// are encoded with, e.g. `hl/1+json`. The default codec isn't named, as in
+/// `hl/1`, so older servers still understand it.
+pub(crate) fn protocol_name(major: u64, codec: &str) -> String {
+    match codec {
+        DEFAULT_CODEC => format!("hl/{major}"),
+        codec => format!("hl/{major}+{codec}"),
+    }
+}
+
+/// Lists versions for a `Sec-WebSocket-Protocol` header, e.g. `hl/1, hl/0`.
+pub(crate) fn protocol_list(majors: &[u64], codec: &str) -> String {
+    majors.iter().map(|&major| protocol_name(major, codec)).collect::<Vec<_>>().join(", ")
+}
+
+/// A HardLight subprotocol listed in a `Sec-WebSocket-Protocol` header.
+pub(crate) struct Offer<'a> {
+    /// The subprotocol as it was written, which is what a server has to
+    /// answer with, e.g. `hl.1+json-text`.
+    pub name: &'a str,
+    pub major: u64,
+    /// The codec, which is the default one if it isn't named.
+    pub codec: &'a str,
+}
+
+/// The HardLight subprotocols in `Sec-WebSocket-Protocol` headers, which list
+/// subprotocols separated by commas, e.g. `hl/1` or `hl/1+json`. Browsers
+/// don't allow a `/` in a subprotocol, so `hl.1+json-text` is accepted too.
+/// Other subprotocols are skipped.
+pub(crate) fn parse_offers<'a>(values: impl IntoIterator<Item = &'a str>) -> Vec<Offer<'a>> {
+    values
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| {
+            let name = name.trim();
+            let protocol = name.strip_prefix("hl/").or_else(|| name.strip_prefix("hl."))?;
+            let (major, codec) = protocol.split_once('+').unwrap_or((protocol, DEFAULT_CODEC));
+            Some(Offer {
+                name,
+                major: major.parse().ok()?,
+                codec,
+            })
+        })
+        .collect()
+}
+
+/// The HardLight versions in `Sec-WebSocket-Protocol` headers, whatever codec
+/// they're with.
+pub(crate) fn parse_protocols<'a>(values: impl IntoIterator<Item = &'a str>) -> Vec<u64> {
+    parse_offers(values).into_iter().map(|offer| offer.major).collect()
+}
+
+/// The highest version both sides speak.
+pub(crate) fn negotiate(offered: &[u64], supported: &[u64]) -> Option<u64> {
+    offered.iter().filter(|major| supported.contains(major)).max().copied()
+}
@@ -0,0 +1,105 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How the binary (version 2) PROXY header starts.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The longest a text (version 1) PROXY header can be, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Reads the PROXY protocol header a load balancer sends ahead of the
+/// client's own bytes, in either the text or the binary format. Returns the
+/// client's address, or `None` if the load balancer didn't give one, e.g. for
+/// its own health checks. Reads nothing past the header, so the TLS
+/// handshake that follows is left intact.
+pub(crate) async fn read_proxy_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // the shortest text header, "PROXY UNKNOWN\r\n", is longer than this
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, start.to_vec()).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Reads the rest of a text header, e.g.
+/// `PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n`.
+async fn read_v1<S>(stream: &mut S, mut header: Vec<u8>) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // a byte at a time, as anything after the header belongs to the client
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header is too long"));
+        }
+        header.push(stream.read_u8().await?);
+    }
+    let header = std::str::from_utf8(&header[..header.len() - 2])
+        .map_err(|_| invalid("PROXY protocol header isn't text"))?;
+    let parts: Vec<&str> = header.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("invalid source address"))?;
+            let port: u16 = source_port.parse().map_err(|_| invalid("invalid source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY protocol header")),
+    }
+}
+
+/// Reads the rest of a binary header: the version and command, the address
+/// family, the length of what follows, then the addresses.
+async fn read_v2<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await?;
+    let mut addresses = vec![0; len as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, i.e. the load balancer's own connection
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+    match family {
+        // TCP over IPv4: source and destination address, then their ports
+        0x11 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x11 | 0x21 => Err(invalid("PROXY protocol addresses are too short")),
+        // unspecified, UDP or Unix sockets, which say nothing useful
+        _ => Ok(None),
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
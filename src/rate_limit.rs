@@ -0,0 +1,94 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How many RPC calls are allowed over a period of time. Calls are allowed in
+/// bursts of up to `calls`, and the allowance refills evenly over `per`.
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    pub calls: u32,
+    pub per: Duration,
+}
+
+impl Rate {
+    pub fn per_second(calls: u32) -> Self {
+        Self {
+            calls,
+            per: Duration::from_secs(1),
+        }
+    }
+
+    pub fn per_minute(calls: u32) -> Self {
+        Self {
+            calls,
+            per: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A token bucket holding up to [Rate::calls] tokens.
+pub(crate) struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            tokens: rate.calls as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, or returns how long until the next one is available.
+    pub fn try_take(&mut self) -> Result<(), Duration> {
+        if self.rate.calls == 0 {
+            return Err(self.rate.per);
+        }
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled_at).as_secs_f64()
+            / self.rate.per.as_secs_f64()
+            * self.rate.calls as f64;
+        self.tokens = (self.tokens + refill).min(self.rate.calls as f64);
+        self.refilled_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - self.tokens;
+            Err(self.rate.per.mul_f64(missing / self.rate.calls as f64))
+        }
+    }
+}
+
+/// The limits that apply to a single connection's calls: its own, and the
+/// server-wide one it shares with every other connection.
+pub(crate) struct RateLimits {
+    connection: Option<TokenBucket>,
+    global: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl RateLimits {
+    pub fn new(connection: Option<Rate>, global: Option<Arc<Mutex<TokenBucket>>>) -> Self {
+        Self {
+            connection: connection.map(TokenBucket::new),
+            global,
+        }
+    }
+
+    /// Takes a token from each limit, or returns how long until the call
+    /// would be allowed.
+    pub fn check(&mut self) -> Result<(), Duration> {
+        if let Some(connection) = &mut self.connection {
+            connection.try_take()?;
+        }
+        if let Some(global) = &self.global {
+            global.lock().unwrap().try_take()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::Mutex;
+
+use crate::{
+    client::{Client, Connection, State},
+    error::ConnectError,
+    server::HandlerResult,
+    wire::RpcHandlerError,
+};
+
+/// A connection that can be opened, closed and opened again, for applications
+/// that need to hold onto a client before it's connected. Calls made while it
+/// isn't connected fail with [RpcHandlerError::ClientNotConnected].
+///
+/// Prefer [Client::connect] where possible: a [Connection] can't exist before
+/// it's connected, so calling too early is a compile error instead.
+pub struct Reconnectable<T>
+where
+    T: State + Default + Clone,
+{
+    /// Creates a configured client for each connection attempt.
+    new_client: Box<dyn Fn() -> Client<T> + Send + Sync>,
+    connection: Mutex<Option<Connection<T>>>,
+}
+
+impl<T> Reconnectable<T>
+where
+    T: State + Default + Clone + Send + Sync + 'static,
+{
+    /// Creates a disconnected client. `new_client` is called for every
+    /// connection attempt, e.g. `|| Client::new("example.com:443")`.
+    pub fn new(new_client: impl Fn() -> Client<T> + Send + Sync + 'static) -> Self {
+        Self {
+            new_client: Box::new(new_client),
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Connects to the server, replacing the current connection if there is
+    /// one. The current connection is kept if connecting fails.
+    pub async fn connect(&self) -> Result<(), ConnectError> {
+        let connection = (self.new_client)().connect().await?;
+        if let Some(old) = self.connection.lock().unwrap().replace(connection) {
+            old.close();
+        }
+        Ok(())
+    }
+
+    /// Closes the current connection, if there is one.
+    pub fn disconnect(&self) {
+        if let Some(connection) = self.connection.lock().unwrap().take() {
+            connection.close();
+        }
+    }
+
+    /// Whether there's a connection that hasn't closed.
+    pub fn is_connected(&self) -> bool {
+        self.connection
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|connection| !connection.is_closed())
+    }
+
+    /// Returns the current connection, if there is one.
+    pub fn connection(&self) -> Option<Connection<T>> {
+        self.connection.lock().unwrap().clone()
+    }
+
+    /// Makes an RPC call on the current connection. See [Connection::call].
+    pub async fn call(&self, input: Vec<u8>) -> HandlerResult<Vec<u8>> {
+        let connection = self
+            .connection()
+            .ok_or(RpcHandlerError::ClientNotConnected)?;
+        connection.call(input).await
+    }
+}
@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use crate::wire::RpcHandlerError;
+
+/// What to do about a failed call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryDecision {
+    /// Make the call again after waiting this long.
+    RetryAfter(Duration),
+    /// Return the error to the application.
+    GiveUp,
+}
+
+/// Decides which failed calls a [Connection](crate::Connection) retries, and
+/// how long it waits first. Set one with
+/// [Client::set_retry_classifier](crate::Client::set_retry_classifier).
+///
+/// Only retry errors where it's safe to run the call again. A call that timed
+/// out may still have run on the server, so retrying it is only safe if the
+/// call is idempotent.
+pub trait RetryClassifier: Send + Sync {
+    /// Classifies the error from a call's `attempt`th try, starting at 1.
+    fn classify(&self, error: &RpcHandlerError, attempt: u32) -> RetryDecision;
+}
+
+/// Retries the errors where the server never ran the call, backing off
+/// exponentially from `base_delay`. Rate limited calls wait at least as long
+/// as the server asks.
+#[derive(Debug, Clone, Copy)]
+pub struct DefaultRetryClassifier {
+    /// The most tries a call gets, including the first.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry. Doubles with every retry.
+    pub base_delay: Duration,
+}
+
+impl Default for DefaultRetryClassifier {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryClassifier for DefaultRetryClassifier {
+    fn classify(&self, error: &RpcHandlerError, attempt: u32) -> RetryDecision {
+        if attempt >= self.max_attempts {
+            return RetryDecision::GiveUp;
+        }
+        let backoff = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        match error {
+            RpcHandlerError::TooManyCallsInFlight | RpcHandlerError::Overloaded => {
+                RetryDecision::RetryAfter(backoff)
+            }
+            RpcHandlerError::RateLimited { retry_after_ms } => {
+                let retry_after = Duration::from_millis(retry_after_ms.unwrap_or(0));
+                RetryDecision::RetryAfter(backoff.max(retry_after))
+            }
+            _ => RetryDecision::GiveUp,
+        }
+    }
+}
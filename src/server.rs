@@ -1,12 +1,29 @@
-use std::{io, net::SocketAddr, str::FromStr, sync::Arc};
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    panic::{catch_unwind, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{future::{join_all, select_all}, stream::SplitSink, FutureExt, SinkExt, StreamExt};
 use rcgen::generate_simple_self_signed;
+use rkyv::{ser::serializers::AllocSerializer, vec::ArchivedVec, Deserialize, Infallible};
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
     select,
-    sync::mpsc,
+    sync::{broadcast, mpsc, oneshot, watch, OwnedSemaphorePermit, Semaphore},
+    task::{JoinHandle, JoinSet},
+    time::Interval,
 };
 use tokio_rustls::{
     rustls::{Certificate, PrivateKey, ServerConfig as TLSServerConfig},
@@ -14,48 +31,519 @@ use tokio_rustls::{
     TlsAcceptor,
 };
 use tokio_tungstenite::{
-    accept_hdr_async,
+    accept_hdr_async, accept_hdr_async_with_config,
     tungstenite::{
-        handshake::server::{Request, Response},
-        http::{HeaderValue, StatusCode},
-        Message,
+        handshake::server::{Callback, ErrorResponse, Request, Response},
+        http::{
+            header::{AUTHORIZATION, HOST, ORIGIN, WWW_AUTHENTICATE},
+            HeaderValue, StatusCode,
+        },
+        protocol::{frame::coding::CloseCode, CloseFrame, WebSocketConfig},
+        Error, Message,
     },
+    WebSocketStream,
 };
-use tracing::{debug, info, span, warn, Level};
+use tracing::{debug, field, info, span, warn, Instrument, Level};
 use version::{version, Version};
 
-use crate::wire::{ClientMessage, RpcHandlerError, ServerMessage};
+use crate::{
+    auth::TokenValidator,
+    codec::{client_message_to_rkyv, server_message_from_rkyv, Codec, RkyvCodec},
+    forwarded::{client_addr, IpNet},
+    frame::Frame,
+    health::screen,
+    connections::{ConnectionRejection, ConnectionTracker},
+    context::{CallContext, CancellationToken, Context},
+    deflate::{self, CompressionConfig, Deflate, Side},
+    error::ServerError,
+    field_sync::{LazyFields, LAZY_FIELDS_HEADER},
+    idempotency::IdempotencyStore,
+    interceptor::{Interceptor, Next},
+    method_stats::{MethodStats, MethodStatsRegistry},
+    metrics_hook::ServerMetricsHook,
+    outbox::{outbox, Outbox, OutboxQueue},
+    origin::{host_allowed, origin_allowed},
+    protocol::{self, protocol_name},
+    proxy_protocol::read_proxy_header,
+    query,
+    rate_limit::{Rate, RateLimits, TokenBucket},
+    socket::set_linger,
+    state_coalescing::CoalescedChanges,
+    streaming::{ActiveStream, StreamSender, STREAM_WINDOW},
+    tls::{load_certificates, load_private_key, no_certificate_config, PemError, TlsInfo},
+    topics::{Broadcaster, ConnectionId, Subscriptions, TopicRegistry},
+    transaction,
+    wire::{
+        to_message_bytes, ArchivedClientMessage, ClientMessage, FieldId, FieldSync, RpcHandlerError, ServerMessage, TransactionOp,
+    },
+};
+#[cfg(unix)]
+use crate::socket::bind_unix;
 
 /// A tokio MPSC channel that is used to send state updates to the runtime.
 /// The runtime will then send these updates to the client.
-pub type StateUpdateChannel = mpsc::Sender<Vec<(String, Vec<u8>)>>;
+///
+/// It's bounded, and the runtime stops taking changes from it while the
+/// client is behind on reading, so a handler that awaits its sends is held
+/// up rather than queueing changes without end. Changes a call sends before
+/// it returns reach the client before the call's response does.
+pub type StateUpdateChannel = mpsc::Sender<Vec<(FieldId, Vec<u8>)>>;
+
+/// How many batches of changes can wait on a [StateUpdateChannel] before
+/// sending waits.
+pub(crate) const STATE_UPDATE_CAPACITY: usize = 64;
+
+/// Where [SharedState](crate::SharedState)s send their changes. It isn't
+/// bounded, as a change is sent to every subscribed connection and shouldn't
+/// wait on any one of them.
+pub(crate) type SharedStateChannel = mpsc::UnboundedSender<Vec<(FieldId, Vec<u8>)>>;
 
 pub type HandlerResult<T> = Result<T, RpcHandlerError>;
 
+/// Decides whether a connection is allowed something. See
+/// [ServerConfig::stats_rpc_access].
+pub type AccessCheck = Arc<dyn Fn(&Context) -> bool + Send + Sync>;
+
+/// Told about clients that fail to connect. See
+/// [ServerConfig::on_accept_error].
+pub type AcceptErrorHook = Arc<dyn Fn(&ServerError) + Send + Sync>;
+
 /// A [Handler] will be created for each connection to the server.
 /// These are user-defined structs that respond to RPC calls
 #[async_trait]
 pub trait Handler {
-    /// Create a new handler using the given state update channel.
-    fn new(state_update_channel: StateUpdateChannel) -> Self
+    /// Create a new handler using the given state update channel. The
+    /// connection's [Context] can be used to set up initial extensions.
+    fn new(state_update_channel: StateUpdateChannel, ctx: &Context) -> Self
     where
         Self: Sized;
     /// Handle an RPC call (method + arguments) from the client.
-    async fn handle_rpc_call(&self, input: &[u8]) -> Result<Vec<u8>, RpcHandlerError>;
+    ///
+    /// `input` is borrowed straight out of the message it arrived in and is
+    /// aligned to 16 bytes, so it can be read in place with
+    /// [rkyv::check_archived_root] rather than deserialized. Nested payloads
+    /// serialized with [Aligned](crate::Aligned) can be read in place too.
+    ///
+    /// Payloads are passed through untouched, including empty ones: rkyv
+    /// serializes `()` to zero bytes, so a method without arguments or without
+    /// a result sends an empty buffer. Decoding an empty buffer as anything
+    /// other than `()` fails rather than panicking, so map that error to
+    /// [RpcHandlerError::BadInputBytes].
+    ///
+    /// Handlers implement either this or [Self::handle_call], which has the
+    /// details of the call too. Calls fail with [RpcHandlerError::Unsupported]
+    /// if neither is implemented.
+    async fn handle_rpc_call(
+        &self,
+        _ctx: &Context,
+        _input: &[u8],
+    ) -> Result<Vec<u8>, RpcHandlerError> {
+        Err(RpcHandlerError::Unsupported)
+    }
+    /// Handle an RPC call like [Self::handle_rpc_call], with the call's id,
+    /// deadline and a token that's cancelled when the connection closes.
+    /// A call that's still running at its deadline is stopped and answered
+    /// with [RpcHandlerError::Timeout].
+    /// Every call comes through here, and by default it's passed on to
+    /// [Self::handle_rpc_call].
+    ///
+    /// When the connection closes, handlers still running are cancelled and
+    /// given a second to return before they're aborted, wherever they're up
+    /// to. Handlers that take a while or have side effects should check
+    /// [CallContext::cancellation] so they can stop cleanly.
+    async fn handle_call(&self, call: &CallContext<'_>, input: &[u8]) -> HandlerResult<Vec<u8>> {
+        self.handle_rpc_call(call.connection(), input).await
+    }
+    /// Handle a fire-and-forget event from the client. Events are ignored
+    /// unless this is implemented. Like calls, events still being handled
+    /// when the connection closes are cancelled, and aborted shortly after,
+    /// so handlers that take a while should check [Context::cancellation].
+    ///
+    /// Events count towards the connection's rate limits and call limit like
+    /// calls do, but as there's no response to refuse them with, events over
+    /// a limit are dropped. So are events beyond the 256 a connection can
+    /// have being handled at once.
+    async fn handle_event(&self, _ctx: &Context, _payload: &[u8]) {}
+    /// Called with every batch of state changes before it's sent to the
+    /// client. Changes can be modified, added or removed, e.g. to redact
+    /// fields this connection isn't allowed to see. If no changes are left,
+    /// nothing is sent. Batches that queue up while the connection is busy
+    /// are merged first, keeping only the latest value of each field.
+    fn prepare_state_changes(&self, _changes: &mut Vec<(FieldId, Vec<u8>)>) {}
+    /// Returns the name of the method an RPC call is for, which the server
+    /// uses to keep [MethodStats]. Calls are counted as `<unknown>` unless
+    /// this is implemented.
+    fn method_name(&self, _input: &[u8]) -> Option<&'static str> {
+        None
+    }
+    /// Returns the permission scope a connection needs to call `method`, as
+    /// named by [Self::method_name], e.g. `counter.write` for `increment`.
+    /// Calls from connections that haven't been granted it with
+    /// [Context::grant_scope] fail with [RpcHandlerError::Unauthorized]
+    /// before any interceptor or the handler runs. Streaming calls are
+    /// checked too. Every method is allowed unless this is implemented.
+    fn required_scope(&self, _method: &'static str) -> Option<&'static str> {
+        None
+    }
+    /// Handle a streaming RPC call from the client, sending the output in
+    /// chunks with the [StreamSender] instead of all at once. The stream ends
+    /// when this returns. If the client cancels the call, this is aborted.
+    /// Streaming calls fail with [RpcHandlerError::Unsupported] unless this
+    /// is implemented.
+    async fn handle_streaming_call(
+        &self,
+        _ctx: &Context,
+        _input: &[u8],
+        _stream: StreamSender,
+    ) -> HandlerResult<()> {
+        Err(RpcHandlerError::Unsupported)
+    }
+    /// Called when the client begins a transaction. Until the transaction
+    /// is committed or rolled back, the state changes the handler sends are
+    /// held back instead of being sent to the client.
+    ///
+    /// The runtime snapshots the handler's [ConnectionState]s as the
+    /// transaction begins and the [SharedState](crate::SharedState)s its
+    /// calls lock, and restores them if it's rolled back, so most handlers
+    /// don't need to implement the transaction hooks. Implement them for
+    /// state kept some other way, e.g. to take a snapshot of it here. Fail
+    /// with [RpcHandlerError::Unsupported] if the handler can't take part.
+    ///
+    /// Transactions can only begin, commit or roll back while none of the
+    /// client's calls are running, and fail with
+    /// [RpcHandlerError::CallsInFlight] otherwise. A transaction that's
+    /// still open when the client disconnects is rolled back.
+    fn begin_transaction(&self, _ctx: &Context) -> HandlerResult<()> {
+        Ok(())
+    }
+    /// Called when the client commits its transaction. If this succeeds, the
+    /// state changes held back during the transaction are sent to the client
+    /// together. If it fails, the transaction stays open.
+    fn commit_transaction(&self, _ctx: &Context) -> HandlerResult<()> {
+        Ok(())
+    }
+    /// Called when the client rolls back its transaction, once the runtime
+    /// has restored the states it snapshotted, to undo the changes made
+    /// during it to any other state. The state changes held back during the
+    /// transaction are discarded, so the client never sees them.
+    fn rollback_transaction(&self, _ctx: &Context) -> HandlerResult<()> {
+        Ok(())
+    }
     // An easy way to get the handler factory.
     // Currently disabled because we can't use impl Trait in traits yet. (https://github.com/rust-lang/rust/issues/91611)
-    // fn init() -> impl Fn(StateUpdateChannel) -> Box<dyn Handler + Send + Sync> +
-    // Send + Sync + 'static + Copy;
+    // fn init() -> impl Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>
+    // + Send + Sync + 'static;
+}
+
+/// A cheaply cloneable handle to a running [Server]. It can be used from
+/// anywhere in the application, including from inside handlers.
+#[derive(Clone)]
+pub struct ServerHandle {
+    topics: Arc<TopicRegistry>,
+    rooms: Arc<TopicRegistry>,
+    broadcaster: Broadcaster,
+    connections: Arc<ConnectionTracker>,
+    method_stats: Arc<MethodStatsRegistry>,
+    next_connection_id: Arc<AtomicU64>,
+    /// The TLS config used for new connections.
+    tls: Arc<ArcSwap<TLSServerConfig>>,
+}
+
+impl ServerHandle {
+    fn new(tls: TLSServerConfig) -> Self {
+        Self {
+            tls: Arc::new(ArcSwap::from_pointee(tls)),
+            topics: Arc::new(TopicRegistry::default()),
+            rooms: Arc::new(TopicRegistry::default()),
+            broadcaster: Broadcaster::new(),
+            connections: Arc::new(ConnectionTracker::default()),
+            method_stats: Arc::new(MethodStatsRegistry::default()),
+            next_connection_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Publishes an event to every connection subscribed to `topic`, returning
+    /// the number of connections it was sent to. Publishing to a topic with no
+    /// subscribers is a cheap no-op.
+    pub fn publish(&self, topic: &str, event: &[u8]) -> usize {
+        self.topics.publish(topic, event)
+    }
+
+    /// Returns a handle for sending events to every connected client.
+    pub fn broadcaster(&self) -> Broadcaster {
+        self.broadcaster.clone()
+    }
+
+    /// Serializes an event and sends it to every connection in `room`,
+    /// returning the number of connections it was sent to. Connections join
+    /// and leave rooms with [Context::join_room] and [Context::leave_room].
+    pub fn broadcast<E>(&self, room: &str, event: &E) -> HandlerResult<usize>
+    where
+        E: rkyv::Serialize<AllocSerializer<1024>>,
+    {
+        let event = rkyv::to_bytes::<E, 1024>(event).map_err(|_| RpcHandlerError::BadOutputBytes)?;
+        Ok(self.rooms.publish(room, &event))
+    }
+
+    /// Returns the connections currently in `room`.
+    pub fn room_members(&self, room: &str) -> Vec<ConnectionId> {
+        self.rooms.subscribers(room)
+    }
+
+    /// Replaces the server's TLS config, e.g. to rotate its certificate.
+    /// Only new connections use the new config; connections that are already
+    /// open keep their existing session.
+    pub fn reload_tls(&self, tls: TLSServerConfig) {
+        self.tls.store(Arc::new(tls));
+        info!("Reloaded TLS config");
+    }
+
+    /// Returns call statistics for every RPC method, sorted by name.
+    pub fn method_stats(&self) -> Vec<MethodStats> {
+        self.method_stats.snapshot()
+    }
+
+    /// The number of clients currently connected.
+    pub fn connection_count(&self) -> usize {
+        self.connections.count()
+    }
+
+    fn next_connection_id(&self) -> ConnectionId {
+        self.next_connection_id.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// What happens to a connection's calls while it has
+/// [ServerConfig::max_concurrent_calls_per_connection] running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CallLimitMode {
+    /// They wait their turn, up to
+    /// [ServerConfig::max_queued_calls_per_connection] of them.
+    #[default]
+    Queue,
+    /// They're answered with [RpcHandlerError::RateLimited] straight away,
+    /// telling the client to try again after `retry_after`, if it's set.
+    RateLimit { retry_after: Option<Duration> },
 }
 
-#[derive(Debug)]
 pub struct ServerConfig {
     pub address: String,
     pub version: Version,
+    /// The TLS config the server starts with. Use [Server::reload_tls] to
+    /// change it while the server is running.
     pub tls: TLSServerConfig,
+    /// How long [Context::call_client] waits for the client to respond.
+    pub client_call_timeout: Duration,
+    /// If set, each connection logs its [ConnectionStats](crate::ConnectionStats)
+    /// at this interval, which is useful for keeping an eye on long-lived
+    /// connections.
+    pub stats_report_interval: Option<Duration>,
+    /// Wrapped around every RPC call, in order. The first interceptor is the
+    /// outermost one.
+    pub interceptors: Vec<Arc<dyn Interceptor>>,
+    /// The most calls a single connection can have running at once. Calls
+    /// over the limit wait their turn, in the order they arrived. Streaming
+    /// calls count until the stream ends. Unlimited if `None`.
+    pub max_concurrent_calls_per_connection: Option<usize>,
+    /// The most calls a single connection can have waiting for a free slot.
+    /// Calls over this are answered with [RpcHandlerError::Overloaded]
+    /// straight away. Only applies if `max_concurrent_calls_per_connection`
+    /// is set. Unlimited if `None`.
+    pub max_queued_calls_per_connection: Option<usize>,
+    /// Whether calls over `max_concurrent_calls_per_connection` are queued or
+    /// turned away. Queued by default.
+    pub call_limit_mode: CallLimitMode,
+    /// How quickly each connection can make RPC calls. Calls over the limit
+    /// are answered with [RpcHandlerError::RateLimited], and events over it
+    /// are dropped. State updates don't count. Unlimited if `None`.
+    pub connection_rate_limit: Option<Rate>,
+    /// How quickly RPC calls can be made across all connections combined.
+    /// Unlimited if `None`.
+    pub global_rate_limit: Option<Rate>,
+    /// How long each connection's [IdempotencyStore] keeps a response.
+    pub idempotency_ttl: Duration,
+    /// The most responses each connection's [IdempotencyStore] keeps. The
+    /// least recently used one is evicted to make room for a new one.
+    pub idempotency_max_keys: usize,
+    /// The most clients that can be connected at once. Clients over the
+    /// limit are turned away with HTTP 503. Unlimited if `None`.
+    pub max_connections: Option<usize>,
+    /// The most clients that can be connected at once from a single IP
+    /// address. Unlimited if `None`.
+    pub max_connections_per_ip: Option<usize>,
+    /// WebSocket settings, e.g. the largest message a client can send,
+    /// passed straight to tungstenite. Clients that send a bigger message
+    /// are disconnected with close code 1009 (message too big). Defaults to
+    /// tungstenite's defaults: 64 MiB messages, 16 MiB frames, an unlimited
+    /// send queue, and unmasked frames from clients refused.
+    pub ws_config: WebSocketConfig,
+    /// How messages are encoded on the wire. Clients have to use the same
+    /// codec, or they're turned away with HTTP 400. Defaults to [RkyvCodec].
+    pub codec: Arc<dyn Codec>,
+    /// If set, messages are compressed with the permessage-deflate WebSocket
+    /// extension, for clients that offer it, as browsers and [Client]s with
+    /// [Client::set_compression] do. It trades CPU time on both ends for
+    /// bandwidth: large, repetitive messages, e.g. state with long lists,
+    /// shrink a lot, while small ones gain little. Unlike a compressing
+    /// [Codec], it needs nothing from the app and browsers do it natively,
+    /// but it only compresses one message at a time. Off by default. See
+    /// [Self::compression()].
+    pub compression: Option<CompressionConfig>,
+    /// If set, `SO_LINGER` is set on each client's socket, so closing a
+    /// connection waits up to this long for the last of its data (e.g. the
+    /// close frame) to be delivered. Note that this blocks the thread closing
+    /// the socket. A zero duration resets the connection on close instead.
+    pub linger: Option<Duration>,
+    /// The largest response the server sends, in bytes. A call whose output
+    /// is bigger is answered with [RpcHandlerError::ResponseTooLarge] instead,
+    /// and a stream with an item that's bigger ends with it. Defaults to
+    /// 64 MiB, the most a client accepts by default. If `None`, responses are
+    /// sent whatever their size, and a client may disconnect when it receives
+    /// one over its own limit.
+    pub max_response_size: Option<usize>,
+    /// The largest call input the server accepts, in bytes, whatever the
+    /// method. A call whose input is bigger is answered with
+    /// [RpcHandlerError::PayloadTooLarge] without being decoded or run. If
+    /// `None`, the default, inputs are only limited by
+    /// [Self::ws_config]'s message size.
+    pub max_call_input_size: Option<usize>,
+    /// Messages that are queued for a client at the same time are sent to it
+    /// as one [ServerMessage::Batch] of up to this many bytes, saving a write
+    /// and a TLS record per message. Nothing is held back waiting for more
+    /// messages, so batches only form when the client or its socket falls
+    /// behind. If `None`, every message is sent on its own.
+    pub max_batch_size: Option<usize>,
+    /// If set, state changes are written to the client ahead of anything
+    /// else that's waiting to be sent, e.g. a backlog of large responses, so
+    /// state stays fresh for apps that depend on it. A message that's already
+    /// being written isn't interrupted. A call's state changes still reach the
+    /// client before its response either way.
+    pub prioritize_state_changes: bool,
+    /// If set, state changes are held for up to this long so the batches
+    /// that arrive meanwhile reach the client as one
+    /// [ServerMessage::StateChange], keeping only the latest value of each
+    /// field, e.g. for a handler that changes its state in a tight loop. A
+    /// call's state changes are still sent before its response, however
+    /// soon that is. Off if `None`, the default.
+    pub state_coalescing: Option<Duration>,
+    /// Decides which clients can fetch the server's [MethodStats] with
+    /// [ControlChannels::method_stats](crate::ControlChannels::method_stats).
+    /// If `None`, no client can.
+    pub stats_rpc_access: Option<AccessCheck>,
+    /// Receives connection, call and traffic events for metrics. See
+    /// [MetricsCrateHook](crate::MetricsCrateHook) (with the `metrics`
+    /// feature) for one that works with the `metrics` crate.
+    pub metrics_hook: Option<Arc<dyn ServerMetricsHook>>,
+    /// If set, clients must connect with a bearer token this accepts, or
+    /// they're turned away with HTTP 401. See [TokenValidator].
+    pub token_validator: Option<Arc<dyn TokenValidator>>,
+    /// If set, the server listens on a Unix socket at this path instead of
+    /// [Self::address], without TLS. See [ServerConfig::new_unix]. Only
+    /// supported on Unix.
+    pub unix_socket: Option<PathBuf>,
+    /// The permissions the Unix socket is created with, e.g. `0o660` to let
+    /// the owner's group connect too. Left to the process's umask if `None`.
+    pub unix_socket_mode: Option<u32>,
+    /// If set, plain HTTP `GET` requests for this path, e.g. `/healthz`, are
+    /// answered with 200 so load balancers can probe the server. Any other
+    /// request that isn't a WebSocket upgrade is answered with 404. Either
+    /// way, the connection is then closed.
+    pub health_check_path: Option<String>,
+    /// The origins browsers can connect from, e.g. `https://example.com`, to
+    /// stop other sites from opening connections with their visitors'
+    /// credentials. Clients whose `Origin` header isn't one of these are
+    /// turned away with HTTP 403. Clients that don't send one, i.e. anything
+    /// but a browser, are let through. Any origin is allowed if `None`.
+    pub allowed_origins: Option<Vec<String>>,
+    /// The hosts clients can address the server as, e.g. `example.com`.
+    /// Clients whose `Host` header isn't one of these are turned away with
+    /// HTTP 403. A host without a port is the same as port 443. Any host is
+    /// allowed if `None`.
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Set this when the server is behind a load balancer that sends the
+    /// PROXY protocol, e.g. an AWS NLB with it turned on. Every connection
+    /// must then start with a PROXY header, in either the text (v1) or binary
+    /// (v2) format, and the client address it gives is used as the
+    /// connection's [Context::peer_addr], including for per-IP limits and
+    /// logs. Connections without a valid header are dropped.
+    pub proxy_protocol: bool,
+    /// The proxies, e.g. nginx or a load balancer terminating TLS, whose
+    /// `X-Forwarded-For` headers are believed. For connections from one of
+    /// these, the rightmost address in the headers that isn't a trusted proxy
+    /// is used as the connection's [Context::peer_addr], including for
+    /// per-IP limits and logs. The headers of any other connection are
+    /// ignored, as a client can send whatever it likes. Empty by default.
+    pub trusted_proxies: Vec<IpNet>,
+    /// More addresses to listen on besides [Self::address], e.g. `[::]:8080`
+    /// alongside `0.0.0.0:8080` for a dual-stack server, or one per network
+    /// interface. Connections from every address are served the same way.
+    /// Note that on Linux, `[::]` also accepts IPv4 connections by default,
+    /// so binding both it and `0.0.0.0` on the same port fails. Ignored by
+    /// servers on a Unix socket.
+    pub additional_addresses: Vec<String>,
+    /// Called whenever a client fails to connect, e.g. because its TLS
+    /// handshake failed, so failures can be counted and alerted on.
+    pub on_accept_error: Option<AcceptErrorHook>,
+}
+
+impl std::fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerConfig")
+            .field("address", &self.address)
+            .field("version", &self.version)
+            .field("tls", &self.tls)
+            .field("client_call_timeout", &self.client_call_timeout)
+            .field("stats_report_interval", &self.stats_report_interval)
+            .field("interceptors", &self.interceptors.len())
+            .field("max_concurrent_calls_per_connection", &self.max_concurrent_calls_per_connection)
+            .field("max_queued_calls_per_connection", &self.max_queued_calls_per_connection)
+            .field("call_limit_mode", &self.call_limit_mode)
+            .field("connection_rate_limit", &self.connection_rate_limit)
+            .field("global_rate_limit", &self.global_rate_limit)
+            .field("idempotency_ttl", &self.idempotency_ttl)
+            .field("idempotency_max_keys", &self.idempotency_max_keys)
+            .field("max_connections", &self.max_connections)
+            .field("max_connections_per_ip", &self.max_connections_per_ip)
+            .field("ws_config", &self.ws_config)
+            .field("codec", &self.codec.name())
+            .field("compression", &self.compression)
+            .field("linger", &self.linger)
+            .field("max_response_size", &self.max_response_size)
+            .field("max_call_input_size", &self.max_call_input_size)
+            .field("max_batch_size", &self.max_batch_size)
+            .field("prioritize_state_changes", &self.prioritize_state_changes)
+            .field("state_coalescing", &self.state_coalescing)
+            .field("stats_rpc_access", &self.stats_rpc_access.is_some())
+            .field("metrics_hook", &self.metrics_hook.is_some())
+            .field("token_validator", &self.token_validator.is_some())
+            .field("unix_socket", &self.unix_socket)
+            .field("unix_socket_mode", &self.unix_socket_mode)
+            .field("health_check_path", &self.health_check_path)
+            .field("allowed_origins", &self.allowed_origins)
+            .field("allowed_hosts", &self.allowed_hosts)
+            .field("proxy_protocol", &self.proxy_protocol)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("additional_addresses", &self.additional_addresses)
+            .field("on_accept_error", &self.on_accept_error.is_some())
+            .finish()
+    }
 }
 
 impl ServerConfig {
+    /// Creates a config for a server that listens on a Unix socket at `path`,
+    /// e.g. for a sidecar, skipping TCP and TLS. Clients connect with
+    /// [Client::new_unix](crate::Client::new_unix). A socket file left behind
+    /// by a server that's no longer running is replaced. The socket is only
+    /// accessible to its owner, unless [Self::unix_socket_mode] is changed.
+    /// Every connection's [Context::peer_addr] is `127.0.0.1:0`.
+    pub fn new_unix(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut config = Self::new(&path.display().to_string(), no_certificate_config());
+        config.unix_socket = Some(path);
+        config.unix_socket_mode = Some(0o600);
+        config
+    }
+
     pub fn new_self_signed(host: &str) -> Self {
         Self::new(host, {
             let cert = generate_simple_self_signed(vec![host.into()]).unwrap();
@@ -70,153 +558,1130 @@ impl ServerConfig {
         })
     }
 
+    /// Creates a config using a certificate chain and private key loaded
+    /// from PEM files, e.g. the `fullchain.pem` and `privkey.pem` issued by
+    /// Let's Encrypt.
+    pub fn from_pem_files(
+        host: &str,
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> Result<Self, PemError> {
+        let certificates = load_certificates(cert_path.as_ref())?;
+        let key = load_private_key(key_path.as_ref())?;
+        let tls = TLSServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .map_err(PemError::Tls)?;
+        Ok(Self::new(host, tls))
+    }
+
     pub fn new(host: &str, tls: TLSServerConfig) -> Self {
         Self {
             address: host.into(),
             version: Version::from_str(HL_VERSION).unwrap(),
             tls,
+            client_call_timeout: Duration::from_secs(30),
+            stats_report_interval: None,
+            interceptors: Vec::new(),
+            max_concurrent_calls_per_connection: None,
+            max_queued_calls_per_connection: None,
+            call_limit_mode: CallLimitMode::Queue,
+            connection_rate_limit: None,
+            global_rate_limit: None,
+            idempotency_ttl: Duration::from_secs(5 * 60),
+            idempotency_max_keys: 1024,
+            max_connections: None,
+            max_connections_per_ip: None,
+            ws_config: WebSocketConfig::default(),
+            codec: Arc::new(RkyvCodec),
+            compression: None,
+            linger: None,
+            max_response_size: Some(64 << 20),
+            max_call_input_size: None,
+            max_batch_size: Some(64 * 1024),
+            prioritize_state_changes: false,
+            state_coalescing: None,
+            stats_rpc_access: None,
+            metrics_hook: None,
+            token_validator: None,
+            unix_socket: None,
+            unix_socket_mode: None,
+            health_check_path: None,
+            allowed_origins: None,
+            allowed_hosts: None,
+            proxy_protocol: false,
+            trusted_proxies: Vec::new(),
+            additional_addresses: Vec::new(),
+            on_accept_error: None,
         }
     }
+
+    /// Limits how quickly clients can make RPC calls, both on each connection
+    /// and across the whole server.
+    pub fn rate_limit(mut self, per_connection: Rate, global: Option<Rate>) -> Self {
+        self.connection_rate_limit = Some(per_connection);
+        self.global_rate_limit = global;
+        self
+    }
+
+    /// Compresses messages with the permessage-deflate WebSocket extension,
+    /// for clients that offer it.
+    pub fn compression(mut self, compression: CompressionConfig) -> Self {
+        self.compression = Some(compression);
+        self
+    }
 }
 
 pub const HL_VERSION: &str = version!();
 
+/// The version of the wire protocol, which clients offer as `hl/5` in
+/// `Sec-WebSocket-Protocol`. It's separate from [HL_VERSION], and bumped
+/// whenever messages change in a way older peers would misread, so that
+/// they turn each other away in the handshake instead.
+///
+/// - 0: the crate's 0.1 releases.
+/// - 1: the server can call the client, with [ServerMessage::RPCRequest] and
+///   [ClientMessage::RPCResponse](crate::ClientMessage::RPCResponse).
+/// - 2: messages that are ready together can be sent as one
+///   [ServerMessage::Batch].
+/// - 3: several calls can be sent as one
+///   [ClientMessage::RPCBatch](crate::ClientMessage::RPCBatch).
+/// - 4: a call can say how long the client waits for it, with
+///   [ClientMessage::TimedRPCRequest](crate::ClientMessage::TimedRPCRequest).
+/// - 5: state fields are identified by a numeric [FieldId] in
+///   [ServerMessage::StateChange], rather than by name.
+pub const PROTOCOL_VERSION: u64 = 5;
+
+/// The header each side sends its full HardLight version in, e.g. `0.2.0`.
+/// It's only for diagnostics: compatibility is decided by the
+/// [PROTOCOL_VERSION] in the `Sec-WebSocket-Protocol` header.
+pub(crate) const FULL_VERSION_HEADER: &str = "hl-version";
+
+/// The header a server that turns a client away for speaking the wrong
+/// protocol version lists the ones it speaks in, e.g. `hl/5, hl/4`.
+pub(crate) const SUPPORTED_VERSIONS_HEADER: &str = "hl-protocols";
+
+/// The header a server that turns a client away for using the wrong
+/// [Codec] names its own in, e.g. `json`.
+pub(crate) const CODEC_HEADER: &str = "hl-codec";
+
+/// How long a new connection has to send its PROXY protocol header, if
+/// [ServerConfig::proxy_protocol] is set.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a closing connection waits for queued messages to be written.
+const WRITER_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a closing connection gives its handlers to notice they've been
+/// cancelled and return before they're aborted.
+const HANDLER_CANCEL_GRACE: Duration = Duration::from_secs(1);
+
+/// The most events a connection can have being handled at once, as calls are
+/// held to the 256 ids they can use. Events over this are dropped.
+const MAX_EVENTS_IN_FLIGHT: usize = 256;
+
+/// Creates a handler for each connection to one of a [MultiServer]'s
+/// services.
+type ServiceFactory = Arc<dyn Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync> + Send + Sync>;
+
+/// A server that hosts several services on one port, each on its own path.
+/// See [Server::new_multi].
+pub type MultiServer = Server<fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>>;
+
 /// The HardLight server, using tokio & tungstenite.
 pub struct Server<T>
 where
-    T: Fn(StateUpdateChannel) -> Box<dyn Handler + Send + Sync>,
-    T: Send + Sync + 'static + Copy,
+    T: Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>,
+    T: Send + Sync + 'static,
 {
     /// The server's configuration.
     pub config: ServerConfig,
     /// A closure that creates a new handler for each connection.
     /// The closure is passed a [StateUpdateChannel] that the handler can use to
-    /// send state updates to the runtime.
-    pub factory: T,
-    pub hl_version_string: HeaderValue,
+    /// send state updates to the runtime, and the connection's [Context].
+    /// It can capture whatever every handler needs, e.g. a database pool,
+    /// as it's shared between connections rather than copied.
+    pub factory: Arc<T>,
+    /// The HardLight protocol versions the server speaks. Each client is
+    /// served with the highest one it speaks too. Defaults to
+    /// [PROTOCOL_VERSION].
+    pub protocol_versions: Vec<u64>,
+    handle: ServerHandle,
+    /// Shared by every connection's [RateLimits].
+    global_rate_limit: Option<Arc<Mutex<TokenBucket>>>,
+    /// A listener handed over by [Server::from_listener], used instead of
+    /// binding to the configured address.
+    listener: Mutex<Option<TcpListener>>,
+    /// The addresses the server is listening on, once it's bound.
+    local_addrs: Mutex<Vec<SocketAddr>>,
+    /// The factory for each path, if the server was made with
+    /// [Server::new_multi]. Otherwise [Self::factory] serves every path.
+    services: Option<Arc<HashMap<String, ServiceFactory>>>,
 }
 
 impl<T> Server<T>
 where
-    T: Fn(StateUpdateChannel) -> Box<dyn Handler + Send + Sync>,
-    T: Send + Sync + 'static + Copy,
+    T: Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>,
+    T: Send + Sync + 'static,
 {
     pub fn new(config: ServerConfig, factory: T) -> Self {
+        let global_rate_limit = config
+            .global_rate_limit
+            .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate))));
+        let handle = ServerHandle::new(config.tls.clone());
         Self {
-            hl_version_string: format!("hl/{}", config.version.major).parse().unwrap(),
+            protocol_versions: vec![PROTOCOL_VERSION],
             config,
-            factory,
+            factory: Arc::new(factory),
+            handle,
+            global_rate_limit,
+            listener: Mutex::new(None),
+            local_addrs: Mutex::new(Vec::new()),
+            services: None,
+        }
+    }
+
+    /// Creates a server that accepts connections from an already bound
+    /// listener, e.g. a socket handed over by systemd, rather than binding to
+    /// [ServerConfig::address].
+    pub fn from_listener(config: ServerConfig, factory: T, listener: TcpListener) -> Self {
+        let server = Self::new(config, factory);
+        if let Ok(local_addr) = listener.local_addr() {
+            *server.local_addrs.lock().unwrap() = vec![local_addr];
         }
+        *server.listener.lock().unwrap() = Some(listener);
+        server
+    }
+
+    /// The address the server is listening on, once it's bound. This is how
+    /// to find the port when binding to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.lock().unwrap().first().copied()
+    }
+
+    /// Every address the server is listening on, once it's bound: the
+    /// first is [ServerConfig::address], followed by
+    /// [ServerConfig::additional_addresses].
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.local_addrs.lock().unwrap().clone()
+    }
+
+    /// Returns a handle to the server that can be used to publish events.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Returns a handle for sending events to every connected client.
+    pub fn broadcaster(&self) -> Broadcaster {
+        self.handle.broadcaster()
+    }
+
+    /// Replaces the server's TLS config without dropping any connections.
+    /// See [ServerHandle::reload_tls].
+    pub fn reload_tls(&self, tls: TLSServerConfig) {
+        self.handle.reload_tls(tls)
+    }
+
+    pub async fn run(&self) -> Result<(), ServerError> {
+        self.serve(None).await
     }
 
-    pub async fn run(&self) -> io::Result<()> {
+    /// Like [Server::run], but sends the address the server is listening on
+    /// to `ready_tx` as soon as it's bound, so clients can wait for it rather
+    /// than racing it. This is also how to find the port when binding to
+    /// port 0. If there are [ServerConfig::additional_addresses], the rest
+    /// are in [Server::local_addrs] by the time this is sent.
+    pub async fn run_with_ready(&self, ready_tx: oneshot::Sender<SocketAddr>) -> Result<(), ServerError> {
+        self.serve(Some(ready_tx)).await
+    }
+
+    async fn serve(&self, ready_tx: Option<oneshot::Sender<SocketAddr>>) -> Result<(), ServerError> {
         info!("Booting HL server v{}...", HL_VERSION);
-        let acceptor = TlsAcceptor::from(Arc::new(self.config.tls.clone()));
-        let listener = TcpListener::bind(&self.config.address).await?;
-        info!("Listening on {} with TLS", self.config.address);
+        if let Some(path) = &self.config.unix_socket {
+            return self.serve_unix(path, ready_tx).await;
+        }
+        let listener = self.listener.lock().unwrap().take();
+        let listener = match listener {
+            Some(listener) => listener,
+            None => TcpListener::bind(&self.config.address)
+                .await
+                .map_err(ServerError::Bind)?,
+        };
+        let mut listeners = vec![listener];
+        for address in &self.config.additional_addresses {
+            listeners.push(TcpListener::bind(address).await.map_err(ServerError::Bind)?);
+        }
+        let local_addrs = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(ServerError::Bind)?;
+        for local_addr in &local_addrs {
+            info!("Listening on {} with TLS", local_addr);
+        }
+        *self.local_addrs.lock().unwrap() = local_addrs.clone();
+        if let Some(ready_tx) = ready_tx {
+            // nobody waiting for it is fine
+            let _ = ready_tx.send(local_addrs[0]);
+        }
 
         loop {
-            let (stream, peer_addr) = listener.accept().await?;
-            let span = span!(Level::DEBUG, "connection", peer_addr = %peer_addr);
-            let _enter = span.enter();
-            let acceptor = acceptor.clone();
-
-            if let Ok(stream) = acceptor.accept(stream).await {
-                debug!("Successfully terminated TLS handshake");
-                self.handle_connection(stream, peer_addr);
+            // accepting is cancel safe, so the listeners that lose the race
+            // don't drop a connection
+            let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+            let (accepted, _, _) = select_all(accepts).await;
+            let (mut stream, peer_addr) = accepted.map_err(ServerError::Accept)?;
+            // messages are batched when it helps, so Nagle's algorithm would
+            // only hold them back waiting for ACKs
+            if let Err(e) = stream.set_nodelay(true) {
+                warn!("Failed to set TCP_NODELAY: {}", e);
+            }
+            if let Some(linger) = self.config.linger {
+                if let Err(e) = set_linger(&stream, linger) {
+                    warn!("Failed to set SO_LINGER: {}", e);
+                }
             }
+            let proxy_protocol = self.config.proxy_protocol;
+            // pick up the latest config, in case it's been reloaded
+            let acceptor = TlsAcceptor::from(self.handle.tls.load_full());
+            let on_accept_error = self.config.on_accept_error.clone();
+            // the PROXY header and the TLS handshake are read in the
+            // connection's own task, so a slow client doesn't hold up the rest
+            self.spawn_connection(async move {
+                let peer_addr = proxied_peer_addr(&mut stream, peer_addr, proxy_protocol).await?;
+                // failures have already been reported to on_accept_error
+                let (stream, tls) = terminate_tls(&acceptor, stream, peer_addr, on_accept_error.as_ref()).await.ok()?;
+                Some((stream, peer_addr, tls))
+            });
+        }
+    }
+
+    /// Accepts connections on a Unix socket, without TLS. `ready_tx` is sent
+    /// `127.0.0.1:0`, as there's no address.
+    #[cfg(unix)]
+    async fn serve_unix(&self, path: &Path, ready_tx: Option<oneshot::Sender<SocketAddr>>) -> Result<(), ServerError> {
+        let listener = bind_unix(path, self.config.unix_socket_mode).map_err(ServerError::Bind)?;
+        info!("Listening on {} without TLS", path.display());
+        let peer_addr = SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0));
+        if let Some(ready_tx) = ready_tx {
+            // nobody waiting for it is fine
+            let _ = ready_tx.send(peer_addr);
         }
+
+        loop {
+            let (mut stream, _) = listener.accept().await.map_err(ServerError::Accept)?;
+            let proxy_protocol = self.config.proxy_protocol;
+            self.spawn_connection(async move {
+                let peer_addr = proxied_peer_addr(&mut stream, peer_addr, proxy_protocol).await?;
+                Some((stream, peer_addr, None))
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    async fn serve_unix(&self, _path: &Path, _ready_tx: Option<oneshot::Sender<SocketAddr>>) -> Result<(), ServerError> {
+        Err(ServerError::Bind(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Unix sockets aren't supported on this platform",
+        )))
+    }
+
+    /// Like [Server::run], but accepts connections from `listener` instead of
+    /// binding to [ServerConfig::address], e.g. a socket handed over by
+    /// systemd. See also [Server::from_listener].
+    pub async fn run_with_listener(&self, listener: TcpListener) -> Result<(), ServerError> {
+        *self.listener.lock().unwrap() = Some(listener);
+        self.serve(None).await
+    }
+
+    /// Serves a single connection that was accepted elsewhere, e.g. from a
+    /// Unix domain socket or a tunnel. `peer_addr` is what [Context::peer_addr]
+    /// reports, so pass a placeholder such as `127.0.0.1:0` for transports
+    /// without one. The server doesn't need to be running.
+    ///
+    /// This waits for the TLS handshake, then returns while the WebSocket
+    /// handshake and the connection run in the background. If the TLS
+    /// handshake fails, the error is also passed to
+    /// [ServerConfig::on_accept_error].
+    pub async fn serve_stream<S>(&self, stream: S, peer_addr: SocketAddr) -> Result<(), ServerError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // pick up the latest config, in case it's been reloaded
+        let acceptor = TlsAcceptor::from(self.handle.tls.load_full());
+        let (stream, tls) = terminate_tls(&acceptor, stream, peer_addr, self.config.on_accept_error.as_ref()).await?;
+        self.handle_connection(stream, peer_addr, tls);
+        Ok(())
+    }
+
+    /// Serves a connection whose transport is already established, e.g. after
+    /// TLS has been terminated, or one end of an in-memory pipe. `tls` is
+    /// `None` if the connection doesn't use TLS.
+    pub(crate) fn handle_connection<S>(&self, stream: S, peer_addr: SocketAddr, tls: Option<TlsInfo>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.spawn_connection(async move { Some((stream, peer_addr, tls)) });
     }
 
-    fn handle_connection(&self, stream: TlsStream<TcpStream>, peer_addr: SocketAddr) {
-        let (state_change_tx, mut state_change_rx) = mpsc::channel(10);
-        let handler = (self.factory)(state_change_tx);
-        let version: HeaderValue = self.hl_version_string.clone();
+    /// Serves a connection in a task of its own, once `transport` has
+    /// established it, e.g. by reading the PROXY header and terminating TLS.
+    /// The connection is dropped if `transport` returns `None`.
+    fn spawn_connection<S, F>(&self, transport: F)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        F: Future<Output = Option<(S, SocketAddr, Option<TlsInfo>)>> + Send + 'static,
+    {
+        let handle = self.handle.clone();
+        let max_connections = self.config.max_connections;
+        let max_connections_per_ip = self.config.max_connections_per_ip;
+        let trusted_proxies = self.config.trusted_proxies.clone();
+        let (state_change_tx, own_state_change_rx) = mpsc::channel(STATE_UPDATE_CAPACITY);
+        let (shared_state_change_tx, shared_state_change_rx) = mpsc::unbounded_channel();
+        let mut state_change_rx = StateChangeReceiver {
+            own: own_state_change_rx,
+            shared: shared_state_change_rx,
+        };
+        let connection_id = self.handle.next_connection_id();
+        let (client_call_tx, mut client_call_rx) = mpsc::channel(10);
+        // events broadcast to rooms this connection is in
+        let (room_tx, mut room_rx) = mpsc::channel(64);
+        let rooms = Subscriptions::new(self.handle.rooms.clone(), connection_id, room_tx);
+        let protocol_versions = self.protocol_versions.clone();
+        let codec = self.config.codec.clone();
+        let hl_version = protocol_versions.iter().max().map(|&major| protocol_name(major, codec.name())).unwrap_or_default();
+        let client_call_timeout = self.config.client_call_timeout;
+        let idempotency = IdempotencyStore::new(self.config.idempotency_ttl, self.config.idempotency_max_keys);
+        let factory = self.factory.clone();
+        let services = self.services.clone();
+        let health_check_path = self.config.health_check_path.clone();
+        let full_version: HeaderValue = self.config.version.to_string().parse().unwrap();
+        let topics = self.handle.topics.clone();
+        let mut broadcasts = self.handle.broadcaster.subscribe();
+        let mut rate_limits = RateLimits::new(self.config.connection_rate_limit, self.global_rate_limit.clone());
+        let stats_report_interval = self.config.stats_report_interval;
+        let ws_config = self.config.ws_config;
+        let compression = self.config.compression;
+        let max_response_size = self.config.max_response_size;
+        let max_call_input_size = self.config.max_call_input_size;
+        let max_batch_size = self.config.max_batch_size;
+        let prioritize_state_changes = self.config.prioritize_state_changes;
+        let state_coalescing = self.config.state_coalescing;
+        let method_stats = self.handle.method_stats.clone();
+        let stats_rpc_access = self.config.stats_rpc_access.clone();
+        let on_accept_error = self.config.on_accept_error.clone();
+        let metrics_hook = self.config.metrics_hook.clone();
+        let token_validator = self.config.token_validator.clone();
+        let allowed_origins = self.config.allowed_origins.clone();
+        let allowed_hosts = self.config.allowed_hosts.clone();
+        let interceptors: Arc<[Arc<dyn Interceptor>]> = self.config.interceptors.clone().into();
+        let call_limit = self.config.max_concurrent_calls_per_connection.map(|max_running| {
+            Arc::new(CallLimit::new(max_running, self.config.max_queued_calls_per_connection, self.config.call_limit_mode))
+        });
         tokio::spawn(async move {
-            let span = span!(Level::DEBUG, "connection", peer_addr = %peer_addr);
+            let Some((stream, peer_addr, tls)) = transport.await else {
+                return;
+            };
+            // answer plain HTTP requests, e.g. health checks, before making a
+            // handler for them
+            let Some((stream, request)) = screen(stream, health_check_path.as_deref()).await else {
+                debug!("Answered a request from {} that wasn't a WebSocket upgrade", peer_addr);
+                return;
+            };
+            // the client's own address, if it came through proxies we trust
+            let peer_addr = client_addr(peer_addr, &request.forwarded_for, &trusted_proxies);
+
+            let span = span!(Level::DEBUG, "connection", peer_addr = %peer_addr, id = connection_id);
             let _enter = span.enter();
+            // counts the connection until this task ends, however it ends
+            let _slot = match handle.connections.try_open(peer_addr.ip(), max_connections, max_connections_per_ip) {
+                Ok(slot) => slot,
+                Err(rejection) => {
+                    reject_connection(stream, peer_addr, rejection);
+                    return;
+                }
+            };
+            // cancels the connection's token when dropped, as the connection closes
+            let (connection_open, connection_open_rx) = watch::channel(());
+            let ctx = Arc::new(Context::new(
+                connection_id,
+                peer_addr,
+                hl_version,
+                tls,
+                handle,
+                client_call_tx,
+                client_call_timeout,
+                rooms,
+                idempotency,
+                shared_state_change_tx,
+                CancellationToken::new(connection_open_rx),
+            ));
 
-            let callback = |req: &Request, mut response: Response| {
-                // request is only valid if req.headers().get("Sec-WebSocket-Protocol") is
-                // Some(req_version) AND req_version == version
-                let req_version = req.headers().get("Sec-WebSocket-Protocol");
-                if req_version.is_none() || req_version.unwrap() != &version {
-                    *response.status_mut() = StatusCode::BAD_REQUEST;
-                    warn!("Invalid request from {}, version mismatch (client gave {:?}, server wanted {:?})", peer_addr, req_version, Some(version));
-                    return Ok(response);
+            let service = match &services {
+                Some(services) => match services.get(&request.path) {
+                    Some(service) => Some(service.clone()),
+                    None => {
+                        reject_connection(stream, peer_addr, ConnectionRejection::UnknownService);
+                        return;
+                    }
+                },
+                None => None,
+            };
+            // made once the handshake has been validated
+            let mut handler = None;
+
+            // the state fields the client wants to fetch rather than be sent
+            let mut lazy_fields = LazyFields::default();
+
+            let callback = BoxedCallback(|req: &Request, mut response: Response| {
+                // browsers send an origin, so a page on another site can't
+                // connect on its visitors' behalf
+                let origin = req.headers().get(ORIGIN).map(|v| v.to_str().unwrap_or_default());
+                let host = req.headers().get(HOST).and_then(|v| v.to_str().ok()).unwrap_or_default();
+                let forbidden = if allowed_origins
+                    .as_ref()
+                    .zip(origin)
+                    .is_some_and(|(allowed, origin)| !origin_allowed(origin, allowed))
+                {
+                    warn!("Rejecting connection from {}: origin {:?} isn't allowed", peer_addr, origin.unwrap_or_default());
+                    Some("origin not allowed")
+                } else if allowed_hosts.as_ref().is_some_and(|allowed| !host_allowed(host, allowed)) {
+                    warn!("Rejecting connection from {}: host {:?} isn't allowed", peer_addr, host);
+                    Some("host not allowed")
                 } else {
-                    let headers = response.headers_mut();
-                    headers.append("Sec-WebSocket-Protocol", version);
-                    debug!(
-                        "Received valid handshake, upgrading connection to HardLight ({})",
-                        req_version.unwrap().to_str().unwrap()
-                    );
-                    Ok(response)
+                    None
+                };
+                if let Some(reason) = forbidden {
+                    let mut response = ErrorResponse::new(Some(reason.to_string()));
+                    *response.status_mut() = StatusCode::FORBIDDEN;
+                    return Err(Box::new(response));
                 }
-            };
 
-            let mut ws_stream = match accept_hdr_async(stream, callback).await {
+                // the client lists the versions it speaks, and we pick the
+                // highest one we speak too
+                let offers = protocol::parse_offers(
+                    req.headers().get_all("Sec-WebSocket-Protocol").iter().filter_map(|v| v.to_str().ok()),
+                );
+                let offered: Vec<u64> = offers.iter().map(|offer| offer.major).collect();
+                // older clients don't send their full version
+                let client_version = req.headers().get(FULL_VERSION_HEADER).and_then(|v| v.to_str().ok());
+                response.headers_mut().append(FULL_VERSION_HEADER, full_version.clone());
+                let Some(negotiated) = protocol::negotiate(&offered, &protocol_versions) else {
+                    let ours = protocol::protocol_list(&protocol_versions, codec.name());
+                    let theirs = match offered.is_empty() {
+                        true => "nothing".to_string(),
+                        false => offers.iter().map(|offer| offer.name).collect::<Vec<_>>().join(", "),
+                    };
+                    warn!("Invalid request from {}, version mismatch (client gave {}, server speaks {}, client version {:?})", peer_addr, theirs, ours, client_version);
+                    // say which versions we speak, so the client can report more
+                    // than a bad request
+                    let reason = format!("version mismatch: server speaks {ours}, client asked for {theirs}");
+                    let mut response = ErrorResponse::new(Some(reason));
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
+                    if let Ok(ours) = HeaderValue::from_str(&ours) {
+                        response.headers_mut().insert(SUPPORTED_VERSIONS_HEADER, ours);
+                    }
+                    response.headers_mut().insert(FULL_VERSION_HEADER, full_version);
+                    return Err(Box::new(response));
+                };
+                // each version comes with the codec the client encodes
+                // messages with, which has to be ours
+                let Some(offer) = offers.iter().find(|offer| offer.major == negotiated && offer.codec == codec.name()) else {
+                    let theirs = offers
+                        .iter()
+                        .filter(|offer| offer.major == negotiated)
+                        .map(|offer| offer.codec)
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    warn!("Rejecting connection from {}: codec mismatch (client uses {}, server uses {})", peer_addr, theirs, codec.name());
+                    let reason = format!("codec mismatch: server uses {}, client uses {theirs}", codec.name());
+                    let mut response = ErrorResponse::new(Some(reason));
+                    *response.status_mut() = StatusCode::BAD_REQUEST;
+                    if let Ok(ours) = HeaderValue::from_str(codec.name()) {
+                        response.headers_mut().insert(CODEC_HEADER, ours);
+                    }
+                    return Err(Box::new(response));
+                };
+                let version = protocol_name(negotiated, codec.name());
+                ctx.set_version(version.clone());
+                let query = req.uri().query().map(query::decode).unwrap_or_default();
+                ctx.set_request(req.headers().clone(), query);
+                if let Some(validator) = &token_validator {
+                    let token = req
+                        .headers()
+                        .get(AUTHORIZATION)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| v.strip_prefix("Bearer "));
+                    if !token.is_some_and(|token| validator.validate(token, &ctx)) {
+                        warn!("Rejecting connection from {}: missing or invalid token", peer_addr);
+                        let mut response = ErrorResponse::new(Some("invalid token".to_string()));
+                        *response.status_mut() = StatusCode::UNAUTHORIZED;
+                        response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+                        return Err(Box::new(response));
+                    }
+                }
+                let headers = response.headers_mut();
+                // answered as the client wrote it, as browsers insist on that
+                headers.append("Sec-WebSocket-Protocol", HeaderValue::from_str(offer.name).unwrap());
+                if let Some(extension) = compression.and_then(|compression| deflate::accept(req.headers(), &compression)) {
+                    headers.append(deflate::EXTENSIONS_HEADER, extension);
+                }
+                debug!(
+                    "Received valid handshake, upgrading connection to HardLight ({}, client version {:?})",
+                    version,
+                    client_version
+                );
+                if let Some(client_version) = client_version {
+                    ctx.set_client_version(client_version.to_string());
+                }
+                lazy_fields = LazyFields::from_header(req.headers().get(LAZY_FIELDS_HEADER));
+
+                // only clients that made it this far get a handler, and the
+                // factory sees what the token validator attached to the context.
+                // A panicking factory shouldn't take the accept loop down with it
+                // states it creates take part in the connection's transactions
+                let made = catch_unwind(AssertUnwindSafe(|| {
+                    transaction::sync_scope(ctx.transactions().clone(), || match service {
+                        Some(service) => service(state_change_tx, &ctx),
+                        None => factory(state_change_tx, &ctx),
+                    })
+                }));
+                match made {
+                    Ok(made) => handler = Some(made),
+                    Err(_) => {
+                        warn!("Handler factory panicked for connection from {}", peer_addr);
+                        let mut response = ErrorResponse::new(Some("internal error".to_string()));
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        return Err(Box::new(response));
+                    }
+                }
+                Ok(response)
+            });
+
+            let stream = Deflate::new(stream, Side::Server, compression, &ws_config);
+            let ws_stream = match accept_hdr_async_with_config(stream, callback, Some(ws_config)).await {
                 Ok(ws_stream) => ws_stream,
-                Err(e) => {
-                    warn!("Error accepting connection from {}: {}", peer_addr, e);
+                Err(error) => {
+                    warn!("Error accepting connection from {}: {}", peer_addr, error);
+                    if let Some(on_accept_error) = on_accept_error {
+                        on_accept_error(&ServerError::Handshake { peer_addr, error });
+                    }
                     return;
                 }
             };
 
+            // the handshake only succeeds once the callback has made it
+            let handler = handler.expect("handshake succeeded without a handler");
+
             debug!("Connection fully established");
+            if let Some(hook) = &metrics_hook {
+                hook.on_connection_open(connection_id);
+            }
 
             // keep track of active RPC calls
             let mut in_flight = [false; u8::MAX as usize + 1];
 
             let (rpc_tx, mut rpc_rx) = mpsc::channel(u8::MAX as usize + 1);
 
+            // the tasks running unary calls' handlers, by call id, so they
+            // can be stopped when the connection closes
+            let mut handler_tasks: [Option<JoinHandle<_>>; 256] = std::array::from_fn(|_| None);
+
+            // keep track of calls we've made to the client. these have their own
+            // id space, so they can't collide with the client's calls
+            let mut client_calls: [Option<oneshot::Sender<HandlerResult<Vec<u8>>>>; 256] =
+                std::array::from_fn(|_| None);
+
             let handler = Arc::new(handler);
 
+            // streaming calls, which also take up an id in in_flight
+            let (stream_tx, mut stream_rx) = mpsc::channel(u8::MAX as usize + 1);
+            // calls whose tasks ended without answering, so their ids can be freed
+            let (abandoned_tx, mut abandoned_rx) = mpsc::unbounded_channel();
+            let mut streams: HashMap<u8, ActiveStream> = HashMap::new();
+            let mut next_stream_key = 0u64;
+            let events_in_flight = Arc::new(Semaphore::new(MAX_EVENTS_IN_FLIGHT));
+            // the tasks handling events, so they can be stopped when the
+            // connection closes
+            let mut event_tasks = JoinSet::new();
+
+            // events published to topics this connection is subscribed to
+            let (event_tx, mut event_rx) = mpsc::channel(64);
+            let mut subscriptions = Subscriptions::new(topics, connection_id, event_tx);
+
+            // state changes held back while a transaction is open
+            let mut transaction: Option<Vec<(FieldId, Vec<u8>)>> = None;
+            // state changes held back to be sent together, if coalescing is on
+            let mut coalesced = CoalescedChanges::default();
+
+            let mut stats_report = stats_report_interval.map(tokio::time::interval);
+
+            // As on the client, the socket is split in two. This task reads
+            // from the client and handles everything else, and a writer task
+            // owns the write half. Responses, stream items, state changes and
+            // events are queued for the writer in the order they're ready, so
+            // a slow client never holds up reading its calls or running their
+            // handlers, and no kind of message can starve another. A client
+            // that doesn't read what it's sent stops being read from once
+            // its queue is full, so its calls can't pile up responses.
+            let written = ws_stream.get_ref().written();
+            let (sink, mut source) = ws_stream.split();
+            let (outbox, outbox_rx) = outbox();
+            // state changes can skip the queue, if that's been asked for
+            let (state_outbox, state_outbox_rx) = if prioritize_state_changes {
+                outbox.sibling()
+            } else {
+                // they share the normal queue, and the priority one stays empty
+                let (_, empty) = outbox.sibling();
+                (outbox.clone(), empty)
+            };
+            let outbox_rx = OutboxReceiver {
+                priority: state_outbox_rx,
+                normal: outbox_rx,
+            };
+            let mut writer = tokio::spawn(write_messages(sink, outbox_rx, codec.clone(), max_batch_size, metrics_hook.clone(), written));
+
             debug!("Starting RPC handler loop");
             loop {
                 select! {
+                    // wait for the writer to catch up
+                    _ = outbox.drained(), if outbox.is_full() => {}
                     // await new messages from the client
-                    Some(msg) = ws_stream.next() => {
+                    msg = source.next(), if !outbox.is_full() => {
                         let msg = match msg {
-                            Ok(msg) => msg,
-                            Err(e) => {
+                            Some(Ok(msg)) => msg,
+                            None => {
+                                debug!("Client disconnected");
+                                break;
+                            }
+                            Some(Err(Error::Capacity(e))) => {
+                                warn!("Client sent a message that's too big: {}. Disconnecting.", e);
+                                let close = CloseFrame {
+                                    code: CloseCode::Size,
+                                    reason: "message too big".into(),
+                                };
+                                let _ = outbox.send(Message::Close(Some(close)));
+                                break;
+                            }
+                            Some(Err(e)) => {
                                 warn!("Error receiving message from client: {}", e);
                                 continue;
                             }
                         };
-                        if msg.is_binary() {
-                            let binary = msg.into_data();
-                            let msg: ClientMessage = rkyv::from_bytes(&binary).unwrap();
+                        if let Message::Close(frame) = &msg {
+                            match frame {
+                                Some(frame) => debug!("Client closed the connection with {}: {}", frame.code, frame.reason),
+                                None => debug!("Client closed the connection"),
+                            }
+                            break;
+                        }
+                        if let Message::Text(text) = &msg {
+                            // unless it's the codec's, most likely a client
+                            // speaking another protocol, which would otherwise
+                            // hang waiting
+                            if !codec.text() {
+                                warn!("Client sent a text message ({} bytes). Disconnecting.", text.len());
+                                let close = CloseFrame {
+                                    code: CloseCode::Unsupported,
+                                    reason: "text messages aren't supported".into(),
+                                };
+                                let _ = outbox.send(Message::Close(Some(close)));
+                                break;
+                            }
+                        }
+                        if msg.is_binary() || msg.is_text() {
+                            let bytes = msg.into_data();
+                            ctx.stats().record_bytes_in(bytes.len());
+                            if let Some(hook) = &metrics_hook {
+                                hook.on_bytes_in(bytes.len());
+                            }
+                            let frame = match client_message_to_rkyv(&*codec, bytes) {
+                                Ok(bytes) => Frame::new(bytes),
+                                Err(e) => {
+                                    warn!("Received a message from the client that doesn't decode. Ignoring. Error: {e}");
+                                    continue;
+                                }
+                            };
+                            // read in place, so payloads are handed to the handler
+                            // without being copied out of the frame
+                            let msg = match rkyv::check_archived_root::<ClientMessage>(&frame) {
+                                Ok(msg) => msg,
+                                Err(e) => {
+                                    // e.g. a newer client using a message we don't know about
+                                    warn!("Received invalid message from client. Ignoring. Error: {e}");
+                                    continue;
+                                }
+                            };
 
                             match msg {
-                                ClientMessage::RPCRequest { id, internal } => {
+                                ArchivedClientMessage::RPCRequest { .. }
+                                | ArchivedClientMessage::TracedRPCRequest { .. }
+                                | ArchivedClientMessage::TimedRPCRequest { .. }
+                                | ArchivedClientMessage::RPCBatch(_) => {
+                                    // a batch's calls are started in order, as if
+                                    // they'd been sent one at a time
+                                    for (id, internal, traceparent, timeout) in unary_calls(msg) {
+                                        let internal = frame.payload(internal);
+                                        let span = span!(
+                                            Level::DEBUG,
+                                            "rpc",
+                                            id = id,
+                                            traceparent = field::Empty,
+                                            queue_wait = field::Empty,
+                                            execution = field::Empty
+                                        );
+                                        if let Some(traceparent) = traceparent {
+                                            span.record("traceparent", traceparent);
+                                        }
+                                        let _enter = span.enter();
+
+                                        if let Some(error) = reject_call(&in_flight, id, internal.len(), max_call_input_size, &mut rate_limits, call_limit.as_deref()) {
+                                            let output = Err(error);
+                                            let binary = to_message_bytes(&ServerMessage::RPCResponse { id, output });
+                                            ctx.stats().record_bytes_out(binary.len());
+                                            if let Err(e) = outbox.send(Message::Binary(binary)) {
+                                                warn!("Error sending response to client: {}", e);
+                                            }
+                                            continue;
+                                        }
+
+                                        debug!("Received call from client. Spawning handler task...");
+
+                                        let received_at = Instant::now();
+                                        let deadline = timeout.map(|timeout| received_at + timeout);
+                                        let cancellation = ctx.cancellation().clone();
+                                        let tx = rpc_tx.clone();
+                                        let slot = CallSlot::new(id, None, abandoned_tx.clone());
+                                        let handler = handler.clone();
+                                        let ctx = ctx.clone();
+                                        let interceptors = interceptors.clone();
+                                        let call_limit = call_limit.clone();
+                                        let method_stats = method_stats.clone();
+                                        let metrics_hook = metrics_hook.clone();
+                                        let call_span = span.clone();
+                                        in_flight[id as usize] = true;
+                                        ctx.stats().record_call();
+                                        let queued = call_limit.as_ref().map(|limit| limit.enqueue());
+                                        handler_tasks[id as usize] = Some(tokio::spawn(transaction::scope(ctx.transactions().clone(), async move {
+                                            let _permit = match queued {
+                                                Some(queued) => Some(queued.start().await),
+                                                None => None,
+                                            };
+                                            // time between reading the frame and the handler
+                                            // actually starting, i.e. how long we were queued
+                                            let queue_wait = received_at.elapsed();
+                                            call_span.record("queue_wait", field::debug(queue_wait));
+                                            let method = handler.method_name(&internal);
+                                            if let Some(hook) = &metrics_hook {
+                                                hook.on_call_queued(method, queue_wait);
+                                                hook.on_call_start(method, ctx.connection_id());
+                                            }
+                                            let started_at = Instant::now();
+                                            let call = CallContext::new(&ctx, id, deadline, cancellation);
+                                            let output = match authorize(&**handler, &ctx, method) {
+                                                Ok(()) => {
+                                                    let run = AssertUnwindSafe(Next::new(&**handler, &interceptors, &call).run(&ctx, &internal))
+                                                        .catch_unwind();
+                                                    match deadline {
+                                                        // the client has given up by then, so there's no point going on
+                                                        Some(deadline) => match tokio::time::timeout_at(deadline.into(), run).await {
+                                                            Ok(output) => output.unwrap_or_else(|_| handler_panicked()),
+                                                            Err(_) => {
+                                                                debug!(id, "Call passed its deadline. Stopped its handler.");
+                                                                Err(RpcHandlerError::Timeout)
+                                                            }
+                                                        },
+                                                        None => run.await.unwrap_or_else(|_| handler_panicked()),
+                                                    }
+                                                }
+                                                Err(e) => Err(e),
+                                            };
+                                            let execution = started_at.elapsed();
+                                            call_span.record("execution", field::debug(execution));
+                                            debug!(id, ?queue_wait, ?execution, "Handler finished.");
+                                            method_stats.record(method, execution, output.is_ok());
+                                            if let Some(hook) = &metrics_hook {
+                                                hook.on_call_end(method, execution, &output);
+                                            }
+                                            match tx.send(ServerMessage::RPCResponse { id, output }).await {
+                                                Ok(()) => slot.answered(),
+                                                Err(_) => debug!(id, "Connection closed before the call finished. Dropping its response."),
+                                            }
+                                        }.instrument(span.clone()))));
+
+                                        debug!("Handler task spawned.");
+                                    }
+                                }
+                                ArchivedClientMessage::RPCStreamRequest { id, internal } => {
+                                    let id = *id;
+                                    let internal = frame.payload(internal);
                                     let span = span!(Level::DEBUG, "rpc", id = id);
                                     let _enter = span.enter();
 
-                                    if in_flight[id as usize] {
-                                        warn!("RPC call already in flight. Ignoring.");
+                                    if let Some(error) = reject_call(&in_flight, id, internal.len(), max_call_input_size, &mut rate_limits, call_limit.as_deref()) {
+                                        let output = Err(error);
+                                        let binary = to_message_bytes(&ServerMessage::RPCResponse { id, output });
+                                        ctx.stats().record_bytes_out(binary.len());
+                                        if let Err(e) = outbox.send(Message::Binary(binary)) {
+                                            warn!("Error sending response to client: {}", e);
+                                        }
                                         continue;
                                     }
 
-                                    debug!("Received call from client. Spawning handler task...");
+                                    debug!("Received streaming call from client. Spawning handler task...");
 
-                                    let tx = rpc_tx.clone();
+                                    let key = next_stream_key;
+                                    next_stream_key += 1;
+                                    let credits = Arc::new(Semaphore::new(STREAM_WINDOW as usize));
+                                    let sender = StreamSender::new(id, key, stream_tx.clone(), credits.clone());
+                                    let tx = stream_tx.clone();
+                                    let slot = CallSlot::new(id, Some(key), abandoned_tx.clone());
                                     let handler = handler.clone();
+                                    let ctx = ctx.clone();
                                     in_flight[id as usize] = true;
-                                    tokio::spawn(async move {
-                                        tx.send(
-                                            ServerMessage::RPCResponse {
-                                                id,
-                                                output: handler.handle_rpc_call(&internal).await,
+                                    ctx.stats().record_call();
+                                    let queued = call_limit.as_ref().map(|limit| limit.enqueue());
+                                    let task = tokio::spawn(transaction::scope(ctx.transactions().clone(), async move {
+                                        // a stream holds its permit until it ends
+                                        let _permit = match queued {
+                                            Some(queued) => Some(queued.start().await),
+                                            None => None,
+                                        };
+                                        let output = match authorize(&**handler, &ctx, handler.method_name(&internal)) {
+                                            Ok(()) => AssertUnwindSafe(handler.handle_streaming_call(&ctx, &internal, sender))
+                                                .catch_unwind()
+                                                .await
+                                                .unwrap_or_else(|_| handler_panicked()),
+                                            Err(e) => Err(e),
+                                        };
+                                        let msg = match output {
+                                            Ok(()) => ServerMessage::RPCStreamEnd { id },
+                                            Err(e) => ServerMessage::RPCResponse { id, output: Err(e) },
+                                        };
+                                        if tx.send((key, msg)).await.is_ok() {
+                                            slot.answered();
+                                        }
+                                    }));
+                                    streams.insert(id, ActiveStream { key, credits, task });
+                                }
+                                ArchivedClientMessage::StatsRequest { id } => {
+                                    let id = *id;
+                                    let span = span!(Level::DEBUG, "rpc", id = id);
+                                    let _enter = span.enter();
+                                    let output = if in_flight[id as usize] {
+                                        Err(RpcHandlerError::DuplicateCallId)
+                                    } else {
+                                        match &stats_rpc_access {
+                                            Some(access) if access(&ctx) => {
+                                                debug!("Client requested method stats");
+                                                rkyv::to_bytes::<Vec<MethodStats>, 1024>(&method_stats.snapshot())
+                                                    .map(|bytes| bytes.into_vec())
+                                                    .map_err(|_| RpcHandlerError::BadOutputBytes)
                                             }
-                                        ).await
-                                    });
-
-                                    debug!("Handler task spawned.");
+                                            Some(_) => Err(RpcHandlerError::Unauthorized),
+                                            None => Err(RpcHandlerError::Unsupported),
+                                        }
+                                    };
+                                    let binary = to_message_bytes(&ServerMessage::RPCResponse { id, output });
+                                    ctx.stats().record_bytes_out(binary.len());
+                                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                                        warn!("Error sending response to client: {}", e);
+                                    }
+                                }
+                                ArchivedClientMessage::Transaction { id, op } => {
+                                    let id = *id;
+                                    let span = span!(Level::DEBUG, "rpc", id = id);
+                                    let _enter = span.enter();
+                                    let op: TransactionOp = op.deserialize(&mut Infallible).unwrap();
+                                    debug!("Client requested transaction {:?}", op);
+                                    let transactions = ctx.transactions();
+                                    let output = if in_flight[id as usize] {
+                                        Err(RpcHandlerError::DuplicateCallId)
+                                    } else if in_flight.contains(&true) {
+                                        // their changes would land on either side of it
+                                        Err(RpcHandlerError::CallsInFlight)
+                                    } else {
+                                        match (op, transaction.take()) {
+                                            (TransactionOp::Begin, None) => {
+                                                // changes from before the transaction aren't part of it
+                                                flush_state_changes(&mut transaction, &mut coalesced, &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
+                                                match transactions.begin().await {
+                                                    Ok(transaction_id) => match handler.begin_transaction(&ctx) {
+                                                        Ok(()) => {
+                                                            transaction = Some(Vec::new());
+                                                            Ok(transaction_id.to_le_bytes().to_vec())
+                                                        }
+                                                        Err(e) => {
+                                                            // nothing's changed, so keeping it is as good as undoing it
+                                                            transactions.commit().await;
+                                                            Err(e)
+                                                        }
+                                                    },
+                                                    Err(e) => Err(e),
+                                                }
+                                            }
+                                            (TransactionOp::Commit(transaction_id), Some(held)) if transactions.open() == Some(transaction_id) => {
+                                                match handler.commit_transaction(&ctx) {
+                                                    Ok(()) => {
+                                                        // shared states send their changes as they let go
+                                                        transactions.commit().await;
+                                                        // the transaction's changes go out together, before the response
+                                                        send_state_changes(held, &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
+                                                        Ok(Vec::new())
+                                                    }
+                                                    Err(e) => {
+                                                        transaction = Some(held);
+                                                        Err(e)
+                                                    }
+                                                }
+                                            }
+                                            (TransactionOp::Rollback(transaction_id), Some(held)) if transactions.open() == Some(transaction_id) => {
+                                                match transactions.rollback().await.and_then(|()| handler.rollback_transaction(&ctx)) {
+                                                    Ok(()) => {
+                                                        // including changes from the transaction that are still
+                                                        // queued. Shared changes other connections made aren't
+                                                        // part of it, so they stay.
+                                                        while state_change_rx.own.try_recv().is_ok() {}
+                                                        Ok(Vec::new())
+                                                    }
+                                                    Err(e) => {
+                                                        transaction = Some(held);
+                                                        Err(e)
+                                                    }
+                                                }
+                                            }
+                                            (_, held) => {
+                                                transaction = held;
+                                                Err(RpcHandlerError::InvalidTransactionOp)
+                                            }
+                                        }
+                                    };
+                                    let binary = to_message_bytes(&ServerMessage::RPCResponse { id, output });
+                                    ctx.stats().record_bytes_out(binary.len());
+                                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                                        warn!("Error sending response to client: {}", e);
+                                    }
+                                }
+                                ArchivedClientMessage::StreamCredit { id, credits } => {
+                                    if let Some(stream) = streams.get(id) {
+                                        // a client can't grant more than a full window
+                                        let available = stream.credits.available_permits() as u32;
+                                        stream.credits.add_permits((*credits).min(STREAM_WINDOW.saturating_sub(available)) as usize);
+                                    }
+                                }
+                                ArchivedClientMessage::CancelStream { id } => {
+                                    let id = *id;
+                                    let span = span!(Level::DEBUG, "rpc", id = id);
+                                    let _enter = span.enter();
+                                    let Some(stream) = streams.remove(&id) else {
+                                        debug!("Client cancelled a stream that already ended. Ignoring.");
+                                        continue;
+                                    };
+                                    debug!("Client cancelled stream. Aborting handler...");
+                                    stream.task.abort();
+                                    in_flight[id as usize] = false;
+                                    let binary = to_message_bytes(&ServerMessage::RPCStreamEnd { id });
+                                    ctx.stats().record_bytes_out(binary.len());
+                                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                                        warn!("Error sending end of stream to client: {}", e);
+                                    }
+                                }
+                                ArchivedClientMessage::Event(payload) => {
+                                    let payload = frame.payload(payload);
+                                    ctx.stats().record_event();
+                                    // events don't take up an RPC id, but they're held
+                                    // to the same limits, so a flood of them can't
+                                    // spawn tasks without end
+                                    let Ok(event_permit) = events_in_flight.clone().try_acquire_owned() else {
+                                        warn!("Too many events being handled on this connection. Dropping event.");
+                                        continue;
+                                    };
+                                    // checked before the rate limits, so an event that's
+                                    // dropped here doesn't use up the client's budget
+                                    if call_limit.as_deref().map(CallLimit::check).is_some_and(|check| check.is_err()) {
+                                        warn!("Too many calls on this connection. Dropping event.");
+                                        continue;
+                                    }
+                                    if rate_limits.check().is_err() {
+                                        warn!("Client is sending events too quickly. Dropping event.");
+                                        continue;
+                                    }
+                                    debug!("Received event from client. Spawning handler task...");
+                                    let handler = handler.clone();
+                                    let ctx = ctx.clone();
+                                    let queued = call_limit.as_ref().map(|limit| limit.enqueue());
+                                    event_tasks.spawn(transaction::scope(ctx.transactions().clone(), async move {
+                                        let _event_permit = event_permit;
+                                        let _permit = match queued {
+                                            Some(queued) => Some(queued.start().await),
+                                            None => None,
+                                        };
+                                        handler.handle_event(&ctx, &payload).await;
+                                    }));
+                                }
+                                ArchivedClientMessage::RPCResponse { id, output } => {
+                                    let id = *id;
+                                    let output: HandlerResult<Vec<u8>> = output.deserialize(&mut Infallible).unwrap();
+                                    let span = span!(Level::DEBUG, "client_rpc", id = id);
+                                    let _enter = span.enter();
+                                    debug!("Received response to call from client");
+                                    if let Some(completion_tx) = client_calls[id as usize].take() {
+                                        // the caller may have timed out, which is fine
+                                        let _ = completion_tx.send(output);
+                                    } else {
+                                        warn!("Received response for unknown call to client. Ignoring.");
+                                    }
+                                }
+                                ArchivedClientMessage::Subscribe { topic } => {
+                                    debug!("Client subscribed to {topic}");
+                                    subscriptions.subscribe(topic.to_string());
+                                }
+                                ArchivedClientMessage::Unsubscribe { topic } => {
+                                    debug!("Client unsubscribed from {topic}");
+                                    subscriptions.unsubscribe(topic);
+                                }
+                                ArchivedClientMessage::SetFieldSync { field, sync } => {
+                                    let field = *field;
+                                    let sync: FieldSync = sync.deserialize(&mut Infallible).unwrap();
+                                    debug!("Client set field {field} to {:?}", sync);
+                                    // changes already queued are sent or held back as before
+                                    flush_state_changes(&mut transaction, &mut coalesced, &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
+                                    if let Some(change) = lazy_fields.set(field, sync) {
+                                        write_state_changes(vec![change], &ctx, &state_outbox);
+                                    }
+                                }
+                                ArchivedClientMessage::FetchFields { id, fields } => {
+                                    let id = *id;
+                                    let span = span!(Level::DEBUG, "rpc", id = id);
+                                    let _enter = span.enter();
+                                    let output = if in_flight[id as usize] {
+                                        Err(RpcHandlerError::DuplicateCallId)
+                                    } else {
+                                        debug!("Client fetched {} field(s)", fields.len());
+                                        // pick up the latest values first
+                                        flush_state_changes(&mut transaction, &mut coalesced, &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
+                                        let fetched = lazy_fields.fetch(fields.iter().copied());
+                                        if !fetched.is_empty() {
+                                            write_state_changes(fetched, &ctx, &state_outbox);
+                                        }
+                                        Ok(Vec::new())
+                                    };
+                                    let binary = to_message_bytes(&ServerMessage::RPCResponse { id, output });
+                                    ctx.stats().record_bytes_out(binary.len());
+                                    if let Err(e) = outbox.send(Message::Binary(binary)) {
+                                        warn!("Error sending response to client: {}", e);
+                                    }
                                 }
                             }
                         }
                     }
+                    // reap event handlers as they finish
+                    Some(finished) = event_tasks.join_next(), if !event_tasks.is_empty() => {
+                        if finished.is_err_and(|e| e.is_panic()) {
+                            warn!("Event handler panicked.");
+                        }
+                    }
                     // await responses from RPC calls
                     Some(msg) = rpc_rx.recv() => {
                         let id = match msg {
@@ -225,31 +1690,847 @@ where
                         };
                         let span = span!(Level::DEBUG, "rpc", id = id);
                         let _enter = span.enter();
+                        // the id is freed as the response goes out, so the
+                        // client can reuse it as soon as it has the response
                         in_flight[id as usize] = false;
+                        handler_tasks[id as usize] = None;
+                        flush_state_changes(&mut transaction, &mut coalesced, &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
                         debug!("RPC call finished. Serializing and sending response...");
-                        let binary = rkyv::to_bytes::<ServerMessage, 1024>(&msg).unwrap().to_vec();
-                        match ws_stream.send(Message::Binary(binary)).await {
+                        let (binary, _) = serialize_response(id, &msg, max_response_size);
+                        ctx.stats().record_bytes_out(binary.len());
+                        match outbox.send(Message::Binary(binary)) {
                             Ok(_) => debug!("Response sent."),
                             Err(e) => {
                                 warn!("Error sending response to client: {}", e);
                                 continue
                             }
                         };
+                        if is_poisoned(&msg) {
+                            close_poisoned(&outbox);
+                            break;
+                        }
+                    }
+                    // answer calls whose tasks ended without answering, which
+                    // means code outside the handler panicked, and free their
+                    // ids. The handler's own panics are caught, so the state
+                    // isn't poisoned and the connection stays open.
+                    Some((id, stream_key)) = abandoned_rx.recv() => {
+                        let span = span!(Level::DEBUG, "rpc", id = id);
+                        let _enter = span.enter();
+                        // a cancelled stream's task is aborted, and its id may be reused
+                        if stream_key.is_some_and(|key| streams.get(&id).map(|stream| stream.key) != Some(key)) {
+                            continue;
+                        }
+                        warn!("Call ended without answering. Answering it with an error.");
+                        streams.remove(&id);
+                        in_flight[id as usize] = false;
+                        handler_tasks[id as usize] = None;
+                        flush_state_changes(&mut transaction, &mut coalesced, &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
+                        let msg = ServerMessage::RPCResponse { id, output: Err(RpcHandlerError::CallAbandoned) };
+                        let binary = to_message_bytes(&msg);
+                        ctx.stats().record_bytes_out(binary.len());
+                        if let Err(e) = outbox.send(Message::Binary(binary)) {
+                            warn!("Error sending response to client: {}", e);
+                        }
+                    }
+                    // await chunks from streaming calls
+                    Some((key, msg)) = stream_rx.recv() => {
+                        let (id, done) = match msg {
+                            ServerMessage::RPCStreamItem { id, .. } => (id, false),
+                            ServerMessage::RPCStreamEnd { id } | ServerMessage::RPCResponse { id, .. } => (id, true),
+                            _ => unreachable!(),
+                        };
+                        // the stream may have been cancelled, and its id reused
+                        if streams.get(&id).map(|stream| stream.key) != Some(key) {
+                            continue;
+                        }
+                        flush_state_changes(&mut transaction, &mut coalesced, &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
+                        let (binary, too_large) = serialize_response(id, &msg, max_response_size);
+                        if done || too_large {
+                            if let Some(stream) = streams.remove(&id) {
+                                stream.task.abort();
+                            }
+                            in_flight[id as usize] = false;
+                        }
+                        ctx.stats().record_bytes_out(binary.len());
+                        if let Err(e) = outbox.send(Message::Binary(binary)) {
+                            warn!("Error sending stream to client: {}", e);
+                        }
+                        if is_poisoned(&msg) {
+                            close_poisoned(&outbox);
+                            break;
+                        }
+                    }
+                    // await state updates from the application, leaving them
+                    // queued while the client is behind, so handlers that
+                    // send more wait for it
+                    Some(state_changes) = state_change_rx.own.recv(), if !state_outbox.is_full() => {
+                        match (&mut transaction, state_coalescing) {
+                            (Some(held), _) => held.extend(state_changes),
+                            (None, Some(window)) => {
+                                if coalesced.hold(state_changes, window) {
+                                    send_state_changes(coalesced.take(), &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
+                                }
+                            }
+                            (None, None) => send_state_changes(state_changes, &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox),
+                        }
+                    }
+                    // await changes to shared states, which a transaction doesn't hold back
+                    Some(state_changes) = state_change_rx.shared.recv() => {
+                        match (&transaction, state_coalescing) {
+                            (Some(_), _) => write_filtered_state_changes(state_changes, &**handler, &ctx, &mut lazy_fields, &state_outbox),
+                            (None, Some(window)) => {
+                                if coalesced.hold(state_changes, window) {
+                                    send_state_changes(coalesced.take(), &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
+                                }
+                            }
+                            (None, None) => send_state_changes(state_changes, &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox),
+                        }
+                    }
+                    // send coalesced state changes once their window has passed
+                    _ = sleep_until(coalesced.deadline()) => {
+                        send_state_changes(coalesced.take(), &mut state_change_rx, &**handler, &ctx, &mut lazy_fields, &state_outbox);
+                    }
+                    // await calls to the client from handlers
+                    Some((internal, completion_tx)) = client_call_rx.recv() => {
+                        // an id stays taken until the client answers, even if the
+                        // caller has given up, so a late response can't
+                        // complete a newer call
+                        let Some(id) = client_calls.iter().position(|call| call.is_none()) else {
+                            warn!("No free id for call to client. Responding with an error.");
+                            let _ = completion_tx.send(Err(RpcHandlerError::TooManyCallsInFlight));
+                            continue;
+                        };
+                        let span = span!(Level::DEBUG, "client_rpc", id = id as u8);
+                        let _enter = span.enter();
+                        debug!("Sending call to client...");
+                        let binary = to_message_bytes(&ServerMessage::RPCRequest { id: id as u8, internal });
+                        ctx.stats().record_bytes_out(binary.len());
+                        match outbox.send(Message::Binary(binary)) {
+                            Ok(_) => {
+                                debug!("Call sent.");
+                                client_calls[id] = Some(completion_tx);
+                            }
+                            Err(e) => {
+                                warn!("Error sending call to client: {}", e);
+                                let _ = completion_tx.send(Err(RpcHandlerError::ClientNotConnected));
+                            }
+                        };
                     }
-                    // await state updates from the application
-                    Some(state_changes) = state_change_rx.recv() => {
-                        debug!("Received {} state update(s) from application. Serializing and sending...", state_changes.len());
-                        let binary = rkyv::to_bytes::<ServerMessage, 1024>(&ServerMessage::StateChange(state_changes)).unwrap().to_vec();
-                        match ws_stream.send(Message::Binary(binary)).await {
-                            Ok(_) => debug!("State update sent."),
+                    // periodically report the connection's stats, if enabled
+                    _ = tick(&mut stats_report) => {
+                        let stats = ctx.stats();
+                        info!(
+                            uptime = ?stats.uptime(),
+                            bytes_in = stats.bytes_in(),
+                            bytes_out = stats.bytes_out(),
+                            calls = stats.calls(),
+                            events = stats.events(),
+                            tls_version = ?ctx.tls().map(|tls| tls.protocol_version),
+                            cipher_suite = ?ctx.tls().map(|tls| tls.cipher_suite),
+                            "Connection stats"
+                        );
+                    }
+                    // await events broadcast to rooms we're in
+                    Some((room, event)) = room_rx.recv() => {
+                        debug!("Received event for room {room}. Serializing and sending...");
+                        let binary = to_message_bytes(&ServerMessage::RoomEvent { room, event });
+                        ctx.stats().record_bytes_out(binary.len());
+                        if let Err(e) = outbox.send(Message::Binary(binary)) {
+                            warn!("Error sending room event to client: {}", e);
+                        }
+                    }
+                    // await events broadcast to every connection
+                    broadcast = broadcasts.recv() => {
+                        let (topic, event) = match broadcast {
+                            Ok(broadcast) => broadcast,
+                            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                                warn!("Connection is lagging behind, missed {missed} broadcast event(s)");
+                                continue;
+                            }
+                            // the server holds onto the sender, so this can't happen
+                            Err(broadcast::error::RecvError::Closed) => continue,
+                        };
+                        debug!("Received broadcast event for {topic}. Serializing and sending...");
+                        let binary = to_message_bytes(&ServerMessage::NewEvent { topic, event });
+                        ctx.stats().record_bytes_out(binary.len());
+                        if let Err(e) = outbox.send(Message::Binary(binary)) {
+                            warn!("Error sending broadcast event to client: {}", e);
+                        }
+                    }
+                    // await events published to topics we're subscribed to
+                    Some((topic, event)) = event_rx.recv() => {
+                        debug!("Received event for {topic}. Serializing and sending...");
+                        let binary = to_message_bytes(&ServerMessage::NewEvent { topic, event });
+                        ctx.stats().record_bytes_out(binary.len());
+                        match outbox.send(Message::Binary(binary)) {
+                            Ok(_) => debug!("Event sent."),
                             Err(e) => {
-                                warn!("Error sending state update to client: {}", e);
+                                warn!("Error sending event to client: {}", e);
                                 continue
                             }
                         };
                     }
                 }
             }
+
+            if let Some(hook) = &metrics_hook {
+                hook.on_connection_close(connection_id);
+            }
+
+            // stop any streams that are still running
+            for stream in streams.into_values() {
+                stream.task.abort();
+            }
+
+            // cancel the calls and events still being handled, give their
+            // handlers a moment to wind down, then abort the ones that haven't
+            drop(connection_open);
+            let mut handler_tasks: Vec<_> = handler_tasks.into_iter().flatten().collect();
+            if !handler_tasks.is_empty() || !event_tasks.is_empty() {
+                debug!("Cancelling {} running handlers...", handler_tasks.len() + event_tasks.len());
+                let finished = async {
+                    join_all(handler_tasks.iter_mut()).await;
+                    while event_tasks.join_next().await.is_some() {}
+                };
+                if tokio::time::timeout(HANDLER_CANCEL_GRACE, finished).await.is_err() {
+                    warn!("Handlers didn't stop when cancelled. Aborting them.");
+                    for task in &handler_tasks {
+                        task.abort();
+                    }
+                    event_tasks.abort_all();
+                }
+            }
+
+            // once its calls have stopped, so they've let go of its states
+            if transaction.is_some() {
+                debug!("Client disconnected during a transaction. Rolling it back.");
+                if let Err(e) = ctx.transactions().rollback().await.and_then(|()| handler.rollback_transaction(&ctx)) {
+                    warn!("Failed to roll back transaction: {:?}", e);
+                }
+            }
+
+            debug!("RPC handler loop exited.");
+            // let the writer flush what's queued and close the socket
+            drop(outbox);
+            drop(state_outbox);
+            if tokio::time::timeout(WRITER_SHUTDOWN_TIMEOUT, &mut writer).await.is_err() {
+                warn!("Timed out flushing messages to the client. Dropping them.");
+                writer.abort();
+            }
+        });
+    }
+}
+
+impl MultiServer {
+    /// Creates a server that hosts several services on one port, e.g.
+    /// `/counter` and `/chat`, each with its own handler and state types.
+    /// Add them with [Server::add_service]. Clients pick one with
+    /// [Client::set_path](crate::Client::set_path), and are turned away with
+    /// HTTP 404 if it isn't one of the server's paths.
+    pub fn new_multi(config: ServerConfig) -> Self {
+        let mut server = Self::new(config, unrouted);
+        server.services = Some(Arc::new(HashMap::new()));
+        server
+    }
+
+    /// Serves the service `factory` creates handlers for at `path`, e.g.
+    /// `/counter`. Paths are matched exactly, ignoring any query string.
+    pub fn add_service(
+        mut self,
+        path: &str,
+        factory: impl Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync> + Send + Sync + 'static,
+    ) -> Self {
+        let services = self.services.get_or_insert_with(Default::default);
+        Arc::make_mut(services).insert(path.to_string(), Arc::new(factory));
+        self
+    }
+}
+
+/// The factory of a [MultiServer], which is never called as every connection
+/// is routed to one of its services instead.
+fn unrouted(_: StateUpdateChannel, _: &Context) -> Box<dyn Handler + Send + Sync> {
+    unreachable!("connections to a multi-service server are routed by path")
+}
+
+/// The queues a connection's writer takes messages from. Messages in
+/// `priority` are written first, which is where state changes go if
+/// [ServerConfig::prioritize_state_changes] is set.
+struct OutboxReceiver {
+    priority: OutboxQueue,
+    normal: OutboxQueue,
+}
+
+impl OutboxReceiver {
+    /// Waits for the next message. `None` once both queues are closed and
+    /// empty.
+    async fn recv(&mut self) -> Option<Message> {
+        select! {
+            biased;
+            Some(msg) = self.priority.recv() => Some(msg),
+            Some(msg) = self.normal.recv() => Some(msg),
+            else => None,
+        }
+    }
+
+    /// Takes the next message that's ready, without waiting.
+    fn try_recv(&mut self) -> Option<Message> {
+        self.priority.try_recv().or_else(|| self.normal.try_recv())
+    }
+}
+
+/// Writes queued messages to the client until the queues close, then closes
+/// the socket. Messages are queued serialized with rkyv, and encoded with
+/// `codec` as they're written. Messages that are already queued together are
+/// batched if `max_batch_size` is set. The metrics hook is told how many
+/// bytes each took on the wire, counted by `written`.
+async fn write_messages<S>(
+    mut sink: SplitSink<WebSocketStream<S>, Message>,
+    mut outbox: OutboxReceiver,
+    codec: Arc<dyn Codec>,
+    max_batch_size: Option<usize>,
+    metrics_hook: Option<Arc<dyn ServerMetricsHook>>,
+    written: Arc<AtomicUsize>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut leftover = None;
+    loop {
+        let msg = match leftover.take() {
+            Some(msg) => msg,
+            None => match outbox.recv().await {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+        let msg = match max_batch_size {
+            Some(max_size) => {
+                let (msg, rest) = batch_messages(msg, &mut outbox, max_size);
+                leftover = rest;
+                msg
+            }
+            None => msg,
+        };
+        let msg = match msg {
+            Message::Binary(bytes) => match server_message_from_rkyv(&*codec, bytes) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Failed to encode a message for the client. Dropping it. Error: {e}");
+                    continue;
+                }
+            },
+            msg => msg,
+        };
+        // sending waits for the message to be flushed, so it's all written
+        // by the time it returns
+        let written_before = written.load(Ordering::Relaxed);
+        if let Err(e) = sink.send(msg).await {
+            warn!("Failed to write to client. Error: {e}");
+            return;
+        }
+        if let Some(hook) = &metrics_hook {
+            hook.on_bytes_out(written.load(Ordering::Relaxed) - written_before);
+        }
+    }
+    let _ = sink.close().await;
+}
+
+/// Collects the binary messages queued behind `first` into one
+/// [ServerMessage::Batch] of up to `max_size` bytes, without waiting for
+/// more. Returns the message to send, and the message that was taken off the
+/// queue but didn't fit, if there is one.
+fn batch_messages(
+    first: Message,
+    outbox: &mut OutboxReceiver,
+    max_size: usize,
+) -> (Message, Option<Message>) {
+    let Message::Binary(first) = first else {
+        return (first, None);
+    };
+    let mut size = first.len();
+    let mut batch = vec![first];
+    let mut leftover = None;
+    while size < max_size {
+        match outbox.try_recv() {
+            Some(Message::Binary(msg)) if size + msg.len() <= max_size => {
+                size += msg.len();
+                batch.push(msg);
+            }
+            Some(msg) => {
+                leftover = Some(msg);
+                break;
+            }
+            None => break,
+        }
+    }
+    if batch.len() == 1 {
+        return (Message::Binary(batch.remove(0)), leftover);
+    }
+    debug!("Sending {} messages as one batch", batch.len());
+    (Message::Binary(to_message_bytes(&ServerMessage::Batch(batch))), leftover)
+}
+
+/// Waits for the next tick of an optional interval, or forever if there isn't
+/// one.
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Waits until an optional deadline, or forever if there isn't one.
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Checks that the connection has the scope the handler requires for
+/// `method`, if any.
+fn authorize(handler: &(dyn Handler + Send + Sync), ctx: &Context, method: Option<&'static str>) -> HandlerResult<()> {
+    match method.and_then(|method| handler.required_scope(method)) {
+        Some(scope) if !ctx.has_scope(scope) => {
+            debug!("Connection doesn't have the {scope} scope. Refusing the call.");
+            Err(RpcHandlerError::Unauthorized)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// What a call whose handler panicked returns. The handler may have left the
+/// connection's state half-changed, so the connection is closed.
+fn handler_panicked<T>() -> HandlerResult<T> {
+    warn!("Handler panicked.");
+    Err(RpcHandlerError::StatePoisoned)
+}
+
+/// Held by a call's task until it has handed over its response. If the task
+/// ends without doing so, e.g. because it panicked outside the handler's
+/// `catch_unwind` or was aborted, dropping this tells the connection loop, so
+/// the call's id doesn't stay in flight and the client isn't left waiting.
+struct CallSlot {
+    id: u8,
+    /// The stream's key, for a streaming call.
+    stream_key: Option<u64>,
+    abandoned_tx: mpsc::UnboundedSender<(u8, Option<u64>)>,
+    answered: bool,
+}
+
+impl CallSlot {
+    fn new(id: u8, stream_key: Option<u64>, abandoned_tx: mpsc::UnboundedSender<(u8, Option<u64>)>) -> Self {
+        Self {
+            id,
+            stream_key,
+            abandoned_tx,
+            answered: false,
+        }
+    }
+
+    /// Marks the call's response as handed over.
+    fn answered(mut self) {
+        self.answered = true;
+    }
+}
+
+impl Drop for CallSlot {
+    fn drop(&mut self) {
+        if !self.answered {
+            // fails if the connection has closed, which frees every id anyway
+            let _ = self.abandoned_tx.send((self.id, self.stream_key));
+        }
+    }
+}
+
+/// Whether `msg` is a call failing with [RpcHandlerError::StatePoisoned],
+/// after which the connection can't be trusted.
+fn is_poisoned(msg: &ServerMessage) -> bool {
+    matches!(
+        msg,
+        ServerMessage::RPCResponse {
+            output: Err(RpcHandlerError::StatePoisoned),
+            ..
+        }
+    )
+}
+
+/// Closes the connection with 1011 (internal error) once the queued messages,
+/// including the poisoned call's response, have been written.
+fn close_poisoned(outbox: &Outbox) {
+    warn!("Connection state is poisoned. Closing the connection.");
+    let close = CloseFrame {
+        code: CloseCode::Error,
+        reason: "connection state poisoned".into(),
+    };
+    let _ = outbox.send(Message::Close(Some(close)));
+}
+
+/// Serializes a response to call `id`. If it's over `limit`, an error response
+/// is serialized in its place, and the second value is `true`.
+fn serialize_response(id: u8, msg: &ServerMessage, limit: Option<usize>) -> (Vec<u8>, bool) {
+    let binary = to_message_bytes(msg);
+    let Some(limit) = limit.filter(|&limit| binary.len() > limit) else {
+        return (binary, false);
+    };
+    warn!("Response is {} bytes, over the {} byte limit. Sending an error instead.", binary.len(), limit);
+    let output = Err(RpcHandlerError::ResponseTooLarge {
+        size: binary.len() as u64,
+        limit: limit as u64,
+    });
+    (to_message_bytes(&ServerMessage::RPCResponse { id, output }), true)
+}
+
+/// Receives the state changes a connection sends the client.
+struct StateChangeReceiver {
+    /// Changes to the connection's own state.
+    own: mpsc::Receiver<Vec<(FieldId, Vec<u8>)>>,
+    /// Changes to [SharedState](crate::SharedState)s the connection is
+    /// subscribed to, which a transaction doesn't hold back.
+    shared: mpsc::UnboundedReceiver<Vec<(FieldId, Vec<u8>)>>,
+}
+
+impl StateChangeReceiver {
+    /// Takes a batch of changes of either kind that's already queued.
+    fn try_recv(&mut self) -> Result<Vec<(FieldId, Vec<u8>)>, mpsc::error::TryRecvError> {
+        self.shared.try_recv().or_else(|_| self.own.try_recv())
+    }
+}
+
+/// Sends the state changes that are already queued or being coalesced, or
+/// holds them back if a transaction is open. Called before anything a call
+/// sends the client, so a call's changes to the state always reach the
+/// client before its response or stream items do.
+fn flush_state_changes(
+    transaction: &mut Option<Vec<(FieldId, Vec<u8>)>>,
+    coalesced: &mut CoalescedChanges,
+    state_change_rx: &mut StateChangeReceiver,
+    handler: &(dyn Handler + Send + Sync),
+    ctx: &Context,
+    lazy_fields: &mut LazyFields,
+    outbox: &Outbox,
+) {
+    if let Some(held) = transaction {
+        while let Ok(more) = state_change_rx.own.try_recv() {
+            held.extend(more);
+        }
+        let mut shared_changes = Vec::new();
+        while let Ok(more) = state_change_rx.shared.try_recv() {
+            shared_changes.extend(more);
+        }
+        if !shared_changes.is_empty() {
+            write_filtered_state_changes(shared_changes, handler, ctx, lazy_fields, outbox);
+        }
+    } else {
+        // nothing is coalesced while a transaction is open
+        let mut state_changes = coalesced.take();
+        if let Ok(more) = state_change_rx.try_recv() {
+            state_changes.extend(more);
+        }
+        if !state_changes.is_empty() {
+            send_state_changes(state_changes, state_change_rx, handler, ctx, lazy_fields, outbox);
+        }
+    }
+}
+
+/// Sends `state_changes` to the client, along with any batches that queued up
+/// behind it, e.g. while the connection loop was busy, as one catch-up batch.
+/// Changes to fields the client made lazy are held back.
+fn send_state_changes(
+    mut state_changes: Vec<(FieldId, Vec<u8>)>,
+    state_change_rx: &mut StateChangeReceiver,
+    handler: &(dyn Handler + Send + Sync),
+    ctx: &Context,
+    lazy_fields: &mut LazyFields,
+    outbox: &Outbox,
+) {
+    while let Ok(more) = state_change_rx.try_recv() {
+        state_changes.extend(more);
+    }
+    write_filtered_state_changes(state_changes, handler, ctx, lazy_fields, outbox);
+}
+
+/// Queues `state_changes` for the client, once the handler has prepared them
+/// and changes to fields the client made lazy are held back.
+fn write_filtered_state_changes(
+    state_changes: Vec<(FieldId, Vec<u8>)>,
+    handler: &(dyn Handler + Send + Sync),
+    ctx: &Context,
+    lazy_fields: &mut LazyFields,
+    outbox: &Outbox,
+) {
+    let mut state_changes = compact_state_changes(state_changes);
+    handler.prepare_state_changes(&mut state_changes);
+    lazy_fields.hold_back(&mut state_changes);
+    if state_changes.is_empty() {
+        debug!("All state updates were filtered out. Nothing to send.");
+        return;
+    }
+    debug!("Received {} state update(s) from application. Serializing and sending...", state_changes.len());
+    write_state_changes(state_changes, ctx, outbox);
+}
+
+/// Queues `state_changes` for the client as one
+/// [ServerMessage::StateChange], as they are.
+fn write_state_changes(
+    state_changes: Vec<(FieldId, Vec<u8>)>,
+    ctx: &Context,
+    outbox: &Outbox,
+) {
+    let binary = to_message_bytes(&ServerMessage::StateChange(state_changes));
+    ctx.stats().record_bytes_out(binary.len());
+    match outbox.send(Message::Binary(binary)) {
+        Ok(_) => debug!("State update sent."),
+        Err(e) => warn!("Error sending state update to client: {}", e),
+    }
+}
+
+/// Keeps only the latest value of each field, as the client only needs the
+/// final state. Fields stay in the order they were first changed.
+fn compact_state_changes(changes: Vec<(FieldId, Vec<u8>)>) -> Vec<(FieldId, Vec<u8>)> {
+    let mut positions: HashMap<FieldId, usize> = HashMap::new();
+    let mut compacted: Vec<(FieldId, Vec<u8>)> = Vec::with_capacity(changes.len());
+    for (field, value) in changes {
+        match positions.get(&field) {
+            Some(&position) => compacted[position].1 = value,
+            None => {
+                positions.insert(field, compacted.len());
+                compacted.push((field, value));
+            }
+        }
+    }
+    compacted
+}
+
+/// If `proxy_protocol` is set, reads the PROXY header in front of a new
+/// connection and returns the client address it gives. Otherwise returns
+/// `peer_addr`. Returns `None` if the connection should be dropped because
+/// the header is missing or malformed. See [ServerConfig::proxy_protocol].
+async fn proxied_peer_addr<S>(stream: &mut S, peer_addr: SocketAddr, proxy_protocol: bool) -> Option<SocketAddr>
+where
+    S: AsyncRead + Unpin,
+{
+    if !proxy_protocol {
+        return Some(peer_addr);
+    }
+    match tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(stream)).await {
+        Ok(Ok(Some(client_addr))) => {
+            debug!("{} is proxying for {}", peer_addr, client_addr);
+            Some(client_addr)
+        }
+        // e.g. the load balancer's own health checks
+        Ok(Ok(None)) => Some(peer_addr),
+        Ok(Err(e)) => {
+            warn!("Dropping connection from {}: {}", peer_addr, e);
+            None
+        }
+        Err(_) => {
+            warn!("Dropping connection from {}: timed out waiting for the PROXY protocol header", peer_addr);
+            None
+        }
+    }
+}
+
+/// Terminates TLS on a new connection, passing failures to
+/// `on_accept_error` as well as returning them.
+async fn terminate_tls<S>(
+    acceptor: &TlsAcceptor,
+    stream: S,
+    peer_addr: SocketAddr,
+    on_accept_error: Option<&AcceptErrorHook>,
+) -> Result<(TlsStream<S>, Option<TlsInfo>), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match acceptor.accept(stream).await {
+        Ok(stream) => {
+            let tls = TlsInfo::from_connection(stream.get_ref().1);
+            debug!(
+                tls_version = ?tls.as_ref().map(|tls| tls.protocol_version),
+                cipher_suite = ?tls.as_ref().map(|tls| tls.cipher_suite),
+                "Successfully terminated TLS handshake"
+            );
+            Ok((stream, tls))
+        }
+        Err(error) => {
+            debug!("TLS handshake failed: {}", error);
+            let error = ServerError::Tls { peer_addr, error };
+            if let Some(on_accept_error) = on_accept_error {
+                on_accept_error(&error);
+            }
+            Err(error)
+        }
+    }
+}
+
+/// Turns a client away during the WebSocket handshake, so it gets a clear
+/// HTTP error rather than a dropped connection.
+fn reject_connection<S>(stream: S, peer_addr: SocketAddr, rejection: ConnectionRejection)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (status, reason) = match rejection {
+        ConnectionRejection::ServerFull => (StatusCode::SERVICE_UNAVAILABLE, "server full"),
+        ConnectionRejection::TooManyFromIp => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "too many connections from this address",
+        ),
+        ConnectionRejection::UnknownService => (StatusCode::NOT_FOUND, "no service at this path"),
+    };
+    warn!("Rejecting connection from {}: {}", peer_addr, reason);
+    tokio::spawn(async move {
+        let callback = BoxedCallback(|_: &Request, _: Response| {
+            let mut response = ErrorResponse::new(Some(reason.to_string()));
+            *response.status_mut() = status;
+            Err(Box::new(response))
+        });
+        // the handshake always fails, as that's how the rejection is sent
+        let _ = accept_hdr_async(stream, callback).await;
+    });
+}
+
+/// A handshake callback that boxes its error response, as it's large, and
+/// unboxes it for tungstenite.
+struct BoxedCallback<F>(F);
+
+impl<F> Callback for BoxedCallback<F>
+where
+    F: FnOnce(&Request, Response) -> Result<Response, Box<ErrorResponse>>,
+{
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        (self.0)(request, response).map_err(|response| *response)
+    }
+}
+
+/// A unary call read from the client: its id, method and arguments, trace
+/// context, and how long the client waits for it.
+type UnaryCall<'a> = (u8, &'a ArchivedVec<u8>, Option<&'a str>, Option<Duration>);
+
+/// The unary calls in a message from the client.
+fn unary_calls(msg: &ArchivedClientMessage) -> Vec<UnaryCall<'_>> {
+    match msg {
+        ArchivedClientMessage::RPCRequest { id, internal } => vec![(*id, internal, None, None)],
+        ArchivedClientMessage::TracedRPCRequest { id, internal, traceparent } => {
+            vec![(*id, internal, Some(traceparent.as_str()), None)]
+        }
+        ArchivedClientMessage::TimedRPCRequest {
+            id,
+            internal,
+            timeout_ms,
+            traceparent,
+        } => {
+            let timeout = Duration::from_millis(*timeout_ms as u64);
+            vec![(*id, internal, traceparent.as_ref().map(|t| t.as_str()), Some(timeout))]
+        }
+        ArchivedClientMessage::RPCBatch(calls) => {
+            calls.iter().map(|call| (call.id, &call.internal, None, None)).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Answers a call straight away if it can't be run, rather than leaving the
+/// client waiting.
+fn reject_call(
+    in_flight: &[bool],
+    id: u8,
+    input_len: usize,
+    max_input_len: Option<usize>,
+    rate_limits: &mut RateLimits,
+    call_limit: Option<&CallLimit>,
+) -> Option<RpcHandlerError> {
+    if in_flight[id as usize] {
+        warn!("RPC call already in flight. Responding with an error.");
+        return Some(RpcHandlerError::DuplicateCallId);
+    }
+    if let Some(limit) = max_input_len.filter(|&limit| input_len > limit) {
+        warn!("Call input is {} bytes, over the {} byte limit. Responding with an error.", input_len, limit);
+        return Some(RpcHandlerError::PayloadTooLarge {
+            size: input_len as u64,
+            limit: limit as u64,
         });
     }
+    if let Err(retry_after) = rate_limits.check() {
+        warn!("Client is making calls too quickly. Responding with an error.");
+        return Some(RpcHandlerError::RateLimited {
+            retry_after_ms: Some(retry_after.as_millis() as u64),
+        });
+    }
+    if let Some(Err(error)) = call_limit.map(CallLimit::check) {
+        warn!("Too many calls on this connection. Responding with an error.");
+        return Some(error);
+    }
+    None
+}
+
+/// Limits how many of a connection's calls run at once.
+struct CallLimit {
+    running: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+    max_queued: Option<usize>,
+    mode: CallLimitMode,
+}
+
+impl CallLimit {
+    fn new(max_running: usize, max_queued: Option<usize>, mode: CallLimitMode) -> Self {
+        Self {
+            running: Arc::new(Semaphore::new(max_running)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            max_queued,
+            mode,
+        }
+    }
+
+    /// Whether another call can be taken, or else what to answer it with.
+    fn check(&self) -> Result<(), RpcHandlerError> {
+        let queued = self.queued.load(Ordering::Relaxed);
+        match self.mode {
+            CallLimitMode::Queue if self.max_queued.is_some_and(|max_queued| queued >= max_queued) => {
+                Err(RpcHandlerError::Overloaded)
+            }
+            // calls that haven't started yet will take the free slots
+            CallLimitMode::RateLimit { retry_after } if queued >= self.running.available_permits() => {
+                Err(RpcHandlerError::RateLimited {
+                    retry_after_ms: retry_after.map(|retry_after| retry_after.as_millis() as u64),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a call as queued until it's started. This happens before the
+    /// call's task is spawned, so a burst of calls can't overshoot the queue.
+    fn enqueue(&self) -> QueuedCall {
+        let queued = self.queued.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!(queued, must_wait = self.running.available_permits() == 0, "Call queued.");
+        QueuedCall {
+            running: self.running.clone(),
+            queued: self.queued.clone(),
+        }
+    }
+}
+
+/// A call waiting for one of the connection's slots.
+struct QueuedCall {
+    running: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl QueuedCall {
+    /// Waits for a free slot. Slots are handed out in the order calls were
+    /// queued. The call runs until the permit is dropped.
+    async fn start(self) -> OwnedSemaphorePermit {
+        let permit = self.running.clone().acquire_owned().await.unwrap();
+        debug!("Call started.");
+        permit
+    }
+}
+
+// also runs if the call is aborted while it's still queued
+impl Drop for QueuedCall {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
 }
@@ -0,0 +1,259 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use tokio::sync::{Mutex, MutexGuard, Notify};
+
+use crate::{
+    connection_state::StateDiff,
+    context::Context,
+    server::{HandlerResult, SharedStateChannel},
+    transaction::{self, Participant},
+    wire::TransactionId,
+};
+
+/// State shared by every connection that subscribes to it, e.g. a global
+/// counter or a leaderboard, which sends each of them what changed each time
+/// it's unlocked. Keep one in an `Arc` captured by the server's factory, and
+/// subscribe each connection's [Context] to it.
+///
+/// Its changes reach the client alongside the connection's own state, so the
+/// client's [State](crate::State) has the fields of both, and their ids must
+/// not overlap. Like a [ConnectionState](crate::ConnectionState), a call's
+/// changes reach the client before its response.
+///
+/// A call made in a transaction has the state to itself until the
+/// transaction ends: other connections that lock it wait until then, and
+/// the changes reach every subscriber, the connection's own client
+/// included, when it's committed, or never if it's rolled back. Two
+/// transactions that each wait for a state the other has wait until one of
+/// their clients gives up, so lock shared states in the same order.
+pub struct SharedState<S> {
+    inner: Arc<Inner<S>>,
+}
+
+struct Inner<S> {
+    state: Mutex<S>,
+    subscribers: std::sync::Mutex<Subscribers<S>>,
+    /// The transaction that has the state to itself, if one does.
+    holder: std::sync::Mutex<Option<Holder<S>>>,
+    /// Notified when a transaction lets go of the state.
+    released: Notify,
+}
+
+struct Holder<S> {
+    transaction: TransactionId,
+    /// The state as of the transaction's first call that locked it.
+    snapshot: S,
+}
+
+struct Subscribers<S> {
+    /// The state as of the last commit, which new subscribers start from.
+    committed: S,
+    channels: Vec<SharedStateChannel>,
+}
+
+impl<S> SharedState<S>
+where
+    S: StateDiff + Default + Send + 'static,
+{
+    /// Wraps `state`, with no connections subscribed yet.
+    pub fn new(state: S) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                subscribers: std::sync::Mutex::new(Subscribers {
+                    committed: state.clone(),
+                    channels: Vec::new(),
+                }),
+                state: Mutex::new(state),
+                holder: std::sync::Mutex::new(None),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    /// Sends the state's changes to a connection from now on, starting with
+    /// the fields that differ from their defaults, as that's where the
+    /// client's state starts. Connections that close are dropped the next
+    /// time the state changes.
+    pub fn subscribe(&self, ctx: &Context) {
+        let channel = ctx.shared_state_changes();
+        let mut subscribers = self.inner.subscribers.lock().unwrap();
+        let changes = subscribers.committed.diff(&S::default());
+        if !changes.is_empty() && channel.send(changes).is_err() {
+            return;
+        }
+        subscribers.channels.push(channel.clone());
+    }
+
+    /// How many connections are subscribed, including any that have closed
+    /// since the state last changed.
+    pub fn subscribers(&self) -> usize {
+        self.inner.subscribers.lock().unwrap().channels.len()
+    }
+
+    /// Locks the state, waiting for other calls to unlock it first, and for
+    /// another connection's transaction that has it to end.
+    pub async fn lock(&self) -> SharedStateGuard<'_, S> {
+        // the open transaction of the connection whose call this is
+        let transaction = transaction::current().and_then(|transactions| Some((transactions.open()?, transactions)));
+        let ours = transaction.as_ref().map(|(id, _)| *id);
+        loop {
+            let released = self.inner.released.notified();
+            if self.inner.held_by_another(ours) {
+                released.await;
+                continue;
+            }
+            let state = self.inner.state.lock().await;
+            // a transaction may have taken it while we waited for the lock
+            if self.inner.held_by_another(ours) {
+                continue;
+            }
+            if let Some((id, transactions)) = &transaction {
+                let mut holder = self.inner.holder.lock().unwrap();
+                if holder.is_none() {
+                    *holder = Some(Holder {
+                        transaction: *id,
+                        snapshot: state.clone(),
+                    });
+                    transactions.join(self.inner.clone());
+                }
+            }
+            return SharedStateGuard {
+                starting_state: state.clone(),
+                state,
+                subscribers: &self.inner.subscribers,
+                in_transaction: ours.is_some(),
+                suppressed: false,
+            };
+        }
+    }
+}
+
+impl<S> Inner<S> {
+    fn held_by_another(&self, ours: Option<TransactionId>) -> bool {
+        let holder = self.holder.lock().unwrap();
+        holder.as_ref().is_some_and(|holder| Some(holder.transaction) != ours)
+    }
+
+    /// Lets other connections have the state again, returning the snapshot
+    /// taken when the transaction that had it first locked it.
+    fn release(&self) -> Option<S> {
+        let snapshot = self.holder.lock().unwrap().take().map(|holder| holder.snapshot);
+        self.released.notify_waiters();
+        snapshot
+    }
+}
+
+// Only the transaction that has the state joins it, and only while it has
+// it, so the state is released by whichever of these runs. Other
+// connections only lock it to find it's taken, so these don't wait long.
+#[async_trait]
+impl<S> Participant for Inner<S>
+where
+    S: StateDiff + Send + 'static,
+{
+    async fn begin(&self) -> HandlerResult<()> {
+        Ok(())
+    }
+
+    async fn commit(&self) {
+        let state = self.state.lock().await;
+        let Some(snapshot) = self.release() else {
+            return;
+        };
+        let changes = state.diff(&snapshot);
+        if changes.is_empty() {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.committed = state.clone();
+        subscribers.channels.retain(|channel| channel.send(changes.clone()).is_ok());
+    }
+
+    async fn rollback(&self) -> HandlerResult<()> {
+        let mut state = self.state.lock().await;
+        if let Some(snapshot) = self.release() {
+            // no one was sent the transaction's changes
+            *state = snapshot;
+        }
+        Ok(())
+    }
+}
+
+/// A locked [SharedState]. Derefs to the state.
+pub struct SharedStateGuard<'a, S>
+where
+    S: StateDiff,
+{
+    state: MutexGuard<'a, S>,
+    /// The state as of locking or the last commit, to compare against.
+    starting_state: S,
+    subscribers: &'a std::sync::Mutex<Subscribers<S>>,
+    /// Whether a transaction has the state, in which case its changes are
+    /// sent when the transaction is committed.
+    in_transaction: bool,
+    suppressed: bool,
+}
+
+impl<S> SharedStateGuard<'_, S>
+where
+    S: StateDiff,
+{
+    /// Sends the changes made so far to every subscribed connection, as one
+    /// state change. In a transaction, they're sent when it's committed
+    /// instead.
+    pub fn commit(&mut self) {
+        if self.in_transaction {
+            return;
+        }
+        let changes = self.state.diff(&self.starting_state);
+        if changes.is_empty() {
+            return;
+        }
+        self.starting_state = self.state.clone();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.committed = self.state.clone();
+        subscribers.channels.retain(|channel| channel.send(changes.clone()).is_ok());
+    }
+
+    /// Stops the guard from sending anything when it's dropped. Changes made
+    /// while suppressed are never sent, and connections that subscribe later
+    /// start from the state as of the last commit.
+    pub fn suppress(&mut self) {
+        self.suppressed = true;
+    }
+}
+
+impl<S> Drop for SharedStateGuard<'_, S>
+where
+    S: StateDiff,
+{
+    fn drop(&mut self) {
+        if !self.suppressed {
+            self.commit();
+        }
+    }
+}
+
+impl<S> Deref for SharedStateGuard<'_, S>
+where
+    S: StateDiff,
+{
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl<S> DerefMut for SharedStateGuard<'_, S>
+where
+    S: StateDiff,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.state
+    }
+}
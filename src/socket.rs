@@ -0,0 +1,52 @@
+use std::{io, time::Duration};
+#[cfg(unix)]
+use std::path::Path;
+
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+/// Sets `SO_LINGER` on a socket, so closing it waits up to `linger` for
+/// unsent data to be delivered. A zero duration makes closing reset the
+/// connection instead, throwing away anything unsent.
+pub(crate) fn set_linger(stream: &TcpStream, linger: Duration) -> io::Result<()> {
+    // deprecated in newer versions of tokio, as a non-zero linger can block
+    // the thread that closes the socket. That's the point here, and the
+    // option is off unless it's been configured.
+    #[allow(deprecated)]
+    stream.set_linger(Some(linger))
+}
+
+/// Binds a Unix socket at `path`, replacing a socket file left behind by a
+/// server that's no longer running, and sets its permissions to `mode` if
+/// given. Fails if another server is still listening on it, or if the path
+/// is something other than a socket.
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another server is listening on this socket",
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "path exists and isn't a socket",
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
@@ -0,0 +1,42 @@
+use std::time::{Duration, Instant};
+
+use crate::wire::FieldId;
+
+/// The most batches of state changes merged before they're sent, even if the
+/// coalescing window hasn't passed, so a state that changes constantly still
+/// reaches the client regularly.
+pub(crate) const MAX_COALESCED_BATCHES: usize = 64;
+
+/// State changes held back by
+/// [ServerConfig::state_coalescing](crate::ServerConfig::state_coalescing),
+/// so that several batches reach the client as one.
+#[derive(Default)]
+pub(crate) struct CoalescedChanges {
+    changes: Vec<(FieldId, Vec<u8>)>,
+    batches: usize,
+    /// When the held changes are due, if there are any.
+    deadline: Option<Instant>,
+}
+
+impl CoalescedChanges {
+    /// Holds `changes` back, starting the window if nothing was held yet.
+    /// Returns whether enough batches have built up to send them now.
+    pub fn hold(&mut self, changes: Vec<(FieldId, Vec<u8>)>, window: Duration) -> bool {
+        self.deadline.get_or_insert_with(|| Instant::now() + window);
+        self.changes.extend(changes);
+        self.batches += 1;
+        self.batches >= MAX_COALESCED_BATCHES
+    }
+
+    /// Takes the held changes, to send them.
+    pub fn take(&mut self) -> Vec<(FieldId, Vec<u8>)> {
+        self.batches = 0;
+        self.deadline = None;
+        std::mem::take(&mut self.changes)
+    }
+
+    /// When the held changes are due, if there are any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
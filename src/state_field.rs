@@ -0,0 +1,148 @@
+use rkyv::{
+    de::deserializers::SharedDeserializeMap, ser::serializers::AllocSerializer, validation::validators::DefaultValidator,
+    Archive, CheckBytes, Deserialize, Serialize,
+};
+
+use crate::{
+    server::HandlerResult,
+    wire::{FieldId, RpcHandlerError},
+};
+
+/// A type that can be sent to clients as a state field, i.e. one that rkyv
+/// can serialize.
+///
+/// Use [assert_state_fields!](crate::assert_state_fields) to check a state
+/// struct's fields at compile time, so a field rkyv can't handle (e.g. a
+/// recursive type without `#[omit_bounds]`) is reported against the state
+/// rather than deep inside the serialization code.
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` can't be used as a state field",
+    label = "rkyv can't serialize `{Self}`",
+    note = "state fields are sent to clients with rkyv, so they must derive `Archive` and `Serialize`",
+    note = "recursive types need `#[omit_bounds]` on the recursive field, see the rkyv docs"
+)]
+pub trait StateField: Archive + Serialize<AllocSerializer<1024>> {}
+
+impl<T> StateField for T where T: Archive + Serialize<AllocSerializer<1024>> {}
+
+/// Checks that `T` is a valid [StateField]. Does nothing at runtime.
+pub const fn assert_state_field<T: StateField>() {}
+
+/// Checks that no two fields of a state share an id. Used by
+/// [state_diff!](crate::state_diff) at compile time.
+///
+/// # Panics
+///
+/// If an id is listed twice.
+pub const fn assert_unique_field_ids(ids: &[FieldId]) {
+    let mut i = 0;
+    while i < ids.len() {
+        let mut j = i + 1;
+        while j < ids.len() {
+            assert!(ids[i] != ids[j], "two state fields have the same id");
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+/// Serializes a state field's value the way clients expect it. Used by
+/// [state_diff!](crate::state_diff).
+pub fn serialize_field<T: StateField>(value: &T) -> Vec<u8> {
+    rkyv::to_bytes::<T, 1024>(value)
+        .expect("failed to serialize state field")
+        .to_vec()
+}
+
+/// Reads a state field's value as the server sent it, failing with
+/// [RpcHandlerError::BadInputBytes] if it doesn't decode. Used by
+/// [impl_state!](crate::impl_state).
+pub fn deserialize_field<T>(bytes: &[u8]) -> HandlerResult<T>
+where
+    T: Archive,
+    for<'a> T::Archived: CheckBytes<DefaultValidator<'a>> + Deserialize<T, SharedDeserializeMap>,
+{
+    rkyv::from_bytes(bytes).map_err(|_| RpcHandlerError::BadInputBytes)
+}
+
+/// Checks at compile time that every field of a state struct can be sent to
+/// clients.
+///
+/// ```ignore
+/// #[derive(Clone, Default)]
+/// struct CounterState {
+///     counter: u32,
+/// }
+///
+/// assert_state_fields!(CounterState { counter: u32 });
+/// ```
+#[macro_export]
+macro_rules! assert_state_fields {
+    ($state:ident { $($field:ident: $ty:ty),* $(,)? }) => {
+        const _: () = {
+            $(
+                // errors point at the field's type
+                $crate::assert_state_field::<$ty>();
+            )*
+            // make sure the listed fields are the struct's fields
+            let _ = |state: &$state| { $(let _ = &state.$field;)* };
+        };
+    };
+}
+
+/// Implements [State](crate::State) and [StateDiff](crate::StateDiff) for a
+/// state struct from its fields, and checks them like
+/// [assert_state_fields!]. Each field is given the [FieldId] it's sent as,
+/// as with [state_diff!](crate::state_diff). With a
+/// [ConnectionState](crate::ConnectionState) on the server, that's all a
+/// service has to write for its state.
+///
+/// ```ignore
+/// #[derive(Clone, Default)]
+/// struct CounterState {
+///     counter: u32,
+/// }
+///
+/// impl_state!(CounterState { counter: u32 = 0 });
+/// ```
+#[macro_export]
+macro_rules! impl_state {
+    ($state:ident { $($field:ident: $ty:ty = $id:expr),* $(,)? }) => {
+        $crate::assert_state_fields!($state { $($field: $ty),* });
+        $crate::state_diff!($state { $($field = $id),* });
+
+        impl $crate::State for $state {
+            fn apply_changes(&mut self, changes: Vec<($crate::FieldId, Vec<u8>)>) -> $crate::HandlerResult<()> {
+                // decode every change before applying any, so a bad one
+                // leaves the state as it was
+                $(let mut $field: Option<$ty> = None;)*
+                for (field, new_value) in changes {
+                    $(
+                        if field == $id {
+                            $field = Some($crate::deserialize_field::<$ty>(&new_value)?);
+                            continue;
+                        }
+                    )*
+                    $crate::State::unknown_field(self, field)?;
+                }
+                $(
+                    if let Some(value) = $field {
+                        self.$field = value;
+                    }
+                )*
+                Ok(())
+            }
+
+            fn apply_batch(&mut self, changes: Vec<($crate::FieldId, Vec<u8>)>) -> $crate::HandlerResult<()> {
+                // nothing is applied unless every change decodes, so there's
+                // no need for a copy
+                $crate::State::apply_changes(self, changes)
+            }
+
+            fn knows_field(&self, field: $crate::FieldId) -> bool {
+                let ids: &[$crate::FieldId] = &[$($id),*];
+                ids.contains(&field)
+            }
+        }
+    };
+}
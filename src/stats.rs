@@ -0,0 +1,301 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Running totals for a single connection.
+///
+/// Connections can stay open for days, so the counters saturate at
+/// [u64::MAX] rather than wrapping back around to zero.
+pub struct ConnectionStats {
+    opened_at: Instant,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    calls: AtomicU64,
+    events: AtomicU64,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self {
+            opened_at: Instant::now(),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            calls: AtomicU64::new(0),
+            events: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ConnectionStats {
+    /// How long the connection has been open.
+    pub fn uptime(&self) -> Duration {
+        self.opened_at.elapsed()
+    }
+
+    /// The number of bytes received from the client.
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// The number of bytes sent to the client.
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// The number of RPC calls the client has made.
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    /// The number of events the client has sent.
+    pub fn events(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_bytes_in(&self, bytes: usize) {
+        saturating_add(&self.bytes_in, bytes as u64);
+    }
+
+    pub(crate) fn record_bytes_out(&self, bytes: usize) {
+        saturating_add(&self.bytes_out, bytes as u64);
+    }
+
+    pub(crate) fn record_call(&self) {
+        saturating_add(&self.calls, 1);
+    }
+
+    pub(crate) fn record_event(&self) {
+        saturating_add(&self.events, 1);
+    }
+}
+
+fn saturating_add(counter: &AtomicU64, amount: u64) {
+    // the closure always returns Some, so this can't fail
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+        Some(value.saturating_add(amount))
+    });
+}
+
+/// How many slots the latency windows are split into.
+const WINDOW_SLOTS: usize = 6;
+/// How long each slot of a latency window covers. Together the slots cover
+/// the last minute.
+const SLOT_LENGTH: Duration = Duration::from_secs(10);
+/// Latencies are bucketed in microseconds, four buckets per power of two, up
+/// to about 70 minutes. Longer latencies go in the last bucket.
+const LATENCY_BUCKETS: usize = 128;
+
+/// Running totals and recent latencies for a client's calls, shared between
+/// the application and the connection. Get them with
+/// [Connection::stats](crate::Connection::stats), or hand the same stats to
+/// every client with [Client::set_stats](crate::Client::set_stats) to keep
+/// them across reconnects.
+///
+/// Recording only touches atomics, so calls never wait on each other for it.
+/// Percentiles cover the calls that finished in the last minute and are
+/// accurate to within 25%.
+pub struct ClientStats {
+    created_at: Instant,
+    in_flight: AtomicU64,
+    calls: AtomicU64,
+    errors: AtomicU64,
+    connects: AtomicU64,
+    latency: LatencyWindow,
+    queue_wait: LatencyWindow,
+}
+
+impl Default for ClientStats {
+    fn default() -> Self {
+        Self {
+            created_at: Instant::now(),
+            in_flight: AtomicU64::new(0),
+            calls: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            connects: AtomicU64::new(0),
+            latency: LatencyWindow::default(),
+            queue_wait: LatencyWindow::default(),
+        }
+    }
+}
+
+impl ClientStats {
+    /// The number of calls waiting for their output.
+    pub fn in_flight(&self) -> u64 {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// The number of calls that have finished, including failed ones. Each
+    /// retry counts as a call.
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    /// The number of calls that failed.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of times a client using these stats connected after the
+    /// first time.
+    pub fn reconnects(&self) -> u64 {
+        self.connects.load(Ordering::Relaxed).saturating_sub(1)
+    }
+
+    /// The latency below which `percentile` (between 0 and 1) of the last
+    /// minute's calls finished, from the call being made to its output
+    /// arriving. `None` if no calls finished in the last minute.
+    pub fn latency_percentile(&self, percentile: f64) -> Option<Duration> {
+        self.latency.percentile(self.epoch(), percentile)
+    }
+
+    /// The median latency of the last minute's calls.
+    pub fn p50_latency(&self) -> Option<Duration> {
+        self.latency_percentile(0.5)
+    }
+
+    /// The 99th percentile latency of the last minute's calls.
+    pub fn p99_latency(&self) -> Option<Duration> {
+        self.latency_percentile(0.99)
+    }
+
+    /// Like [Self::latency_percentile], but for how long calls waited to be
+    /// handed to the connection. This grows when the application makes calls
+    /// faster than the connection can send them.
+    pub fn queue_wait_percentile(&self, percentile: f64) -> Option<Duration> {
+        self.queue_wait.percentile(self.epoch(), percentile)
+    }
+
+    /// The 99th percentile queue wait of the last minute's calls.
+    pub fn p99_queue_wait(&self) -> Option<Duration> {
+        self.queue_wait_percentile(0.99)
+    }
+
+    /// Counts a call as in flight until the returned guard is dropped.
+    pub(crate) fn start_call(&self) -> InFlightCall<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightCall(self)
+    }
+
+    pub(crate) fn record_queue_wait(&self, wait: Duration) {
+        self.queue_wait.record(self.epoch(), wait);
+    }
+
+    pub(crate) fn record_call(&self, latency: Duration, failed: bool) {
+        saturating_add(&self.calls, 1);
+        if failed {
+            saturating_add(&self.errors, 1);
+        }
+        self.latency.record(self.epoch(), latency);
+    }
+
+    pub(crate) fn record_connect(&self) {
+        saturating_add(&self.connects, 1);
+    }
+
+    /// The current slot number of the latency windows.
+    fn epoch(&self) -> u64 {
+        (self.created_at.elapsed().as_nanos() / SLOT_LENGTH.as_nanos()) as u64
+    }
+}
+
+/// Marks a call as no longer in flight when dropped, including when the call
+/// is cancelled.
+pub(crate) struct InFlightCall<'a>(&'a ClientStats);
+
+impl Drop for InFlightCall<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A latency histogram over the last [WINDOW_SLOTS] slots. Each slot is
+/// reused once it falls out of the window.
+struct LatencyWindow {
+    slots: [LatencySlot; WINDOW_SLOTS],
+}
+
+struct LatencySlot {
+    /// The epoch this slot's buckets are for, or [u64::MAX] if unused.
+    epoch: AtomicU64,
+    buckets: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl Default for LatencyWindow {
+    fn default() -> Self {
+        Self {
+            slots: std::array::from_fn(|_| LatencySlot {
+                epoch: AtomicU64::new(u64::MAX),
+                buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            }),
+        }
+    }
+}
+
+impl LatencyWindow {
+    fn record(&self, epoch: u64, latency: Duration) {
+        let slot = &self.slots[epoch as usize % WINDOW_SLOTS];
+        let seen = slot.epoch.load(Ordering::Acquire);
+        // Whoever moves the slot to this epoch clears it. Calls recorded by
+        // other threads in the meantime may be lost, which is fine for stats.
+        if seen != epoch
+            && slot
+                .epoch
+                .compare_exchange(seen, epoch, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+        {
+            for bucket in &slot.buckets {
+                bucket.store(0, Ordering::Relaxed);
+            }
+        }
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        slot.buckets[bucket_index(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn percentile(&self, epoch: u64, percentile: f64) -> Option<Duration> {
+        let mut counts = [0u64; LATENCY_BUCKETS];
+        for slot in &self.slots {
+            let slot_epoch = slot.epoch.load(Ordering::Acquire);
+            if slot_epoch == u64::MAX || slot_epoch + (WINDOW_SLOTS as u64) <= epoch {
+                continue;
+            }
+            for (count, bucket) in counts.iter_mut().zip(&slot.buckets) {
+                *count += bucket.load(Ordering::Relaxed);
+            }
+        }
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return None;
+        }
+        let rank = ((total as f64 * percentile.clamp(0.0, 1.0)).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, count) in counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(Duration::from_micros(bucket_upper_bound(index)));
+            }
+        }
+        unreachable!("rank is at most the total count")
+    }
+}
+
+/// The bucket for a latency in microseconds. Below 4µs each microsecond has
+/// its own bucket, above that each power of two is split into four.
+fn bucket_index(micros: u64) -> usize {
+    if micros < 4 {
+        return micros as usize;
+    }
+    let exponent = 63 - micros.leading_zeros() as usize;
+    let sub_bucket = (micros >> (exponent - 2)) as usize & 3;
+    (4 * (exponent - 1) + sub_bucket).min(LATENCY_BUCKETS - 1)
+}
+
+/// The largest latency in microseconds that goes in bucket `index`.
+fn bucket_upper_bound(index: usize) -> u64 {
+    if index < 4 {
+        return index as u64;
+    }
+    let exponent = index / 4 + 1;
+    let sub_bucket = (index % 4) as u64;
+    ((4 + sub_bucket + 1) << (exponent - 2)) - 1
+}
@@ -0,0 +1,106 @@
+use std::sync::Arc;
+
+use tokio::{
+    select,
+    sync::{mpsc, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{
+    server::HandlerResult,
+    wire::{RpcHandlerError, ServerMessage},
+};
+
+/// How many chunks of a stream the server may send before the client has to
+/// grant it more credit. This bounds how much of a stream is buffered when
+/// the application reads slowly.
+pub(crate) const STREAM_WINDOW: u32 = 16;
+
+/// Messages from streaming calls to the connection task, tagged with the
+/// stream's key. Stream ids are reused, so the key is what tells a new stream
+/// apart from a cancelled one that still had messages queued up.
+pub(crate) type StreamMessageSender = mpsc::Sender<(u64, ServerMessage)>;
+
+/// Sends the chunks of a streaming call to the client. See
+/// [Handler::handle_streaming_call](crate::Handler::handle_streaming_call).
+pub struct StreamSender {
+    id: u8,
+    key: u64,
+    tx: StreamMessageSender,
+    credits: Arc<Semaphore>,
+}
+
+impl StreamSender {
+    pub(crate) fn new(id: u8, key: u64, tx: StreamMessageSender, credits: Arc<Semaphore>) -> Self {
+        Self {
+            id,
+            key,
+            tx,
+            credits,
+        }
+    }
+
+    /// Sends a chunk to the client, waiting if the client has fallen too far
+    /// behind. Fails with [RpcHandlerError::ClientNotConnected] if the client
+    /// has gone away, in which case the handler should stop producing chunks.
+    pub async fn send(&self, payload: Vec<u8>) -> HandlerResult<()> {
+        self.credits
+            .acquire()
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)?
+            .forget();
+        self.tx
+            .send((
+                self.key,
+                ServerMessage::RPCStreamItem {
+                    id: self.id,
+                    payload,
+                },
+            ))
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)
+    }
+}
+
+/// A streaming call the server is running for the client.
+pub(crate) struct ActiveStream {
+    pub key: u64,
+    pub credits: Arc<Semaphore>,
+    pub task: JoinHandle<()>,
+}
+
+/// What the client's stream forwarder reports back to the connection loop.
+pub(crate) enum StreamFeedback {
+    /// The application took a chunk, so the server can send another one.
+    Credit,
+    /// The application dropped its receiver.
+    Cancel,
+}
+
+/// Moves chunks from the connection loop to the application, reporting back
+/// as it goes. The connection loop never waits on the application, so one
+/// slow stream can't hold up the rest of the connection.
+pub(crate) async fn forward_stream(
+    id: u8,
+    key: u64,
+    mut items: mpsc::UnboundedReceiver<HandlerResult<Vec<u8>>>,
+    subscriber: mpsc::Sender<HandlerResult<Vec<u8>>>,
+    feedback: mpsc::Sender<(u8, u64, StreamFeedback)>,
+) {
+    loop {
+        let item = select! {
+            item = items.recv() => item,
+            _ = subscriber.closed() => {
+                let _ = feedback.send((id, key, StreamFeedback::Cancel)).await;
+                return;
+            }
+        };
+        // the stream has ended
+        let Some(item) = item else { return };
+        if subscriber.send(item).await.is_err() {
+            let _ = feedback.send((id, key, StreamFeedback::Cancel)).await;
+            return;
+        }
+        let _ = feedback.send((id, key, StreamFeedback::Credit)).await;
+    }
+}
@@ -0,0 +1,164 @@
+//! Runs a [Server] and a [Client] in the same process over an in-memory pipe,
+//! so handlers can be tested without binding a port, TLS or waiting for the
+//! server to start.
+//!
+//! ```ignore
+//! let server = Server::new(ServerConfig::new_self_signed("localhost:8080"), factory);
+//! let client = hardlight::testing::connect::<CounterState, _>(&server).await?;
+//! let (output, state) = client.call_with_state_change(input).await?;
+//! assert_eq!(state.counter, 1);
+//! ```
+//!
+//! Without a server at all, [connect_in_memory] takes just the handler
+//! factory and hands back a regular [Connection]:
+//!
+//! ```ignore
+//! let (connection, server) = hardlight::testing::connect_in_memory::<CounterState, _>(factory).await?;
+//! connection.call(input).await?;
+//! server.publish("counter", &event);
+//! ```
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::io::duplex;
+
+use crate::{
+    client::{Client, Connection, ControlChannels, State},
+    error::ConnectError,
+    protocol::parse_protocols,
+    server::{Handler, HandlerResult, Server, ServerConfig, ServerHandle, StateUpdateChannel},
+    tls::no_certificate_config,
+    wire::RpcHandlerError,
+    Context,
+};
+
+/// How many bytes can be in flight in each direction of the pipe.
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// How long [TestClient::call_with_state_change] waits for the state to change.
+const STATE_CHANGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client connected to a [Server] over an in-memory pipe. Dropping it closes
+/// the connection.
+pub struct TestClient<T> {
+    connection: Connection<T>,
+}
+
+/// Connects a new client to `server` over an in-memory pipe. The server
+/// doesn't have to be running: the connection is handed to it directly, with
+/// the same limits, interceptors and handler factory as any other connection,
+/// but without TLS. Its peer address is `127.0.0.1:0`.
+pub async fn connect<T, F>(server: &Server<F>) -> Result<TestClient<T>, ConnectError>
+where
+    T: State + Default + Clone + Send + Sync + 'static,
+    F: Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>,
+    F: Send + Sync + 'static,
+{
+    connect_client(server, Client::new_self_signed("localhost")).await
+}
+
+/// Like [connect], but the client offers `version` (e.g. `hl/1`, or a list
+/// like `hl/2, hl/1`) instead of this crate's protocol version, to test
+/// version negotiation. To test the server's side, set
+/// [Server::protocol_versions].
+///
+/// # Panics
+///
+/// If `version` doesn't list any HardLight versions.
+pub async fn connect_with_version<T, F>(
+    server: &Server<F>,
+    version: &str,
+) -> Result<TestClient<T>, ConnectError>
+where
+    T: State + Default + Clone + Send + Sync + 'static,
+    F: Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>,
+    F: Send + Sync + 'static,
+{
+    let mut client = Client::new_self_signed("localhost");
+    let versions = parse_protocols([version]);
+    assert!(!versions.is_empty(), "invalid version string");
+    client.set_protocol_versions(versions);
+    connect_client(server, client).await
+}
+
+/// Connects a new client to a handler made by `factory`, over an in-memory
+/// pipe. There's no server to set up: one with the default [ServerConfig] is
+/// made for the connection, without a certificate, as TLS isn't used. The
+/// connection goes through the same message handling as any other, so calls,
+/// streams, state changes and events all work. Use the returned handle to
+/// publish events or broadcast to rooms.
+///
+/// Dropping the connection closes it, and the server's side of it ends too.
+pub async fn connect_in_memory<T, F>(factory: F) -> Result<(Connection<T>, ServerHandle), ConnectError>
+where
+    T: State + Default + Clone + Send + Sync + 'static,
+    F: Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>,
+    F: Send + Sync + 'static,
+{
+    let server = Server::new(ServerConfig::new("localhost", no_certificate_config()), factory);
+    let client = connect_client(&server, Client::new_self_signed("localhost")).await?;
+    Ok((client.connection, server.handle()))
+}
+
+async fn connect_client<T, F>(
+    server: &Server<F>,
+    client: Client<T>,
+) -> Result<TestClient<T>, ConnectError>
+where
+    T: State + Default + Clone + Send + Sync + 'static,
+    F: Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>,
+    F: Send + Sync + 'static,
+{
+    let (client_stream, server_stream) = duplex(PIPE_CAPACITY);
+    server.handle_connection(server_stream, SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), None);
+
+    let connection = Connection::spawn(
+        client,
+        |mut client, shutdown, control_channels_tx, ok_tx| async move {
+            client
+                .connect_over(client_stream, shutdown, control_channels_tx, ok_tx)
+                .await
+        },
+    )
+    .await?;
+    Ok(TestClient { connection })
+}
+
+impl<T> TestClient<T>
+where
+    T: Clone,
+{
+    /// The connection's control channels, for anything the helpers here
+    /// don't cover, e.g. streaming calls and subscriptions.
+    pub fn channels(&self) -> &ControlChannels<T> {
+        self.connection.channels()
+    }
+
+    /// Returns a copy of the current state.
+    pub fn state(&self) -> T {
+        self.connection.state().borrow().clone()
+    }
+
+    /// Makes an RPC call and waits for its output.
+    pub async fn call(&self, input: Vec<u8>) -> HandlerResult<Vec<u8>> {
+        self.connection.call(input).await
+    }
+
+    /// Makes an RPC call and waits for both its output and the state change it
+    /// causes, returning the new state. Fails with [RpcHandlerError::Timeout]
+    /// if the state doesn't change within 5 seconds.
+    pub async fn call_with_state_change(&self, input: Vec<u8>) -> HandlerResult<(Vec<u8>, T)> {
+        let mut state = self.connection.state();
+        state.borrow_and_update();
+        let output = self.call(input).await?;
+        tokio::time::timeout(STATE_CHANGE_TIMEOUT, state.changed())
+            .await
+            .map_err(|_| RpcHandlerError::Timeout)?
+            .map_err(|_| RpcHandlerError::ClientNotConnected)?;
+        let state = state.borrow().clone();
+        Ok((output, state))
+    }
+}
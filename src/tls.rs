@@ -0,0 +1,130 @@
+use std::{fmt, fs::File, io, io::BufReader, path::Path, sync::Arc};
+
+use rustls_pemfile::{read_all, Item};
+use tokio_rustls::rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    Certificate, CipherSuite, PrivateKey, ProtocolVersion, ServerConfig, ServerConnection,
+};
+
+/// An error loading a certificate chain or private key from PEM files.
+#[derive(Debug)]
+pub enum PemError {
+    /// A file couldn't be read.
+    Io { path: String, error: io::Error },
+    /// The certificate file didn't contain any certificates.
+    NoCertificates { path: String },
+    /// The key file didn't contain a private key.
+    NoPrivateKey { path: String },
+    /// rustls rejected the certificate chain or key.
+    Tls(tokio_rustls::rustls::Error),
+}
+
+impl fmt::Display for PemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PemError::Io { path, error } => write!(f, "failed to read {path}: {error}"),
+            PemError::NoCertificates { path } => write!(f, "no certificates found in {path}"),
+            PemError::NoPrivateKey { path } => write!(f, "no private key found in {path}"),
+            PemError::Tls(error) => write!(f, "invalid certificate or key: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for PemError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PemError::Io { error, .. } => Some(error),
+            PemError::Tls(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// The TLS session a client connected with, for security monitoring. See
+/// [Context::tls](crate::Context::tls).
+#[derive(Debug, Clone)]
+pub struct TlsInfo {
+    /// The negotiated TLS version, e.g. `TLSv1_3`.
+    pub protocol_version: ProtocolVersion,
+    /// The negotiated cipher suite, e.g. `TLS13_AES_256_GCM_SHA384`.
+    pub cipher_suite: CipherSuite,
+    /// The certificate chain the client presented, leaf first, in DER form.
+    /// Its subject can be read with an X.509 parser. Empty unless the
+    /// server's TLS config asks clients for certificates (mTLS).
+    pub client_certificates: Vec<Certificate>,
+}
+
+impl TlsInfo {
+    /// Reads the details of an established session. `None` if the handshake
+    /// hasn't finished.
+    pub(crate) fn from_connection(connection: &ServerConnection) -> Option<Self> {
+        Some(Self {
+            protocol_version: connection.protocol_version()?,
+            cipher_suite: connection.negotiated_cipher_suite()?.suite(),
+            client_certificates: connection
+                .peer_certificates()
+                .map(<[Certificate]>::to_vec)
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Reads every PEM item in a file.
+fn read_pem_file(path: &Path) -> Result<Vec<Item>, PemError> {
+    let io_error = |error| PemError::Io {
+        path: path.display().to_string(),
+        error,
+    };
+    let file = File::open(path).map_err(io_error)?;
+    read_all(&mut BufReader::new(file)).map_err(io_error)
+}
+
+/// Loads a certificate chain from a PEM file, leaf certificate first.
+pub(crate) fn load_certificates(path: &Path) -> Result<Vec<Certificate>, PemError> {
+    let certificates: Vec<_> = read_pem_file(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(certificate) => Some(Certificate(certificate)),
+            _ => None,
+        })
+        .collect();
+    if certificates.is_empty() {
+        return Err(PemError::NoCertificates {
+            path: path.display().to_string(),
+        });
+    }
+    Ok(certificates)
+}
+
+/// Loads the first private key from a PEM file. PKCS#8, PKCS#1 (RSA) and
+/// SEC1 (EC) keys are supported.
+pub(crate) fn load_private_key(path: &Path) -> Result<PrivateKey, PemError> {
+    read_pem_file(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| PemError::NoPrivateKey {
+            path: path.display().to_string(),
+        })
+}
+
+/// A TLS config without a certificate, for servers whose connections never
+/// use TLS, e.g. over a Unix socket or an in-memory pipe.
+pub(crate) fn no_certificate_config() -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(NoCertificate))
+}
+
+/// Has no certificate for anyone.
+struct NoCertificate;
+
+impl ResolvesServerCert for NoCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        None
+    }
+}
@@ -0,0 +1,168 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, warn};
+
+/// A unique identifier the server gives to each connection.
+pub type ConnectionId = u64;
+
+/// The channel the runtime uses to push `(topic, event)` pairs to a
+/// connection. The connection task forwards these to the client as
+/// [ServerMessage::NewEvent](crate::ServerMessage::NewEvent).
+pub(crate) type EventSender = mpsc::Sender<(String, Vec<u8>)>;
+
+/// Keeps track of which connections are subscribed to which topics. Rooms use
+/// their own registry, with room names as topics.
+#[derive(Default)]
+pub(crate) struct TopicRegistry {
+    topics: Mutex<HashMap<String, HashMap<ConnectionId, EventSender>>>,
+}
+
+impl TopicRegistry {
+    /// Subscribes a connection to a topic. Subscribing to a topic twice is a
+    /// no-op, so events are never delivered twice to the same connection.
+    pub fn subscribe(&self, topic: &str, connection: ConnectionId, sender: EventSender) {
+        let mut topics = self.topics.lock().unwrap();
+        topics
+            .entry(topic.to_string())
+            .or_default()
+            .entry(connection)
+            .or_insert(sender);
+    }
+
+    /// Unsubscribes a connection from a topic, removing the topic entirely if
+    /// it has no subscribers left.
+    pub fn unsubscribe(&self, topic: &str, connection: ConnectionId) {
+        let mut topics = self.topics.lock().unwrap();
+        if let Some(subscribers) = topics.get_mut(topic) {
+            subscribers.remove(&connection);
+            if subscribers.is_empty() {
+                topics.remove(topic);
+            }
+        }
+    }
+
+    /// Returns the connections subscribed to a topic.
+    pub fn subscribers(&self, topic: &str) -> Vec<ConnectionId> {
+        let topics = self.topics.lock().unwrap();
+        topics
+            .get(topic)
+            .map(|subscribers| subscribers.keys().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Sends an event to every connection subscribed to the topic, returning
+    /// the number of connections it was delivered to.
+    ///
+    /// Events are dropped for connections that can't keep up rather than
+    /// blocking the publisher.
+    pub fn publish(&self, topic: &str, event: &[u8]) -> usize {
+        let topics = self.topics.lock().unwrap();
+        let subscribers = match topics.get(topic) {
+            Some(subscribers) => subscribers,
+            None => return 0,
+        };
+
+        let mut delivered = 0;
+        for (connection, sender) in subscribers {
+            match sender.try_send((topic.to_string(), event.to_vec())) {
+                Ok(_) => delivered += 1,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("Connection {connection} is lagging behind, dropping event for topic {topic}")
+                }
+                // the connection is shutting down and will unsubscribe itself
+                Err(mpsc::error::TrySendError::Closed(_)) => {}
+            }
+        }
+        delivered
+    }
+}
+
+/// Sends events to every connected client, whatever topics they're subscribed
+/// to, e.g. to warn everyone that the server is restarting. Cheap to clone and
+/// usable from anywhere in the application.
+#[derive(Clone)]
+pub struct Broadcaster {
+    tx: broadcast::Sender<(String, Vec<u8>)>,
+}
+
+impl Broadcaster {
+    /// How many events a connection can fall behind by before it starts
+    /// missing them.
+    const CAPACITY: usize = 256;
+
+    pub(crate) fn new() -> Self {
+        let (tx, _) = broadcast::channel(Self::CAPACITY);
+        Self { tx }
+    }
+
+    /// Sends an event to every connection as a
+    /// [ServerMessage::NewEvent](crate::ServerMessage::NewEvent) on `topic`,
+    /// returning the number of connections it was sent to.
+    pub fn send(&self, topic: &str, event: &[u8]) -> usize {
+        // fails if there are no connections, which is fine
+        self.tx
+            .send((topic.to_string(), event.to_vec()))
+            .unwrap_or(0)
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<(String, Vec<u8>)> {
+        self.tx.subscribe()
+    }
+}
+
+/// The set of topics a single connection is subscribed to. Unsubscribes from
+/// all of them when dropped, so subscriptions are cleaned up however the
+/// connection ends.
+pub(crate) struct Subscriptions {
+    registry: Arc<TopicRegistry>,
+    connection: ConnectionId,
+    sender: EventSender,
+    topics: HashSet<String>,
+}
+
+impl Subscriptions {
+    pub fn new(
+        registry: Arc<TopicRegistry>,
+        connection: ConnectionId,
+        sender: EventSender,
+    ) -> Self {
+        Self {
+            registry,
+            connection,
+            sender,
+            topics: HashSet::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self, topic: String) {
+        if self.topics.contains(&topic) {
+            debug!("Already subscribed to {topic}. Ignoring.");
+            return;
+        }
+        self.registry
+            .subscribe(&topic, self.connection, self.sender.clone());
+        self.topics.insert(topic);
+    }
+
+    pub fn unsubscribe(&mut self, topic: &str) {
+        if self.topics.remove(topic) {
+            self.registry.unsubscribe(topic, self.connection);
+        }
+    }
+
+    pub fn topics(&self) -> impl Iterator<Item = &String> {
+        self.topics.iter()
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for topic in self.topics.drain() {
+            self.registry.unsubscribe(&topic, self.connection);
+        }
+    }
+}
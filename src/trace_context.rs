@@ -0,0 +1,26 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Makes a W3C `traceparent` for a call, e.g.
+/// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`, with a new
+/// random trace id and parent id. Both sides record it on their `rpc` span,
+/// so a call's logs can be found on the server from the client's.
+pub(crate) fn new_traceparent() -> String {
+    format!(
+        "00-{:016x}{:016x}-{:016x}-01",
+        random_u64(),
+        random_u64(),
+        random_u64()
+    )
+}
+
+/// A random number, from std's randomly keyed hasher rather than a dependency.
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
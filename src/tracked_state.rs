@@ -0,0 +1,197 @@
+use std::ops::{Deref, DerefMut};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+    connection_state::send_dropped,
+    server::{HandlerResult, StateUpdateChannel},
+    state_field::{serialize_field, StateField},
+    wire::{FieldId, RpcHandlerError},
+};
+
+/// A state field that remembers whether it's been changed, so a
+/// [TrackedState] only has to send the fields that were. Reading it through
+/// `Deref` leaves it unchanged, and any mutable access through `DerefMut`
+/// counts as a change, even if the value ends up the same.
+#[derive(Debug, Clone, Default)]
+pub struct Changed<T> {
+    value: T,
+    changed: bool,
+}
+
+impl<T> Changed<T> {
+    /// Wraps a field's starting value, which doesn't count as a change.
+    pub fn new(value: T) -> Self {
+        Self { value, changed: false }
+    }
+
+    /// Whether the field has been changed since its change was last taken.
+    pub fn is_changed(&self) -> bool {
+        self.changed
+    }
+
+    /// The field's new value, serialized as [field](FieldId) `id`, if it has
+    /// changed. Used by [track_changes!](crate::track_changes).
+    pub fn take_change(&mut self, id: FieldId) -> Option<(FieldId, Vec<u8>)>
+    where
+        T: StateField,
+    {
+        if !std::mem::take(&mut self.changed) {
+            return None;
+        }
+        Some((id, serialize_field(&self.value)))
+    }
+}
+
+impl<T> Deref for Changed<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.value
+    }
+}
+
+impl<T> DerefMut for Changed<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.changed = true;
+        &mut self.value
+    }
+}
+
+/// A state whose fields track their own changes, so [TrackedState] can send
+/// them without keeping a copy of the state to compare against. Implement it
+/// with [track_changes!](crate::track_changes).
+pub trait TrackChanges {
+    /// The fields that have changed since this was last called, with their
+    /// new values serialized, as they're sent on a [StateUpdateChannel].
+    fn take_changes(&mut self) -> Vec<(FieldId, Vec<u8>)>;
+}
+
+/// Implements [TrackChanges] for a state struct whose listed fields are all
+/// [Changed], each with the [FieldId] it's sent as.
+///
+/// ```ignore
+/// #[derive(Default)]
+/// struct InventoryState {
+///     items: Changed<Vec<u32>>,
+/// }
+///
+/// track_changes!(InventoryState { items = 0 });
+/// ```
+#[macro_export]
+macro_rules! track_changes {
+    ($state:ident { $($field:ident = $id:expr),* $(,)? }) => {
+        const _: () = $crate::assert_unique_field_ids(&[$($id),*]);
+
+        impl $crate::TrackChanges for $state {
+            fn take_changes(&mut self) -> Vec<($crate::FieldId, Vec<u8>)> {
+                let mut changes = Vec::new();
+                $(changes.extend(self.$field.take_change($id));)*
+                changes
+            }
+        }
+    };
+}
+
+/// Like [ConnectionState](crate::ConnectionState), but for a state whose
+/// fields track their own changes. Locking it doesn't clone the state, and
+/// unlocking only serializes the fields that were changed, which matters
+/// for a state with large collections in it.
+pub struct TrackedState<S> {
+    state: Mutex<S>,
+    channel: StateUpdateChannel,
+}
+
+impl<S> TrackedState<S>
+where
+    S: TrackChanges,
+{
+    /// Wraps `state`, sending its changes on `channel`, the one the runtime
+    /// gave [Handler::new](crate::Handler::new).
+    pub fn new(channel: StateUpdateChannel, state: S) -> Self {
+        Self {
+            state: Mutex::new(state),
+            channel,
+        }
+    }
+
+    /// Locks the state, waiting for other calls to unlock it first.
+    pub async fn lock(&self) -> TrackedStateGuard<'_, S> {
+        TrackedStateGuard {
+            state: self.state.lock().await,
+            channel: &self.channel,
+            suppressed: false,
+        }
+    }
+}
+
+/// A locked [TrackedState]. Derefs to the state.
+pub struct TrackedStateGuard<'a, S>
+where
+    S: TrackChanges,
+{
+    state: MutexGuard<'a, S>,
+    channel: &'a StateUpdateChannel,
+    suppressed: bool,
+}
+
+impl<S> TrackedStateGuard<'_, S>
+where
+    S: TrackChanges,
+{
+    /// Sends the changes made so far, as one state change, waiting while the
+    /// client is behind. Fails with [RpcHandlerError::ClientNotConnected] if
+    /// the client has disconnected. See
+    /// [ConnectionStateGuard::commit](crate::ConnectionStateGuard::commit).
+    pub async fn commit(&mut self) -> HandlerResult<()> {
+        let changes = self.state.take_changes();
+        if changes.is_empty() {
+            return Ok(());
+        }
+        self.channel
+            .send(changes)
+            .await
+            .map_err(|_| RpcHandlerError::ClientNotConnected)
+    }
+
+    /// Stops the guard from sending anything when it's dropped. Changes made
+    /// so far that haven't been committed are never sent.
+    pub fn suppress(&mut self) {
+        self.suppressed = true;
+    }
+}
+
+impl<S> Drop for TrackedStateGuard<'_, S>
+where
+    S: TrackChanges,
+{
+    fn drop(&mut self) {
+        if self.suppressed {
+            // forget the changes, so the next lock doesn't send them
+            self.state.take_changes();
+        } else {
+            let changes = self.state.take_changes();
+            send_dropped(self.channel, changes);
+        }
+    }
+}
+
+impl<S> Deref for TrackedStateGuard<'_, S>
+where
+    S: TrackChanges,
+{
+    type Target = S;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl<S> DerefMut for TrackedStateGuard<'_, S>
+where
+    S: TrackChanges,
+{
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.state
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use async_trait::async_trait;
+
+use crate::{server::HandlerResult, wire::TransactionId};
+
+/// Ids are unique across connections, as a [SharedState](crate::SharedState)
+/// can be held by any connection's transaction.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+tokio::task_local! {
+    /// The transactions of the connection whose handler is running, so the
+    /// states it creates and locks can take part in them.
+    static CONNECTION: Arc<Transactions>;
+}
+
+/// A state the runtime snapshots when a transaction begins, and restores if
+/// it's rolled back.
+#[async_trait]
+pub(crate) trait Participant: Send + Sync {
+    /// Takes the snapshot. Only called on registered states, as the ones that
+    /// join take theirs as they join.
+    async fn begin(&self) -> HandlerResult<()>;
+    /// Drops the snapshot, keeping the changes.
+    async fn commit(&self);
+    /// Puts the snapshot back. Does nothing if there isn't one, so a
+    /// rollback that failed partway can be tried again.
+    async fn rollback(&self) -> HandlerResult<()>;
+}
+
+/// A connection's open transaction, if there is one, and the states that
+/// take part in it.
+#[derive(Default)]
+pub(crate) struct Transactions {
+    open: Mutex<Option<TransactionId>>,
+    /// The connection's own states, which take part in every transaction.
+    /// They're dropped along with the handler.
+    registered: Mutex<Vec<Weak<dyn Participant>>>,
+    /// States that joined the open transaction when one of its calls first
+    /// locked them.
+    joined: Mutex<Vec<Arc<dyn Participant>>>,
+}
+
+impl Transactions {
+    /// Has `state` take part in the connection's transactions from now on.
+    pub fn register(&self, state: Weak<dyn Participant>) {
+        let mut registered = self.registered.lock().unwrap();
+        registered.retain(|state| state.strong_count() > 0);
+        registered.push(state);
+    }
+
+    /// Has `state` take part in the open transaction, which it's taken a
+    /// snapshot for.
+    pub fn join(&self, state: Arc<dyn Participant>) {
+        self.joined.lock().unwrap().push(state);
+    }
+
+    /// The open transaction's id, if there is one.
+    pub fn open(&self) -> Option<TransactionId> {
+        *self.open.lock().unwrap()
+    }
+
+    /// Opens a transaction, snapshotting the registered states.
+    pub async fn begin(&self) -> HandlerResult<TransactionId> {
+        let registered = self.registered();
+        for (i, state) in registered.iter().enumerate() {
+            if let Err(e) = state.begin().await {
+                for state in &registered[..i] {
+                    state.commit().await;
+                }
+                return Err(e);
+            }
+        }
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        *self.open.lock().unwrap() = Some(id);
+        Ok(id)
+    }
+
+    /// Closes the open transaction, keeping its changes.
+    pub async fn commit(&self) {
+        for state in self.participants() {
+            state.commit().await;
+        }
+        self.close();
+    }
+
+    /// Closes the open transaction, undoing its changes. If a state can't be
+    /// restored, the rest still are, and it stays open.
+    pub async fn rollback(&self) -> HandlerResult<()> {
+        let mut result = Ok(());
+        for state in self.participants() {
+            result = result.and(state.rollback().await);
+        }
+        if result.is_ok() {
+            self.close();
+        }
+        result
+    }
+
+    fn close(&self) {
+        self.joined.lock().unwrap().clear();
+        *self.open.lock().unwrap() = None;
+    }
+
+    fn registered(&self) -> Vec<Arc<dyn Participant>> {
+        self.registered.lock().unwrap().iter().filter_map(Weak::upgrade).collect()
+    }
+
+    fn participants(&self) -> Vec<Arc<dyn Participant>> {
+        let mut participants = self.registered();
+        participants.extend(self.joined.lock().unwrap().iter().cloned());
+        participants
+    }
+}
+
+/// Runs `f` as part of the connection with `transactions`.
+pub(crate) fn scope<F: Future>(transactions: Arc<Transactions>, f: F) -> impl Future<Output = F::Output> {
+    CONNECTION.scope(transactions, f)
+}
+
+/// Like [scope], for the handler factory, which isn't async.
+pub(crate) fn sync_scope<R>(transactions: Arc<Transactions>, f: impl FnOnce() -> R) -> R {
+    CONNECTION.sync_scope(transactions, f)
+}
+
+/// The transactions of the connection whose handler is running, if one is.
+pub(crate) fn current() -> Option<Arc<Transactions>> {
+    CONNECTION.try_with(Arc::clone).ok()
+}
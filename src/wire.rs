@@ -1,7 +1,56 @@
-use rkyv::{Archive, Serialize, Deserialize, CheckBytes};
+use rkyv::{
+    de::deserializers::SharedDeserializeMap,
+    ser::{
+        serializers::{
+            AllocScratch, AllocSerializer, CompositeSerializer, FallbackScratch, HeapScratch,
+            SharedSerializeMap, WriteSerializer,
+        },
+        ScratchSpace, Serializer,
+    },
+    validation::validators::DefaultValidator,
+    vec::{ArchivedVec, VecResolver},
+    with::{ArchiveWith, DeserializeWith, SerializeWith},
+    AlignedVec, Archive, CheckBytes, Deserialize, Fallible, Serialize,
+};
+
+/// Serializes a `Vec<u8>` field aligned to 16 bytes, so that if it holds an
+/// rkyv archive itself (e.g. a call's arguments), it can be read in place
+/// with [rkyv::check_archived_root] instead of being copied out first. The
+/// archived form is the same as a plain `Vec<u8>`.
+///
+/// Use it with `#[with(Aligned)]` on the field.
+pub struct Aligned;
+
+impl ArchiveWith<Vec<u8>> for Aligned {
+    type Archived = ArchivedVec<u8>;
+    type Resolver = VecResolver;
+
+    unsafe fn resolve_with(
+        field: &Vec<u8>,
+        pos: usize,
+        resolver: Self::Resolver,
+        out: *mut Self::Archived,
+    ) {
+        ArchivedVec::resolve_from_slice(field.as_slice(), pos, resolver, out);
+    }
+}
+
+impl<S: ScratchSpace + Serializer + ?Sized> SerializeWith<Vec<u8>, S> for Aligned {
+    fn serialize_with(field: &Vec<u8>, serializer: &mut S) -> Result<Self::Resolver, S::Error> {
+        serializer.align(AlignedVec::ALIGNMENT)?;
+        ArchivedVec::serialize_from_slice(field.as_slice(), serializer)
+    }
+}
+
+impl<D: Fallible + ?Sized> DeserializeWith<ArchivedVec<u8>, Vec<u8>, D> for Aligned {
+    fn deserialize_with(field: &ArchivedVec<u8>, _: &mut D) -> Result<Vec<u8>, D::Error> {
+        Ok(field.as_slice().to_vec())
+    }
+}
 
 #[derive(Archive, Serialize, Deserialize)]
 #[archive_attr(derive(CheckBytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ClientMessage {
     /// A message from the client when it calls a method on the server.
     RPCRequest {
@@ -9,18 +58,176 @@ pub enum ClientMessage {
         /// This is used to match responses to requests. It restricts the number
         /// of concurrent operations to 256. Active operations cannot
         /// reuse the same ID, therefore IDs of completed requests can
-        /// be reused.
+        /// be reused. The client allocates ids and must only reuse one once
+        /// it has received the response. The server answers a call that
+        /// reuses an active id with [RpcHandlerError::DuplicateCallId].
         id: u8,
         /// The internal message serialized with rkyv. This will include the
         /// method name and arguments. The format of this message will slightly
         /// differ depending on the number of methods, and types of arguments.
-        /// The macros handle generating the code for this.
+        /// The macros handle generating the code for this. It's aligned so the
+        /// server's handler can read it in place.
+        #[with(Aligned)]
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
+        internal: Vec<u8>,
+    },
+    /// A fire-and-forget event from the client, e.g. a typing indicator. These
+    /// don't use an RPC id and the server never responds to them.
+    Event(
+        #[with(Aligned)]
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
+        Vec<u8>,
+    ),
+    /// The client's response to a [ServerMessage::RPCRequest].
+    RPCResponse {
+        /// The id of the server's call. Server-initiated calls have their own
+        /// id space, separate from the client's calls.
+        id: u8,
+        /// The output of the client's handler.
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::output"))]
+        output: Result<Vec<u8>, RpcHandlerError>,
+    },
+    /// Subscribes the connection to events published to a topic.
+    /// Subscribing to a topic the connection is already subscribed to is a
+    /// no-op.
+    Subscribe {
+        /// The name of the topic, e.g. `room:42`.
+        topic: String,
+    },
+    /// Unsubscribes the connection from a topic.
+    Unsubscribe {
+        /// The name of the topic.
+        topic: String,
+    },
+    /// Like [ClientMessage::RPCRequest], but the server responds with a
+    /// stream of [ServerMessage::RPCStreamItem]s. Streaming calls share the
+    /// same id space as regular calls.
+    RPCStreamRequest {
+        /// A unique counter for each RPC call.
+        id: u8,
+        /// The method name and arguments serialized with rkyv.
+        #[with(Aligned)]
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
+        internal: Vec<u8>,
+    },
+    /// A reserved call that fetches the server's
+    /// [MethodStats](crate::MethodStats). It shares the id space of regular
+    /// calls, and the server answers with an [ServerMessage::RPCResponse]
+    /// holding a `Vec<MethodStats>` serialized with rkyv.
+    StatsRequest {
+        /// A unique counter for each RPC call.
+        id: u8,
+    },
+    /// A reserved call that begins, commits or rolls back a transaction on
+    /// the connection. It shares the id space of regular calls. The server
+    /// answers a [TransactionOp::Begin] with the [TransactionId] as 8 bytes,
+    /// little-endian, and the others with an empty
+    /// [ServerMessage::RPCResponse].
+    Transaction {
+        /// A unique counter for each RPC call.
+        id: u8,
+        op: TransactionOp,
+    },
+    /// Allows the server to send more chunks of a stream. The client grants
+    /// credit as the application reads chunks, so a slow reader slows down
+    /// the server rather than making either side buffer the whole stream.
+    StreamCredit {
+        /// The id of the streaming call.
+        id: u8,
+        /// How many more chunks the server may send.
+        credits: u32,
+    },
+    /// Stops a streaming call early, e.g. because the application has
+    /// stopped reading it. The server responds with
+    /// [ServerMessage::RPCStreamEnd].
+    CancelStream {
+        /// The id of the streaming call.
+        id: u8,
+    },
+    /// Changes whether the server pushes a state field to the client as it
+    /// changes. Switching a field back to [FieldSync::Eager] sends it
+    /// straight away if it changed while it was lazy.
+    SetFieldSync {
+        /// The id of the state field.
+        field: FieldId,
+        sync: FieldSync,
+    },
+    /// A reserved call that sends the client the current value of lazy state
+    /// fields, if they've changed since it last had them. It shares the id
+    /// space of regular calls. The values arrive as a
+    /// [ServerMessage::StateChange] before the empty
+    /// [ServerMessage::RPCResponse].
+    FetchFields {
+        /// A unique counter for each RPC call.
+        id: u8,
+        /// The ids of the state fields.
+        fields: Vec<FieldId>,
+    },
+    /// Like [ClientMessage::RPCRequest], but carries the call's W3C trace
+    /// context, so the server's span for the call can be tied to the
+    /// client's. Sent instead of it with the `trace-context` feature.
+    TracedRPCRequest {
+        /// A unique counter for each RPC call.
+        id: u8,
+        /// The method name and arguments serialized with rkyv.
+        #[with(Aligned)]
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
         internal: Vec<u8>,
+        /// The W3C `traceparent`, e.g.
+        /// `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+        traceparent: String,
     },
+    /// Several calls sent in one frame to save framing and writes, e.g. by
+    /// [ControlChannels::call_batch](crate::ControlChannels::call_batch).
+    /// The server starts them in order, exactly as if each had been sent as
+    /// an [ClientMessage::RPCRequest], so they run concurrently and each is
+    /// answered with its own [ServerMessage::RPCResponse] as it finishes.
+    /// Responses that are ready together arrive as a [ServerMessage::Batch].
+    RPCBatch(Vec<BatchedCall>),
+    /// Like [ClientMessage::RPCRequest], but says how long the client waits
+    /// for the output, so the handler can tell when it's given up. Sent by
+    /// [ControlChannels::call_with_timeout](crate::ControlChannels::call_with_timeout).
+    TimedRPCRequest {
+        /// A unique counter for each RPC call.
+        id: u8,
+        /// The method name and arguments serialized with rkyv.
+        #[with(Aligned)]
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
+        internal: Vec<u8>,
+        /// How long the client waits, in milliseconds from sending the call.
+        timeout_ms: u32,
+        /// The W3C `traceparent`, with the `trace-context` feature.
+        traceparent: Option<String>,
+    },
+}
+
+/// Identifies a state field in [ServerMessage::StateChange] and the messages
+/// that refer to fields. Ids are assigned by the state, e.g. with
+/// [impl_state!](crate::impl_state), and stay the same when a field is
+/// renamed, so renaming one doesn't break older clients.
+pub type FieldId = u16;
+
+/// State changes keyed by field name, as they were sent before fields had
+/// ids.
+#[deprecated(note = "state changes are keyed by `FieldId` now, see the README's migration note")]
+pub type NamedStateChanges = Vec<(String, Vec<u8>)>;
+
+/// One of the calls in a [ClientMessage::RPCBatch].
+#[derive(Archive, Serialize, Deserialize)]
+#[archive_attr(derive(CheckBytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BatchedCall {
+    /// The call's id, from the same id space as every other call.
+    pub id: u8,
+    /// The method name and arguments serialized with rkyv.
+    #[with(Aligned)]
+    #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
+    pub internal: Vec<u8>,
 }
 
 #[derive(Archive, Serialize, Deserialize)]
 #[archive_attr(derive(CheckBytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ServerMessage {
     /// A message from the server with the output of a client's method call.
     RPCResponse {
@@ -33,21 +240,93 @@ pub enum ServerMessage {
         /// The function's output serialized with rkyv. The format of this
         /// message will differ with each application.
         /// The macros handle generating the code for this.
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::output"))]
         output: Result<Vec<u8>, RpcHandlerError>,
     },
+    /// A message from the server when it calls a method on the client. This
+    /// mirrors [ClientMessage::RPCRequest], but uses its own id space.
+    RPCRequest {
+        /// A unique counter for each server-initiated call.
+        id: u8,
+        /// The method name and arguments serialized with rkyv.
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
+        internal: Vec<u8>,
+    },
     /// A message from the server with a new event.
     NewEvent {
+        /// The topic the event was published to.
+        topic: String,
         /// The event serialized with rkyv. The format of this will differ
         /// between applications. The macros handle generating the code for
         /// this.
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
         event: Vec<u8>,
     },
-    /// The server updates the connection state.
-    StateChange(Vec<(String, Vec<u8>)>),
+    /// An event broadcast to a room the connection is in. Unlike topics, the
+    /// server decides which rooms a connection is in.
+    RoomEvent {
+        /// The name of the room, e.g. `lobby:7`.
+        room: String,
+        /// The event serialized with rkyv.
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
+        event: Vec<u8>,
+    },
+    /// The server updates the connection state. Each change is a field id
+    /// and its new value serialized with rkyv. Values are never empty unless
+    /// the field is `()`, as even an empty collection has a header.
+    StateChange(#[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::state_changes"))] Vec<(FieldId, Vec<u8>)>),
+    /// A chunk of the output of a streaming call.
+    RPCStreamItem {
+        /// The id of the streaming call.
+        id: u8,
+        /// The chunk serialized with rkyv.
+        #[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))]
+        payload: Vec<u8>,
+    },
+    /// The end of a streaming call. If the call fails, it ends with an
+    /// [ServerMessage::RPCResponse] holding the error instead.
+    RPCStreamEnd {
+        /// The id of the streaming call.
+        id: u8,
+    },
+    /// Several messages that were ready to send at once, sent together to
+    /// save writes. Each one is a [ServerMessage] serialized on its own, and
+    /// they're handled in order. Batches are never nested.
+    Batch(#[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::list"))] Vec<Vec<u8>>),
+}
+
+/// Identifies a transaction, which the server assigns when it begins. Ids
+/// are unique across the server's connections.
+pub type TransactionId = u64;
+
+/// A step in a connection's transaction. See
+/// [Handler::begin_transaction](crate::Handler::begin_transaction).
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[archive_attr(derive(CheckBytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TransactionOp {
+    Begin,
+    Commit(TransactionId),
+    Rollback(TransactionId),
+}
+
+/// How the server keeps a state field up to date on the client.
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[archive_attr(derive(CheckBytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FieldSync {
+    /// Changes are pushed as they happen. This is the default.
+    #[default]
+    Eager,
+    /// Changes are held on the server until the client fetches them, e.g.
+    /// with [ControlChannels::fetch_fields](crate::ControlChannels::fetch_fields).
+    /// Only the latest value is kept.
+    Lazy,
 }
 
 #[derive(Archive, Serialize, Deserialize, Debug)]
 #[archive_attr(derive(CheckBytes))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RpcHandlerError {
     /// The input bytes for the RPC call were invalid.
     BadInputBytes,
@@ -56,9 +335,130 @@ pub enum RpcHandlerError {
     /// The connection state was poisoned. This means that the connection
     /// state was dropped while it was locked. This is a fatal error.
     /// If the runtime receives this error, it will close the connection.
+    /// Calls whose handler panics fail with this too, as the panic may have
+    /// left the state half-changed.
     StatePoisoned,
     /// You tried to make an RPC call before your client was connected
     ClientNotConnected,
     /// You've tried to make too many RPC calls at once.
     TooManyCallsInFlight,
-}
\ No newline at end of file
+    /// The client made a call with the id of a call that's still in flight.
+    /// This is a bug in the client, as ids must only be reused once the
+    /// previous call has been answered.
+    DuplicateCallId,
+    /// The server is too busy to take the call, e.g. the connection has too
+    /// many calls queued. It's safe to retry later.
+    Overloaded,
+    /// The client is making calls too quickly, either on this connection or
+    /// across the whole server.
+    RateLimited {
+        /// Roughly how long to wait before calling again, in milliseconds.
+        retry_after_ms: Option<u64>,
+    },
+    /// The client isn't allowed to make this call.
+    Unauthorized,
+    /// The other side didn't respond in time.
+    Timeout,
+    /// An application-defined error returned by the handler, serialized with
+    /// rkyv. Create one with [RpcHandlerError::application] and read it back
+    /// with [split_application_error].
+    Application(#[cfg_attr(feature = "serde", serde(with = "crate::payload_serde::bytes"))] Vec<u8>),
+    /// The other side doesn't handle this kind of call, e.g. the server called
+    /// a client that has no [ClientHandler](crate::ClientHandler).
+    Unsupported,
+    /// The call's output, or one of its stream items, was too big to send.
+    /// See [ServerConfig::max_response_size](crate::ServerConfig::max_response_size).
+    ResponseTooLarge {
+        /// The size of the response, in bytes.
+        size: u64,
+        /// The largest response the server sends, in bytes.
+        limit: u64,
+    },
+    /// The call's input was too big for the server to accept. See
+    /// [ServerConfig::max_call_input_size](crate::ServerConfig::max_call_input_size).
+    PayloadTooLarge {
+        /// The size of the input, in bytes.
+        size: u64,
+        /// The largest input the server accepts, in bytes.
+        limit: u64,
+    },
+    /// The client tried to begin a transaction while one is open, or to
+    /// commit or roll back a transaction that isn't open.
+    InvalidTransactionOp,
+    /// The client tried to begin, commit or roll back a transaction while
+    /// calls it made are still running. Wait for them to return first.
+    CallsInFlight,
+    /// The server changed a state field the client's [State](crate::State)
+    /// doesn't have, e.g. because the server was built with a newer schema.
+    /// Only returned by states that are strict about it. See
+    /// [State::unknown_field](crate::State::unknown_field).
+    UnknownStateField(FieldId),
+    /// The call's task ended without answering it, e.g. because code around
+    /// the handler panicked. Unlike [RpcHandlerError::StatePoisoned], the
+    /// handler never ran to a half-finished state, so the connection stays
+    /// open.
+    CallAbandoned,
+}
+
+impl RpcHandlerError {
+    /// Wraps an application-defined error so a handler can return it, e.g.
+    /// `Err(RpcHandlerError::application(&CounterError::Overflow))`.
+    pub fn application<E>(error: &E) -> Self
+    where
+        E: Serialize<AllocSerializer<1024>>,
+    {
+        match rkyv::to_bytes::<E, 1024>(error) {
+            Ok(bytes) => RpcHandlerError::Application(bytes.into_vec()),
+            Err(_) => RpcHandlerError::BadOutputBytes,
+        }
+    }
+}
+
+/// Separates an application's own errors from transport errors. The outer
+/// result holds transport errors (e.g. [RpcHandlerError::Timeout]), and the
+/// inner one holds the application's error type `E`, decoded from
+/// [RpcHandlerError::Application].
+///
+/// Fails with [RpcHandlerError::BadOutputBytes] if the application error
+/// isn't a valid `E`.
+pub fn split_application_error<T, E>(result: Result<T, RpcHandlerError>) -> Result<Result<T, E>, RpcHandlerError>
+where
+    E: Archive,
+    E::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<E, SharedDeserializeMap>,
+{
+    match result {
+        Ok(output) => Ok(Ok(output)),
+        Err(RpcHandlerError::Application(bytes)) => match rkyv::from_bytes::<E>(&bytes) {
+            Ok(error) => Ok(Err(error)),
+            Err(_) => Err(RpcHandlerError::BadOutputBytes),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Serializes messages straight into a `Vec<u8>`, which tungstenite can send
+/// as is. Otherwise the same as [AllocSerializer].
+pub(crate) type MessageSerializer = CompositeSerializer<
+    WriteSerializer<Vec<u8>>,
+    FallbackScratch<HeapScratch<1024>, AllocScratch>,
+    SharedSerializeMap,
+>;
+
+/// Serializes a message to send. Unlike [rkyv::to_bytes], the bytes don't
+/// have to be copied out of an [AlignedVec] first. The buffer itself isn't
+/// aligned, but the receiver's [Frame](crate::frame::Frame) realigns it if
+/// it has to.
+pub(crate) fn to_message_bytes<T>(msg: &T) -> Vec<u8>
+where
+    T: Serialize<MessageSerializer>,
+{
+    let mut serializer = MessageSerializer::new(
+        WriteSerializer::new(Vec::with_capacity(256)),
+        FallbackScratch::default(),
+        SharedSerializeMap::default(),
+    );
+    serializer
+        .serialize_value(msg)
+        .expect("failed to serialize message");
+    serializer.into_serializer().into_inner()
+}
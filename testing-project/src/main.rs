@@ -2,19 +2,25 @@
 // see: https://github.com/rust-lang/rust/issues/91611
 use async_trait::async_trait;
 use hardlight::{
-    tungstenite, Client, Handler, HandlerResult, RpcHandlerError, Server, ServerConfig, State,
-    StateUpdateChannel,
+    impl_state, split_application_error, Aligned, CallLimitMode, Client, CompressionConfig, ClientHandler, ClientMessage, ConnectError, Connection, Context, DefaultRetryClassifier, FieldId, FieldSync, Handler, HandlerResult, RpcHandlerError, Server, ServerConfig, PROTOCOL_VERSION,
+    state_diff, track_changes, CallContext, Changed, Codec, ConnectionState, ServerMetricsHook, SharedState, State, StateHandle, StateUpdateChannel, TokenValidator, TrackedState,
 };
 use rkyv::{Archive, CheckBytes, Deserialize, Serialize};
 use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
     select,
-    sync::{mpsc, oneshot},
+    sync::{mpsc::error::TrySendError, oneshot},
 };
-use tracing::{debug, error, info};
+use tracing::{debug, info};
 
 use std::{
+    net::SocketAddr,
     ops::{Deref, DerefMut},
-    sync::Arc,
+    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
 use parking_lot::{Mutex, MutexGuard};
@@ -24,19 +30,171 @@ async fn main() -> Result<(), std::io::Error> {
     tracing_subscriber::fmt::init();
 
     info!("Starting server on localhost:8080");
-    let config = ServerConfig::new_self_signed("localhost:8080");
+    let mut config = ServerConfig::new_self_signed("localhost:8080");
+    config.token_validator = Some(Arc::new(CounterTokens));
     info!("Config: {:?}", config);
-    let server = Server::new(config, CounterHandler::init());
+    // the token has been checked by the time a handler is made, so the
+    // factory knows who connected, and rejected clients never get one
+    let handlers_made = Arc::new(AtomicUsize::new(0));
+    let server = Server::new(config, {
+        let handlers_made = handlers_made.clone();
+        let init = CounterHandler::init();
+        move |state_update_channel, ctx: &Context| {
+            assert!(ctx.extensions().get::<User>().is_some(), "handler made before the token was checked");
+            handlers_made.fetch_add(1, Ordering::SeqCst);
+            init(state_update_channel, ctx)
+        }
+    });
+    let server_handle = server.handle();
 
+    let (ready_tx, ready_rx) = oneshot::channel();
     tokio::spawn(async move {
-        let _ = server.run().await;
+        let _ = server.run_with_ready(ready_tx).await;
     });
 
     // wait for the server to start
-    tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+    let address = ready_rx.await.expect("server failed to start");
+    info!("Server listening on {}", address);
+
+    // the number of changes is only sent when we ask for it
+    let mut client = Client::new_self_signed("localhost:8080");
+    client.set_field_sync(CHANGES, FieldSync::Lazy);
+    client.set_token(COUNTER_TOKEN);
+    let client = CounterClient::connect(client).await.unwrap();
+
+    // a client without a valid token is turned away during the handshake
+    let handlers_before = handlers_made.load(Ordering::SeqCst);
+    let mut intruder = Client::<CounterState>::new_self_signed("localhost:8080");
+    intruder.set_token("not-the-token");
+    match intruder.connect().await {
+        Err(ConnectError::HandshakeRejected { status }) if status == 401 => {
+            info!("Connecting with an invalid token failed as expected")
+        }
+        Err(e) => panic!("expected a 401, got {}", e),
+        Ok(_) => panic!("expected a 401"),
+    }
+
+    // headers set by hand replace the client's own, here the token
+    let mut with_header = Client::<CounterState>::new_self_signed("localhost:8080");
+    with_header.set_token("not-the-token");
+    with_header.set_header(
+        hardlight::tungstenite::http::header::AUTHORIZATION,
+        format!("Bearer {COUNTER_TOKEN}").parse().unwrap(),
+    );
+    with_header.set_header("x-tenant-id".parse().unwrap(), "counters & co".parse().unwrap());
+    with_header.set_query_param("tenant", "counters & co");
+    with_header.connect().await.expect("connecting with a custom header failed").close();
 
-    let mut client = CounterClient::new_self_signed("localhost:8080");
-    client.connect().await.unwrap();
+    // the server sees both as the client sent them, so a mismatch is refused
+    let mut wrong_tenant = Client::<CounterState>::new_self_signed("localhost:8080");
+    wrong_tenant.set_token(COUNTER_TOKEN);
+    wrong_tenant.set_header("x-tenant-id".parse().unwrap(), "counters".parse().unwrap());
+    wrong_tenant.set_query_param("tenant", "counters & co");
+    assert!(matches!(
+        wrong_tenant.connect().await,
+        Err(ConnectError::HandshakeRejected { status }) if status == 401
+    ));
+    // only the client with the custom header got a handler
+    assert_eq!(handlers_made.load(Ordering::SeqCst), handlers_before + 1);
+
+    // the application hears about state changes that can't be applied
+    let state_errors = Arc::new(AtomicUsize::new(0));
+    let mut outdated = Client::<CounterOnlyState>::new_self_signed("localhost:8080");
+    outdated.set_token(COUNTER_TOKEN);
+    outdated.set_on_state_error({
+        let state_errors = state_errors.clone();
+        Arc::new(move |e| {
+            assert!(matches!(e, RpcHandlerError::UnknownStateField(CHANGES)));
+            state_errors.fetch_add(1, Ordering::SeqCst);
+        })
+    });
+    let outdated = outdated.connect().await.expect("connecting an outdated client failed");
+    let args = rkyv::to_bytes::<IncrementArgs, 1024>(&IncrementArgs { amount: 1 }).unwrap().to_vec();
+    let increment = rkyv::to_bytes::<RpcCall, 1024>(&RpcCall { method: Method::Increment, args }).unwrap().to_vec();
+    outdated.call(increment).await.expect("increment failed");
+    assert_eq!(state_errors.load(Ordering::SeqCst), 1);
+    assert_eq!(outdated.state().borrow().counter, 0);
+    outdated.close();
+    info!("An outdated client was told its state couldn't be updated");
+
+    // connections can be tunneled through an HTTP proxy, which checks the
+    // client's credentials, or a SOCKS5 one
+    let tunnels = Arc::new(AtomicUsize::new(0));
+    let http_proxy = start_http_proxy(tunnels.clone()).await;
+    let mut proxied = Client::<CounterState>::new_self_signed("localhost:8080");
+    proxied.set_token(COUNTER_TOKEN);
+    proxied.set_proxy(format!("http://user:p%40ss@{http_proxy}").parse().unwrap());
+    proxied.connect().await.expect("connecting through the HTTP proxy failed").close();
+    assert_eq!(tunnels.load(Ordering::SeqCst), 1);
+    let mut bad_credentials = Client::<CounterState>::new_self_signed("localhost:8080");
+    bad_credentials.set_token(COUNTER_TOKEN);
+    bad_credentials.set_proxy(format!("http://user:wrong@{http_proxy}").parse().unwrap());
+    assert!(matches!(bad_credentials.connect().await, Err(ConnectError::Proxy(_))));
+    let socks5_proxy = start_socks5_proxy(tunnels.clone()).await;
+    let mut proxied = Client::<CounterState>::new_self_signed("localhost:8080");
+    proxied.set_token(COUNTER_TOKEN);
+    proxied.set_proxy(format!("socks5://{socks5_proxy}").parse().unwrap());
+    proxied.connect().await.expect("connecting through the SOCKS5 proxy failed").close();
+    assert_eq!(tunnels.load(Ordering::SeqCst), 2);
+
+    // a host that can't be reached is passed over for the next one, and the
+    // client says which it ended up using
+    let mut fallback = Client::<CounterState>::new_self_signed("unreachable.invalid:8080");
+    fallback.set_token(COUNTER_TOKEN);
+    fallback.set_fallback_hosts(vec!["localhost:8080".to_string()]);
+    let connection = fallback.connect().await.expect("connecting to the fallback host failed");
+    assert_eq!(connection.server_host(), "localhost:8080");
+    assert_eq!(connection.server_addr().map(|addr| addr.port()), Some(8080));
+    info!("Fell back to {} at {:?}", connection.server_host(), connection.server_addr());
+    connection.close();
+
+    // a pool spreads calls over several connections, and reconnects the
+    // ones that close
+    let pool = Arc::new(hardlight::ClientPool::<CounterState>::new(3, |_| {
+        let mut client = Client::new_self_signed("localhost:8080");
+        client.set_token(COUNTER_TOKEN);
+        client
+    }));
+    pool.connect().await.expect("connecting the pool failed");
+    let increment = rkyv::to_bytes::<IncrementArgs, 1024>(&IncrementArgs { amount: 1 }).unwrap().to_vec();
+    let increment = rkyv::to_bytes::<RpcCall, 1024>(&RpcCall { method: Method::Increment, args: increment }).unwrap().to_vec();
+    let calls: Vec<_> = (0..30)
+        .map(|_| {
+            let (pool, increment) = (pool.clone(), increment.clone());
+            tokio::spawn(async move { pool.call(increment).await })
+        })
+        .collect();
+    for call in calls {
+        call.await.unwrap().expect("pooled increment failed");
+    }
+    let connections = pool.connections();
+    assert_eq!(connections.len(), 3);
+    for connection in &connections {
+        assert!(connection.channels().state.get_field(|state| state.counter) > 0);
+    }
+    connections[0].close();
+    while !connections[0].is_closed() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    pool.call(increment.clone()).await.expect("pooled increment failed");
+    while pool.connections().len() < 3 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    pool.disconnect();
+
+    // the environment's proxy is skipped for hosts in NO_PROXY
+    std::env::set_var("HTTPS_PROXY", format!("socks5://{socks5_proxy}"));
+    std::env::set_var("NO_PROXY", "example.com, localhost");
+    let mut direct = Client::<CounterState>::new_self_signed("localhost:8080");
+    direct.set_token(COUNTER_TOKEN);
+    direct.set_proxy(hardlight::ProxyConfig::from_env().expect("HTTPS_PROXY is set"));
+    direct.connect().await.expect("connecting directly failed").close();
+    assert_eq!(tunnels.load(Ordering::SeqCst), 2);
+    std::env::remove_var("HTTPS_PROXY");
+    std::env::remove_var("NO_PROXY");
+
+    info!("Server version: {:?}", client.connection.server_version());
+    assert_eq!(client.connection.server_version(), Some(hardlight::HL_VERSION));
 
     let first_value = client.get().await.expect("get failed");
     let num_tasks = 12;
@@ -44,7 +202,8 @@ async fn main() -> Result<(), std::io::Error> {
     info!("Incrementing counter using {num_tasks} tasks with {num_increments_per_task} increments each");
     info!("First value: {}", first_value);
 
-    let counter = Arc::new(client);
+    // clones share the same connection
+    let counter = client;
 
 
     let mut tasks = Vec::new();
@@ -52,10 +211,7 @@ async fn main() -> Result<(), std::io::Error> {
         let counter = counter.clone();
         tasks.push(tokio::spawn(async move {
             for _ in 0..num_increments_per_task {
-                let start = tokio::time::Instant::now();
                 let _ = counter.increment(1).await;
-                let elapsed = start.elapsed();
-                info!("Task: {:?}", elapsed);
             }
         }));
     }
@@ -63,18 +219,1051 @@ async fn main() -> Result<(), std::io::Error> {
     for task in tasks {
         task.await.expect("task failed");
     }
+
+    let stats = counter.connection.stats();
+    info!(
+        "Calls: {}, errors: {}, in flight: {}, p50 latency: {:?}, p99 latency: {:?}, p99 queue wait: {:?}",
+        stats.calls(),
+        stats.errors(),
+        stats.in_flight(),
+        stats.p50_latency(),
+        stats.p99_latency(),
+        stats.p99_queue_wait(),
+    );
+    assert_eq!(stats.in_flight(), 0);
     
 
     let final_value = counter.get().await.expect("get failed");
 
     info!("Final value: {}", final_value);
+    info!("Final value from state: {}", counter.state().get_field(|state| state.counter));
 
     // make sure server-side mutex is working...
     assert!(final_value == first_value + (num_tasks * num_increments_per_task) as u32);
 
+    info!("Method stats: {:?}", server_handle.method_stats());
+
+    // a call's state changes arrive before its response
+    let value = counter.increment(1).await.expect("increment failed");
+    assert_eq!(counter.state().get_field(|state| state.counter), value);
+    let final_value = counter.decrement(1).await.expect("decrement failed");
+    assert_eq!(counter.state().get_field(|state| state.counter), final_value);
+
+    // the counter is pushed as it changes, but the lazy number of changes
+    // stays as it was until it's fetched
+    assert_eq!(counter.state().get_field(|state| state.changes), 0);
+    counter.connection.fetch_fields(&[CHANGES]).await.expect("fetch failed");
+    let changes = counter.state().get_field(|state| state.changes);
+    assert_eq!(changes, (num_tasks * num_increments_per_task + 2) as u32);
+    counter.increment(1).await.expect("increment failed");
+    assert_eq!(counter.state().get_field(|state| state.changes), changes);
+    counter.connection.fetch_fields(&[CHANGES]).await.expect("fetch failed");
+    assert_eq!(counter.state().get_field(|state| state.changes), changes + 1);
+    let final_value = counter.decrement(1).await.expect("decrement failed");
+
+    // changes made in a transaction reach the client when it's committed, and
+    // never if it's rolled back
+    let channels = counter.connection.channels();
+    let transaction = channels.begin_transaction().await.expect("begin failed");
+    counter.increment(1).await.expect("increment failed");
+    counter.increment(1).await.expect("increment failed");
+    assert_eq!(counter.state().get_field(|state| state.counter), final_value);
+    // only the open transaction can be committed
+    assert!(matches!(channels.commit_transaction(transaction + 1).await, Err(RpcHandlerError::InvalidTransactionOp)));
+    channels.commit_transaction(transaction).await.expect("commit failed");
+    assert_eq!(counter.state().get_field(|state| state.counter), final_value + 2);
+    let transaction = channels.begin_transaction().await.expect("begin failed");
+    counter.decrement(2).await.expect("decrement failed");
+    channels.rollback_transaction(transaction).await.expect("rollback failed");
+    assert_eq!(counter.get().await.expect("get failed"), final_value + 2);
+    assert_eq!(counter.state().get_field(|state| state.counter), final_value + 2);
+
+    // a call can say how long the client waits, which the handler can see
+    let get = rkyv::to_bytes::<RpcCall, 1024>(&RpcCall { method: Method::Get, args: vec![] }).unwrap().to_vec();
+    let output = counter.connection.call_with_timeout(get, Duration::from_secs(5)).await.expect("timed get failed");
+    assert_eq!(rkyv::from_bytes::<u32>(&output).unwrap(), final_value + 2);
+
+    // a batch of calls goes out in one frame, with the results in order
+    let increment = |amount| {
+        let args = rkyv::to_bytes::<IncrementArgs, 1024>(&IncrementArgs { amount }).unwrap().to_vec();
+        rkyv::to_bytes::<RpcCall, 1024>(&RpcCall { method: Method::Increment, args }).unwrap().to_vec()
+    };
+    let outputs = counter.connection.call_batch(vec![increment(1), increment(2), increment(3)]).await;
+    assert_eq!(outputs.len(), 3);
+    for output in outputs {
+        let _: u32 = rkyv::from_bytes(&output.expect("batched increment failed")).unwrap();
+    }
+    assert_eq!(counter.get().await.expect("get failed"), final_value + 8);
+    let final_value = counter.decrement(8).await.expect("decrement failed");
+
+    // application errors come back typed, separate from transport errors
+    match split_application_error::<_, CounterError>(counter.decrement(final_value + 1).await) {
+        Ok(Err(e)) => info!("Decrementing past zero failed as expected: {:?}", e),
+        other => panic!("expected an underflow error, got {:?}", other),
+    }
+
+    // the same handler, driven in-process without binding a port
+    let server = Server::new(ServerConfig::new_self_signed("localhost:8080"), CounterHandler::init());
+    let test_client = hardlight::testing::connect::<CounterState, _>(&server)
+        .await
+        .expect("in-memory connect failed");
+    let args = rkyv::to_bytes::<IncrementArgs, 1024>(&IncrementArgs { amount: 5 }).unwrap().to_vec();
+    let call = rkyv::to_bytes::<RpcCall, 1024>(&RpcCall { method: Method::Increment, args }).unwrap().to_vec();
+    let (_, state) = test_client.call_with_state_change(call).await.expect("increment failed");
+    assert_eq!(state.counter, 5);
+    info!("In-memory increment updated the state to {}", state.counter);
+
+    // inputs over the server's limit are refused before they're decoded
+    let mut config = ServerConfig::new_self_signed("localhost:8080");
+    config.max_call_input_size = Some(256);
+    let server = Server::new(config, CounterHandler::init());
+    let test_client = hardlight::testing::connect::<CounterState, _>(&server)
+        .await
+        .expect("in-memory connect failed");
+    let args = rkyv::to_bytes::<IncrementArgs, 1024>(&IncrementArgs { amount: 1 }).unwrap().to_vec();
+    let call = rkyv::to_bytes::<RpcCall, 1024>(&RpcCall { method: Method::Increment, args }).unwrap().to_vec();
+    test_client.call(call).await.expect("small increment failed");
+    let call = rkyv::to_bytes::<RpcCall, 1024>(&RpcCall { method: Method::Get, args: vec![0; 1024] }).unwrap().to_vec();
+    assert!(matches!(
+        test_client.call(call).await,
+        Err(RpcHandlerError::PayloadTooLarge { limit: 256, .. })
+    ));
+
+    // or without a server at all, through a regular connection
+    let (connection, _) = hardlight::testing::connect_in_memory::<CounterState, _>(CounterHandler::init())
+        .await
+        .expect("in-memory connect failed");
+    let in_memory = CounterClient { connection };
+    let value = in_memory.increment(3).await.expect("increment failed");
+    assert_eq!(in_memory.state().get_field(|state| state.counter), value);
+    info!("In-memory increment without a server returned {}", value);
+
+    // a burst of state changes is coalesced into a few batches, but a call's
+    // changes still arrive before its response
+    let mut config = ServerConfig::new_self_signed("localhost");
+    config.state_coalescing = Some(Duration::from_millis(50));
+    let server = Server::new(config, |state_update_channel, ctx| Box::new(BurstHandler::new(state_update_channel, ctx)));
+    let test_client = hardlight::testing::connect::<BatchCountingState, _>(&server)
+        .await
+        .expect("in-memory connect failed");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while test_client.state().counter.counter != BURST_SIZE {
+        assert!(Instant::now() < deadline, "burst never arrived");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let batches = test_client.state().batches;
+    assert!(batches < 10, "{BURST_SIZE} changes arrived in {batches} batches");
+    test_client.call(vec![]).await.expect("call failed");
+    assert_eq!(test_client.state().counter.counter, 0);
+    info!("{BURST_SIZE} state changes were coalesced into {batches} batch(es)");
+
+    // state shared by every connection reaches all of them, alongside each
+    // one's own state
+    let total = Arc::new(SharedState::new(TotalCount::default()));
+    let server = Server::new(ServerConfig::new_self_signed("localhost"), SharedCountHandler::init(total.clone()));
+    let first = hardlight::testing::connect::<CombinedCount, _>(&server).await.expect("in-memory connect failed");
+    let second = hardlight::testing::connect::<CombinedCount, _>(&server).await.expect("in-memory connect failed");
+    first.call(vec![]).await.expect("call failed");
+    first.call(vec![]).await.expect("call failed");
+    second.call(vec![]).await.expect("call failed");
+    let state = second.state();
+    assert_eq!((state.mine, state.total), (1, 3));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while first.state().total != 3 {
+        assert!(Instant::now() < deadline, "shared change never arrived");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(first.state().mine, 2);
+    // connections that join later start from the shared state as it is
+    let late = hardlight::testing::connect::<CombinedCount, _>(&server).await.expect("in-memory connect failed");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while late.state().total != 3 {
+        assert!(Instant::now() < deadline, "shared state never arrived");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(late.state().mine, 0);
+    assert_eq!(total.subscribers(), 3);
+    // a transaction doesn't hold back other connections' shared changes, so
+    // rolling it back doesn't leave the client's copy of them stale
+    let transaction = first.channels().begin_transaction().await.expect("begin failed");
+    second.call(vec![]).await.expect("call failed");
+    first.channels().rollback_transaction(transaction).await.expect("rollback failed");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while first.state().total != 4 {
+        assert!(Instant::now() < deadline, "shared change was lost in the rollback");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // but its own only reach other clients once it's committed
+    let transaction = first.channels().begin_transaction().await.expect("begin failed");
+    first.call(vec![]).await.expect("call failed");
+    let state = first.state();
+    assert_eq!((state.mine, state.total), (2, 4));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(second.state().total, 4);
+    first.channels().commit_transaction(transaction).await.expect("commit failed");
+    let state = first.state();
+    assert_eq!((state.mine, state.total), (3, 5));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while second.state().total != 5 {
+        assert!(Instant::now() < deadline, "committed change never arrived");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    // and never if it's rolled back, which undoes them for every connection
+    let transaction = first.channels().begin_transaction().await.expect("begin failed");
+    first.call(vec![]).await.expect("call failed");
+    first.channels().rollback_transaction(transaction).await.expect("rollback failed");
+    second.call(vec![]).await.expect("call failed");
+    let state = second.state();
+    assert_eq!((state.mine, state.total), (3, 6));
+    first.call(vec![]).await.expect("call failed");
+    let state = first.state();
+    assert_eq!((state.mine, state.total), (4, 7));
+    info!("Three connections share a total of {}", late.state().total);
+
+    // committing waits while the channel is full, e.g. because the client is
+    // behind, and fails once there's no one to tell
+    let (channel, mut changes) = tokio::sync::mpsc::channel(1);
+    let counted = ConnectionState::new(channel, CounterState::default());
+    let mut guard = counted.lock().await;
+    guard.counter = 1;
+    guard.commit().await.expect("commit failed");
+    guard.counter = 2;
+    assert!(tokio::time::timeout(Duration::from_millis(50), guard.commit()).await.is_err());
+    changes.try_recv().unwrap();
+    guard.counter = 3;
+    guard.commit().await.expect("commit failed");
+    drop(changes);
+    guard.counter = 4;
+    assert!(matches!(guard.commit().await, Err(RpcHandlerError::ClientNotConnected)));
+    drop(guard);
+
+    // a tracked state only sends the fields that were changed. The channels
+    // have room for every change made below, as most aren't read
+    let (channel, mut changes) = tokio::sync::mpsc::channel(1024);
+    let tracked = TrackedState::new(channel, TrackedInventory::default());
+    tracked.lock().await.items.extend(0..INVENTORY_SIZE);
+    assert_eq!(changes.try_recv().unwrap().iter().map(|(field, _)| *field).collect::<Vec<_>>(), [ITEMS]);
+    let mut inventory = tracked.lock().await;
+    assert_eq!(inventory.items.len(), INVENTORY_SIZE as usize);
+    *inventory.revision += 1;
+    drop(inventory);
+    assert_eq!(changes.try_recv().unwrap().iter().map(|(field, _)| *field).collect::<Vec<_>>(), [REVISION]);
+    let mut inventory = tracked.lock().await;
+    inventory.items.push(0);
+    inventory.suppress();
+    drop(inventory);
+    tracked.lock().await;
+    assert!(changes.try_recv().is_err());
+
+    // and doesn't clone the state to find them, unlike a ConnectionState
+    let (channel, _changes) = tokio::sync::mpsc::channel(1024);
+    let cloned = ConnectionState::new(channel, PlainInventory { items: (0..INVENTORY_SIZE).collect(), revision: 0 });
+    let started_at = Instant::now();
+    for _ in 0..1000 {
+        cloned.lock().await.revision += 1;
+    }
+    let cloned_time = started_at.elapsed();
+    let started_at = Instant::now();
+    for _ in 0..1000 {
+        *tracked.lock().await.revision += 1;
+    }
+    let tracked_time = started_at.elapsed();
+    info!(
+        "1000 locks of a state with {INVENTORY_SIZE} items took {:?} cloned and {:?} tracked",
+        cloned_time, tracked_time
+    );
+
+    // call ids are freed however calls end, so they can be reused over and
+    // over, many at a time
+    let server = Server::new(ServerConfig::new_self_signed("localhost"), |state_update_channel, ctx| {
+        Box::new(ChurnHandler::new(state_update_channel, ctx))
+    });
+    let test_client = hardlight::testing::connect::<CounterState, _>(&server)
+        .await
+        .expect("in-memory connect failed");
+    let mut calls = tokio::task::JoinSet::new();
+    for round in 0..20u32 {
+        for call in 0..200u32 {
+            let channels = test_client.channels().clone();
+            let n = round * 200 + call;
+            calls.spawn(async move { (n, channels.call(n.to_le_bytes().to_vec()).await) });
+        }
+        while let Some(result) = calls.join_next().await {
+            match result.expect("call task failed") {
+                (n, Err(RpcHandlerError::BadInputBytes)) => assert_eq!(n % 7, 0),
+                (n, Ok(output)) => assert_eq!(output, n.to_le_bytes()),
+                (n, Err(e)) => panic!("call {n} failed: {e:?}"),
+            }
+        }
+    }
+    // a call whose task panics outside the handler is still answered, and
+    // its id freed without closing the connection
+    for _ in 0..300 {
+        let panicked = tokio::time::timeout(Duration::from_secs(5), test_client.call(b"panic".to_vec()))
+            .await
+            .expect("call that panicked was never answered");
+        assert!(matches!(panicked, Err(RpcHandlerError::CallAbandoned)));
+    }
+    let output = test_client.call(7u32.to_le_bytes().to_vec()).await;
+    assert!(matches!(output, Err(RpcHandlerError::BadInputBytes)));
+    info!("4000 calls churned through 256 ids");
+
+    // a call to the client that times out keeps its id until the client's
+    // late answer arrives, so the answer can't complete a newer call
+    let mut asking_config = ServerConfig::new_self_signed("127.0.0.1:0");
+    asking_config.client_call_timeout = Duration::from_millis(100);
+    let asking_server = Server::new(asking_config, |state_update_channel, ctx| {
+        Box::new(AskClientHandler::new(state_update_channel, ctx))
+    });
+    let (asking_ready_tx, asking_ready_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let _ = asking_server.run_with_ready(asking_ready_tx).await;
+    });
+    let asking_address = asking_ready_rx.await.expect("server failed to start");
+    let mut answering = Client::<CounterState>::new_self_signed(&asking_address.to_string());
+    answering.set_handler(Arc::new(LateAnswers));
+    let answering = answering.connect().await.expect("connect failed");
+    let mut timed_out = tokio::task::JoinSet::new();
+    for _ in 0..256 {
+        let answering = answering.clone();
+        timed_out.spawn(async move { answering.call(b"slow".to_vec()).await });
+    }
+    while let Some(result) = timed_out.join_next().await {
+        assert!(matches!(result.expect("call task failed"), Err(RpcHandlerError::Timeout)));
+    }
+    // every id is waiting on a late answer
+    assert!(matches!(
+        answering.call(b"fast".to_vec()).await,
+        Err(RpcHandlerError::TooManyCallsInFlight)
+    ));
+    tokio::time::sleep(LATE_ANSWER_DELAY).await;
+    assert_eq!(answering.call(b"fast".to_vec()).await.expect("call failed"), b"fast");
+    answering.close();
+    info!("Late answers to calls that timed out were ignored");
+
+    // a connection with all its slots busy can have its calls turned away
+    // rather than queued
+    let mut limited_config = ServerConfig::new_self_signed("localhost");
+    limited_config.max_concurrent_calls_per_connection = Some(2);
+    limited_config.call_limit_mode = CallLimitMode::RateLimit {
+        retry_after: Some(Duration::from_millis(50)),
+    };
+    let limited_server = Server::new(limited_config, |state_update_channel, ctx| {
+        Box::new(NappingHandler::new(state_update_channel, ctx))
+    });
+    let limited = hardlight::testing::connect::<CounterState, _>(&limited_server)
+        .await
+        .expect("in-memory connect failed");
+    let napping: Vec<_> = (0..2)
+        .map(|_| {
+            let channels = limited.channels().clone();
+            tokio::spawn(async move { channels.call(vec![]).await })
+        })
+        .collect();
+    tokio::time::sleep(NAP / 4).await;
+    assert!(matches!(
+        limited.call(vec![]).await,
+        Err(RpcHandlerError::RateLimited { retry_after_ms: Some(50) })
+    ));
+    for call in napping {
+        call.await.expect("call task failed").expect("call failed");
+    }
+    limited.call(vec![]).await.expect("call after the others finished failed");
+    info!("A call over the concurrency limit was rate limited");
+
+    // calls that wait for a slot report how long they waited, apart from how
+    // long their handler took
+    let queue_waits = Arc::new(QueueWaits::default());
+    let mut queued_config = ServerConfig::new_self_signed("localhost");
+    queued_config.max_concurrent_calls_per_connection = Some(1);
+    queued_config.metrics_hook = Some(queue_waits.clone());
+    let queued_server = Server::new(queued_config, |state_update_channel, ctx| {
+        Box::new(NappingHandler::new(state_update_channel, ctx))
+    });
+    let queued = hardlight::testing::connect::<CounterState, _>(&queued_server)
+        .await
+        .expect("in-memory connect failed");
+    let calls: Vec<_> = (0..3)
+        .map(|_| {
+            let channels = queued.channels().clone();
+            tokio::spawn(async move { channels.call(vec![]).await })
+        })
+        .collect();
+    for call in calls {
+        call.await.expect("call task failed").expect("call failed");
+    }
+    let mut waits = queue_waits.0.lock().clone();
+    waits.sort();
+    assert_eq!(waits.len(), 3);
+    // the first call started straight away, and the others waited for it
+    assert!(waits[0] < NAP / 4, "the first call was queued for {:?}", waits[0]);
+    assert!(waits[1] >= NAP / 2 && waits[2] >= NAP * 3 / 2, "queued calls waited only {:?}", waits);
+    info!("Calls waited {:?} for a free slot", waits);
+
+    // however many calls arrive at once, no more than the limit run together
+    let peak = Arc::new(Concurrency::default());
+    let mut capped_config = ServerConfig::new_self_signed("localhost");
+    capped_config.max_concurrent_calls_per_connection = Some(3);
+    let capped_server = Server::new(capped_config, {
+        let peak = peak.clone();
+        move |_, _| Box::new(ConcurrencyTracker(peak.clone()))
+    });
+    let capped = hardlight::testing::connect::<CounterState, _>(&capped_server)
+        .await
+        .expect("in-memory connect failed");
+    let calls: Vec<_> = (0..10)
+        .map(|_| {
+            let channels = capped.channels().clone();
+            tokio::spawn(async move { channels.call(vec![]).await })
+        })
+        .collect();
+    for call in calls {
+        call.await.expect("call task failed").expect("call failed");
+    }
+    assert_eq!(peak.highest.load(Ordering::SeqCst), 3);
+    info!("10 calls at once ran at most 3 at a time");
+
+    // events count towards the rate limit like calls do, so a flood of them
+    // is cut off rather than each getting a task of its own
+    let mut flooded_config = ServerConfig::new_self_signed("localhost");
+    flooded_config.connection_rate_limit = Some(hardlight::Rate::per_minute(10));
+    let events_handled = Arc::new(AtomicUsize::new(0));
+    let flooded_server = Server::new(flooded_config, {
+        let events_handled = events_handled.clone();
+        move |_, _| {
+            Box::new(EventCounter {
+                handled: events_handled.clone(),
+            })
+        }
+    });
+    let flooded = hardlight::testing::connect::<CounterState, _>(&flooded_server)
+        .await
+        .expect("in-memory connect failed");
+    for _ in 0..100 {
+        flooded.channels().event_tx.send(vec![]).await.expect("sending event failed");
+    }
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while events_handled.load(Ordering::SeqCst) < 10 {
+        assert!(Instant::now() < deadline, "events within the limit were never handled");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(matches!(flooded.call(vec![]).await, Err(RpcHandlerError::RateLimited { .. })));
+    assert_eq!(events_handled.load(Ordering::SeqCst), 10);
+    info!("100 events over a limit of 10 were cut off at 10");
+
+    // events the call limit drops don't use up the rate limit
+    let mut busy_config = ServerConfig::new_self_signed("localhost");
+    busy_config.max_concurrent_calls_per_connection = Some(1);
+    busy_config.call_limit_mode = CallLimitMode::RateLimit { retry_after: None };
+    busy_config.connection_rate_limit = Some(hardlight::Rate::per_minute(3));
+    let busy_server = Server::new(busy_config, |state_update_channel, ctx| {
+        Box::new(NappingHandler::new(state_update_channel, ctx))
+    });
+    let busy = hardlight::testing::connect::<CounterState, _>(&busy_server)
+        .await
+        .expect("in-memory connect failed");
+    let napping = {
+        let channels = busy.channels().clone();
+        tokio::spawn(async move { channels.call(vec![]).await })
+    };
+    tokio::time::sleep(NAP / 4).await;
+    for _ in 0..20 {
+        busy.channels().event_tx.send(vec![]).await.expect("sending event failed");
+    }
+    napping.await.expect("call task failed").expect("call failed");
+    for _ in 0..2 {
+        busy.call(vec![]).await.expect("call within the rate limit failed");
+    }
+    info!("Events dropped by the call limit left the rate limit alone");
+
+    // handlers still running when the client goes away are cancelled
+    let (connection, _) = hardlight::testing::connect_in_memory::<CounterState, _>(|state_update_channel, ctx| {
+        Box::new(SlowHandler::new(state_update_channel, ctx))
+    })
+    .await
+    .expect("in-memory connect failed");
+    let slow_call = tokio::spawn({
+        let connection = connection.clone();
+        async move { connection.call(vec![]).await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let closed_at = Instant::now();
+    connection.close();
+    assert!(slow_call.await.unwrap().is_err());
+    wait_for_slow_calls_stopped(1, closed_at).await;
+    info!("Slow handler was cancelled {:?} after the client disconnected", closed_at.elapsed());
+
+    // and ones still running at the call's deadline are stopped
+    let (connection, _) = hardlight::testing::connect_in_memory::<CounterState, _>(|state_update_channel, ctx| {
+        Box::new(SlowHandler::new(state_update_channel, ctx))
+    })
+    .await
+    .expect("in-memory connect failed");
+    let called_at = Instant::now();
+    let output = connection.call_with_timeout(vec![], Duration::from_millis(100)).await;
+    assert!(matches!(output, Err(RpcHandlerError::Timeout)));
+    wait_for_slow_calls_stopped(2, called_at).await;
+    info!("Slow handler was stopped {:?} after its call was made with a deadline", called_at.elapsed());
+    connection.close();
+
+    // so are events, whether or not their handlers check the connection's
+    // cancellation
+    let (connection, _) = hardlight::testing::connect_in_memory::<CounterState, _>(|state_update_channel, ctx| {
+        Box::new(SlowHandler::new(state_update_channel, ctx))
+    })
+    .await
+    .expect("in-memory connect failed");
+    connection.channels().event_tx.send(vec![]).await.expect("sending event failed");
+    connection.channels().event_tx.send(b"stubborn".to_vec()).await.expect("sending event failed");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let closed_at = Instant::now();
+    connection.close();
+    wait_for_slow_calls_stopped(4, closed_at).await;
+    info!("Slow event handlers were stopped {:?} after the client disconnected", closed_at.elapsed());
+
+    // handlers can use the crate's ConnectionState instead of their own guard,
+    // and factories can capture what every handler shares
+    let total_tallies = Arc::new(AtomicUsize::new(0));
+    let (connection, _) = hardlight::testing::connect_in_memory::<CounterState, _>(TallyHandler::init(total_tallies.clone()))
+        .await
+        .expect("in-memory connect failed");
+    for expected in 1..=3u32 {
+        let output = connection.call(vec![]).await.expect("tally failed");
+        assert_eq!(rkyv::from_bytes::<u32>(&output).unwrap(), expected);
+        let state = connection.state().borrow().clone();
+        assert_eq!((state.counter, state.changes), (expected, expected));
+    }
+    assert_eq!(total_tallies.load(Ordering::SeqCst), 3);
+    connection.close();
+    info!("Tallied with a ConnectionState, committing explicitly");
+
+    // fields the client doesn't know about are skipped, unless the state is
+    // strict about them
+    let added = || vec![(2, rkyv::to_bytes::<u32, 1024>(&1).unwrap().to_vec())];
+    let mut state = CounterState::default();
+    assert!(state.knows_field(CHANGES) && !state.knows_field(2));
+    state.apply_changes(added()).expect("unknown field wasn't ignored");
+    assert!(matches!(
+        state.apply_changes(vec![(COUNTER, vec![1])]),
+        Err(RpcHandlerError::BadInputBytes)
+    ));
+    assert!(matches!(
+        StrictCounterState(CounterState::default()).apply_changes(added()),
+        Err(RpcHandlerError::UnknownStateField(2))
+    ));
+
+    // a batch with a bad change in it leaves the state as it was
+    let mut state = CounterState { counter: 1, changes: 1 };
+    let five = rkyv::to_bytes::<u32, 1024>(&5).unwrap().to_vec();
+    assert!(matches!(
+        state.apply_batch(vec![(COUNTER, five), (CHANGES, vec![1])]),
+        Err(RpcHandlerError::BadInputBytes)
+    ));
+    assert_eq!((state.counter, state.changes), (1, 1));
+
+    // and the client applies changes in place, rather than to a copy
+    let (connection, _) = hardlight::testing::connect_in_memory::<CopyCountingState, _>(TallyHandler::init(Arc::default()))
+        .await
+        .expect("in-memory connect failed");
+    for expected in 1..=3u32 {
+        connection.call(vec![]).await.expect("tally failed");
+        let mut state = connection.state();
+        while state.borrow_and_update().changes != expected {
+            state.changed().await.expect("connection closed");
+        }
+    }
+    assert_eq!(STATE_COPIES.load(Ordering::SeqCst), 0);
+    connection.close();
+    info!("State changes were applied without copying the state");
+
+    // connections without the counter.write scope can read but not change it
+    let (connection, _) = hardlight::testing::connect_in_memory::<CounterState, _>(CounterHandler::init_read_only())
+        .await
+        .expect("in-memory connect failed");
+    let read_only = CounterClient { connection };
+    assert_eq!(read_only.get().await.expect("get failed"), 0);
+    assert!(matches!(read_only.increment(1).await, Err(RpcHandlerError::Unauthorized)));
+    info!("Incrementing without the counter.write scope was refused as expected");
+
+    // several services on one port, picked by path
+    // listening on IPv4 and IPv6 loopback at once
+    let mut multi_config = ServerConfig::new_self_signed("127.0.0.1:0");
+    multi_config.additional_addresses = vec!["[::1]:0".to_string()];
+    let multi_server = Server::new_multi(multi_config)
+        .add_service("/counter", CounterHandler::init())
+        .add_service("/read-only", CounterHandler::init_read_only());
+    let multi_server = Arc::new(multi_server);
+    let (multi_ready_tx, multi_ready_rx) = oneshot::channel();
+    tokio::spawn({
+        let multi_server = multi_server.clone();
+        async move {
+            let _ = multi_server.run_with_ready(multi_ready_tx).await;
+        }
+    });
+    multi_ready_rx.await.expect("multi-service server failed to start");
+    let multi_addrs = multi_server.local_addrs();
+    assert_eq!(multi_addrs.len(), 2);
+    info!("Multi-service server listening on {:?}", multi_addrs);
+    let service_client = |path: &str| {
+        let mut client = Client::new_self_signed(&multi_addrs[0].to_string());
+        client.set_path(path);
+        client
+    };
+    // the query string is passed along but doesn't change the service
+    let counter_service = CounterClient::connect(service_client("/counter?room=abc")).await.unwrap();
+    assert_eq!(counter_service.increment(4).await.expect("increment failed"), 4);
+    let mut ipv6_client = Client::new_self_signed(&multi_addrs[1].to_string());
+    ipv6_client.set_path("/read-only");
+    let read_only_service = CounterClient::connect(ipv6_client).await.unwrap();
+    assert!(matches!(read_only_service.increment(1).await, Err(RpcHandlerError::Unauthorized)));
+    match service_client("/missing").connect().await {
+        Err(ConnectError::HandshakeRejected { status }) if status == 404 => {
+            info!("Connecting to a path without a service failed as expected")
+        }
+        Err(e) => panic!("expected a 404, got {}", e),
+        Ok(_) => panic!("expected a 404"),
+    }
+    counter_service.disconnect();
+    read_only_service.disconnect();
+
+    // over a Unix socket, e.g. to a sidecar, without TCP or TLS
+    #[cfg(unix)]
+    {
+        let socket_path = std::env::temp_dir().join("hardlight-counter.sock");
+        let mut unix_config = ServerConfig::new_unix(&socket_path);
+        unix_config.health_check_path = Some("/healthz".to_string());
+        unix_config.allowed_origins = Some(vec!["https://app.example.com".to_string()]);
+        unix_config.allowed_hosts = Some(vec!["localhost".to_string()]);
+        let unix_server = Server::new(unix_config, CounterHandler::init());
+        let (unix_ready_tx, unix_ready_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = unix_server.run_with_ready(unix_ready_tx).await;
+        });
+        unix_ready_rx.await.expect("unix server failed to start");
+        let unix_client = CounterClient::connect(Client::new_unix(&socket_path))
+            .await
+            .expect("unix connect failed");
+        let value = unix_client.increment(2).await.expect("increment failed");
+        assert_eq!(value, 2);
+        assert_eq!(unix_client.state().get_field(|state| state.counter), value);
+        info!("Increment over a Unix socket returned {}", value);
+
+        // plain HTTP requests, e.g. from a load balancer, get a response too
+        for (path, status) in [("/healthz", "HTTP/1.1 200 OK"), ("/", "HTTP/1.1 404 Not Found")] {
+            let mut probe = tokio::net::UnixStream::connect(&socket_path).await?;
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            probe.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            probe.read_to_string(&mut response).await?;
+            assert!(response.starts_with(status), "unexpected response to {}: {:?}", path, response);
+        }
+        info!("Health check answered over plain HTTP");
+
+        // the unix client sent no origin, as it isn't a browser, but a page
+        // on another site is turned away, as is an unexpected host
+        for (origin, host, forbidden) in [
+            ("https://evil.example.com", "localhost", true),
+            ("HTTPS://App.Example.com:443", "LOCALHOST:443", false),
+            ("https://app.example.com", "example.com", true),
+        ] {
+            let mut probe = tokio::net::UnixStream::connect(&socket_path).await?;
+            let request = format!(
+                "GET / HTTP/1.1\r\nHost: {}\r\nOrigin: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+                host, origin
+            );
+            probe.write_all(request.as_bytes()).await?;
+            let mut response = vec![0; 12];
+            probe.read_exact(&mut response).await?;
+            assert_eq!(response == b"HTTP/1.1 403", forbidden, "{} from {}", host, origin);
+        }
+        info!("Origin and host checks passed");
+
+        // a client sending text, e.g. JSON, is told it's unsupported
+        let text_socket_path = socket_path.clone();
+        let close = tokio::task::spawn_blocking(move || {
+            use hardlight::tungstenite::{client::IntoClientRequest, protocol::frame::coding::CloseCode, Message};
+            let stream = std::os::unix::net::UnixStream::connect(text_socket_path).unwrap();
+            let mut request = "ws://localhost/".into_client_request().unwrap();
+            let protocol = format!("hl/{}", hardlight::PROTOCOL_VERSION);
+            request.headers_mut().insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+            let (mut socket, _) = hardlight::tungstenite::client(request, stream).expect("handshake failed");
+            socket.write_message(Message::Text("{\"method\": \"get\"}".into())).unwrap();
+            loop {
+                match socket.read_message() {
+                    Ok(Message::Close(frame)) => return frame.map(|frame| frame.code),
+                    Ok(_) => continue,
+                    Err(_) => return Some(CloseCode::Abnormal),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(close, Some(hardlight::tungstenite::protocol::frame::coding::CloseCode::Unsupported));
+        info!("Text message was refused with close code {:?}", close);
+        unix_client.disconnect();
+        let _ = std::fs::remove_file(&socket_path);
+
+        // a client whose server has stopped reading stops taking on events
+        // once its queue is full, rather than queueing them without end
+        let deaf_path = std::env::temp_dir().join("hardlight-deaf.sock");
+        let _ = std::fs::remove_file(&deaf_path);
+        let listener = std::os::unix::net::UnixListener::bind(&deaf_path)?;
+        let (hang_up_tx, hang_up_rx) = std::sync::mpsc::channel::<()>();
+        let deaf_server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let _socket = hardlight::tungstenite::accept_hdr(stream, PickCurrentVersion).expect("handshake failed");
+            let _ = hang_up_rx.recv();
+        });
+        let deaf = Client::<CounterState>::new_unix(&deaf_path).connect().await.expect("unix connect failed");
+        let event = vec![7; 64 * 1024];
+        let mut sent = 0;
+        let _ = tokio::time::timeout(Duration::from_secs(1), async {
+            for _ in 0..1000 {
+                deaf.channels().event_tx.send(event.clone()).await.expect("sending event failed");
+                sent += 1;
+            }
+        })
+        .await;
+        assert!(sent < 100, "{sent} events were queued for a server that isn't reading");
+        info!("A server that stopped reading held the client to {} queued events", sent);
+        drop(hang_up_tx);
+        deaf_server.join().expect("deaf server panicked");
+        deaf.close();
+        let _ = std::fs::remove_file(&deaf_path);
+
+        // and a client that doesn't read its responses stops being read
+        // from, rather than having them queued without end
+        let hoarder_path = std::env::temp_dir().join("hardlight-hoarder.sock");
+        let calls_handled = Arc::new(AtomicUsize::new(0));
+        let hoarder_server = Server::new(ServerConfig::new_unix(&hoarder_path), {
+            let calls_handled = calls_handled.clone();
+            move |_, _| {
+                Box::new(BulkResponder {
+                    handled: calls_handled.clone(),
+                })
+            }
+        });
+        let (hoarder_ready_tx, hoarder_ready_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = hoarder_server.run_with_ready(hoarder_ready_tx).await;
+        });
+        hoarder_ready_rx.await.expect("hoarder server failed to start");
+        let calls = 2000;
+        let hoarder = tokio::task::spawn_blocking({
+            let hoarder_path = hoarder_path.clone();
+            move || {
+                use hardlight::tungstenite::{client::IntoClientRequest, Message};
+                let stream = std::os::unix::net::UnixStream::connect(hoarder_path).unwrap();
+                // once the server stops reading, sending blocks
+                stream.set_write_timeout(Some(Duration::from_millis(200))).unwrap();
+                let mut request = "ws://localhost/".into_client_request().unwrap();
+                let protocol = format!("hl/{PROTOCOL_VERSION}");
+                request.headers_mut().insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+                let (mut socket, _) = hardlight::tungstenite::client(request, stream).expect("handshake failed");
+                for id in 0..calls {
+                    // a moment between rounds of ids, for the last round to
+                    // have been answered
+                    if id % 256 == 0 {
+                        std::thread::sleep(Duration::from_millis(20));
+                    }
+                    let call = ClientMessage::RPCRequest {
+                        id: (id % 256) as u8,
+                        internal: vec![],
+                    };
+                    let binary = rkyv::to_bytes::<_, 1024>(&call).unwrap().to_vec();
+                    if socket.write_message(Message::Binary(binary)).is_err() {
+                        return (socket, id);
+                    }
+                }
+                (socket, calls)
+            }
+        })
+        .await
+        .unwrap();
+        let (hoarder, sent) = hoarder;
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        // the first round, which it could have in flight at once, is
+        // answered, but the rest wait until it reads
+        let handled = calls_handled.load(Ordering::SeqCst);
+        assert!(handled <= 256, "{handled} of {sent} calls were answered for a client that isn't reading");
+        info!("A client that stopped reading had {} of its {} calls answered", handled, sent);
+        drop(hoarder);
+        let _ = std::fs::remove_file(&hoarder_path);
+
+        // with compression on, the server accepts permessage-deflate from
+        // clients that offer it, and calls go back and forth compressed, each
+        // way with the smallest window either side asked for
+        let deflate_path = std::env::temp_dir().join("hardlight-deflate.sock");
+        let deflate_config = ServerConfig::new_unix(&deflate_path).compression(CompressionConfig {
+            window_bits: 12,
+            ..CompressionConfig::default()
+        });
+        let deflate_server = Server::new(deflate_config, |_, _| {
+            Box::new(BulkResponder {
+                handled: Arc::default(),
+            })
+        });
+        let (deflate_ready_tx, deflate_ready_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = deflate_server.run_with_ready(deflate_ready_tx).await;
+        });
+        deflate_ready_rx.await.expect("deflate server failed to start");
+        let accepted = tokio::task::spawn_blocking({
+            let deflate_path = deflate_path.clone();
+            move || {
+                use hardlight::tungstenite::client::IntoClientRequest;
+                let stream = std::os::unix::net::UnixStream::connect(deflate_path).unwrap();
+                let mut request = "ws://localhost/".into_client_request().unwrap();
+                let protocol = format!("hl/{PROTOCOL_VERSION}");
+                request.headers_mut().insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+                // what a browser offers
+                let offer = "permessage-deflate; client_max_window_bits".parse().unwrap();
+                request.headers_mut().insert("Sec-WebSocket-Extensions", offer);
+                let (_, response) = hardlight::tungstenite::client(request, stream).expect("handshake failed");
+                response.headers().get("Sec-WebSocket-Extensions").cloned()
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            accepted.unwrap(),
+            "permessage-deflate; server_no_context_takeover; server_max_window_bits=12; client_max_window_bits=12"
+        );
+        let mut compressing = Client::<CounterState>::new_unix(&deflate_path);
+        compressing.set_compression(CompressionConfig {
+            window_bits: 10,
+            ..CompressionConfig::default()
+        });
+        let compressing = compressing.connect().await.expect("connecting with compression failed");
+        for i in 0..8 {
+            let output = compressing.call(vec![i; 200 * 1024]).await.expect("compressed call failed");
+            assert_eq!(output, vec![7; 64 * 1024]);
+        }
+        // and clients that don't offer it are answered as they always were
+        let plain = Client::<CounterState>::new_unix(&deflate_path).connect().await.expect("connecting without compression failed");
+        assert_eq!(plain.call(vec![1; 1024]).await.expect("uncompressed call failed"), vec![7; 64 * 1024]);
+        info!("Made calls with and without compression");
+        compressing.close();
+        plain.close();
+        let _ = std::fs::remove_file(&deflate_path);
+
+        // a large state change that compresses well goes over the wire a lot
+        // smaller, as the server's metrics see it
+        let report_path = std::env::temp_dir().join("hardlight-report.sock");
+        let bytes_out = Arc::new(BytesOut::default());
+        let mut report_config = ServerConfig::new_unix(&report_path).compression(CompressionConfig::default());
+        report_config.metrics_hook = Some(bytes_out.clone());
+        let report_server = Server::new(report_config, |state_update_channel, ctx| {
+            Box::new(ReportHandler::new(state_update_channel, ctx))
+        });
+        let (report_ready_tx, report_ready_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = report_server.run_with_ready(report_ready_tx).await;
+        });
+        report_ready_rx.await.expect("report server failed to start");
+        let mut largest = Vec::new();
+        for compression in [None, Some(CompressionConfig::default())] {
+            let mut client = Client::<ReportState>::new_unix(&report_path);
+            if let Some(compression) = compression {
+                client.set_compression(compression);
+            }
+            let connection = client.connect().await.expect("connecting to the report server failed");
+            connection.call(vec![]).await.expect("report call failed");
+            assert_eq!(connection.state().borrow().report, report());
+            // the hook is told once the message has been flushed, which may
+            // be just after the client has it
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            largest.push(std::mem::take(&mut *bytes_out.0.lock()).into_iter().max().unwrap());
+            connection.close();
+        }
+        let (uncompressed, compressed) = (largest[0], largest[1]);
+        assert!(uncompressed > REPORT_SIZE, "the report took {uncompressed} bytes uncompressed");
+        assert!(compressed < REPORT_SIZE / 10, "the report took {compressed} bytes compressed");
+        info!("A {REPORT_SIZE} byte report took {} bytes on the wire, or {} compressed", uncompressed, compressed);
+        let _ = std::fs::remove_file(&report_path);
+
+        // behind a load balancer speaking the PROXY protocol, per-IP limits
+        // apply to the client addresses it passes on
+        let proxied_path = std::env::temp_dir().join("hardlight-proxied.sock");
+        let mut proxied_config = ServerConfig::new_unix(&proxied_path);
+        proxied_config.proxy_protocol = true;
+        proxied_config.max_connections_per_ip = Some(1);
+        proxied_config.health_check_path = Some("/healthz".to_string());
+        proxied_config.trusted_proxies = vec!["203.0.113.9".parse().unwrap()];
+        let proxied_server = Server::new(proxied_config, CounterHandler::init());
+        let (proxied_ready_tx, proxied_ready_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = proxied_server.run_with_ready(proxied_ready_tx).await;
+        });
+        proxied_ready_rx.await.expect("proxied server failed to start");
+
+        let v1_header = b"PROXY TCP4 203.0.113.7 10.0.0.1 56324 443\r\n".to_vec();
+        // the same client, in the binary format
+        let mut v2_header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+        v2_header.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        let mut v2_ipv6_header = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        v2_ipv6_header.extend_from_slice(&"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        v2_ipv6_header.extend_from_slice(&[0; 16]);
+        v2_ipv6_header.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let upgrade = "GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+        let health_check = "GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let send = |header: Vec<u8>, request: &str| {
+            let request = request.to_string();
+            let proxied_path = proxied_path.clone();
+            async move {
+                let mut stream = tokio::net::UnixStream::connect(proxied_path).await?;
+                stream.write_all(&header).await?;
+                stream.write_all(request.as_bytes()).await?;
+                let mut status = vec![0; 12];
+                // a dropped connection reads as an empty status
+                let read = stream.read(&mut status).await.unwrap_or(0);
+                status.truncate(read);
+                Ok::<_, std::io::Error>((stream, String::from_utf8_lossy(&status).into_owned()))
+            }
+        };
+        // the first connection from 203.0.113.7 is held open...
+        let protocol = format!("Sec-WebSocket-Protocol: hl/{PROTOCOL_VERSION}\r\nHost:");
+        let (held, status) = send(v1_header, &upgrade.replace("Host:", &protocol)).await?;
+        assert_eq!(status, "HTTP/1.1 101");
+        // ...so a second one from it is turned away, whichever format says so
+        let (_, status) = send(v2_header, upgrade).await?;
+        assert_eq!(status, "HTTP/1.1 503");
+        // while another client can still connect
+        let (_, status) = send(v2_ipv6_header.clone(), health_check).await?;
+        assert_eq!(status, "HTTP/1.1 200");
+        // and a connection without the header is dropped
+        let (_, status) = send(Vec::new(), health_check).await?;
+        assert_eq!(status, "");
+        // a client that hasn't sent its header yet doesn't hold up the others
+        let _silent = tokio::net::UnixStream::connect(&proxied_path).await?;
+        let (_, status) = tokio::time::timeout(Duration::from_secs(1), send(v2_ipv6_header, health_check))
+            .await
+            .expect("a silent client held up the accept loop")?;
+        assert_eq!(status, "HTTP/1.1 200");
+
+        // X-Forwarded-For is only believed from the trusted proxy, and then
+        // the rightmost address that isn't the proxy is the client
+        let from = |ip: &str| format!("PROXY TCP4 {ip} 10.0.0.1 56324 443\r\n").into_bytes();
+        let forwarded = |headers: &str| upgrade.replace("Host:", &format!("{headers}Host:"));
+        let (_, status) = send(from("198.51.100.1"), &forwarded("X-Forwarded-For: 198.51.100.2\r\n")).await?;
+        assert_eq!(status, "HTTP/1.1 400", "an untrusted peer's header was believed");
+        let (_, status) = send(from("198.51.100.2"), &forwarded("X-Forwarded-For: 203.0.113.7\r\n")).await?;
+        assert_eq!(status, "HTTP/1.1 400", "an untrusted peer's header was believed");
+        let (_, status) = send(from("203.0.113.9"), &forwarded("X-Forwarded-For: 198.51.100.3, 203.0.113.7\r\n")).await?;
+        assert_eq!(status, "HTTP/1.1 503", "the trusted proxy's header wasn't believed");
+        let (_, status) = send(
+            from("203.0.113.9"),
+            &forwarded("X-Forwarded-For: 203.0.113.7\r\nX-Forwarded-For: [2001:db8::2]:4711, 203.0.113.9\r\n"),
+        )
+        .await?;
+        assert_eq!(status, "HTTP/1.1 400", "the rightmost client wasn't picked");
+        // garbage left of the client doesn't matter, but to its right it
+        // leaves only the proxy to go on
+        let (_, status) = send(from("203.0.113.9"), &forwarded("X-Forwarded-For: not-an-ip, 203.0.113.7\r\n")).await?;
+        assert_eq!(status, "HTTP/1.1 503", "the client behind a garbage entry wasn't found");
+        let (_, status) = send(from("203.0.113.9"), &forwarded("X-Forwarded-For: 203.0.113.7, not-an-ip\r\n")).await?;
+        assert_eq!(status, "HTTP/1.1 400", "an address left of a garbage entry was believed");
+        drop(held);
+        info!("PROXY protocol and X-Forwarded-For headers were applied to per-IP limits");
+        let _ = std::fs::remove_file(&proxied_path);
+    }
+
+    // a client speaking another protocol version is turned away
+    match hardlight::testing::connect_with_version::<CounterState, _>(&server, "hl/999").await {
+        Err(e @ ConnectError::VersionMismatch { .. }) => {
+            assert_eq!(e.to_string(), format!("version mismatch: we speak hl/999, server speaks hl/{PROTOCOL_VERSION}"));
+            info!("Connecting with the wrong version failed as expected: {}", e)
+        }
+        Err(e) => panic!("expected a version mismatch, got {}", e),
+        Ok(_) => panic!("expected a version mismatch"),
+    }
+    // including one from before the wire protocol was versioned apart from
+    // the crate, whose messages it would misread
+    assert!(matches!(
+        hardlight::testing::connect_with_version::<CounterState, _>(&server, "hl/0").await,
+        Err(ConnectError::VersionMismatch { .. })
+    ));
+
+    // sides that speak several versions settle on the highest they share
+    let major = PROTOCOL_VERSION;
+    let mut newer_server = Server::new(ServerConfig::new_self_signed("localhost"), CounterHandler::init());
+    newer_server.protocol_versions = vec![major, major + 1];
+    let offered = format!("hl/{}, hl/{}", major + 1, major);
+    let newer = hardlight::testing::connect_with_version::<CounterState, _>(&newer_server, &offered)
+        .await
+        .expect("negotiating failed");
+    assert_eq!(newer.channels().protocol_version(), format!("hl/{}", major + 1));
+    let current = hardlight::testing::connect::<CounterState, _>(&newer_server).await.expect("connecting failed");
+    assert_eq!(current.channels().protocol_version(), format!("hl/{major}"));
+    info!("Negotiated {} with a newer client and {} with a current one", newer.channels().protocol_version(), current.channels().protocol_version());
+
+    // the counter works the same with messages encoded another way, as long
+    // as both sides agree on it
+    let json_counter = counter_with_codec("localhost:8090", hardlight::JsonCodec).await;
+    assert_eq!(json_counter.connection.protocol_version(), format!("hl/{major}+json"));
+    assert_eq!(json_counter.increment(5).await.expect("JSON increment failed"), 5);
+    assert_eq!(json_counter.decrement(2).await.expect("JSON decrement failed"), 3);
+    assert_eq!(json_counter.state().get_field(|state| state.counter), 3);
+    let bincode_counter = counter_with_codec("localhost:8091", hardlight::BincodeCodec).await;
+    assert_eq!(bincode_counter.increment(7).await.expect("bincode increment failed"), 7);
+    assert_eq!(bincode_counter.state().get_field(|state| state.counter), 7);
+    // JSON in text frames is what a browser speaks, with the payloads in base64
+    let text_counter = counter_with_codec("localhost:8092", hardlight::JsonTextCodec).await;
+    assert_eq!(text_counter.connection.protocol_version(), format!("hl/{major}+json-text"));
+    assert_eq!(text_counter.increment(4).await.expect("JSON text increment failed"), 4);
+    assert_eq!(text_counter.state().get_field(|state| state.counter), 4);
+    let call = hardlight::ClientMessage::RPCRequest { id: 3, internal: vec![1, 2, 3] };
+    assert_eq!(hardlight::JsonTextCodec.encode_client(&call).unwrap(), br#"{"RPCRequest":{"id":3,"internal":"AQID"}}"#);
+    let changes = hardlight::ServerMessage::StateChange(vec![(COUNTER, 5u32.to_le_bytes().to_vec())]);
+    assert_eq!(hardlight::JsonTextCodec.encode_server(&changes).unwrap(), br#"{"StateChange":[[0,"BQAAAA=="]]}"#);
+    match Client::<CounterState>::new_self_signed("localhost:8090").connect().await {
+        Err(e @ ConnectError::CodecMismatch { .. }) => {
+            assert_eq!(e.to_string(), "codec mismatch: we use rkyv, server uses json");
+            info!("Connecting with the wrong codec failed as expected: {}", e)
+        }
+        Err(e) => panic!("expected a codec mismatch, got {}", e),
+        Ok(_) => panic!("expected a codec mismatch"),
+    }
+    json_counter.disconnect();
+    bincode_counter.disconnect();
+    text_counter.disconnect();
+
+    // closing sends the server a close frame and waits for its answer
+    counter.disconnect();
+    while !counter.connection.is_closed() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    info!("Disconnected");
+
     Ok(())
 }
 
+/// The token clients connect with. A real server would verify a signed token
+/// instead, e.g. a JWT
+const COUNTER_TOKEN: &str = "counter-token";
+
+/// The user a connection's token belongs to, attached to its Context
+#[derive(Clone)]
+struct User(String);
+
+struct CounterTokens;
+
+impl TokenValidator for CounterTokens {
+    fn validate(&self, token: &str, ctx: &Context) -> bool {
+        if token != COUNTER_TOKEN {
+            return false;
+        }
+        // a tenant given in the request's query has to match its header
+        if let Some(tenant) = ctx.query_param("tenant") {
+            if ctx.header("x-tenant-id").and_then(|v| v.to_str().ok()) != Some(tenant) {
+                return false;
+            }
+        }
+        ctx.extensions().insert(User("demo".to_string()));
+        true
+    }
+}
+
 #[async_trait]
 trait Counter {
     async fn increment(&self, amount: u32) -> HandlerResult<u32>;
@@ -86,6 +1275,25 @@ trait Counter {
 #[derive(Clone, Default)]
 struct CounterState {
     counter: u32,
+    /// How many times the counter has been changed
+    changes: u32,
+}
+
+// the ids the fields are sent as, which stay the same if they're renamed
+const COUNTER: FieldId = 0;
+const CHANGES: FieldId = 1;
+
+// how the client applies changes from the server, and how a ConnectionState
+// works out what changed
+impl_state!(CounterState { counter: u32 = COUNTER, changes: u32 = CHANGES });
+
+// application-defined errors, returned to the client as
+// RpcHandlerError::Application
+#[derive(Archive, Serialize, Deserialize, Debug)]
+#[archive_attr(derive(CheckBytes))]
+enum CounterError {
+    Overflow,
+    Underflow,
 }
 
 // enum Events {
@@ -106,10 +1314,23 @@ struct CounterHandler {
 }
 
 impl CounterHandler {
-    fn init(
-    ) -> impl Fn(StateUpdateChannel) -> Box<dyn Handler + Send + Sync> + Send + Sync + 'static + Copy
-    {
-        |state_update_channel| Box::new(Self::new(state_update_channel))
+    fn init() -> impl Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>
+           + Send
+           + Sync
+           + 'static {
+        |state_update_channel, ctx| Box::new(Self::new(state_update_channel, ctx))
+    }
+
+    /// Like init, but connections can only read the counter
+    fn init_read_only() -> impl Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync>
+           + Send
+           + Sync
+           + 'static {
+        |state_update_channel, ctx| {
+            let handler = Self::new(state_update_channel, ctx);
+            ctx.revoke_scope("counter.write");
+            Box::new(handler)
+        }
     }
 }
 
@@ -131,36 +1352,92 @@ struct DecrementArgs {
 #[archive_attr(derive(CheckBytes))]
 struct RpcCall {
     method: Method,
+    // aligned so the handler can read the args in place
+    #[with(Aligned)]
     args: Vec<u8>,
 }
 
 #[async_trait]
 impl Handler for CounterHandler {
-    fn new(state_update_channel: StateUpdateChannel) -> Self {
+    fn new(state_update_channel: StateUpdateChannel, ctx: &Context) -> Self {
+        if let Some(tls) = ctx.tls() {
+            info!("Client connected with {:?} using {:?}", tls.protocol_version, tls.cipher_suite);
+        }
+        // a real server would check the client's certificate or a token
+        ctx.grant_scope("counter.write");
         Self {
             state: Arc::new(CounterConnectionState::new(state_update_channel)),
         }
     }
 
-    async fn handle_rpc_call(&self, input: &[u8]) -> Result<Vec<u8>, RpcHandlerError> {
-        let call: RpcCall = rkyv::from_bytes(input).map_err(|_| RpcHandlerError::BadInputBytes)?;
+    fn method_name(&self, input: &[u8]) -> Option<&'static str> {
+        let call = rkyv::check_archived_root::<RpcCall>(input).ok()?;
+        Some(match call.method {
+            ArchivedMethod::Increment => "increment",
+            ArchivedMethod::Decrement => "decrement",
+            ArchivedMethod::Get => "get",
+        })
+    }
+
+    fn required_scope(&self, method: &'static str) -> Option<&'static str> {
+        match method {
+            "increment" | "decrement" => Some("counter.write"),
+            _ => None,
+        }
+    }
+
+    fn begin_transaction(&self, _ctx: &Context) -> HandlerResult<()> {
+        *self.state.snapshot.lock() = Some(self.state.state.lock().clone());
+        Ok(())
+    }
+
+    fn commit_transaction(&self, _ctx: &Context) -> HandlerResult<()> {
+        self.state.snapshot.lock().take();
+        Ok(())
+    }
+
+    fn rollback_transaction(&self, _ctx: &Context) -> HandlerResult<()> {
+        if let Some(snapshot) = self.state.snapshot.lock().take() {
+            let mut state = self.state.lock();
+            *state = snapshot;
+            // the client never saw the changes, so there's nothing to undo
+            state.suppress();
+        }
+        Ok(())
+    }
+
+    async fn handle_call(&self, call: &CallContext<'_>, input: &[u8]) -> HandlerResult<Vec<u8>> {
+        debug!(id = call.id(), deadline = ?call.deadline(), "Handling call");
+        // nobody is waiting for the output any more
+        if call.deadline().is_some_and(|deadline| deadline <= Instant::now()) || call.cancellation().is_cancelled() {
+            return Err(RpcHandlerError::Timeout);
+        }
+        let ctx = call.connection();
+        // the claims the token validator attached, if the server checks tokens
+        if let Some(User(name)) = ctx.extensions().get::<User>() {
+            debug!("Call from {}", name);
+        }
+        // read the call in place rather than deserializing a copy of it
+        let call = rkyv::check_archived_root::<RpcCall>(input).map_err(|_| RpcHandlerError::BadInputBytes)?;
 
         match call.method {
-            Method::Increment => {
-                let args: IncrementArgs =
-                    rkyv::from_bytes(&call.args).map_err(|_| RpcHandlerError::BadInputBytes)?;
+            ArchivedMethod::Increment => {
+                let args = rkyv::check_archived_root::<IncrementArgs>(&call.args)
+                    .map_err(|_| RpcHandlerError::BadInputBytes)?;
                 let result = self.increment(args.amount).await?;
                 let result = rkyv::to_bytes::<u32, 1024>(&result).unwrap();
                 Ok(result.to_vec())
             }
-            Method::Decrement => {
-                let args: DecrementArgs =
-                    rkyv::from_bytes(&call.args).map_err(|_| RpcHandlerError::BadInputBytes)?;
+            ArchivedMethod::Decrement => {
+                let args = rkyv::check_archived_root::<DecrementArgs>(&call.args)
+                    .map_err(|_| RpcHandlerError::BadInputBytes)?;
                 let result = self.decrement(args.amount).await?;
                 let result = rkyv::to_bytes::<u32, 1024>(&result).unwrap();
                 Ok(result.to_vec())
             }
-            Method::Get => {
+            ArchivedMethod::Get => {
+                // no arguments, so these are the zero bytes of a serialized ()
+                let () = rkyv::from_bytes(call.args.as_slice()).map_err(|_| RpcHandlerError::BadInputBytes)?;
                 let result = self.get().await?;
                 let result = rkyv::to_bytes::<u32, 1024>(&result).unwrap();
                 Ok(result.to_vec())
@@ -174,14 +1451,25 @@ impl Counter for CounterHandler {
     async fn increment(&self, amount: u32) -> HandlerResult<u32> {
         // lock the state to the current thread
         let mut state: StateGuard = self.state.lock();
-        state.counter += amount;
+        state.counter = state
+            .counter
+            .checked_add(amount)
+            .ok_or_else(|| RpcHandlerError::application(&CounterError::Overflow))?;
+        state.changes += 1;
+        // changes can be sent before unlocking, too. dropping the guard then
+        // only sends whatever changes after this
+        state.commit();
         Ok(state.counter)
     } // state is automatically unlocked here; any changes are sent to the client
       // automagically ✨
 
     async fn decrement(&self, amount: u32) -> HandlerResult<u32> {
         let mut state = self.state.lock();
-        state.counter -= amount;
+        state.counter = state
+            .counter
+            .checked_sub(amount)
+            .ok_or_else(|| RpcHandlerError::application(&CounterError::Underflow))?;
+        state.changes += 1;
         Ok(state.counter)
     }
 
@@ -200,7 +1488,9 @@ struct CounterConnectionState {
     /// The channel is given by the runtime when it creates the connection,
     /// allowing us to tell the runtime when the connection's state is modified
     /// so it can send the changes to the client automatically
-    channel: Arc<mpsc::Sender<Vec<(String, Vec<u8>)>>>,
+    channel: Arc<StateUpdateChannel>,
+    /// The state as it was when the open transaction began, if there is one
+    snapshot: Mutex<Option<CounterState>>,
 }
 
 impl CounterConnectionState {
@@ -209,17 +1499,19 @@ impl CounterConnectionState {
             // use default values for the state
             state: Mutex::new(Default::default()),
             channel: Arc::new(channel),
+            snapshot: Mutex::new(None),
         }
     }
 
     /// locks the state to the current thread by providing a StateGuard
     /// the StateGuard gets
-    fn lock(&self) -> StateGuard {
+    fn lock(&self) -> StateGuard<'_> {
         let state = self.state.lock();
         StateGuard {
             starting_state: state.clone(),
             state,
             channel: self.channel.clone(),
+            suppressed: false,
         }
     }
 }
@@ -229,40 +1521,73 @@ impl CounterConnectionState {
 struct StateGuard<'a> {
     /// The StateGuard is given ownership of a lock to the state
     state: MutexGuard<'a, CounterState>,
-    /// A copy of the state before we locked it
+    /// A copy of the state before we locked it, or as of the last commit
     /// We use this to compare changes when the StateGuard is dropped
     starting_state: CounterState,
     /// A channel pointer that we can use to send changes to the runtime
     /// which will handle sending them to the client
-    channel: Arc<mpsc::Sender<Vec<(String, Vec<u8>)>>>,
+    channel: Arc<StateUpdateChannel>,
+    /// Set by suppress(), so nothing is sent when the guard is dropped
+    suppressed: bool,
 }
 
-impl<'a> Drop for StateGuard<'a> {
-    /// Our custom drop implementation will send any changes to the runtime
-    fn drop(&mut self) {
+impl StateGuard<'_> {
+    /// Sends the changes made so far straight away, as one StateChange.
+    /// Dropping the guard afterwards only sends changes made after this
+    fn commit(&mut self) {
         // "diff" the two states to see what changed
         let mut changes = Vec::new();
 
         if self.state.counter != self.starting_state.counter {
             changes.push((
-                "counter".to_string(),
+                COUNTER,
                 rkyv::to_bytes::<u32, 1024>(&self.state.counter)
                     .unwrap()
                     .to_vec(),
             ));
         }
 
+        if self.state.changes != self.starting_state.changes {
+            changes.push((
+                CHANGES,
+                rkyv::to_bytes::<u32, 1024>(&self.state.changes)
+                    .unwrap()
+                    .to_vec(),
+            ));
+        }
+
         // if there are no changes, don't bother sending anything
         if changes.is_empty() {
             return;
         }
+        self.starting_state = self.state.clone();
 
-        // send the changes to the runtime
-        // we have to spawn a new task because we can't await inside a drop
-        let channel = self.channel.clone();
-        tokio::spawn(async move {
-            channel.send(changes).await.unwrap();
-        });
+        // send the changes to the runtime. this can't wait, as the state is
+        // locked with a blocking mutex, so if the client is so far behind
+        // that the channel is full, the changes are sent from a task of
+        // their own. ConnectionState's commit().await waits instead, keeping
+        // them in order
+        if let Err(TrySendError::Full(changes)) = self.channel.try_send(changes) {
+            let channel = self.channel.clone();
+            tokio::spawn(async move { channel.send(changes).await });
+        }
+    }
+
+    /// Stops the guard from sending anything when it's dropped, e.g. for a
+    /// transient change that's undone before unlocking, or one the client
+    /// already knows about. Changes made while suppressed are never sent, so
+    /// the client's copy of the state won't have them
+    fn suppress(&mut self) {
+        self.suppressed = true;
+    }
+}
+
+impl<'a> Drop for StateGuard<'a> {
+    /// Our custom drop implementation will send any changes to the runtime
+    fn drop(&mut self) {
+        if !self.suppressed {
+            self.commit();
+        }
     }
 }
 
@@ -282,107 +1607,36 @@ impl DerefMut for StateGuard<'_> {
     }
 }
 
-// RPC client that implements the Counter trait
+// RPC client that implements the Counter trait. It only exists once
+// connected, so there's no calling it too early
+#[derive(Clone)]
 struct CounterClient {
-    host: String,
-    self_signed: bool,
-    shutdown: Option<oneshot::Sender<()>>,
-    rpc_tx: Option<mpsc::Sender<(Vec<u8>, oneshot::Sender<Result<Vec<u8>, RpcHandlerError>>)>>,
+    connection: Connection<CounterState>,
 }
 
 impl CounterClient {
-    pub fn new_self_signed(host: &str) -> Self {
-        Self {
-            host: host.to_string(),
-            self_signed: true,
-            shutdown: None,
-            rpc_tx: None,
-        }
-    }
-
-    pub fn new(host: &str) -> Self {
-        Self {
-            host: host.to_string(),
-            self_signed: false,
-            shutdown: None,
-            rpc_tx: None,
-        }
+    pub async fn connect(mut client: Client<CounterState>) -> Result<Self, ConnectError> {
+        // retry calls the server turned away before running them
+        client.set_retry_classifier(Arc::new(DefaultRetryClassifier::default()));
+        Ok(Self {
+            connection: client.connect().await?,
+        })
     }
 
-    pub async fn connect(&mut self) -> Result<(), tungstenite::Error> {
-        let (shutdown, shutdown_rx) = oneshot::channel();
-        let (control_channels_tx, control_channels_rx) = oneshot::channel();
-        let (error_tx, error_rx) = oneshot::channel();
-        let (ok_tx, ok_rx) = oneshot::channel();
-
-        let self_signed = self.self_signed;
-        let host = self.host.clone();
-
-        tokio::spawn(async move {
-            let mut client: Client<CounterState> = if self_signed {
-                Client::new_self_signed(&host)
-            } else {
-                Client::new(&host)
-            };
-
-            if let Err(e) = client
-                .connect(shutdown_rx, control_channels_tx, ok_tx)
-                .await
-            {
-                error_tx.send(e).unwrap()
-            };
-        });
-
-        select! {
-            _ = ok_rx => {
-                // at this point, the client will NOT return any errors, so we
-                // can safely ignore the error_rx channel
-                debug!("Ok received from client")
-            }
-            e = error_rx => {
-                error!("Error received from client: {:?}", e);
-                return Err(e.unwrap());
-            }
-        }
-
-        let (rpc_tx,) = control_channels_rx.await.unwrap();
-
-        self.shutdown = Some(shutdown);
-        self.rpc_tx = Some(rpc_tx);
-        Ok(())
+    /// The connection state, as last pushed by the server.
+    pub fn state(&self) -> &StateHandle<CounterState> {
+        &self.connection.channels().state
     }
 
-    pub fn disconnect(&mut self) {
-        match self.shutdown.take() {
-            Some(shutdown) => {
-                let _ = shutdown.send(());
-            }
-            None => {}
-        }
+    pub fn disconnect(&self) {
+        self.connection.close();
     }
 
     async fn handle_rpc_call(&self, method: Method, args: Vec<u8>) -> HandlerResult<Vec<u8>> {
-        if let Some(rpc_chan) = self.rpc_tx.clone() {
-            let (tx, rx) = oneshot::channel();
-            rpc_chan
-                .send((
-                    rkyv::to_bytes::<RpcCall, 1024>(&RpcCall { method, args })
-                        .map_err(|_| RpcHandlerError::BadInputBytes)?
-                        .to_vec(),
-                    tx,
-                ))
-                .await
-                .unwrap();
-            rx.await.unwrap()
-        } else {
-            Err(RpcHandlerError::ClientNotConnected)
-        }
-    }
-}
-
-impl Drop for CounterClient {
-    fn drop(&mut self) {
-        self.disconnect();
+        let input = rkyv::to_bytes::<RpcCall, 1024>(&RpcCall { method, args })
+            .map_err(|_| RpcHandlerError::BadInputBytes)?
+            .to_vec();
+        self.connection.call(input).await
     }
 }
 
@@ -425,19 +1679,497 @@ impl Counter for CounterClient {
     }
 }
 
-impl State for CounterState {
-    fn apply_changes(&mut self, changes: Vec<(String, Vec<u8>)>) -> HandlerResult<()> {
+/// How many of [SlowHandler]'s calls have ended, however they ended.
+static SLOW_CALLS_STOPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Waits for `count` of [SlowHandler]'s calls to have ended, which should be
+/// well before the 10 seconds they take.
+async fn wait_for_slow_calls_stopped(count: usize, since: Instant) {
+    while SLOW_CALLS_STOPPED.load(Ordering::SeqCst) < count {
+        assert!(since.elapsed() < Duration::from_secs(2), "slow handler wasn't stopped");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+/// Counts a [SlowHandler] call as ended when it's dropped, including when
+/// the handler is stopped partway.
+struct SlowCall;
+
+impl Drop for SlowCall {
+    fn drop(&mut self) {
+        SLOW_CALLS_STOPPED.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// A handler whose calls and events take 10 seconds, unless they're
+/// cancelled. The event `stubborn` ignores cancellation.
+struct SlowHandler;
+
+#[async_trait]
+impl Handler for SlowHandler {
+    fn new(_state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self
+    }
+
+    async fn handle_call(&self, call: &CallContext<'_>, _input: &[u8]) -> HandlerResult<Vec<u8>> {
+        let _call = SlowCall;
+        select! {
+            _ = tokio::time::sleep(Duration::from_secs(10)) => Ok(vec![]),
+            _ = call.cancellation().cancelled() => Err(RpcHandlerError::Timeout),
+        }
+    }
+
+    async fn handle_event(&self, ctx: &Context, payload: &[u8]) {
+        let _event = SlowCall;
+        if payload == b"stubborn" {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            return;
+        }
+        select! {
+            _ = tokio::time::sleep(Duration::from_secs(10)) => {}
+            _ = ctx.cancellation().cancelled() => {}
+        }
+    }
+}
+
+/// A handler that counts the events it's sent.
+struct EventCounter {
+    handled: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl Handler for EventCounter {
+    fn new(_state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self {
+            handled: Arc::default(),
+        }
+    }
+
+    async fn handle_event(&self, _ctx: &Context, _payload: &[u8]) {
+        self.handled.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// A handler whose calls each answer with 64 KiB, counting the calls.
+struct BulkResponder {
+    handled: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl Handler for BulkResponder {
+    fn new(_state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self {
+            handled: Arc::default(),
+        }
+    }
+
+    async fn handle_rpc_call(&self, _ctx: &Context, _input: &[u8]) -> HandlerResult<Vec<u8>> {
+        self.handled.fetch_add(1, Ordering::SeqCst);
+        Ok(vec![7; 64 * 1024])
+    }
+}
+
+/// Collects how long calls waited before their handler started.
+#[derive(Default)]
+struct QueueWaits(Mutex<Vec<Duration>>);
+
+impl ServerMetricsHook for QueueWaits {
+    fn on_call_queued(&self, _method: Option<&'static str>, wait: Duration) {
+        self.0.lock().push(wait);
+    }
+}
+
+/// Collects how many bytes each message took on the wire.
+#[derive(Default)]
+struct BytesOut(Mutex<Vec<usize>>);
+
+impl ServerMetricsHook for BytesOut {
+    fn on_bytes_out(&self, bytes: usize) {
+        self.0.lock().push(bytes);
+    }
+}
+
+/// How big a [ReportHandler]'s report is, in bytes.
+const REPORT_SIZE: usize = 100 * 1024;
+
+/// A state with one large field, e.g. a report of many similar records.
+#[derive(Clone, Default)]
+struct ReportState {
+    report: String,
+}
+
+impl_state!(ReportState { report: String = 0 });
+
+/// A handler whose calls fill the state with a report, which compresses
+/// well.
+struct ReportHandler {
+    state: ConnectionState<ReportState>,
+}
+
+#[async_trait]
+impl Handler for ReportHandler {
+    fn new(state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self {
+            state: ConnectionState::new(state_update_channel, ReportState::default()),
+        }
+    }
+
+    async fn handle_rpc_call(&self, _ctx: &Context, _input: &[u8]) -> HandlerResult<Vec<u8>> {
+        let mut state = self.state.lock().await;
+        state.report = report();
+        state.commit().await?;
+        Ok(vec![])
+    }
+}
+
+/// [REPORT_SIZE] bytes of JSON records, much like one another.
+fn report() -> String {
+    let mut report = String::new();
+    let mut id = 0;
+    while report.len() < REPORT_SIZE {
+        report += &format!(r#"{{"id":{id},"status":"ok","retries":0}},"#);
+        id += 1;
+    }
+    report.truncate(REPORT_SIZE);
+    report
+}
+
+/// How long a [NappingHandler]'s calls take.
+const NAP: Duration = Duration::from_millis(200);
+
+/// A handler whose calls take a moment.
+struct NappingHandler;
+
+#[async_trait]
+impl Handler for NappingHandler {
+    fn new(_state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self
+    }
+
+    async fn handle_rpc_call(&self, _ctx: &Context, _input: &[u8]) -> HandlerResult<Vec<u8>> {
+        tokio::time::sleep(NAP).await;
+        Ok(vec![])
+    }
+}
+
+/// How many calls are running at once, and the most there have been.
+#[derive(Default)]
+struct Concurrency {
+    running: AtomicUsize,
+    highest: AtomicUsize,
+}
+
+/// A handler whose calls take a moment, keeping track of how many run at
+/// once.
+struct ConcurrencyTracker(Arc<Concurrency>);
+
+#[async_trait]
+impl Handler for ConcurrencyTracker {
+    fn new(_state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self(Arc::default())
+    }
+
+    async fn handle_rpc_call(&self, _ctx: &Context, _input: &[u8]) -> HandlerResult<Vec<u8>> {
+        let running = self.0.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.0.highest.fetch_max(running, Ordering::SeqCst);
+        tokio::time::sleep(NAP / 4).await;
+        self.0.running.fetch_sub(1, Ordering::SeqCst);
+        Ok(vec![])
+    }
+}
+
+/// How many state changes a [BurstHandler] sends.
+const BURST_SIZE: u32 = 100;
+
+/// A handler that sends a burst of state changes, a millisecond apart, as
+/// soon as it's created, and resets the counter when it's called.
+struct BurstHandler {
+    state: Arc<ConnectionState<CounterState>>,
+}
+
+#[async_trait]
+impl Handler for BurstHandler {
+    fn new(state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        let state = Arc::new(ConnectionState::new(state_update_channel, CounterState::default()));
+        let burst = state.clone();
+        tokio::spawn(async move {
+            for _ in 0..BURST_SIZE {
+                burst.lock().await.counter += 1;
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        });
+        Self { state }
+    }
+
+    async fn handle_rpc_call(&self, _ctx: &Context, _input: &[u8]) -> HandlerResult<Vec<u8>> {
+        let mut state = self.state.lock().await;
+        state.counter = 0;
+        state.commit().await?;
+        Ok(vec![])
+    }
+}
+
+/// A [CounterState] that counts the batches of changes it's had.
+#[derive(Clone, Default)]
+struct BatchCountingState {
+    counter: CounterState,
+    batches: u32,
+}
+
+impl State for BatchCountingState {
+    fn apply_changes(&mut self, changes: Vec<(FieldId, Vec<u8>)>) -> HandlerResult<()> {
+        self.batches += 1;
+        self.counter.apply_changes(changes)
+    }
+}
+
+// the client's view of a [SharedCountHandler], whose ids are shared by the
+// connection's own state and the shared one
+const MINE: FieldId = 0;
+const TOTAL: FieldId = 1;
+
+#[derive(Clone, Default)]
+struct CombinedCount {
+    mine: u32,
+    total: u32,
+}
+
+impl_state!(CombinedCount { mine: u32 = MINE, total: u32 = TOTAL });
+
+/// The part of a [CombinedCount] that's each connection's own.
+#[derive(Clone, Default)]
+struct OwnCount {
+    mine: u32,
+}
+
+state_diff!(OwnCount { mine = MINE });
+
+/// The part of a [CombinedCount] that every connection shares.
+#[derive(Clone, Default)]
+struct TotalCount {
+    total: u32,
+}
+
+state_diff!(TotalCount { total = TOTAL });
+
+/// A handler that counts its calls, both for its own connection and in a
+/// total shared by every connection.
+struct SharedCountHandler {
+    own: ConnectionState<OwnCount>,
+    total: Arc<SharedState<TotalCount>>,
+}
+
+impl SharedCountHandler {
+    /// A factory whose handlers are all subscribed to `total`.
+    fn init(total: Arc<SharedState<TotalCount>>) -> impl Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync> + Send + Sync + 'static {
+        move |state_update_channel, ctx| {
+            total.subscribe(ctx);
+            Box::new(Self {
+                own: ConnectionState::new(state_update_channel, OwnCount::default()),
+                total: total.clone(),
+            })
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for SharedCountHandler {
+    fn new(state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self {
+            own: ConnectionState::new(state_update_channel, OwnCount::default()),
+            total: Arc::new(SharedState::new(TotalCount::default())),
+        }
+    }
+
+    async fn handle_rpc_call(&self, _ctx: &Context, _input: &[u8]) -> HandlerResult<Vec<u8>> {
+        self.own.lock().await.mine += 1;
+        self.total.lock().await.total += 1;
+        Ok(vec![])
+    }
+}
+
+/// A handler that echoes its input after a moment, failing every seventh
+/// call, and whose [Handler::method_name] panics for the input `panic`.
+struct ChurnHandler;
+
+#[async_trait]
+impl Handler for ChurnHandler {
+    fn new(_state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self
+    }
+
+    async fn handle_rpc_call(&self, _ctx: &Context, input: &[u8]) -> HandlerResult<Vec<u8>> {
+        let n = u32::from_le_bytes(input.try_into().map_err(|_| RpcHandlerError::BadInputBytes)?);
+        tokio::time::sleep(Duration::from_micros((n % 5) as u64 * 100)).await;
+        if n % 7 == 0 {
+            return Err(RpcHandlerError::BadInputBytes);
+        }
+        Ok(input.to_vec())
+    }
+
+    fn method_name(&self, input: &[u8]) -> Option<&'static str> {
+        assert!(input != b"panic", "method_name panicked");
+        Some("echo")
+    }
+}
+
+/// Passes every call on to the client, and returns its answer.
+struct AskClientHandler;
+
+#[async_trait]
+impl Handler for AskClientHandler {
+    fn new(_state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self
+    }
+
+    async fn handle_rpc_call(&self, ctx: &Context, input: &[u8]) -> HandlerResult<Vec<u8>> {
+        ctx.call_client(input.to_vec()).await
+    }
+}
+
+/// How long [LateAnswers] takes to answer a slow call.
+const LATE_ANSWER_DELAY: Duration = Duration::from_millis(300);
+
+/// Answers calls from the server with their own input, after the server has
+/// stopped waiting if the input is `slow`.
+struct LateAnswers;
+
+#[async_trait]
+impl ClientHandler for LateAnswers {
+    async fn handle_rpc_call(&self, input: &[u8]) -> HandlerResult<Vec<u8>> {
+        if input == b"slow" {
+            tokio::time::sleep(LATE_ANSWER_DELAY).await;
+        }
+        Ok(input.to_vec())
+    }
+}
+
+/// How many items the inventories compared above hold.
+const INVENTORY_SIZE: u32 = 10_000;
+
+const ITEMS: FieldId = 0;
+const REVISION: FieldId = 1;
+
+/// A large state, compared by cloning it.
+#[derive(Clone)]
+struct PlainInventory {
+    items: Vec<u32>,
+    revision: u32,
+}
+
+state_diff!(PlainInventory { items = ITEMS, revision = REVISION });
+
+/// The same state, with fields that track their own changes.
+#[derive(Default)]
+struct TrackedInventory {
+    items: Changed<Vec<u32>>,
+    revision: Changed<u32>,
+}
+
+track_changes!(TrackedInventory { items = ITEMS, revision = REVISION });
+
+/// A handler that counts its calls in a [ConnectionState], and across every
+/// connection in a total shared by the server.
+struct TallyHandler {
+    state: ConnectionState<CounterState>,
+    total: Arc<AtomicUsize>,
+}
+
+impl TallyHandler {
+    /// A factory whose handlers all count towards `total`.
+    fn init(total: Arc<AtomicUsize>) -> impl Fn(StateUpdateChannel, &Context) -> Box<dyn Handler + Send + Sync> + Send + Sync + 'static {
+        move |state_update_channel, _ctx| {
+            Box::new(Self {
+                state: ConnectionState::new(state_update_channel, CounterState::default()),
+                total: total.clone(),
+            })
+        }
+    }
+}
+
+#[async_trait]
+impl Handler for TallyHandler {
+    fn new(state_update_channel: StateUpdateChannel, _ctx: &Context) -> Self {
+        Self {
+            state: ConnectionState::new(state_update_channel, CounterState::default()),
+            total: Arc::default(),
+        }
+    }
+
+    async fn handle_rpc_call(&self, _ctx: &Context, _input: &[u8]) -> HandlerResult<Vec<u8>> {
+        let mut state = self.state.lock().await;
+        state.counter += 1;
+        state.changes += 1;
+        // sent now, rather than whenever the guard happens to be dropped, and
+        // waiting for the client if it's behind
+        state.commit().await?;
+        self.total.fetch_add(1, Ordering::SeqCst);
+        Ok(rkyv::to_bytes::<u32, 1024>(&state.counter).unwrap().to_vec())
+    }
+}
+
+/// A client's view of the counter from before it counted changes, which is
+/// strict about fields it doesn't have.
+#[derive(Clone, Default)]
+struct CounterOnlyState {
+    counter: u32,
+}
+
+impl State for CounterOnlyState {
+    fn apply_changes(&mut self, changes: Vec<(FieldId, Vec<u8>)>) -> HandlerResult<()> {
         for (field, new_value) in changes {
-            match field.as_ref() {
-                "counter" => {
-                    self.counter =
-                        rkyv::from_bytes(&new_value).map_err(|_| RpcHandlerError::BadInputBytes)?
-                }
-                _ => {}
+            match field {
+                COUNTER => self.counter = rkyv::from_bytes(&new_value).map_err(|_| RpcHandlerError::BadInputBytes)?,
+                _ => self.unknown_field(field)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn unknown_field(&mut self, field: FieldId) -> HandlerResult<()> {
+        Err(RpcHandlerError::UnknownStateField(field))
+    }
+}
+
+/// How many times a [CopyCountingState] has been copied.
+static STATE_COPIES: AtomicUsize = AtomicUsize::new(0);
+
+/// A [CounterState] that counts how many times it's copied.
+#[derive(Default)]
+struct CopyCountingState {
+    counter: u32,
+    changes: u32,
+}
+
+impl Clone for CopyCountingState {
+    fn clone(&self) -> Self {
+        STATE_COPIES.fetch_add(1, Ordering::SeqCst);
+        Self {
+            counter: self.counter,
+            changes: self.changes,
+        }
+    }
+}
+
+impl_state!(CopyCountingState { counter: u32 = COUNTER, changes: u32 = CHANGES });
+
+/// A [CounterState] that refuses changes to fields it doesn't have.
+struct StrictCounterState(CounterState);
+
+impl State for StrictCounterState {
+    fn apply_changes(&mut self, changes: Vec<(FieldId, Vec<u8>)>) -> HandlerResult<()> {
+        for (field, new_value) in changes {
+            match field {
+                COUNTER | CHANGES => self.0.apply_changes(vec![(field, new_value)])?,
+                _ => self.unknown_field(field)?,
             }
         }
         Ok(())
     }
+
+    fn unknown_field(&mut self, field: FieldId) -> HandlerResult<()> {
+        Err(RpcHandlerError::UnknownStateField(field))
+    }
 }
 
 // we need to be able to serialise and deserialise the method enum
@@ -451,3 +2183,94 @@ enum Method {
     Decrement,
     Get,
 }
+
+/// Starts a counter server on `address` that encodes messages with `codec`,
+/// and connects a client that does too.
+async fn counter_with_codec(address: &str, codec: impl Codec + Clone + 'static) -> CounterClient {
+    let mut config = ServerConfig::new_self_signed(address);
+    config.codec = Arc::new(codec.clone());
+    let server = Server::new(config, CounterHandler::init());
+    let (ready_tx, ready_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let _ = server.run_with_ready(ready_tx).await;
+    });
+    ready_rx.await.expect("server failed to start");
+    let mut client = Client::new_self_signed(address);
+    client.set_codec(codec);
+    CounterClient::connect(client).await.expect("connecting failed")
+}
+
+/// A bare-bones HTTP proxy that opens tunnels for clients logging in as
+/// `user:p@ss`, counting them in `tunnels`.
+async fn start_http_proxy(tunnels: Arc<AtomicUsize>) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            let tunnels = tunnels.clone();
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(client.read_u8().await?);
+                }
+                let head = String::from_utf8_lossy(&head).to_string();
+                let target = head.split(' ').nth(1).unwrap_or_default().to_string();
+                if !head.contains("Proxy-Authorization: Basic dXNlcjpwQHNz\r\n") {
+                    return client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n").await;
+                }
+                let mut server = tokio::net::TcpStream::connect(target).await?;
+                tunnels.fetch_add(1, Ordering::SeqCst);
+                client.write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n").await?;
+                tokio::io::copy_bidirectional(&mut client, &mut server).await.map(|_| ())
+            });
+        }
+    });
+    addr
+}
+
+/// A bare-bones SOCKS5 proxy without authentication that only connects to
+/// host names, counting its tunnels in `tunnels`.
+async fn start_socks5_proxy(tunnels: Arc<AtomicUsize>) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            let tunnels = tunnels.clone();
+            tokio::spawn(async move {
+                let mut greeting = [0; 2];
+                client.read_exact(&mut greeting).await?;
+                let mut methods = vec![0; greeting[1] as usize];
+                client.read_exact(&mut methods).await?;
+                client.write_all(&[5, 0]).await?;
+                let mut request = [0; 5];
+                client.read_exact(&mut request).await?;
+                assert_eq!(request[3], 3, "expected a host name");
+                let mut host = vec![0; request[4] as usize];
+                client.read_exact(&mut host).await?;
+                let port = client.read_u16().await?;
+                let host = String::from_utf8_lossy(&host).to_string();
+                let mut server = tokio::net::TcpStream::connect((host.as_str(), port)).await?;
+                tunnels.fetch_add(1, Ordering::SeqCst);
+                client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+                tokio::io::copy_bidirectional(&mut client, &mut server).await.map(|_| ())
+            });
+        }
+    });
+    addr
+}
+
+/// Accepts a HardLight handshake on a bare tungstenite socket, for a test
+/// server that doesn't run the protocol.
+struct PickCurrentVersion;
+
+impl hardlight::tungstenite::handshake::server::Callback for PickCurrentVersion {
+    fn on_request(
+        self,
+        _request: &hardlight::tungstenite::handshake::server::Request,
+        mut response: hardlight::tungstenite::handshake::server::Response,
+    ) -> Result<hardlight::tungstenite::handshake::server::Response, hardlight::tungstenite::handshake::server::ErrorResponse> {
+        let protocol = format!("hl/{PROTOCOL_VERSION}");
+        response.headers_mut().insert("Sec-WebSocket-Protocol", protocol.parse().unwrap());
+        Ok(response)
+    }
+}
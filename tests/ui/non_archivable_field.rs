@@ -0,0 +1,18 @@
+use hardlight::assert_state_fields;
+
+/// Doesn't derive rkyv's traits, so it can't be sent as a state field.
+#[derive(Default)]
+struct Cursor {
+    line: u32,
+    column: u32,
+}
+
+#[derive(Default)]
+struct EditorState {
+    revision: u32,
+    cursor: Cursor,
+}
+
+assert_state_fields!(EditorState { revision: u32, cursor: Cursor });
+
+fn main() {}